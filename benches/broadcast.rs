@@ -0,0 +1,127 @@
+//! Throughput of publishing to many subscribed peers, from `broadcast` until
+//! the behaviour handed the message to all their handlers.
+//!
+//! The queues of all peers share one `Message::Broadcast`, each handler
+//! takes its own copy on its connection's task. The `queue` benchmarks
+//! compare this to cloning the message for every peer.
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use libp2p::core::connection::ConnectionId;
+use libp2p::core::{ConnectedPoint, Endpoint};
+use libp2p::identity::Keypair;
+use libp2p::swarm::{AddressRecord, NetworkBehaviour, PollParameters};
+use libp2p::{Multiaddr, PeerId};
+use libp2p_broadcast::{
+    Broadcast, BroadcastConfig, Extensions, HandlerEvent, Message, Signature, Topic,
+};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::task::Context;
+
+struct Params(PeerId);
+
+impl PollParameters for Params {
+    type SupportedProtocolsIter = std::iter::Empty<Vec<u8>>;
+    type ListenedAddressesIter = std::iter::Empty<Multiaddr>;
+    type ExternalAddressesIter = std::iter::Empty<AddressRecord>;
+
+    fn supported_protocols(&self) -> Self::SupportedProtocolsIter {
+        std::iter::empty()
+    }
+
+    fn listened_addresses(&self) -> Self::ListenedAddressesIter {
+        std::iter::empty()
+    }
+
+    fn external_addresses(&self) -> Self::ExternalAddressesIter {
+        std::iter::empty()
+    }
+
+    fn local_peer_id(&self) -> &PeerId {
+        &self.0
+    }
+}
+
+/// A behaviour connected to `peers` peers, each subscribed to `topics`
+/// topics including the published one.
+fn setup(peers: usize, topics: usize) -> (Broadcast, Topic) {
+    let topic = Topic::new(b"topic");
+    let mut broadcast = Broadcast::new(BroadcastConfig::default());
+    broadcast.subscribe(topic).unwrap().detach();
+    let endpoint = ConnectedPoint::Dialer {
+        address: "/memory/1".parse().unwrap(),
+        role_override: Endpoint::Dialer,
+    };
+    for i in 0..peers {
+        let peer = PeerId::random();
+        let id = ConnectionId::new(i);
+        broadcast.inject_connection_established(&peer, &id, &endpoint, None, 0);
+        let subscriptions = (1..topics)
+            .map(|i| Topic::new(format!("topic-{}", i).as_bytes()))
+            .chain(std::iter::once(topic))
+            .collect();
+        broadcast.inject_event(
+            peer,
+            id,
+            HandlerEvent::Rx(Message::SubscribeMany(subscriptions)),
+        );
+    }
+    (broadcast, topic)
+}
+
+/// Polls the behaviour until it has nothing left to do.
+fn drain(broadcast: &mut Broadcast, cx: &mut Context, params: &mut Params) {
+    while broadcast.poll(cx, params).is_ready() {}
+}
+
+fn publish(c: &mut Criterion) {
+    let waker = futures::task::noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    let mut params = Params(PeerId::random());
+    let mut group = c.benchmark_group("publish");
+    for peers in [10, 100] {
+        for topics in [1, 100] {
+            for size in [64, 1024, 16 * 1024] {
+                let (mut broadcast, topic) = setup(peers, topics);
+                drain(&mut broadcast, &mut cx, &mut params);
+                let payload = Bytes::from(vec![0; size]);
+                group.throughput(Throughput::Bytes((size * peers) as u64));
+                let id = BenchmarkId::from_parameter(format!("{}/{}/{}", peers, topics, size));
+                group.bench_function(id, |b| {
+                    b.iter(|| {
+                        broadcast.broadcast(&topic, payload.clone()).unwrap();
+                        drain(&mut broadcast, &mut cx, &mut params);
+                    })
+                });
+            }
+        }
+    }
+    group.finish();
+}
+
+/// Queueing a signed broadcast for each peer, as a copy of the message or as
+/// a reference to a shared one.
+fn queue(c: &mut Criterion) {
+    let keypair = Keypair::generate_ed25519();
+    let topic = Topic::new(b"topic");
+    let payload = Bytes::from(vec![0; 1024]);
+    let ext = Extensions {
+        signature: Some(Signature::sign(&keypair, &topic, &payload).unwrap()),
+        ..Default::default()
+    };
+    let msg = Message::Broadcast(topic, ext, payload);
+    let shared = Arc::new(msg.clone());
+    let mut group = c.benchmark_group("queue");
+    for peers in [10, 100] {
+        group.bench_function(BenchmarkId::new("cloned", peers), |b| {
+            b.iter(|| (0..peers).map(|_| msg.clone()).collect::<VecDeque<_>>())
+        });
+        group.bench_function(BenchmarkId::new("shared", peers), |b| {
+            b.iter(|| (0..peers).map(|_| shared.clone()).collect::<VecDeque<_>>())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, publish, queue);
+criterion_main!(benches);
@@ -0,0 +1,62 @@
+use crate::protocol::MessageId;
+use fnv::FnvHashMap;
+use futures::FutureExt;
+use futures_timer::Delay;
+use instant::Instant;
+use libp2p::PeerId;
+use std::collections::VecDeque;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// Acknowledgements expected from peers, expiring after a fixed timeout.
+#[derive(Default)]
+pub(crate) struct PendingAcks {
+    timeout: Duration,
+    /// Time the messages were sent.
+    pending: FnvHashMap<(PeerId, MessageId), Instant>,
+    /// Deadlines in the order they expire.
+    deadlines: VecDeque<(Instant, PeerId, MessageId)>,
+    timer: Option<Delay>,
+}
+
+impl PendingAcks {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            ..Default::default()
+        }
+    }
+
+    pub fn insert(&mut self, peer: PeerId, id: MessageId) {
+        let now = Instant::now();
+        self.pending.insert((peer, id), now);
+        self.deadlines.push_back((now + self.timeout, peer, id));
+    }
+
+    /// Removes an expected ack, returning the round trip time of the
+    /// message or `None` if the ack wasn't expected.
+    pub fn remove(&mut self, peer: PeerId, id: MessageId) -> Option<Duration> {
+        let sent = self.pending.remove(&(peer, id))?;
+        Some(sent.elapsed())
+    }
+
+    /// Returns the next ack that timed out.
+    pub fn poll_expired(&mut self, cx: &mut Context) -> Poll<(PeerId, MessageId)> {
+        let now = Instant::now();
+        while let Some((deadline, peer, id)) = self.deadlines.front().copied() {
+            if deadline > now {
+                let timer = self.timer.get_or_insert_with(|| Delay::new(deadline - now));
+                timer.reset(deadline - now);
+                if timer.poll_unpin(cx).is_ready() {
+                    cx.waker().wake_by_ref();
+                }
+                break;
+            }
+            self.deadlines.pop_front();
+            if self.pending.remove(&(peer, id)).is_some() {
+                return Poll::Ready((peer, id));
+            }
+        }
+        Poll::Pending
+    }
+}
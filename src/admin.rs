@@ -0,0 +1,216 @@
+//! A JSON-RPC 2.0 interface to a running `Broadcast` behaviour, for
+//! inspecting and controlling headless nodes like relays.
+//!
+//! Requests and responses are single lines of JSON, served on any byte
+//! stream the application accepts, e.g. a local TCP or Unix socket:
+//!
+//! ```text
+//! {"jsonrpc":"2.0","id":1,"method":"subscribe","params":{"topic":"chat"}}
+//! {"jsonrpc":"2.0","id":1,"result":1}
+//! ```
+//!
+//! The methods are `subscribe` and `unsubscribe`, returning the number of
+//! local subscriptions to the topic, `broadcast` with a UTF-8 `message`,
+//! `stats` and `state`. Topics are given by their names and mapped by the
+//! behaviour, see `Broadcast::topic`. Subscriptions made through the
+//! interface last until they are unsubscribed.
+use crate::{BroadcastClient, BroadcastError, Topic};
+use futures::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use futures::StreamExt;
+use serde_json::{json, Value};
+use std::io;
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+/// Code of the errors returned by the behaviour.
+const BROADCAST_ERROR: i64 = -32000;
+
+type RpcError = (i64, String);
+
+/// Answers JSON-RPC requests with a `BroadcastClient`, see the module
+/// documentation.
+#[derive(Clone, Debug)]
+pub struct AdminServer {
+    client: BroadcastClient,
+}
+
+impl AdminServer {
+    pub fn new(client: BroadcastClient) -> Self {
+        Self { client }
+    }
+
+    /// Answers the requests read from `io` until it is closed.
+    pub async fn serve<S: AsyncRead + AsyncWrite + Unpin>(&self, io: S) -> io::Result<()> {
+        let (reader, mut writer) = io.split();
+        let mut lines = BufReader::new(reader).lines();
+        while let Some(line) = lines.next().await {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let mut response = self.handle(&line).await;
+            response.push('\n');
+            writer.write_all(response.as_bytes()).await?;
+            writer.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Answers a single request.
+    pub async fn handle(&self, request: &str) -> String {
+        let request: Value = match serde_json::from_str(request) {
+            Ok(request) => request,
+            Err(err) => return response(Value::Null, Err((PARSE_ERROR, err.to_string()))),
+        };
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+        let method = match request.get("method").and_then(Value::as_str) {
+            Some(method) => method,
+            None => return response(id, Err((INVALID_REQUEST, "missing method".into()))),
+        };
+        let params = request.get("params").cloned().unwrap_or(Value::Null);
+        response(id, self.call(method, &params).await)
+    }
+
+    async fn call(&self, method: &str, params: &Value) -> Result<Value, RpcError> {
+        match method {
+            "subscribe" => {
+                let handle = self.client.subscribe(self.topic(params).await?).await;
+                let handle = handle.map_err(broadcast_error)?;
+                let count = handle.count();
+                handle.detach();
+                Ok(json!(count))
+            }
+            "unsubscribe" => {
+                let left = self.client.unsubscribe(self.topic(params).await?).await;
+                Ok(json!(left.map_err(broadcast_error)?))
+            }
+            "broadcast" => {
+                let topic = self.topic(params).await?;
+                let msg = match params.get("message").and_then(Value::as_str) {
+                    Some(msg) => msg.as_bytes().to_vec(),
+                    None => return Err((INVALID_PARAMS, "missing message".into())),
+                };
+                let result = self.client.broadcast(topic, msg).await;
+                result.map_err(broadcast_error)?;
+                Ok(Value::Null)
+            }
+            "stats" => to_value(self.client.stats().await.map_err(broadcast_error)?),
+            "state" => to_value(self.client.state().await.map_err(broadcast_error)?),
+            _ => Err((METHOD_NOT_FOUND, format!("unknown method {}", method))),
+        }
+    }
+
+    async fn topic(&self, params: &Value) -> Result<Topic, RpcError> {
+        let name = match params.get("topic").and_then(Value::as_str) {
+            Some(name) => name,
+            None => return Err((INVALID_PARAMS, "missing topic".into())),
+        };
+        self.client.topic(name).await.map_err(broadcast_error)
+    }
+}
+
+fn broadcast_error(err: BroadcastError) -> RpcError {
+    match err {
+        BroadcastError::InvalidTopic => (INVALID_PARAMS, err.to_string()),
+        _ => (BROADCAST_ERROR, err.to_string()),
+    }
+}
+
+fn to_value(value: impl serde::Serialize) -> Result<Value, RpcError> {
+    serde_json::to_value(value).map_err(|err| (INTERNAL_ERROR, err.to_string()))
+}
+
+fn response(id: Value, result: Result<Value, RpcError>) -> String {
+    let response = match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err((code, message)) => json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": code, "message": message },
+        }),
+    };
+    response.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::DummyPollParameters;
+    use crate::{Broadcast, BroadcastConfig, TopicRepresentation};
+    use futures::Future;
+    use libp2p::swarm::NetworkBehaviour;
+    use std::task::{Context, Poll};
+
+    #[test]
+    fn test_admin_server() {
+        let mut params = DummyPollParameters(PeerId::random());
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut behaviour = Broadcast::new(BroadcastConfig::default());
+        let server = AdminServer::new(behaviour.client());
+        let mut call = |request: &str| {
+            let mut response = Box::pin(server.handle(request));
+            loop {
+                if let Poll::Ready(response) = response.as_mut().poll(&mut cx) {
+                    return serde_json::from_str::<Value>(&response).unwrap();
+                }
+                while behaviour.poll(&mut cx, &mut params).is_ready() {}
+            }
+        };
+
+        let response =
+            call(r#"{"jsonrpc":"2.0","id":1,"method":"subscribe","params":{"topic":"chat"}}"#);
+        assert_eq!(response, json!({ "jsonrpc": "2.0", "id": 1, "result": 1 }));
+        let response = call(r#"{"jsonrpc":"2.0","id":2,"method":"state"}"#);
+        assert_eq!(response["result"]["subscriptions"], json!(["chat"]));
+        let response = call(r#"{"jsonrpc":"2.0","id":3,"method":"stats"}"#);
+        assert_eq!(response["result"]["peers"], json!(0));
+        let response = call(
+            r#"{"jsonrpc":"2.0","id":4,"method":"broadcast","params":{"topic":"chat","message":"hi"}}"#,
+        );
+        assert_eq!(response["error"]["code"], json!(BROADCAST_ERROR));
+        let response =
+            call(r#"{"jsonrpc":"2.0","id":5,"method":"unsubscribe","params":{"topic":"chat"}}"#);
+        assert_eq!(response["result"], json!(0));
+
+        assert_eq!(call("{")["error"]["code"], json!(PARSE_ERROR));
+        let response = call(r#"{"jsonrpc":"2.0","id":6,"method":"restart"}"#);
+        assert_eq!(response["error"]["code"], json!(METHOD_NOT_FOUND));
+        let response = call(r#"{"jsonrpc":"2.0","id":7,"method":"subscribe"}"#);
+        assert_eq!(response["error"]["code"], json!(INVALID_PARAMS));
+    }
+
+    #[test]
+    fn test_admin_topic_names() {
+        let mut params = DummyPollParameters(PeerId::random());
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let config = BroadcastConfig::default()
+            .namespace("testnet")
+            .topic_representation(TopicRepresentation::Hashed);
+        let mut behaviour = Broadcast::new(config);
+        let server = AdminServer::new(behaviour.client());
+        let mut call = |request: &str| {
+            let mut response = Box::pin(server.handle(request));
+            loop {
+                if let Poll::Ready(response) = response.as_mut().poll(&mut cx) {
+                    return serde_json::from_str::<Value>(&response).unwrap();
+                }
+                while behaviour.poll(&mut cx, &mut params).is_ready() {}
+            }
+        };
+
+        let response =
+            call(r#"{"jsonrpc":"2.0","id":1,"method":"subscribe","params":{"topic":"chat"}}"#);
+        assert_eq!(response["result"], json!(1));
+        drop(call);
+        let topic = TopicRepresentation::Hashed
+            .topic_in("testnet", "chat")
+            .unwrap();
+        assert!(behaviour.is_subscribed(&topic));
+        assert_eq!(behaviour.topic_name(&topic), Some("chat"));
+    }
+}
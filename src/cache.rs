@@ -0,0 +1,65 @@
+use crate::protocol::MessageId;
+use fnv::FnvHashSet;
+use std::collections::VecDeque;
+
+/// Bounded set of recently seen message ids, evicting the oldest entry first.
+#[derive(Debug, Default)]
+pub(crate) struct SeenCache {
+    capacity: usize,
+    ids: FnvHashSet<MessageId>,
+    order: VecDeque<MessageId>,
+}
+
+impl SeenCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ..Default::default()
+        }
+    }
+
+    /// Records `id`, returning `false` if it has been seen before.
+    ///
+    /// A cache with zero capacity never reports duplicates.
+    pub fn insert(&mut self, id: MessageId) -> bool {
+        if self.capacity == 0 {
+            return true;
+        }
+        if !self.ids.insert(id) {
+            return false;
+        }
+        if self.order.len() >= self.capacity {
+            if let Some(old) = self.order.pop_front() {
+                self.ids.remove(&old);
+            }
+        }
+        self.order.push_back(id);
+        true
+    }
+
+    pub fn contains(&self, id: &MessageId) -> bool {
+        self.ids.contains(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Topic;
+
+    #[test]
+    fn test_seen_cache() {
+        let topic = Topic::new(b"topic");
+        let a = MessageId::new(&topic, b"a");
+        let b = MessageId::new(&topic, b"b");
+        let mut cache = SeenCache::new(1);
+        assert!(cache.insert(a));
+        assert!(!cache.insert(a));
+        assert!(cache.insert(b));
+        assert!(cache.insert(a));
+
+        let mut disabled = SeenCache::new(0);
+        assert!(disabled.insert(a));
+        assert!(disabled.insert(a));
+    }
+}
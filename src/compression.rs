@@ -0,0 +1,133 @@
+use std::io::Result;
+
+/// Compression of broadcast payloads.
+///
+/// Payloads are only compressed when the remote supports the algorithm,
+/// which is negotiated together with the protocol version.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Compression {
+    None,
+    #[cfg(feature = "zstd")]
+    Zstd {
+        level: i32,
+    },
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self::None
+    }
+}
+
+impl Compression {
+    pub(crate) fn codec(&self) -> Option<Codec> {
+        match self {
+            Self::None => None,
+            #[cfg(feature = "zstd")]
+            Self::Zstd { .. } => Some(Codec::Zstd),
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => Some(Codec::Lz4),
+        }
+    }
+
+    pub(crate) fn compress(&self, payload: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Self::None => Ok(payload.to_vec()),
+            #[cfg(feature = "zstd")]
+            Self::Zstd { level } => zstd::bulk::compress(payload, *level),
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => Ok(lz4_flex::compress_prepend_size(payload)),
+        }
+    }
+}
+
+/// Compression algorithm negotiated on a substream.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Codec {
+    #[cfg(feature = "zstd")]
+    Zstd,
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+impl Codec {
+    /// All algorithms this build can decompress.
+    pub(crate) const ALL: &'static [Codec] = &[
+        #[cfg(feature = "zstd")]
+        Codec::Zstd,
+        #[cfg(feature = "lz4")]
+        Codec::Lz4,
+    ];
+
+    pub(crate) fn protocol_name(&self) -> &'static [u8] {
+        match *self {
+            #[cfg(feature = "zstd")]
+            Self::Zstd => b"/ax/broadcast/1.1.0/zstd",
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => b"/ax/broadcast/1.1.0/lz4",
+        }
+    }
+
+    /// Suffix of the protocol name of the compressed variant.
+    pub(crate) fn name(&self) -> &'static str {
+        match *self {
+            #[cfg(feature = "zstd")]
+            Self::Zstd => "zstd",
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => "lz4",
+        }
+    }
+
+    /// Decompresses a payload, failing if it would exceed `max_size` bytes.
+    #[cfg_attr(not(any(feature = "zstd", feature = "lz4")), allow(unused_variables))]
+    pub(crate) fn decompress(&self, payload: &[u8], max_size: usize) -> Result<Vec<u8>> {
+        match *self {
+            #[cfg(feature = "zstd")]
+            Self::Zstd => zstd::bulk::decompress(payload, max_size),
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => lz4_decompress(payload, max_size),
+        }
+    }
+}
+
+/// Decompresses a size-prepended lz4 block, checking the size before
+/// allocating.
+#[cfg(feature = "lz4")]
+fn lz4_decompress(payload: &[u8], max_size: usize) -> Result<Vec<u8>> {
+    use std::io::{Error, ErrorKind};
+    if payload.len() < 4 {
+        return Err(Error::new(ErrorKind::InvalidData, "payload truncated"));
+    }
+    let (size, payload) = payload.split_at(4);
+    let size = u32::from_le_bytes([size[0], size[1], size[2], size[3]]) as usize;
+    if size > max_size {
+        return Err(Error::new(ErrorKind::InvalidData, "payload too large"));
+    }
+    lz4_flex::decompress(payload, size).map_err(|err| Error::new(ErrorKind::InvalidData, err))
+}
+
+#[cfg(all(test, any(feature = "zstd", feature = "lz4")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compression_roundtrip() {
+        let payload = vec![42; 4096];
+        let algorithms = [
+            #[cfg(feature = "zstd")]
+            Compression::Zstd { level: 3 },
+            #[cfg(feature = "lz4")]
+            Compression::Lz4,
+        ];
+        for compression in algorithms {
+            let codec = compression.codec().unwrap();
+            let compressed = compression.compress(&payload).unwrap();
+            assert!(compressed.len() < payload.len());
+            assert_eq!(codec.decompress(&compressed, 4096).unwrap(), payload);
+            assert!(codec.decompress(&compressed, 4095).is_err());
+        }
+        assert_eq!(Compression::None.codec(), None);
+    }
+}
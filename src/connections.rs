@@ -0,0 +1,195 @@
+use crate::protocol::{ConnectionPolicy, ConnectionSelector, Topic};
+use fnv::FnvHashMap;
+use libp2p::core::connection::ConnectionId;
+use libp2p::swarm::NotifyHandler;
+use libp2p::{Multiaddr, PeerId};
+
+/// An established connection offered to the
+/// `BroadcastConfig::connection_selector`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConnectionInfo {
+    pub id: ConnectionId,
+    /// Address of the remote end.
+    pub address: Multiaddr,
+}
+
+#[derive(Debug, Default)]
+struct PeerConnections {
+    /// Oldest connection first.
+    connections: Vec<ConnectionInfo>,
+    /// Index of the next connection for `ConnectionPolicy::RoundRobin`.
+    next: usize,
+}
+
+/// Established connections of each peer, choosing the handlers messages are
+/// sent to.
+#[derive(Debug, Default)]
+pub(crate) struct Connections {
+    policy: ConnectionPolicy,
+    selector: Option<ConnectionSelector>,
+    peers: FnvHashMap<PeerId, PeerConnections>,
+}
+
+impl Connections {
+    pub fn new(policy: ConnectionPolicy, selector: Option<ConnectionSelector>) -> Self {
+        Self {
+            policy,
+            selector,
+            ..Default::default()
+        }
+    }
+
+    pub fn established(&mut self, peer: PeerId, id: ConnectionId, address: Multiaddr) {
+        let connections = &mut self.peers.entry(peer).or_default().connections;
+        connections.push(ConnectionInfo { id, address });
+    }
+
+    pub fn address_changed(&mut self, peer: &PeerId, id: &ConnectionId, address: Multiaddr) {
+        let connections = self
+            .peers
+            .get_mut(peer)
+            .into_iter()
+            .flat_map(|peer| peer.connections.iter_mut());
+        for info in connections.filter(|info| info.id == *id) {
+            info.address = address.clone();
+        }
+    }
+
+    pub fn closed(&mut self, peer: &PeerId, connection: &ConnectionId) {
+        if let Some(connections) = self.peers.get_mut(peer) {
+            connections
+                .connections
+                .retain(|info| info.id != *connection);
+            if connections.connections.is_empty() {
+                self.peers.remove(peer);
+            }
+        }
+    }
+
+    /// Number of established connections to the peer.
+    pub fn count(&self, peer: &PeerId) -> usize {
+        self.peers
+            .get(peer)
+            .map_or(0, |connections| connections.connections.len())
+    }
+
+    /// All established connections.
+    pub fn all(&self) -> Vec<(PeerId, ConnectionId)> {
+        self.peers
+            .iter()
+            .flat_map(|(peer, connections)| {
+                connections
+                    .connections
+                    .iter()
+                    .map(move |info| (*peer, info.id))
+            })
+            .collect()
+    }
+
+    /// The handlers a message to `peer` is sent to. Broadcasts on `topic`
+    /// go to the connection chosen by the selector if there is one.
+    pub fn handlers(&mut self, peer: &PeerId, topic: Option<&Topic>) -> Vec<NotifyHandler> {
+        let connections = match self.peers.get_mut(peer) {
+            Some(connections) => connections,
+            None => return vec![NotifyHandler::Any],
+        };
+        if let (Some(selector), Some(topic)) = (&self.selector, topic) {
+            if let Some(id) = selector.select(peer, topic, &connections.connections) {
+                if connections.connections.iter().any(|info| info.id == id) {
+                    return vec![NotifyHandler::One(id)];
+                }
+            }
+        }
+        let ids: Vec<_> = connections.connections.iter().map(|info| info.id).collect();
+        match self.policy {
+            ConnectionPolicy::Any => vec![NotifyHandler::Any],
+            ConnectionPolicy::PreferNewest => vec![NotifyHandler::One(ids[ids.len() - 1])],
+            ConnectionPolicy::RoundRobin => {
+                let id = ids[connections.next % ids.len()];
+                connections.next = connections.next.wrapping_add(1);
+                vec![NotifyHandler::One(id)]
+            }
+            ConnectionPolicy::All => ids.iter().copied().map(NotifyHandler::One).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The connection a handler refers to, `None` for any connection.
+    fn ids(handlers: Vec<NotifyHandler>) -> Vec<Option<ConnectionId>> {
+        handlers
+            .into_iter()
+            .map(|handler| match handler {
+                NotifyHandler::One(id) => Some(id),
+                NotifyHandler::Any => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_connection_policy() {
+        let peer = PeerId::random();
+        let (a, b) = (ConnectionId::new(1), ConnectionId::new(2));
+        let handlers = |policy| {
+            let mut connections = Connections::new(policy, None);
+            connections.established(peer, a, Multiaddr::empty());
+            connections.established(peer, b, Multiaddr::empty());
+            ids((0..3)
+                .flat_map(|_| connections.handlers(&peer, None))
+                .collect())
+        };
+        assert_eq!(handlers(ConnectionPolicy::Any), vec![None, None, None]);
+        assert_eq!(
+            handlers(ConnectionPolicy::PreferNewest),
+            vec![Some(b), Some(b), Some(b)]
+        );
+        assert_eq!(
+            handlers(ConnectionPolicy::RoundRobin),
+            vec![Some(a), Some(b), Some(a)]
+        );
+        assert_eq!(
+            handlers(ConnectionPolicy::All),
+            vec![Some(a), Some(b), Some(a), Some(b), Some(a), Some(b)]
+        );
+
+        let mut connections = Connections::new(ConnectionPolicy::PreferNewest, None);
+        connections.established(peer, a, Multiaddr::empty());
+        connections.established(peer, b, Multiaddr::empty());
+        connections.closed(&peer, &b);
+        assert_eq!(ids(connections.handlers(&peer, None)), vec![Some(a)]);
+        connections.closed(&peer, &a);
+        assert_eq!(ids(connections.handlers(&peer, None)), vec![None]);
+    }
+
+    #[test]
+    fn test_connection_selector() {
+        let peer = PeerId::random();
+        let (tcp, quic) = (ConnectionId::new(1), ConnectionId::new(2));
+        let bulk = Topic::new(b"bulk");
+        let selector = ConnectionSelector::new(move |_, topic, connections| {
+            let quic = connections
+                .iter()
+                .find(|info| info.address.to_string().contains("quic"))?;
+            if *topic == bulk {
+                None
+            } else {
+                Some(quic.id)
+            }
+        });
+        let mut connections = Connections::new(ConnectionPolicy::Any, Some(selector));
+        connections.established(peer, tcp, "/ip4/1.2.3.4/tcp/1".parse().unwrap());
+        assert_eq!(ids(connections.handlers(&peer, Some(&bulk))), vec![None]);
+        let topic = Topic::new(b"topic");
+        assert_eq!(ids(connections.handlers(&peer, Some(&topic))), vec![None]);
+        connections.established(peer, quic, "/ip4/1.2.3.4/udp/1/quic".parse().unwrap());
+        assert_eq!(
+            ids(connections.handlers(&peer, Some(&topic))),
+            vec![Some(quic)]
+        );
+        assert_eq!(ids(connections.handlers(&peer, Some(&bulk))), vec![None]);
+        assert_eq!(ids(connections.handlers(&peer, None)), vec![None]);
+    }
+}
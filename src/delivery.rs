@@ -0,0 +1,221 @@
+use crate::BroadcastEvent;
+use fnv::FnvHashMap;
+use libp2p::PeerId;
+use std::io;
+
+/// Identifies a message sent with `Broadcast::broadcast_tracked`.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct BroadcastId(u64);
+
+/// Reason a tracked broadcast wasn't delivered to a peer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DeliveryError {
+    /// The send queue of the peer was full.
+    QueueFull,
+    /// The ttl of the message expired before it was sent.
+    Expired,
+    /// The protocol spoken with the peer can't carry the message.
+    Unsupported,
+    /// The message was dropped to stay within
+    /// `BroadcastConfig::max_buffered_bytes`.
+    Evicted,
+    /// Writing the message to the substream failed.
+    Io(io::ErrorKind),
+    /// The peer disconnected before the message was written.
+    ConnectionClosed,
+    /// The peer was blocked with `Broadcast::block_peer`.
+    Blocked,
+}
+
+#[derive(Debug, Default)]
+struct Delivery {
+    /// Handler reports still expected from each peer.
+    pending: FnvHashMap<PeerId, usize>,
+    delivered: usize,
+    failed: usize,
+    /// Set until all peers were handed the message.
+    sending: bool,
+}
+
+/// Outcome of the tracked broadcasts, collected from the handlers' reports.
+#[derive(Debug, Default)]
+pub(crate) struct Deliveries {
+    next_id: u64,
+    deliveries: FnvHashMap<BroadcastId, Delivery>,
+}
+
+impl Deliveries {
+    /// Starts tracking a broadcast, completed by `finish`.
+    pub fn start(&mut self) -> BroadcastId {
+        let id = BroadcastId(self.next_id);
+        self.next_id += 1;
+        self.deliveries.insert(
+            id,
+            Delivery {
+                sending: true,
+                ..Default::default()
+            },
+        );
+        id
+    }
+
+    /// Expects reports from `handlers` more handlers of the peer. Zero
+    /// handlers mark a peer whose message was delayed.
+    pub fn sent(&mut self, id: BroadcastId, peer: PeerId, handlers: usize) {
+        if let Some(delivery) = self.deliveries.get_mut(&id) {
+            *delivery.pending.entry(peer).or_default() += handlers;
+        }
+    }
+
+    /// The message couldn't be handed to the peer's handlers.
+    pub fn fail(
+        &mut self,
+        id: BroadcastId,
+        peer: PeerId,
+        error: DeliveryError,
+    ) -> Vec<BroadcastEvent> {
+        let mut events = Vec::new();
+        if let Some(delivery) = self.deliveries.get_mut(&id) {
+            delivery.pending.remove(&peer);
+            delivery.failed += 1;
+            events.push(BroadcastEvent::SendFailed { id, peer, error });
+        }
+        self.complete(id, &mut events);
+        events
+    }
+
+    /// A handler of the peer wrote the message or failed to. The peer
+    /// counts as delivered once any handler wrote it.
+    pub fn report(
+        &mut self,
+        id: BroadcastId,
+        peer: PeerId,
+        result: Result<(), DeliveryError>,
+    ) -> Vec<BroadcastEvent> {
+        let delivery = match self.deliveries.get_mut(&id) {
+            Some(delivery) => delivery,
+            None => return Vec::new(),
+        };
+        let pending = match delivery.pending.get_mut(&peer) {
+            Some(pending) => pending,
+            None => return Vec::new(),
+        };
+        let mut events = Vec::new();
+        match result {
+            Ok(()) => {
+                delivery.pending.remove(&peer);
+                delivery.delivered += 1;
+                events.push(BroadcastEvent::Delivered { id, peer });
+            }
+            Err(error) => {
+                *pending = pending.saturating_sub(1);
+                if *pending > 0 {
+                    return events;
+                }
+                delivery.pending.remove(&peer);
+                delivery.failed += 1;
+                events.push(BroadcastEvent::SendFailed { id, peer, error });
+            }
+        }
+        self.complete(id, &mut events);
+        events
+    }
+
+    /// Fails the pending deliveries to a disconnected peer.
+    pub fn disconnected(&mut self, peer: &PeerId) -> Vec<BroadcastEvent> {
+        let ids: Vec<_> = self
+            .deliveries
+            .iter()
+            .filter(|(_, delivery)| delivery.pending.contains_key(peer))
+            .map(|(id, _)| *id)
+            .collect();
+        let mut events = Vec::new();
+        for id in ids {
+            events.extend(self.fail(id, *peer, DeliveryError::ConnectionClosed));
+        }
+        events
+    }
+
+    /// All peers were handed the message.
+    pub fn finish(&mut self, id: BroadcastId) -> Vec<BroadcastEvent> {
+        if let Some(delivery) = self.deliveries.get_mut(&id) {
+            delivery.sending = false;
+        }
+        let mut events = Vec::new();
+        self.complete(id, &mut events);
+        events
+    }
+
+    fn complete(&mut self, id: BroadcastId, events: &mut Vec<BroadcastEvent>) {
+        match self.deliveries.get(&id) {
+            Some(delivery) if !delivery.sending && delivery.pending.is_empty() => {
+                events.push(BroadcastEvent::DeliveryComplete {
+                    id,
+                    delivered: delivery.delivered,
+                    failed: delivery.failed,
+                });
+                self.deliveries.remove(&id);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deliveries() {
+        let (a, b, c) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let mut deliveries = Deliveries::default();
+        let id = deliveries.start();
+        deliveries.sent(id, a, 2);
+        deliveries.sent(id, b, 1);
+        deliveries.sent(id, c, 0);
+        assert!(deliveries.finish(id).is_empty());
+
+        // One of the two connections to `a` failed.
+        assert!(deliveries
+            .report(id, a, Err(DeliveryError::Io(io::ErrorKind::BrokenPipe)))
+            .is_empty());
+        assert_eq!(
+            deliveries.report(id, a, Ok(())),
+            vec![BroadcastEvent::Delivered { id, peer: a }]
+        );
+        assert_eq!(
+            deliveries.report(id, b, Err(DeliveryError::Expired)),
+            vec![BroadcastEvent::SendFailed {
+                id,
+                peer: b,
+                error: DeliveryError::Expired
+            }]
+        );
+        assert_eq!(
+            deliveries.disconnected(&c),
+            vec![
+                BroadcastEvent::SendFailed {
+                    id,
+                    peer: c,
+                    error: DeliveryError::ConnectionClosed
+                },
+                BroadcastEvent::DeliveryComplete {
+                    id,
+                    delivered: 1,
+                    failed: 2
+                }
+            ]
+        );
+        assert!(deliveries.report(id, a, Ok(())).is_empty());
+
+        let id = deliveries.start();
+        assert_eq!(
+            deliveries.finish(id),
+            vec![BroadcastEvent::DeliveryComplete {
+                id,
+                delivered: 0,
+                failed: 0
+            }]
+        );
+    }
+}
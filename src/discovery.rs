@@ -0,0 +1,210 @@
+use crate::protocol::Topic;
+use fnv::FnvHashMap;
+use futures::stream::{BoxStream, StreamExt};
+use libp2p::{Multiaddr, PeerId};
+use std::collections::VecDeque;
+use std::task::{Context, Poll};
+
+/// Finds peers subscribed to a topic, for example through a DHT or a
+/// rendezvous server.
+pub trait Discovery: Send {
+    /// Starts looking for subscribers of `topic`. The behaviour dials the
+    /// peers found until the stream ends.
+    fn discover(&mut self, topic: &Topic) -> BoxStream<'static, (PeerId, Multiaddr)>;
+}
+
+/// Running lookups of the topics with too few subscribers.
+#[derive(Default)]
+pub(crate) struct TopicDiscovery {
+    discovery: Option<Box<dyn Discovery>>,
+    lookups: FnvHashMap<Topic, BoxStream<'static, (PeerId, Multiaddr)>>,
+    /// Addresses of discovered peers until they are connected.
+    addresses: FnvHashMap<PeerId, Vec<Multiaddr>>,
+    /// Peers suggested by other peers, dialed before the lookup results.
+    suggested: VecDeque<PeerId>,
+}
+
+impl TopicDiscovery {
+    pub fn set(&mut self, discovery: Box<dyn Discovery>) {
+        self.discovery = Some(discovery);
+        self.lookups.clear();
+    }
+
+    /// Starts a lookup for `topic` unless one is running already.
+    pub fn start(&mut self, topic: &Topic) {
+        if let Some(discovery) = &mut self.discovery {
+            if !self.lookups.contains_key(topic) {
+                let lookup = discovery.discover(topic);
+                self.lookups.insert(*topic, lookup);
+            }
+        }
+    }
+
+    pub fn stop(&mut self, topic: &Topic) {
+        self.lookups.remove(topic);
+    }
+
+    pub fn addresses(&self, peer: &PeerId) -> Vec<Multiaddr> {
+        self.addresses.get(peer).cloned().unwrap_or_default()
+    }
+
+    /// Dials a peer suggested by another peer.
+    pub fn suggest(&mut self, peer: PeerId, addresses: &[Multiaddr]) {
+        let known = self.addresses.entry(peer).or_default();
+        for address in addresses {
+            if !known.contains(address) {
+                known.push(address.clone());
+            }
+        }
+        self.suggested.push_back(peer);
+    }
+
+    /// Forgets the addresses of a peer that was connected or couldn't be
+    /// dialed.
+    pub fn remove(&mut self, peer: &PeerId) {
+        self.addresses.remove(peer);
+    }
+
+    /// Returns the next peer found by a lookup.
+    pub fn poll(&mut self, cx: &mut Context) -> Poll<PeerId> {
+        if let Some(peer) = self.suggested.pop_front() {
+            return Poll::Ready(peer);
+        }
+        let mut found = None;
+        let mut ended = Vec::new();
+        for (topic, lookup) in &mut self.lookups {
+            match lookup.poll_next_unpin(cx) {
+                Poll::Ready(Some(peer)) => {
+                    found = Some(peer);
+                    break;
+                }
+                Poll::Ready(None) => ended.push(*topic),
+                Poll::Pending => {}
+            }
+        }
+        for topic in ended {
+            self.lookups.remove(&topic);
+        }
+        match found {
+            Some((peer, address)) => {
+                let addresses = self.addresses.entry(peer).or_default();
+                if !addresses.contains(&address) {
+                    addresses.push(address);
+                }
+                Poll::Ready(peer)
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(feature = "kad")]
+pub use kad::KademliaDiscovery;
+
+#[cfg(feature = "kad")]
+mod kad {
+    use super::Discovery;
+    use crate::protocol::Topic;
+    use fnv::FnvHashMap;
+    use futures::channel::mpsc;
+    use futures::stream::{BoxStream, StreamExt};
+    use libp2p::kad::record::Key;
+    use libp2p::kad::store::{self, RecordStore};
+    use libp2p::kad::{Kademlia, KademliaEvent, QueryId, QueryResult};
+    use libp2p::swarm::NetworkBehaviour;
+    use libp2p::{Multiaddr, PeerId};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Default)]
+    struct Lookups {
+        /// Topics to look up with the next `start_queries`.
+        pending: Vec<Topic>,
+        queries: FnvHashMap<QueryId, Topic>,
+        streams: FnvHashMap<Topic, mpsc::UnboundedSender<(PeerId, Multiaddr)>>,
+    }
+
+    /// Discovers subscribers through Kademlia provider records.
+    ///
+    /// The application owns the `Kademlia` behaviour: it announces its own
+    /// subscriptions with `start_providing`, calls `start_queries` after
+    /// polling the swarm and passes the Kademlia events to `inject_event`.
+    /// Clones share their state.
+    #[derive(Clone, Default)]
+    pub struct KademliaDiscovery {
+        lookups: Arc<Mutex<Lookups>>,
+    }
+
+    impl KademliaDiscovery {
+        /// Key of the provider records of the subscribers of `topic`.
+        pub fn provider_key(topic: &Topic) -> Key {
+            Key::new(topic)
+        }
+
+        /// Announces the local peer as subscriber of `topic`.
+        pub fn start_providing<S>(
+            &self,
+            kademlia: &mut Kademlia<S>,
+            topic: &Topic,
+        ) -> Result<QueryId, store::Error>
+        where
+            for<'a> S: RecordStore<'a>,
+            S: Send + 'static,
+        {
+            kademlia.start_providing(Self::provider_key(topic))
+        }
+
+        /// Starts the provider lookups requested by the behaviour.
+        pub fn start_queries<S>(&self, kademlia: &mut Kademlia<S>)
+        where
+            for<'a> S: RecordStore<'a>,
+            S: Send + 'static,
+        {
+            let mut lookups = self.lookups.lock().unwrap();
+            let pending = std::mem::take(&mut lookups.pending);
+            for topic in pending {
+                let id = kademlia.get_providers(Self::provider_key(&topic));
+                lookups.queries.insert(id, topic);
+            }
+        }
+
+        /// Passes the providers found by a lookup to the behaviour, with the
+        /// addresses Kademlia knows for them.
+        pub fn inject_event<S>(&self, kademlia: &mut Kademlia<S>, event: &KademliaEvent)
+        where
+            for<'a> S: RecordStore<'a>,
+            S: Send + 'static,
+        {
+            let (id, result) = match event {
+                KademliaEvent::OutboundQueryCompleted {
+                    id,
+                    result: QueryResult::GetProviders(result),
+                    ..
+                } => (id, result),
+                _ => return,
+            };
+            let mut lookups = self.lookups.lock().unwrap();
+            let tx = match lookups.queries.remove(id) {
+                Some(topic) => lookups.streams.remove(&topic),
+                None => None,
+            };
+            // Dropping the sender ends the stream.
+            if let (Some(tx), Ok(ok)) = (tx, result) {
+                for peer in &ok.providers {
+                    for address in kademlia.addresses_of_peer(peer) {
+                        tx.unbounded_send((*peer, address)).ok();
+                    }
+                }
+            }
+        }
+    }
+
+    impl Discovery for KademliaDiscovery {
+        fn discover(&mut self, topic: &Topic) -> BoxStream<'static, (PeerId, Multiaddr)> {
+            let (tx, rx) = mpsc::unbounded();
+            let mut lookups = self.lookups.lock().unwrap();
+            lookups.pending.push(*topic);
+            lookups.streams.insert(*topic, tx);
+            rx.boxed()
+        }
+    }
+}
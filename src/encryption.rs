@@ -0,0 +1,87 @@
+use crate::protocol::Topic;
+use bytes::Bytes;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::RngCore;
+use std::fmt;
+
+const NONCE_LEN: usize = 12;
+
+/// Symmetric key encrypting the payloads of a topic with
+/// ChaCha20-Poly1305.
+#[derive(Clone)]
+pub struct TopicKey([u8; 32]);
+
+impl TopicKey {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self(key)
+    }
+
+    pub fn generate() -> Self {
+        let mut key = [0; 32];
+        rand::thread_rng().fill_bytes(&mut key);
+        Self(key)
+    }
+
+    /// Encrypts `msg`, prepending the random nonce. The topic is
+    /// authenticated as well.
+    pub(crate) fn encrypt(&self, topic: &Topic, msg: &[u8]) -> Option<Bytes> {
+        let mut nonce = [0; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let payload = Payload { msg, aad: topic };
+        let ciphertext = self
+            .cipher()
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .ok()?;
+        let mut buf = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        buf.extend_from_slice(&nonce);
+        buf.extend_from_slice(&ciphertext);
+        Some(buf.into())
+    }
+
+    pub(crate) fn decrypt(&self, topic: &Topic, msg: &[u8]) -> Option<Bytes> {
+        if msg.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, msg) = msg.split_at(NONCE_LEN);
+        let payload = Payload { msg, aad: topic };
+        let plaintext = self
+            .cipher()
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .ok()?;
+        Some(plaintext.into())
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(Key::from_slice(&self.0))
+    }
+}
+
+impl From<[u8; 32]> for TopicKey {
+    fn from(key: [u8; 32]) -> Self {
+        Self(key)
+    }
+}
+
+impl fmt::Debug for TopicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TopicKey(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_topic_key() {
+        let topic = Topic::new(b"topic");
+        let key = TopicKey::generate();
+        let ciphertext = key.encrypt(&topic, b"msg").unwrap();
+        assert_ne!(&ciphertext[NONCE_LEN..], b"msg");
+        assert_eq!(&key.decrypt(&topic, &ciphertext).unwrap()[..], b"msg");
+        assert!(key.decrypt(&Topic::new(b"other"), &ciphertext).is_none());
+        assert!(TopicKey::generate().decrypt(&topic, &ciphertext).is_none());
+        assert!(key.decrypt(&topic, b"short").is_none());
+    }
+}
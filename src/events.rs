@@ -0,0 +1,192 @@
+use crate::handler::{BroadcastHandler, HandlerIn};
+use crate::protocol::Message;
+use crate::BroadcastEvent;
+use libp2p::swarm::NetworkBehaviourAction;
+use libp2p::PeerId;
+use std::collections::{vec_deque, VecDeque};
+use std::task::Waker;
+
+type Action = NetworkBehaviourAction<BroadcastEvent, BroadcastHandler>;
+
+/// Actions returned by `Broadcast::poll`. Queueing one wakes the task that
+/// last polled the behaviour, so actions queued by calls from outside of
+/// `poll` are returned without waiting for other activity of the swarm.
+///
+/// The payload bytes of the queued broadcasts are accounted for
+/// `BroadcastConfig::max_buffered_bytes`.
+#[derive(Default)]
+pub(crate) struct EventQueue {
+    actions: VecDeque<Action>,
+    bytes: usize,
+    waker: Option<Waker>,
+}
+
+impl EventQueue {
+    /// Remembers the task polling the behaviour.
+    pub fn register(&mut self, waker: &Waker) {
+        match &self.waker {
+            Some(registered) if registered.will_wake(waker) => {}
+            _ => self.waker = Some(waker.clone()),
+        }
+    }
+
+    /// Wakes the task polling the behaviour, once until it polls again.
+    pub fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.actions.len()
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    /// Payload bytes of the queued broadcasts.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn iter(&self) -> vec_deque::Iter<'_, Action> {
+        self.actions.iter()
+    }
+
+    pub fn push_back(&mut self, action: Action) {
+        self.bytes += queued_len(&action).map_or(0, |(_, len)| len);
+        self.actions.push_back(action);
+        self.wake();
+    }
+
+    pub fn extend(&mut self, actions: impl IntoIterator<Item = Action>) {
+        let len = self.actions.len();
+        for action in actions {
+            self.bytes += queued_len(&action).map_or(0, |(_, len)| len);
+            self.actions.push_back(action);
+        }
+        if self.actions.len() > len {
+            self.wake();
+        }
+    }
+
+    pub fn pop_front(&mut self) -> Option<Action> {
+        let action = self.actions.pop_front()?;
+        self.bytes -= queued_len(&action).map_or(0, |(_, len)| len);
+        Some(action)
+    }
+
+    pub fn remove(&mut self, i: usize) -> Option<Action> {
+        let action = self.actions.remove(i)?;
+        self.bytes -= queued_len(&action).map_or(0, |(_, len)| len);
+        Some(action)
+    }
+
+    /// Replaces the action at `i`, returning the previous one.
+    pub fn replace(&mut self, i: usize, action: Action) -> Option<Action> {
+        let slot = self.actions.get_mut(i)?;
+        self.bytes += queued_len(&action).map_or(0, |(_, len)| len);
+        let old = std::mem::replace(slot, action);
+        self.bytes -= queued_len(&old).map_or(0, |(_, len)| len);
+        Some(old)
+    }
+
+    pub fn retain(&mut self, mut f: impl FnMut(&Action) -> bool) {
+        let mut removed = 0;
+        self.actions.retain(|action| {
+            let keep = f(action);
+            if !keep {
+                removed += queued_len(action).map_or(0, |(_, len)| len);
+            }
+            keep
+        });
+        self.bytes -= removed;
+    }
+
+    /// Removes the actions from `at` on.
+    pub fn split_off(&mut self, at: usize) -> VecDeque<Action> {
+        let tail = self.actions.split_off(at);
+        self.bytes -= tail
+            .iter()
+            .filter_map(queued_len)
+            .map(|(_, len)| len)
+            .sum::<usize>();
+        tail
+    }
+
+    #[cfg(test)]
+    pub fn clear(&mut self) {
+        self.actions.clear();
+        self.bytes = 0;
+    }
+}
+
+/// The peer and payload size of a queued broadcast. Batches aren't
+/// accounted, shared broadcasts are for each peer.
+pub(crate) fn queued_len(action: &Action) -> Option<(PeerId, usize)> {
+    match action {
+        NetworkBehaviourAction::NotifyHandler { peer_id, event, .. } => match event.message()? {
+            Message::Broadcast(_, _, msg) => Some((*peer_id, msg.len())),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Topic;
+    use bytes::Bytes;
+    use libp2p::swarm::NotifyHandler;
+    use std::sync::Arc;
+
+    fn broadcast(peer: PeerId, msg: &'static [u8]) -> Action {
+        let topic = Topic::new(b"topic");
+        NetworkBehaviourAction::NotifyHandler {
+            peer_id: peer,
+            handler: NotifyHandler::Any,
+            event: HandlerIn::Send(Message::Broadcast(
+                topic,
+                Default::default(),
+                Bytes::from_static(msg),
+            )),
+        }
+    }
+
+    #[test]
+    fn test_queued_bytes() {
+        let peer = PeerId::random();
+        let mut queue = EventQueue::default();
+        queue.push_back(broadcast(peer, b"a"));
+        queue.extend(vec![broadcast(peer, b"bb"), broadcast(peer, b"ccc")]);
+        let shared = Arc::new(Message::Broadcast(
+            Topic::new(b"topic"),
+            Default::default(),
+            Bytes::from_static(b"ee"),
+        ));
+        for _ in 0..2 {
+            queue.push_back(NetworkBehaviourAction::NotifyHandler {
+                peer_id: PeerId::random(),
+                handler: NotifyHandler::Any,
+                event: HandlerIn::SendShared(Default::default(), None, shared.clone()),
+            });
+        }
+        queue.push_back(NetworkBehaviourAction::GenerateEvent(
+            BroadcastEvent::PeerConnected(peer),
+        ));
+        assert_eq!(queue.bytes(), 10);
+
+        let tail = queue.split_off(2);
+        assert_eq!((queue.len(), tail.len(), queue.bytes()), (2, 4, 3));
+        queue.replace(1, broadcast(peer, b"dddd"));
+        assert_eq!(queue.bytes(), 5);
+        queue.retain(|action| queued_len(action) != Some((peer, 4)));
+        assert_eq!(queue.bytes(), 1);
+        assert!(queue.pop_front().is_some());
+        assert_eq!(queue.bytes(), 0);
+        assert!(queue.is_empty());
+    }
+}
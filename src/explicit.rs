@@ -0,0 +1,148 @@
+use fnv::FnvHashMap;
+use futures::FutureExt;
+use futures_timer::Delay;
+use instant::Instant;
+use libp2p::{Multiaddr, PeerId};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// Delay before the first redial of a disconnected explicit peer.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+/// Upper bound of the redial delay.
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+struct ExplicitPeer {
+    addresses: Vec<Multiaddr>,
+    connected: bool,
+    backoff: Duration,
+    /// When to dial the peer next, `None` while connected or dialing.
+    next_dial: Option<Instant>,
+}
+
+/// Peers we always stay connected to, redialing them with exponential
+/// backoff.
+#[derive(Default)]
+pub(crate) struct ExplicitPeers {
+    peers: FnvHashMap<PeerId, ExplicitPeer>,
+    timer: Option<Delay>,
+}
+
+impl ExplicitPeers {
+    pub fn insert(&mut self, peer: PeerId, address: Multiaddr, connected: bool) {
+        let entry = self.peers.entry(peer).or_insert_with(|| ExplicitPeer {
+            addresses: Vec::new(),
+            connected,
+            backoff: INITIAL_BACKOFF,
+            next_dial: if connected {
+                None
+            } else {
+                Some(Instant::now())
+            },
+        });
+        if !entry.addresses.contains(&address) {
+            entry.addresses.push(address);
+        }
+    }
+
+    pub fn remove(&mut self, peer: &PeerId) -> bool {
+        self.peers.remove(peer).is_some()
+    }
+
+    pub fn contains(&self, peer: &PeerId) -> bool {
+        self.peers.contains_key(peer)
+    }
+
+    /// Explicit peers that are currently connected.
+    pub fn connected(&self) -> impl Iterator<Item = &PeerId> + '_ {
+        self.peers
+            .iter()
+            .filter(|(_, peer)| peer.connected)
+            .map(|(peer_id, _)| peer_id)
+    }
+
+    pub fn addresses(&self, peer: &PeerId) -> Vec<Multiaddr> {
+        self.peers
+            .get(peer)
+            .map(|peer| peer.addresses.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn inject_connected(&mut self, peer: &PeerId) {
+        if let Some(peer) = self.peers.get_mut(peer) {
+            peer.connected = true;
+            peer.backoff = INITIAL_BACKOFF;
+            peer.next_dial = None;
+        }
+    }
+
+    /// Schedules a redial of a disconnected peer or one that couldn't be
+    /// dialed, doubling the delay each time.
+    pub fn inject_disconnected(&mut self, peer: &PeerId) {
+        if let Some(peer) = self.peers.get_mut(peer) {
+            peer.connected = false;
+            peer.next_dial = Some(Instant::now() + peer.backoff);
+            peer.backoff = (peer.backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    pub fn inject_dial_failure(&mut self, peer: &PeerId) {
+        if matches!(self.peers.get(peer), Some(peer) if !peer.connected) {
+            self.inject_disconnected(peer);
+        }
+    }
+
+    /// Returns the next peer that should be dialed.
+    pub fn poll_dial(&mut self, cx: &mut Context) -> Poll<PeerId> {
+        let now = Instant::now();
+        let mut next = None;
+        for (peer_id, peer) in &mut self.peers {
+            match peer.next_dial {
+                Some(at) if at <= now => {
+                    peer.next_dial = None;
+                    return Poll::Ready(*peer_id);
+                }
+                Some(at) => next = Some(next.map_or(at, |next: Instant| next.min(at))),
+                None => {}
+            }
+        }
+        if let Some(at) = next {
+            let wait = at - now;
+            let timer = self.timer.get_or_insert_with(|| Delay::new(wait));
+            timer.reset(wait);
+            if timer.poll_unpin(cx).is_ready() {
+                cx.waker().wake_by_ref();
+            }
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redial_backoff() {
+        let peer = PeerId::random();
+        let address: Multiaddr = "/memory/1".parse().unwrap();
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut peers = ExplicitPeers::default();
+        peers.insert(peer, address.clone(), false);
+        peers.insert(peer, address.clone(), false);
+        assert_eq!(peers.addresses(&peer), vec![address]);
+        assert_eq!(peers.poll_dial(&mut cx), Poll::Ready(peer));
+        assert!(peers.poll_dial(&mut cx).is_pending());
+
+        peers.inject_dial_failure(&peer);
+        peers.inject_dial_failure(&peer);
+        assert_eq!(peers.peers[&peer].backoff, INITIAL_BACKOFF * 4);
+        assert!(peers.poll_dial(&mut cx).is_pending());
+
+        peers.inject_connected(&peer);
+        assert_eq!(peers.connected().collect::<Vec<_>>(), vec![&peer]);
+        peers.inject_dial_failure(&peer);
+        assert_eq!(peers.peers[&peer].backoff, INITIAL_BACKOFF);
+        assert!(peers.poll_dial(&mut cx).is_pending());
+    }
+}
@@ -0,0 +1,186 @@
+use crate::protocol::Message;
+use bytes::Bytes;
+use fnv::FnvHashMap;
+use instant::Instant;
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Result};
+use std::time::Duration;
+
+/// Splits an encoded frame into fragments of at most `size` bytes.
+pub(crate) fn split(id: u64, frame: &[u8], size: usize) -> Vec<Message> {
+    let total = frame.chunks(size).len() as u32;
+    frame
+        .chunks(size)
+        .enumerate()
+        .map(|(index, data)| Message::Fragment {
+            id,
+            index: index as u32,
+            total,
+            data: Bytes::copy_from_slice(data),
+        })
+        .collect()
+}
+
+struct Partial {
+    total: u32,
+    fragments: BTreeMap<u32, Bytes>,
+    len: usize,
+    started: Instant,
+}
+
+/// Fragmented frames received on a connection.
+pub(crate) struct Reassembly {
+    max_frame_size: usize,
+    max_bytes: usize,
+    timeout: Duration,
+    partial: FnvHashMap<u64, Partial>,
+    /// Bytes of all incomplete frames.
+    bytes: usize,
+}
+
+impl Reassembly {
+    pub fn new(max_frame_size: usize, max_bytes: usize, timeout: Duration) -> Self {
+        Self {
+            max_frame_size,
+            max_bytes,
+            timeout,
+            partial: Default::default(),
+            bytes: 0,
+        }
+    }
+
+    /// Adds a fragment, returning the frame once all its fragments arrived.
+    ///
+    /// Frames exceeding the size limit and incomplete frames evicted by the
+    /// limits are dropped silently.
+    pub fn insert(
+        &mut self,
+        id: u64,
+        index: u32,
+        total: u32,
+        data: Bytes,
+    ) -> Result<Option<Vec<u8>>> {
+        if index >= total || total as usize > self.max_frame_size.max(1) {
+            return Err(Error::new(ErrorKind::InvalidData, "invalid fragment"));
+        }
+        self.expire();
+        let now = Instant::now();
+        let partial = self.partial.entry(id).or_insert_with(|| Partial {
+            total,
+            fragments: BTreeMap::new(),
+            len: 0,
+            started: now,
+        });
+        if partial.total != total {
+            return Err(Error::new(ErrorKind::InvalidData, "invalid fragment"));
+        }
+        let len = data.len();
+        if let Some(prev) = partial.fragments.insert(index, data) {
+            partial.len -= prev.len();
+            self.bytes -= prev.len();
+        }
+        partial.len += len;
+        self.bytes += len;
+        if partial.len > self.max_frame_size {
+            self.remove(id);
+            return Ok(None);
+        }
+        if partial.fragments.len() == total as usize {
+            let partial = self.remove(id).unwrap();
+            let mut frame = Vec::with_capacity(partial.len);
+            for data in partial.fragments.values() {
+                frame.extend_from_slice(data);
+            }
+            return Ok(Some(frame));
+        }
+        while self.bytes > self.max_bytes {
+            let oldest = self
+                .partial
+                .iter()
+                .min_by_key(|(id, partial)| (partial.started, **id))
+                .map(|(id, _)| *id);
+            match oldest {
+                Some(oldest) => self.remove(oldest),
+                None => break,
+            };
+        }
+        Ok(None)
+    }
+
+    fn remove(&mut self, id: u64) -> Option<Partial> {
+        let partial = self.partial.remove(&id)?;
+        self.bytes -= partial.len;
+        Some(partial)
+    }
+
+    /// Drops the frames that weren't completed in time.
+    fn expire(&mut self) {
+        let timeout = self.timeout;
+        let expired: Vec<_> = self
+            .partial
+            .iter()
+            .filter(|(_, partial)| partial.started.elapsed() >= timeout)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            self.remove(id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insert(reassembly: &mut Reassembly, fragment: Message) -> Result<Option<Vec<u8>>> {
+        match fragment {
+            Message::Fragment {
+                id,
+                index,
+                total,
+                data,
+            } => reassembly.insert(id, index, total, data),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_reassembly() {
+        let frame: Vec<u8> = (0..100).collect();
+        let mut fragments = split(1, &frame, 30);
+        assert_eq!(fragments.len(), 4);
+        let mut reassembly = Reassembly::new(100, 1000, Duration::from_secs(60));
+        let last = fragments.remove(0);
+        for fragment in fragments {
+            assert_eq!(insert(&mut reassembly, fragment).unwrap(), None);
+        }
+        assert_eq!(insert(&mut reassembly, last).unwrap(), Some(frame.clone()));
+        assert_eq!(reassembly.bytes, 0);
+
+        // Frames over the size limit are dropped.
+        let mut reassembly = Reassembly::new(50, 1000, Duration::from_secs(60));
+        for fragment in split(2, &frame, 30) {
+            assert_eq!(insert(&mut reassembly, fragment).unwrap(), None);
+        }
+        assert!(reassembly.bytes <= 50);
+
+        // The oldest incomplete frame is evicted to stay within the limit.
+        let mut reassembly = Reassembly::new(100, 80, Duration::from_secs(60));
+        let (first, second) = (split(3, &frame, 50), split(4, &frame, 50));
+        assert_eq!(insert(&mut reassembly, first[0].clone()).unwrap(), None);
+        assert_eq!(insert(&mut reassembly, second[0].clone()).unwrap(), None);
+        assert_eq!(
+            insert(&mut reassembly, second[1].clone()).unwrap(),
+            Some(frame.clone())
+        );
+        assert_eq!(insert(&mut reassembly, first[1].clone()).unwrap(), None);
+
+        // Incomplete frames expire.
+        let mut reassembly = Reassembly::new(100, 1000, Duration::ZERO);
+        let fragments = split(5, &frame, 50);
+        assert_eq!(insert(&mut reassembly, fragments[0].clone()).unwrap(), None);
+        assert_eq!(insert(&mut reassembly, fragments[1].clone()).unwrap(), None);
+
+        assert!(reassembly.insert(6, 2, 2, Bytes::new()).is_err());
+    }
+}
@@ -0,0 +1,80 @@
+use crate::protocol::Topic;
+use fnv::FnvHashMap;
+use futures::FutureExt;
+use futures_timer::Delay;
+use instant::Instant;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// Topics without remote subscribers, forgotten once idle for the
+/// retention, see `BroadcastConfig::topic_gc`.
+#[derive(Default)]
+pub(crate) struct TopicGc {
+    interval: Duration,
+    retention: Duration,
+    /// Time since which each topic has no subscribers.
+    idle: FnvHashMap<Topic, Instant>,
+    timer: Option<Delay>,
+}
+
+impl TopicGc {
+    pub fn new(gc: Option<(Duration, Duration)>) -> Self {
+        match gc {
+            Some((interval, retention)) => Self {
+                interval,
+                retention,
+                idle: Default::default(),
+                timer: Some(Delay::new(interval)),
+            },
+            None => Self::default(),
+        }
+    }
+
+    /// Tracks the topics currently without subscribers, returning those
+    /// that had none for the retention.
+    pub fn collect(&mut self, empty: impl Iterator<Item = Topic>) -> Vec<Topic> {
+        let now = Instant::now();
+        let mut idle = FnvHashMap::default();
+        let mut expired = Vec::new();
+        for topic in empty {
+            let since = self.idle.get(&topic).copied().unwrap_or(now);
+            if now.duration_since(since) >= self.retention {
+                expired.push(topic);
+            } else {
+                idle.insert(topic, since);
+            }
+        }
+        self.idle = idle;
+        expired
+    }
+
+    /// Ready when the topics are due to be collected.
+    pub fn poll_tick(&mut self, cx: &mut Context) -> Poll<()> {
+        if let Some(timer) = &mut self.timer {
+            if timer.poll_unpin(cx).is_ready() {
+                timer.reset(self.interval);
+                return Poll::Ready(());
+            }
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_topic_gc() {
+        let (a, b) = (Topic::new(b"a"), Topic::new(b"b"));
+        let mut gc = TopicGc::new(Some((Duration::from_secs(1), Duration::from_secs(60))));
+        assert!(gc.collect([a, b].iter().copied()).is_empty());
+        // `a` got a subscriber in between.
+        assert!(gc.collect(std::iter::once(b)).is_empty());
+        assert_eq!(gc.idle.len(), 1);
+
+        gc.retention = Duration::ZERO;
+        assert_eq!(gc.collect([a, b].iter().copied()), vec![a, b]);
+        assert!(gc.idle.is_empty());
+    }
+}
@@ -0,0 +1,180 @@
+use crate::{
+    BroadcastError, BroadcastState, BroadcastStats, Topic, TopicSender, TOPIC_STREAM_CAPACITY,
+};
+use bytes::Bytes;
+use futures::channel::{mpsc, oneshot};
+use futures::Stream;
+use libp2p::PeerId;
+
+type Reply<T> = oneshot::Sender<Result<T, BroadcastError>>;
+
+/// Request of a `SubscriptionHandle` or `BroadcastClient` to the behaviour.
+#[derive(Debug)]
+pub(crate) enum Command {
+    Subscribe(Topic, Reply<SubscriptionHandle>),
+    Unsubscribe(Topic, Reply<usize>),
+    /// Sent by a dropped `SubscriptionHandle` with its token.
+    Release(Topic, u64),
+    Topic(String, Reply<Topic>),
+    Publish(Topic, Bytes, Option<Reply<()>>),
+    Stream(Topic, TopicSender),
+    Stats(Reply<BroadcastStats>),
+    State(Reply<BroadcastState>),
+}
+
+/// Channel of the commands sent by subscription handles and clients,
+/// processed when the behaviour is polled.
+#[derive(Debug)]
+pub(crate) struct Commands {
+    pub tx: mpsc::UnboundedSender<Command>,
+    pub rx: mpsc::UnboundedReceiver<Command>,
+}
+
+impl Default for Commands {
+    fn default() -> Self {
+        let (tx, rx) = mpsc::unbounded();
+        Self { tx, rx }
+    }
+}
+
+fn send(tx: &mpsc::UnboundedSender<Command>, command: Command) -> Result<(), BroadcastError> {
+    tx.unbounded_send(command)
+        .map_err(|_| BroadcastError::ShuttingDown)
+}
+
+/// Subscription to a topic, unsubscribing from it when dropped.
+///
+/// Commands are applied the next time the behaviour is polled.
+#[derive(Debug)]
+#[must_use = "dropping the handle unsubscribes from the topic, use `detach` to keep the subscription"]
+pub struct SubscriptionHandle {
+    topic: Topic,
+    count: usize,
+    token: u64,
+    tx: Option<mpsc::UnboundedSender<Command>>,
+}
+
+impl SubscriptionHandle {
+    pub(crate) fn new(
+        topic: Topic,
+        count: usize,
+        token: u64,
+        tx: mpsc::UnboundedSender<Command>,
+    ) -> Self {
+        Self {
+            topic,
+            count,
+            token,
+            tx: Some(tx),
+        }
+    }
+
+    pub fn topic(&self) -> &Topic {
+        &self.topic
+    }
+
+    /// Number of local subscriptions to the topic when the handle was
+    /// created, see `BroadcastConfig::counted_subscriptions`.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Broadcasts a message to all peers subscribed to the topic.
+    pub fn publish(&self, msg: impl Into<Bytes>) -> Result<(), BroadcastError> {
+        self.send(Command::Publish(self.topic, msg.into(), None))
+    }
+
+    /// Unsubscribes from the topic, same as dropping the handle. Does
+    /// nothing if the subscription was already ended with
+    /// `Broadcast::unsubscribe` or replaced by a newer uncounted one.
+    pub fn unsubscribe(self) {}
+
+    /// Drops the handle without unsubscribing.
+    pub fn detach(mut self) {
+        self.tx = None;
+    }
+
+    fn send(&self, command: Command) -> Result<(), BroadcastError> {
+        match &self.tx {
+            Some(tx) => send(tx, command),
+            None => Err(BroadcastError::ShuttingDown),
+        }
+    }
+}
+
+impl Drop for SubscriptionHandle {
+    fn drop(&mut self) {
+        self.send(Command::Release(self.topic, self.token)).ok();
+    }
+}
+
+/// Cloneable handle to a `Broadcast` behaviour for use from other tasks.
+///
+/// Requests are processed when the behaviour is polled by the swarm and
+/// fail with `BroadcastError::ShuttingDown` once it is dropped.
+#[derive(Clone, Debug)]
+pub struct BroadcastClient {
+    tx: mpsc::UnboundedSender<Command>,
+}
+
+impl BroadcastClient {
+    pub(crate) fn new(tx: mpsc::UnboundedSender<Command>) -> Self {
+        Self { tx }
+    }
+
+    /// The topic for `name`, see `Broadcast::topic`. Fails with
+    /// `BroadcastError::InvalidTopic` if a raw name is too long.
+    pub async fn topic(&self, name: impl Into<String>) -> Result<Topic, BroadcastError> {
+        let (tx, rx) = oneshot::channel();
+        send(&self.tx, Command::Topic(name.into(), tx))?;
+        rx.await.unwrap_or(Err(BroadcastError::ShuttingDown))
+    }
+
+    pub async fn subscribe(&self, topic: Topic) -> Result<SubscriptionHandle, BroadcastError> {
+        let (tx, rx) = oneshot::channel();
+        send(&self.tx, Command::Subscribe(topic, tx))?;
+        rx.await.unwrap_or(Err(BroadcastError::ShuttingDown))
+    }
+
+    /// Ends a subscription to `topic`, returning the number of subscriptions
+    /// left, see `Broadcast::unsubscribe`.
+    pub async fn unsubscribe(&self, topic: Topic) -> Result<usize, BroadcastError> {
+        let (tx, rx) = oneshot::channel();
+        send(&self.tx, Command::Unsubscribe(topic, tx))?;
+        rx.await.unwrap_or(Err(BroadcastError::ShuttingDown))
+    }
+
+    pub async fn broadcast(
+        &self,
+        topic: Topic,
+        msg: impl Into<Bytes>,
+    ) -> Result<(), BroadcastError> {
+        let (tx, rx) = oneshot::channel();
+        send(&self.tx, Command::Publish(topic, msg.into(), Some(tx)))?;
+        rx.await.unwrap_or(Err(BroadcastError::ShuttingDown))
+    }
+
+    /// Stream of the messages received on `topic`, see
+    /// `Broadcast::topic_stream`. Messages received before the behaviour is
+    /// polled next are missed, as are messages received while the stream
+    /// buffers 256 of them, reported with `BroadcastEvent::StreamLagged`.
+    pub fn messages(&self, topic: Topic) -> impl Stream<Item = (PeerId, Bytes)> + Send + Unpin {
+        let (tx, rx) = mpsc::channel(TOPIC_STREAM_CAPACITY);
+        send(&self.tx, Command::Stream(topic, tx)).ok();
+        rx
+    }
+
+    /// See `Broadcast::stats`.
+    pub async fn stats(&self) -> Result<BroadcastStats, BroadcastError> {
+        let (tx, rx) = oneshot::channel();
+        send(&self.tx, Command::Stats(tx))?;
+        rx.await.unwrap_or(Err(BroadcastError::ShuttingDown))
+    }
+
+    /// See `Broadcast::snapshot`.
+    pub async fn state(&self) -> Result<BroadcastState, BroadcastError> {
+        let (tx, rx) = oneshot::channel();
+        send(&self.tx, Command::State(tx))?;
+        rx.await.unwrap_or(Err(BroadcastError::ShuttingDown))
+    }
+}
@@ -0,0 +1,1682 @@
+use crate::compression::{Codec, Compression};
+use crate::delivery::{BroadcastId, DeliveryError};
+use crate::fragment::{self, Reassembly};
+use crate::protocol::{
+    BroadcastConfig, BroadcastProtocol, Frame, Message, RejectReason, Version, MAX_FRAME_OVERHEAD,
+};
+use crate::queue::{Priority, Queued, SendQueue};
+use crate::Topic;
+use fnv::FnvHashSet;
+use futures::future::{self, BoxFuture, Either, FutureExt};
+use futures::io::AsyncWriteExt;
+use futures_timer::Delay;
+use instant::Instant;
+use libp2p::core::upgrade::{NegotiationError, UpgradeError};
+use libp2p::swarm::{
+    ConnectionHandler, ConnectionHandlerEvent, ConnectionHandlerUpgrErr, KeepAlive,
+    NegotiatedSubstream, SubstreamProtocol,
+};
+use std::collections::VecDeque;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// How long an idle connection is kept alive after the last message.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+type SendFuture = BoxFuture<'static, io::Result<Option<NegotiatedSubstream>>>;
+type RecvFuture =
+    BoxFuture<'static, io::Result<(NegotiatedSubstream, Negotiated, Vec<HandlerEvent>)>>;
+
+/// Protocol version and compression negotiated on a substream.
+type Negotiated = (Version, Option<Codec>);
+
+type HandlerAction = ConnectionHandlerEvent<
+    BroadcastProtocol,
+    Plane,
+    HandlerEvent,
+    ConnectionHandlerUpgrErr<io::Error>,
+>;
+
+/// Requests of the behaviour to the `BroadcastHandler`.
+#[derive(Clone, Debug)]
+pub enum HandlerIn {
+    /// Queues a message for sending.
+    Send(Message),
+    /// Queues a broadcast, reporting whether it was written with
+    /// `HandlerEvent::Delivered` or `HandlerEvent::SendFailed`.
+    SendTracked(BroadcastId, Message),
+    /// Queues a broadcast ahead of or behind the normal ones, tracked if it
+    /// has an id.
+    SendWithPriority(Priority, Option<BroadcastId>, Message),
+    /// Queues a broadcast like `SendWithPriority` that the behaviour shares
+    /// with the handlers of other connections. The handler takes its own
+    /// copy, so the behaviour doesn't clone the message for every peer.
+    SendShared(Priority, Option<BroadcastId>, Arc<Message>),
+    /// Asks for a `HandlerEvent::Flushed` once the send queue is empty.
+    Flush,
+    /// Inbound substreams of all connections to the peer, limited by
+    /// `BroadcastConfig::max_inbound_streams_per_peer`.
+    InboundStreams(Arc<AtomicUsize>),
+    /// Messages queued by all connections to the peer, see
+    /// `Broadcast::pending_to_peer`.
+    PendingSends(Arc<PendingSends>),
+}
+
+impl HandlerIn {
+    /// The message queued by the request, if any.
+    pub(crate) fn message(&self) -> Option<&Message> {
+        match self {
+            Self::Send(msg) | Self::SendTracked(_, msg) | Self::SendWithPriority(_, _, msg) => {
+                Some(msg)
+            }
+            Self::SendShared(_, _, msg) => Some(msg.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// Events emitted by the `BroadcastHandler` to the behaviour.
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum HandlerEvent {
+    /// We received a `Message` from a remote.
+    Rx(Message),
+    /// We successfully sent a `Message`.
+    Tx,
+    /// We rejected a message from a remote.
+    Rejected(Topic, RejectReason),
+    /// A broadcast was dropped because the send queue is full.
+    Dropped(Topic),
+    /// A queued broadcast was dropped because its ttl expired.
+    Expired(Topic),
+    /// The remote sent a frame that couldn't be decoded.
+    Malformed,
+    /// A substream was negotiated with a different protocol version than
+    /// the previous one.
+    Version(Version),
+    /// All messages queued before a `HandlerIn::Flush` were written.
+    Flushed,
+    /// A tracked broadcast was written.
+    Delivered(BroadcastId),
+    /// A tracked broadcast wasn't written.
+    SendFailed(BroadcastId, DeliveryError),
+    /// A batch of that many messages was written, `coalesced` if it was
+    /// taken from a congested queue.
+    BatchSent { len: usize, coalesced: bool },
+    /// A broadcast was lost because writing it failed, after all retries.
+    SendError(Topic),
+    /// Negotiating or writing to a substream took longer than
+    /// `BroadcastConfig::substream_timeout` or `BroadcastConfig::send_timeout`.
+    TimedOut,
+    /// Broadcasts are queued until the remote grants more credits, see
+    /// `BroadcastConfig::flow_control`.
+    Backpressured,
+    /// The send queue reached `BroadcastConfig::backlog_threshold`, reported
+    /// once until it is empty again.
+    Backlogged,
+}
+
+/// Messages and their payload bytes queued by the handlers of a peer but not
+/// yet written, see `Broadcast::pending_to_peer`.
+#[derive(Debug, Default)]
+pub struct PendingSends {
+    messages: AtomicUsize,
+    bytes: AtomicUsize,
+}
+
+impl PendingSends {
+    pub fn messages(&self) -> usize {
+        self.messages.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Replaces the share `previous` of a handler with `current`.
+    fn update(&self, previous: (usize, usize), current: (usize, usize)) {
+        self.messages.fetch_add(current.0, Ordering::Relaxed);
+        self.messages.fetch_sub(previous.0, Ordering::Relaxed);
+        self.bytes.fetch_add(current.1, Ordering::Relaxed);
+        self.bytes.fetch_sub(previous.1, Ordering::Relaxed);
+    }
+}
+
+/// Topics and prefixes one side of the connection is subscribed to.
+#[derive(Default)]
+struct Subscriptions {
+    topics: FnvHashSet<Topic>,
+    prefixes: FnvHashSet<Topic>,
+}
+
+impl Subscriptions {
+    fn update(&mut self, msg: &Message) {
+        match msg {
+            Message::Subscribe(topic)
+            | Message::SubscribeLease(topic, _)
+            | Message::SubscribeWithSnapshot(topic)
+            | Message::SubscribeWithAddresses(topic, _) => {
+                self.topics.insert(*topic);
+            }
+            Message::SubscribeMany(topics) => self.topics.extend(topics),
+            Message::SyncTopics(topics) => self.topics = topics.iter().copied().collect(),
+            Message::Unsubscribe(topic) => {
+                self.topics.remove(topic);
+            }
+            Message::SubscribePrefix(prefix) => {
+                self.prefixes.insert(*prefix);
+            }
+            Message::UnsubscribePrefix(prefix) => {
+                self.prefixes.remove(prefix);
+            }
+            Message::Batch(msgs) => msgs.iter().for_each(|msg| self.update(msg)),
+            Message::Broadcast(..)
+            | Message::Ack(..)
+            | Message::IHave(..)
+            | Message::IWant(..)
+            | Message::SubscribeRejected(..)
+            | Message::TopicSummary(..)
+            | Message::Fragment { .. }
+            | Message::PeerExchange(..)
+            | Message::Heartbeat(..)
+            | Message::Credit(..)
+            | Message::Snapshot(..)
+            | Message::KeyFilter(..)
+            | Message::Replay(..) => {}
+        }
+    }
+
+    fn matches(&self, topic: &Topic) -> bool {
+        self.topics.contains(topic) || self.prefixes.iter().any(|prefix| topic.has_prefix(prefix))
+    }
+
+    fn shares_topic(&self, other: &Self) -> bool {
+        self.topics.iter().any(|topic| other.matches(topic))
+            || other.topics.iter().any(|topic| self.matches(topic))
+    }
+}
+
+/// Takes a shared message, cloning it unless this was its last reference.
+pub(crate) fn unshare(msg: Arc<Message>) -> Message {
+    Arc::try_unwrap(msg).unwrap_or_else(|msg| Message::clone(&msg))
+}
+
+pub(crate) fn payload_len(msg: &Message) -> usize {
+    match msg {
+        Message::Broadcast(_, _, payload) => payload.len(),
+        Message::Fragment { data, .. } => data.len(),
+        _ => 0,
+    }
+}
+
+/// Whether `msg` is written to the control substream, see
+/// `BroadcastConfig::control_substream`.
+fn is_control(msg: &Message) -> bool {
+    !matches!(
+        msg,
+        Message::Broadcast(..)
+            | Message::Fragment { .. }
+            | Message::Snapshot(..)
+            | Message::Batch(..)
+    )
+}
+
+/// Whether writing `msg` takes a credit, see `BroadcastConfig::flow_control`.
+fn uses_credit(msg: &Message) -> bool {
+    matches!(msg, Message::Broadcast(..) | Message::Fragment { .. })
+}
+
+/// Compresses the payloads of broadcasts of at least `threshold` bytes.
+fn compress(msg: &mut Message, compression: Compression, threshold: usize) -> io::Result<()> {
+    match msg {
+        Message::Broadcast(_, ext, payload) if payload.len() >= threshold => {
+            let compressed = compression.compress(payload)?;
+            if compressed.len() < payload.len() {
+                *payload = compressed.into();
+                ext.compressed = true;
+            }
+        }
+        Message::Batch(msgs) => {
+            for msg in msgs {
+                compress(msg, compression, threshold)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// The outbound substreams of a connection.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Plane {
+    /// Broadcasts, and all other messages without a control substream.
+    Data,
+    /// Control messages, see `BroadcastConfig::control_substream`.
+    Control,
+}
+
+enum OutboundState {
+    /// No outbound substream is open.
+    Closed,
+    /// An outbound substream has been requested.
+    Opening,
+    /// The outbound substream is waiting for the next message.
+    Idle(NegotiatedSubstream, Negotiated),
+    /// A message is being written to the outbound substream.
+    Sending(SendFuture, Negotiated),
+}
+
+/// Connection handler keeping a single outbound substream open and writing
+/// queued messages to it one at a time, plus one for control messages with
+/// `BroadcastConfig::control_substream`.
+///
+/// At most `BroadcastConfig::max_send_queue_len` broadcasts are queued,
+/// further ones are dropped until the remote catches up. Control messages
+/// and `Priority::High` broadcasts are written before the others.
+pub struct BroadcastHandler {
+    config: BroadcastConfig,
+    send_queue: SendQueue,
+    /// Number of broadcasts in `send_queue`.
+    queued_broadcasts: usize,
+    events: VecDeque<HandlerEvent>,
+    outbound: OutboundState,
+    /// Messages being written, requeued if writing fails.
+    writing: Vec<Queued>,
+    /// Substream for control messages, `None` without
+    /// `BroadcastConfig::control_substream` or once it failed.
+    control: Option<OutboundState>,
+    control_queue: VecDeque<Message>,
+    control_writing: Vec<Message>,
+    /// Consecutive failed writes or substream negotiations.
+    failures: u32,
+    /// Delays opening a substream after a failure.
+    retry: Option<Delay>,
+    /// Inbound substreams, at most `BroadcastConfig::max_inbound_streams`.
+    inbound: Vec<RecvFuture>,
+    peer_inbound: Option<Arc<AtomicUsize>>,
+    pending: Option<Arc<PendingSends>>,
+    /// Messages and bytes last added to `pending`.
+    reported: (usize, usize),
+    /// Set once the queue reached `BroadcastConfig::backlog_threshold`,
+    /// until it is empty.
+    backlogged: bool,
+    /// Set when the remote doesn't speak the broadcast protocol.
+    unsupported: bool,
+    /// Protocol version of the most recently negotiated substream.
+    version: Option<Version>,
+    /// Our subscriptions, tracked with `keep_alive_shared_topics`.
+    local: Subscriptions,
+    /// Subscriptions of the remote, tracked with `keep_alive_shared_topics`.
+    remote: Subscriptions,
+    /// Delays writing a batch that isn't full by `BroadcastConfig::batch_window`.
+    batch_timer: Option<Delay>,
+    /// Whether the batch being written was taken from a congested queue,
+    /// see `BroadcastConfig::adaptive_batching`.
+    coalesced: bool,
+    /// Broadcasts the remote allows us to write, unlimited until it grants
+    /// credits, see `BroadcastConfig::flow_control`.
+    send_credits: Option<u32>,
+    /// Broadcasts received since we last granted credits.
+    received: u32,
+    /// Whether `HandlerEvent::Backpressured` was emitted since the last grant.
+    backpressured: bool,
+    keep_alive: KeepAlive,
+    /// Set by `HandlerIn::Flush` until the send queue is empty.
+    flush: bool,
+    pending_error: Option<ConnectionHandlerUpgrErr<io::Error>>,
+    /// Id of the next broadcast split into fragments.
+    next_fragment_id: u64,
+    reassembly: Reassembly,
+}
+
+impl BroadcastHandler {
+    pub fn new(config: BroadcastConfig) -> Self {
+        let reassembly = Reassembly::new(
+            config.max_message_size + MAX_FRAME_OVERHEAD,
+            config.max_reassembly_bytes,
+            config.reassembly_timeout,
+        );
+        let credits = config.flow_control;
+        let control = config.control_substream.then(|| OutboundState::Closed);
+        let mut handler = Self {
+            config,
+            send_queue: Default::default(),
+            queued_broadcasts: 0,
+            events: Default::default(),
+            outbound: OutboundState::Closed,
+            writing: Vec::new(),
+            control,
+            control_queue: Default::default(),
+            control_writing: Vec::new(),
+            failures: 0,
+            retry: None,
+            inbound: Vec::new(),
+            peer_inbound: None,
+            pending: None,
+            reported: (0, 0),
+            backlogged: false,
+            unsupported: false,
+            version: None,
+            local: Default::default(),
+            remote: Default::default(),
+            batch_timer: None,
+            coalesced: false,
+            send_credits: None,
+            received: 0,
+            backpressured: false,
+            // Closed unless a message is sent or received in time.
+            keep_alive: KeepAlive::Until(Instant::now() + IDLE_TIMEOUT),
+            flush: false,
+            pending_error: None,
+            next_fragment_id: 0,
+            reassembly,
+        };
+        if let Some(credits) = credits {
+            handler.inject_event(HandlerIn::Send(Message::Credit(credits)));
+        }
+        handler
+    }
+
+    fn protocol(&self, codecs: &[Codec]) -> BroadcastProtocol {
+        BroadcastProtocol::new(
+            &self.config.versions,
+            codecs,
+            self.config.protocol_name.as_ref(),
+        )
+    }
+
+    /// Protocols offered when opening a substream, only proposing the
+    /// configured compression.
+    fn outbound_protocol(&self) -> BroadcastProtocol {
+        let codec = self.config.compression.codec();
+        self.protocol(codec.as_slice())
+    }
+
+    fn send(
+        &self,
+        mut socket: NegotiatedSubstream,
+        (version, codec): Negotiated,
+        mut msg: Message,
+    ) -> SendFuture {
+        let keypair = self.config.keypair.clone();
+        let compression = self.config.compression;
+        let threshold = match self.config.adaptive_batching {
+            Some(batching) if batching.compress && self.coalesced => 0,
+            _ => self.config.compression_threshold,
+        };
+        let wire = self.config.codec.clone();
+        let write = async move {
+            if codec.is_some() {
+                compress(&mut msg, compression, threshold)?;
+            }
+            match version {
+                Version::Floodsub => msg.write_rpc(&mut socket, keypair.as_ref()).await?,
+                _ => msg.write(&mut socket, &*wire).await?,
+            }
+            if !version.is_stream() {
+                socket.close().await?;
+                return Ok(None);
+            }
+            Ok(Some(socket))
+        }
+        .boxed();
+        let timeout = match self.config.send_timeout {
+            Some(timeout) => timeout,
+            None => return write,
+        };
+        async move {
+            match future::select(write, Delay::new(timeout)).await {
+                Either::Left((res, _)) => res,
+                Either::Right(_) => Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "writing to the substream timed out",
+                )),
+            }
+        }
+        .boxed()
+    }
+
+    fn recv(&self, mut socket: NegotiatedSubstream, (version, codec): Negotiated) -> RecvFuture {
+        let max_message_size = self.config.max_message_size;
+        let wire = self.config.codec.clone();
+        async move {
+            let frames = match version {
+                Version::Floodsub => Message::read_rpc(&mut socket, max_message_size).await?,
+                _ => vec![Message::read(&mut socket, max_message_size, &*wire).await?],
+            };
+            let frames = frames.into_iter().flat_map(|frame| match frame {
+                Frame::Message(Message::Batch(msgs)) => msgs
+                    .into_iter()
+                    .map(|msg| match msg {
+                        Message::Broadcast(topic, _, payload)
+                            if payload.len() > max_message_size =>
+                        {
+                            Frame::TooLarge(topic)
+                        }
+                        msg => Frame::Message(msg),
+                    })
+                    .collect(),
+                frame => vec![frame],
+            });
+            let mut events = Vec::new();
+            for frame in frames {
+                events.push(match frame {
+                    Frame::Message(Message::Broadcast(topic, mut ext, payload))
+                        if ext.compressed =>
+                    {
+                        let codec = codec.ok_or_else(|| {
+                            io::Error::new(io::ErrorKind::InvalidData, "unexpected compression")
+                        })?;
+                        ext.compressed = false;
+                        match codec.decompress(&payload, max_message_size) {
+                            Ok(payload) => {
+                                HandlerEvent::Rx(Message::Broadcast(topic, ext, payload.into()))
+                            }
+                            Err(_) => HandlerEvent::Rejected(topic, RejectReason::TooLarge),
+                        }
+                    }
+                    Frame::Message(msg) => HandlerEvent::Rx(msg),
+                    Frame::TooLarge(topic) => HandlerEvent::Rejected(topic, RejectReason::TooLarge),
+                });
+            }
+            Ok((socket, (version, codec), events))
+        }
+        .boxed()
+    }
+
+    /// Next queued message that can be encoded in `version`.
+    fn next_message(&mut self, version: Version) -> Option<Message> {
+        loop {
+            let front = self.send_queue.front();
+            if self.send_credits == Some(0)
+                && matches!(front, Some((msg, _, _)) if uses_credit(msg))
+            {
+                if !self.backpressured {
+                    self.backpressured = true;
+                    self.events.push_back(HandlerEvent::Backpressured);
+                }
+                return None;
+            }
+            let (msg, id, priority) = self.send_queue.pop_front()?;
+            if let Message::Broadcast(topic, ext, _) = &msg {
+                self.queued_broadcasts -= 1;
+                if ext.is_expired() {
+                    self.events.push_back(HandlerEvent::Expired(*topic));
+                    self.failed(id, DeliveryError::Expired);
+                    continue;
+                }
+            }
+            if let (Version::V1_1, Some(size)) = (version, self.config.fragment_size) {
+                if payload_len(&msg) > size && matches!(msg, Message::Broadcast(..)) {
+                    self.split(msg, id, priority, size);
+                    continue;
+                }
+            }
+            // Single message substreams announce subscriptions one by one.
+            let msg = match (version, msg) {
+                (Version::V1_0, Message::SubscribeMany(topics) | Message::SyncTopics(topics)) => {
+                    for topic in topics.into_iter().rev() {
+                        self.send_queue
+                            .push_front((Message::Subscribe(topic), None, priority));
+                    }
+                    continue;
+                }
+                (_, msg) => msg,
+            };
+            match version.encodable(msg) {
+                Some(msg) => {
+                    if let (Some(credits), true) = (&mut self.send_credits, uses_credit(&msg)) {
+                        *credits -= 1;
+                    }
+                    self.writing.push((msg.clone(), id, priority));
+                    return Some(msg);
+                }
+                None => self.failed(id, DeliveryError::Unsupported),
+            }
+        }
+    }
+
+    /// Queues the fragments of a broadcast in its place. Only the last one
+    /// is tracked.
+    fn split(&mut self, msg: Message, id: Option<BroadcastId>, priority: Priority, size: usize) {
+        let frame = self.config.codec.encode(&msg);
+        let fragments = fragment::split(self.next_fragment_id, &frame, size);
+        self.next_fragment_id += 1;
+        let last = fragments.len() - 1;
+        for (i, fragment) in fragments.into_iter().enumerate().rev() {
+            let id = if i == last { id } else { None };
+            self.send_queue.push_front((fragment, id, priority));
+        }
+    }
+
+    /// Takes the credits granted by the remote and counts the broadcasts
+    /// it sent, see `BroadcastConfig::flow_control`.
+    fn flow_control(&mut self, event: HandlerEvent) -> Option<HandlerEvent> {
+        match &event {
+            HandlerEvent::Rx(Message::Credit(credits)) => {
+                let credits = self.send_credits.unwrap_or(0).saturating_add(*credits);
+                self.send_credits = Some(credits);
+                self.backpressured = false;
+                return None;
+            }
+            HandlerEvent::Rx(msg) if self.config.flow_control.is_some() && uses_credit(msg) => {
+                self.received = self.received.saturating_add(1);
+            }
+            _ => {}
+        }
+        Some(event)
+    }
+
+    /// Grants the remote credits for the broadcasts received once half of
+    /// them are used up and their events were passed on.
+    fn grant_credits(&mut self) {
+        let window = match self.config.flow_control {
+            Some(window) => window,
+            None => return,
+        };
+        if self.received >= (window / 2).max(1) {
+            let credits = std::mem::take(&mut self.received);
+            self.inject_event(HandlerIn::Send(Message::Credit(credits)));
+        }
+    }
+
+    /// Replaces the last received fragment of a broadcast with the
+    /// reassembled broadcast.
+    fn reassemble(&mut self, event: HandlerEvent) -> Option<HandlerEvent> {
+        let (id, index, total, data) = match event {
+            HandlerEvent::Rx(Message::Fragment {
+                id,
+                index,
+                total,
+                data,
+            }) => (id, index, total, data),
+            event => return Some(event),
+        };
+        let frame = match self.reassembly.insert(id, index, total, data) {
+            Ok(frame) => frame?,
+            Err(_) => return Some(HandlerEvent::Malformed),
+        };
+        Some(match self.config.codec.decode(&frame) {
+            Ok(Message::Broadcast(topic, _, payload))
+                if payload.len() > self.config.max_message_size =>
+            {
+                HandlerEvent::Rejected(topic, RejectReason::TooLarge)
+            }
+            Ok(Message::Broadcast(topic, ext, payload)) if !ext.compressed => {
+                HandlerEvent::Rx(Message::Broadcast(topic, ext, payload))
+            }
+            _ => HandlerEvent::Malformed,
+        })
+    }
+
+    /// Reports a tracked broadcast that won't be written.
+    fn failed(&mut self, id: Option<BroadcastId>, error: DeliveryError) {
+        if let Some(id) = id {
+            self.events.push_back(HandlerEvent::SendFailed(id, error));
+        }
+    }
+
+    /// Requeues the messages of a failed write while retries are left,
+    /// backing off exponentially. Otherwise they are reported as lost.
+    fn write_failed(&mut self, error: DeliveryError) {
+        let writing = std::mem::take(&mut self.writing);
+        if self.failures < self.config.send_retries {
+            self.retry = Some(Delay::new(self.backoff()));
+            self.failures += 1;
+            for (msg, id, priority) in writing.into_iter().rev() {
+                if let Message::Broadcast(..) = &msg {
+                    self.queued_broadcasts += 1;
+                }
+                self.send_queue.push_front((msg, id, priority));
+            }
+            return;
+        }
+        self.failures = 0;
+        for (msg, id, _) in writing {
+            if let Message::Broadcast(topic, _, _) = &msg {
+                self.events.push_back(HandlerEvent::SendError(*topic));
+            }
+            self.failed(id, error);
+        }
+    }
+
+    fn backoff(&self) -> Duration {
+        self.config.retry_backoff * 2u32.saturating_pow(self.failures.min(16))
+    }
+
+    /// Next queued messages that can be encoded in `version`, combined into
+    /// a batch on streams.
+    fn next_batch(&mut self, version: Version) -> Option<Message> {
+        self.coalesced = self.is_congested();
+        let max_batch_len = match self.config.adaptive_batching {
+            Some(batching) if self.coalesced => batching.max_batch_len,
+            _ => self.config.max_batch_len,
+        };
+        let first = self.next_message(version)?;
+        if !version.is_stream() {
+            return Some(first);
+        }
+        let mut size = payload_len(&first);
+        let mut batch = vec![first];
+        while batch.len() < max_batch_len {
+            match self.send_queue.front() {
+                Some((msg, _, _)) if size + payload_len(msg) <= self.config.max_message_size => {}
+                _ => break,
+            }
+            match self.next_message(version) {
+                Some(msg) => {
+                    size += payload_len(&msg);
+                    batch.push(msg);
+                }
+                None => break,
+            }
+        }
+        if batch.len() == 1 {
+            return batch.pop();
+        }
+        Some(Message::Batch(batch))
+    }
+
+    /// Whether the send queue is deep enough to coalesce batches, see
+    /// `BroadcastConfig::adaptive_batching`.
+    fn is_congested(&self) -> bool {
+        matches!(self.config.adaptive_batching, Some(batching) if self.send_queue.len() >= batching.threshold)
+    }
+
+    /// Whether to wait for more messages before writing a batch.
+    fn batch_pending(&mut self, cx: &mut Context<'_>, version: Version) -> bool {
+        let window = self.config.batch_window;
+        let (shallow, max_batch_len) = match self.config.adaptive_batching {
+            Some(batching) => (!self.is_congested(), batching.max_batch_len),
+            None => (false, self.config.max_batch_len),
+        };
+        if window == Duration::ZERO
+            || !version.is_stream()
+            || shallow
+            || self.send_queue.is_empty()
+            || self.send_queue.len() >= max_batch_len
+        {
+            self.batch_timer = None;
+            return false;
+        }
+        let timer = self.batch_timer.get_or_insert_with(|| Delay::new(window));
+        if timer.poll_unpin(cx).is_ready() {
+            self.batch_timer = None;
+            return false;
+        }
+        true
+    }
+
+    fn negotiated(&mut self, version: Version) {
+        if self.version != Some(version) {
+            self.version = Some(version);
+            self.events.push_back(HandlerEvent::Version(version));
+        }
+    }
+
+    /// Whether another inbound substream is within the limits, counting it
+    /// towards the limit of the peer if so.
+    fn accept_inbound(&mut self) -> bool {
+        if self.inbound.len() >= self.config.max_inbound_streams {
+            return false;
+        }
+        if let (Some(count), Some(max)) =
+            (&self.peer_inbound, self.config.max_inbound_streams_per_peer)
+        {
+            if count.fetch_add(1, Ordering::Relaxed) >= max {
+                count.fetch_sub(1, Ordering::Relaxed);
+                return false;
+            }
+        }
+        true
+    }
+
+    fn remove_inbound(&mut self, i: usize) {
+        drop(self.inbound.swap_remove(i));
+        if let Some(count) = &self.peer_inbound {
+            count.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Messages and payload bytes queued or being written.
+    fn queued(&self) -> (usize, usize) {
+        let messages = self.send_queue.len()
+            + self.writing.len()
+            + self.control_queue.len()
+            + self.control_writing.len();
+        let writing: usize = self
+            .writing
+            .iter()
+            .map(|(msg, _, _)| payload_len(msg))
+            .sum();
+        (messages, self.send_queue.bytes() + writing)
+    }
+
+    /// Updates `pending` and emits `HandlerEvent::Backlogged` once the
+    /// queue reaches the backlog threshold.
+    fn report_pending(&mut self) {
+        let queued = self.queued();
+        if let Some(pending) = &self.pending {
+            pending.update(self.reported, queued);
+        }
+        self.reported = queued;
+        if queued.0 == 0 {
+            self.backlogged = false;
+        } else if queued.0 >= self.config.backlog_threshold && !self.backlogged {
+            self.backlogged = true;
+            self.events.push_back(HandlerEvent::Backlogged);
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.send_queue.is_empty()
+            && self.control_queue.is_empty()
+            && matches!(
+                self.outbound,
+                OutboundState::Closed | OutboundState::Idle(..)
+            )
+            && !matches!(
+                self.control,
+                Some(OutboundState::Opening | OutboundState::Sending(..))
+            )
+    }
+
+    /// Writes the control messages to the data substream from now on.
+    fn disable_control(&mut self) {
+        self.control = None;
+        if self.unsupported {
+            self.control_queue.clear();
+            self.control_writing.clear();
+            return;
+        }
+        let writing = std::mem::take(&mut self.control_writing);
+        for msg in writing.into_iter().chain(self.control_queue.drain(..)) {
+            self.send_queue.push_back((msg, None, Priority::High));
+        }
+    }
+
+    /// Writes the queued control messages to their own substream, see
+    /// `BroadcastConfig::control_substream`.
+    fn poll_control(&mut self, cx: &mut Context<'_>) -> Option<HandlerAction> {
+        loop {
+            match self.control.take()? {
+                OutboundState::Closed => {
+                    if self.control_queue.is_empty() {
+                        self.control = Some(OutboundState::Closed);
+                        return None;
+                    }
+                    self.control = Some(OutboundState::Opening);
+                    return Some(ConnectionHandlerEvent::OutboundSubstreamRequest {
+                        protocol: SubstreamProtocol::new(self.outbound_protocol(), Plane::Control)
+                            .with_timeout(self.config.substream_timeout),
+                    });
+                }
+                OutboundState::Idle(socket, negotiated) => {
+                    if self.control_queue.is_empty() {
+                        self.control = Some(OutboundState::Idle(socket, negotiated));
+                        return None;
+                    }
+                    let len = self
+                        .control_queue
+                        .len()
+                        .min(self.config.max_batch_len.max(1));
+                    self.control_writing = self.control_queue.drain(..len).collect();
+                    let msg = match self.control_writing.as_slice() {
+                        [msg] => msg.clone(),
+                        msgs => Message::Batch(msgs.to_vec()),
+                    };
+                    let fut = self.send(socket, negotiated, msg);
+                    self.control = Some(OutboundState::Sending(fut, negotiated));
+                }
+                OutboundState::Sending(mut fut, negotiated) => match fut.poll_unpin(cx) {
+                    Poll::Ready(Ok(Some(socket))) => {
+                        self.control_writing.clear();
+                        self.control = Some(OutboundState::Idle(socket, negotiated));
+                        if self.is_idle() {
+                            self.keep_alive = KeepAlive::Until(Instant::now() + IDLE_TIMEOUT);
+                        }
+                    }
+                    Poll::Ready(res) => {
+                        let err = res.err();
+                        event!(debug, "handler", error = ?err, "control substream failed");
+                        if matches!(&err, Some(err) if err.kind() == io::ErrorKind::TimedOut) {
+                            self.events.push_back(HandlerEvent::TimedOut);
+                        }
+                        self.disable_control();
+                        return None;
+                    }
+                    Poll::Pending => {
+                        self.control = Some(OutboundState::Sending(fut, negotiated));
+                        return None;
+                    }
+                },
+                state => {
+                    self.control = Some(state);
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for BroadcastHandler {
+    fn drop(&mut self) {
+        if let Some(count) = &self.peer_inbound {
+            count.fetch_sub(self.inbound.len(), Ordering::Relaxed);
+        }
+        if let Some(pending) = &self.pending {
+            pending.update(self.reported, (0, 0));
+        }
+    }
+}
+
+impl ConnectionHandler for BroadcastHandler {
+    type InEvent = HandlerIn;
+    type OutEvent = HandlerEvent;
+    type Error = ConnectionHandlerUpgrErr<io::Error>;
+    type InboundProtocol = BroadcastProtocol;
+    type OutboundProtocol = BroadcastProtocol;
+    type InboundOpenInfo = ();
+    type OutboundOpenInfo = Plane;
+
+    fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol, Self::InboundOpenInfo> {
+        SubstreamProtocol::new(self.protocol(Codec::ALL), ())
+            .with_timeout(self.config.substream_timeout)
+    }
+
+    fn inject_fully_negotiated_inbound(
+        &mut self,
+        (socket, version, codec): (NegotiatedSubstream, Version, Option<Codec>),
+        _: Self::InboundOpenInfo,
+    ) {
+        self.negotiated(version);
+        // Excess substreams are dropped, closing them.
+        if self.accept_inbound() {
+            let recv = self.recv(socket, (version, codec));
+            self.inbound.push(recv);
+        }
+    }
+
+    fn inject_fully_negotiated_outbound(
+        &mut self,
+        (socket, version, codec): (NegotiatedSubstream, Version, Option<Codec>),
+        plane: Self::OutboundOpenInfo,
+    ) {
+        self.negotiated(version);
+        let negotiated = (version, codec);
+        if plane == Plane::Control {
+            if version.is_stream() {
+                self.control = Some(OutboundState::Idle(socket, negotiated));
+            } else {
+                self.disable_control();
+            }
+            return;
+        }
+        self.outbound = match self.next_batch(version) {
+            Some(msg) => OutboundState::Sending(self.send(socket, negotiated, msg), negotiated),
+            None if version.is_stream() => OutboundState::Idle(socket, negotiated),
+            None => OutboundState::Closed,
+        };
+    }
+
+    fn inject_event(&mut self, event: HandlerIn) {
+        let (msg, id, priority) = match event {
+            HandlerIn::Send(msg) => (msg, None, Priority::Normal),
+            HandlerIn::SendTracked(id, msg) => (msg, Some(id), Priority::Normal),
+            HandlerIn::SendWithPriority(priority, id, msg) => (msg, id, priority),
+            HandlerIn::SendShared(priority, id, msg) => (unshare(msg), id, priority),
+            HandlerIn::Flush => {
+                self.flush = true;
+                return;
+            }
+            HandlerIn::InboundStreams(count) => {
+                count.fetch_add(self.inbound.len(), Ordering::Relaxed);
+                self.peer_inbound = Some(count);
+                return;
+            }
+            HandlerIn::PendingSends(pending) => {
+                pending.update((0, 0), self.reported);
+                self.pending = Some(pending);
+                return;
+            }
+        };
+        // Queued together, so they are written as one batch if possible.
+        if let Message::Batch(msgs) = msg {
+            for msg in msgs {
+                self.inject_event(HandlerIn::SendWithPriority(priority, id, msg));
+            }
+            return;
+        }
+        if self.unsupported {
+            self.failed(id, DeliveryError::Unsupported);
+            return;
+        }
+        if self.config.keep_alive_shared_topics {
+            self.local.update(&msg);
+        }
+        if self.control.is_some() && id.is_none() && is_control(&msg) {
+            self.control_queue.push_back(msg);
+            self.keep_alive = KeepAlive::Yes;
+            return;
+        }
+        let priority = match &msg {
+            Message::Broadcast(..) => priority,
+            _ => Priority::High,
+        };
+        if let Message::Broadcast(topic, _, _) = &msg {
+            if self.queued_broadcasts >= self.config.max_send_queue_len {
+                event!(debug, "queue", topic = ?topic, "send queue full, dropping broadcast");
+                self.events.push_back(HandlerEvent::Dropped(*topic));
+                self.failed(id, DeliveryError::QueueFull);
+                return;
+            }
+            self.queued_broadcasts += 1;
+        }
+        #[cfg(feature = "tracing")]
+        if let Message::Broadcast(topic, _, payload) = &msg {
+            event!(
+                trace,
+                "queue",
+                topic = ?topic,
+                msg_len = payload.len(),
+                ?priority,
+                queued = self.send_queue.len() + 1,
+                "queued broadcast",
+            );
+        }
+        self.send_queue.push_back((msg, id, priority));
+        self.keep_alive = KeepAlive::Yes;
+    }
+
+    fn inject_dial_upgrade_error(
+        &mut self,
+        plane: Self::OutboundOpenInfo,
+        error: ConnectionHandlerUpgrErr<io::Error>,
+    ) {
+        if let ConnectionHandlerUpgrErr::Timeout = error {
+            self.events.push_back(HandlerEvent::TimedOut);
+        }
+        // The data substream reports errors the control one shares with it.
+        if plane == Plane::Control {
+            self.disable_control();
+            return;
+        }
+        self.outbound = OutboundState::Closed;
+        match error {
+            ConnectionHandlerUpgrErr::Upgrade(UpgradeError::Select(NegotiationError::Failed)) => {
+                self.unsupported = true;
+                for (_, id, _) in self.send_queue.drain() {
+                    self.failed(id, DeliveryError::Unsupported);
+                }
+                self.queued_broadcasts = 0;
+                self.keep_alive = KeepAlive::No;
+            }
+            _ if self.failures < self.config.send_retries => {
+                self.retry = Some(Delay::new(self.backoff()));
+                self.failures += 1;
+            }
+            error => {
+                let kind = match &error {
+                    ConnectionHandlerUpgrErr::Upgrade(UpgradeError::Apply(err)) => err.kind(),
+                    ConnectionHandlerUpgrErr::Timeout => io::ErrorKind::TimedOut,
+                    _ => io::ErrorKind::Other,
+                };
+                self.writing = self.send_queue.drain();
+                self.queued_broadcasts = 0;
+                self.write_failed(DeliveryError::Io(kind));
+                self.pending_error = Some(error);
+            }
+        }
+    }
+
+    fn connection_keep_alive(&self) -> KeepAlive {
+        if self.config.keep_alive_shared_topics
+            && !self.unsupported
+            && self.local.shares_topic(&self.remote)
+        {
+            return KeepAlive::Yes;
+        }
+        self.keep_alive
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<ConnectionHandlerEvent<BroadcastProtocol, Plane, HandlerEvent, Self::Error>> {
+        self.report_pending();
+        if let Some(event) = self.events.pop_front() {
+            return Poll::Ready(ConnectionHandlerEvent::Custom(event));
+        }
+
+        if let Some(error) = self.pending_error.take() {
+            return Poll::Ready(ConnectionHandlerEvent::Close(error));
+        }
+
+        self.grant_credits();
+
+        let mut i = 0;
+        while i < self.inbound.len() {
+            match self.inbound[i].poll_unpin(cx) {
+                Poll::Ready(Ok((socket, negotiated, events))) => {
+                    event!(trace, "handler", events = events.len(), "received frame");
+                    self.inbound[i] = self.recv(socket, negotiated);
+                    let events: Vec<_> = events
+                        .into_iter()
+                        .filter_map(|event| {
+                            let event = self.flow_control(event)?;
+                            self.reassemble(event)
+                        })
+                        .collect();
+                    if !self.keep_alive.is_yes() {
+                        self.keep_alive = KeepAlive::Until(Instant::now() + IDLE_TIMEOUT);
+                    }
+                    if self.config.keep_alive_shared_topics {
+                        for event in &events {
+                            if let HandlerEvent::Rx(msg) = event {
+                                self.remote.update(msg);
+                            }
+                        }
+                    }
+                    self.events.extend(events);
+                    if let Some(event) = self.events.pop_front() {
+                        return Poll::Ready(ConnectionHandlerEvent::Custom(event));
+                    }
+                }
+                Poll::Ready(Err(err)) => {
+                    event!(debug, "handler", error = %err, "inbound substream failed");
+                    self.remove_inbound(i);
+                    if err.kind() == io::ErrorKind::InvalidData {
+                        return Poll::Ready(ConnectionHandlerEvent::Custom(
+                            HandlerEvent::Malformed,
+                        ));
+                    }
+                }
+                Poll::Pending => i += 1,
+            }
+        }
+
+        if let Some(event) = self.poll_control(cx) {
+            return Poll::Ready(event);
+        }
+
+        loop {
+            match std::mem::replace(&mut self.outbound, OutboundState::Closed) {
+                OutboundState::Closed => {
+                    if self.send_queue.is_empty() {
+                        break;
+                    }
+                    if let Some(retry) = self.retry.as_mut() {
+                        if retry.poll_unpin(cx).is_pending() {
+                            break;
+                        }
+                        self.retry = None;
+                    }
+                    self.outbound = OutboundState::Opening;
+                    return Poll::Ready(ConnectionHandlerEvent::OutboundSubstreamRequest {
+                        protocol: SubstreamProtocol::new(self.outbound_protocol(), Plane::Data)
+                            .with_timeout(self.config.substream_timeout),
+                    });
+                }
+                OutboundState::Opening => {
+                    self.outbound = OutboundState::Opening;
+                    break;
+                }
+                OutboundState::Idle(socket, negotiated) => {
+                    if self.batch_pending(cx, negotiated.0) {
+                        self.outbound = OutboundState::Idle(socket, negotiated);
+                        break;
+                    }
+                    match self.next_batch(negotiated.0) {
+                        Some(msg) => {
+                            self.outbound = OutboundState::Sending(
+                                self.send(socket, negotiated, msg),
+                                negotiated,
+                            );
+                        }
+                        None => {
+                            self.outbound = OutboundState::Idle(socket, negotiated);
+                            break;
+                        }
+                    }
+                }
+                OutboundState::Sending(mut fut, negotiated) => {
+                    let res = match fut.poll_unpin(cx) {
+                        Poll::Ready(res) => res,
+                        Poll::Pending => {
+                            self.outbound = OutboundState::Sending(fut, negotiated);
+                            break;
+                        }
+                    };
+                    let sent = res.is_ok();
+                    match &res {
+                        Ok(_) => {
+                            event!(trace, "queue", sent = self.writing.len(), "sent messages");
+                            self.failures = 0;
+                            if self.writing.len() > 1 {
+                                self.events.push_back(HandlerEvent::BatchSent {
+                                    len: self.writing.len(),
+                                    coalesced: self.coalesced,
+                                });
+                            }
+                            for (_, id, _) in std::mem::take(&mut self.writing) {
+                                self.events.extend(id.map(HandlerEvent::Delivered));
+                            }
+                        }
+                        Err(err) => {
+                            event!(debug, "handler", error = %err, "outbound substream failed");
+                            if err.kind() == io::ErrorKind::TimedOut {
+                                self.events.push_back(HandlerEvent::TimedOut);
+                            }
+                            // The remote grants no credits for lost broadcasts.
+                            if let Some(credits) = &mut self.send_credits {
+                                let lost =
+                                    self.writing.iter().filter(|(msg, _, _)| uses_credit(msg));
+                                *credits = credits.saturating_add(lost.count() as u32);
+                            }
+                            self.write_failed(DeliveryError::Io(err.kind()))
+                        }
+                    }
+                    if let Ok(Some(socket)) = res {
+                        self.outbound = OutboundState::Idle(socket, negotiated);
+                    }
+                    if self.is_idle() {
+                        self.keep_alive = KeepAlive::Until(Instant::now() + IDLE_TIMEOUT);
+                    }
+                    // On error the message is lost and a new substream is
+                    // opened for the remaining ones.
+                    if sent {
+                        // Counted as written before the behaviour learns of it.
+                        self.report_pending();
+                        return Poll::Ready(ConnectionHandlerEvent::Custom(HandlerEvent::Tx));
+                    }
+                }
+            }
+        }
+
+        if let Some(event) = self.events.pop_front() {
+            return Poll::Ready(ConnectionHandlerEvent::Custom(event));
+        }
+
+        if self.flush && self.is_idle() {
+            self.flush = false;
+            return Poll::Ready(ConnectionHandlerEvent::Custom(HandlerEvent::Flushed));
+        }
+
+        self.report_pending();
+        if let Some(event) = self.events.pop_front() {
+            return Poll::Ready(ConnectionHandlerEvent::Custom(event));
+        }
+
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Extensions;
+    use crate::queue::AdaptiveBatching;
+    use bytes::Bytes;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_send_queue_limit() {
+        let topic = Topic::new(b"topic");
+        let config = BroadcastConfig::default().max_send_queue_len(1);
+        let mut handler = BroadcastHandler::new(config);
+        let msg = Message::Broadcast(topic, Extensions::default(), Bytes::from_static(b"msg"));
+        handler.inject_event(HandlerIn::Send(msg.clone()));
+        handler.inject_event(HandlerIn::Send(msg));
+        handler.inject_event(HandlerIn::Send(Message::Subscribe(topic)));
+        assert_eq!(handler.send_queue.len(), 2);
+
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        match handler.poll(&mut cx) {
+            Poll::Ready(ConnectionHandlerEvent::Custom(HandlerEvent::Dropped(dropped))) => {
+                assert_eq!(dropped, topic)
+            }
+            _ => panic!("expected dropped event"),
+        }
+        assert!(matches!(
+            handler.poll(&mut cx),
+            Poll::Ready(ConnectionHandlerEvent::OutboundSubstreamRequest { .. })
+        ));
+    }
+
+    #[test]
+    fn test_subscribe_many_v1_0() {
+        let topics = vec![Topic::new(b"a"), Topic::new(b"b")];
+        let mut handler = BroadcastHandler::new(BroadcastConfig::default());
+        handler.inject_event(HandlerIn::Send(Message::SubscribeMany(topics.clone())));
+        assert_eq!(
+            handler.next_message(Version::V1_1),
+            Some(Message::SubscribeMany(topics.clone()))
+        );
+        handler.inject_event(HandlerIn::Send(Message::SubscribeMany(topics.clone())));
+        for topic in topics {
+            assert_eq!(
+                handler.next_message(Version::V1_0),
+                Some(Message::Subscribe(topic))
+            );
+        }
+        assert_eq!(handler.next_message(Version::V1_0), None);
+    }
+
+    #[test]
+    fn test_idle_keep_alive() {
+        let mut handler = BroadcastHandler::new(BroadcastConfig::default());
+        handler.inject_event(HandlerIn::InboundStreams(Arc::new(AtomicUsize::new(0))));
+        assert!(!handler.connection_keep_alive().is_yes());
+        handler.inject_event(HandlerIn::Send(Message::Subscribe(Topic::new(b"topic"))));
+        assert!(handler.connection_keep_alive().is_yes());
+    }
+
+    #[test]
+    fn test_keep_alive_shared_topics() {
+        let config = BroadcastConfig::default().keep_alive_shared_topics(true);
+        let mut handler = BroadcastHandler::new(config);
+        handler.inject_event(HandlerIn::Send(Message::SubscribeMany(vec![Topic::new(
+            b"chat/a",
+        )])));
+        handler.keep_alive = KeepAlive::No;
+        assert!(!handler.connection_keep_alive().is_yes());
+        handler
+            .remote
+            .update(&Message::SubscribePrefix(Topic::new(b"chat/")));
+        assert!(handler.connection_keep_alive().is_yes());
+        handler.inject_event(HandlerIn::Send(Message::Unsubscribe(Topic::new(b"chat/a"))));
+        handler.keep_alive = KeepAlive::No;
+        assert!(!handler.connection_keep_alive().is_yes());
+    }
+
+    #[test]
+    fn test_next_batch() {
+        let topic = Topic::new(b"topic");
+        let config = BroadcastConfig::default()
+            .max_batch_len(3)
+            .max_message_size(5);
+        let mut handler = BroadcastHandler::new(config);
+        let msg = |payload: &[u8]| {
+            Message::Broadcast(
+                topic,
+                Extensions::default(),
+                Bytes::copy_from_slice(payload),
+            )
+        };
+        for payload in [&b"ab"[..], b"cd", b"ef", b"g", b"h", b"i", b"j"] {
+            handler.inject_event(HandlerIn::Send(msg(payload)));
+        }
+        assert_eq!(
+            handler.next_batch(Version::V1_1),
+            Some(Message::Batch(vec![msg(b"ab"), msg(b"cd")]))
+        );
+        assert_eq!(
+            handler.next_batch(Version::V1_1),
+            Some(Message::Batch(vec![msg(b"ef"), msg(b"g"), msg(b"h")]))
+        );
+        assert_eq!(handler.next_batch(Version::V1_0), Some(msg(b"i")));
+        assert_eq!(handler.next_batch(Version::V1_1), Some(msg(b"j")));
+        assert_eq!(handler.queued_broadcasts, 0);
+    }
+
+    #[test]
+    fn test_adaptive_batching() {
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let topic = Topic::new(b"topic");
+        let config = BroadcastConfig::default()
+            .max_batch_len(2)
+            .batch_window(Duration::from_secs(60))
+            .adaptive_batching(AdaptiveBatching {
+                threshold: 4,
+                max_batch_len: 8,
+                compress: true,
+            });
+        let mut handler = BroadcastHandler::new(config);
+        let msg = Message::Broadcast(topic, Extensions::default(), Bytes::from_static(b"msg"));
+        for _ in 0..3 {
+            handler.inject_event(HandlerIn::Send(msg.clone()));
+        }
+        // Shallow queues are written right away.
+        assert!(!handler.batch_pending(&mut cx, Version::V1_1));
+        assert_eq!(
+            handler.next_batch(Version::V1_1),
+            Some(Message::Batch(vec![msg.clone(), msg.clone()]))
+        );
+        assert!(!handler.coalesced);
+        handler.next_batch(Version::V1_1);
+
+        for _ in 0..6 {
+            handler.inject_event(HandlerIn::Send(msg.clone()));
+        }
+        assert!(handler.batch_pending(&mut cx, Version::V1_1));
+        assert_eq!(
+            handler.next_batch(Version::V1_1),
+            Some(Message::Batch(vec![msg; 6]))
+        );
+        assert!(handler.coalesced);
+    }
+
+    #[test]
+    fn test_flow_control() {
+        let topic = Topic::new(b"topic");
+        let msg = Message::Broadcast(topic, Extensions::default(), Bytes::from_static(b"msg"));
+        let mut handler = BroadcastHandler::new(BroadcastConfig::default().flow_control(4));
+        // The initial grant to the remote.
+        assert_eq!(handler.next_batch(Version::V1_1), Some(Message::Credit(4)));
+
+        assert!(handler
+            .flow_control(HandlerEvent::Rx(Message::Credit(2)))
+            .is_none());
+        for _ in 0..3 {
+            handler.inject_event(HandlerIn::Send(msg.clone()));
+        }
+        assert_eq!(
+            handler.next_batch(Version::V1_1),
+            Some(Message::Batch(vec![msg.clone(), msg.clone()]))
+        );
+        assert_eq!(handler.next_batch(Version::V1_1), None);
+        assert!(matches!(
+            handler.events.pop_front(),
+            Some(HandlerEvent::Backpressured)
+        ));
+        assert_eq!(handler.next_batch(Version::V1_1), None);
+        assert!(handler.events.is_empty());
+
+        handler.flow_control(HandlerEvent::Rx(Message::Credit(1)));
+        assert_eq!(handler.next_batch(Version::V1_1), Some(msg.clone()));
+
+        // Credits are granted for received broadcasts once half are used.
+        handler.flow_control(HandlerEvent::Rx(msg.clone()));
+        handler.grant_credits();
+        assert!(handler.send_queue.is_empty());
+        handler.flow_control(HandlerEvent::Rx(msg));
+        handler.grant_credits();
+        assert_eq!(handler.next_batch(Version::V1_1), Some(Message::Credit(2)));
+    }
+
+    #[test]
+    fn test_queue_batch() {
+        let topic = Topic::new(b"topic");
+        let mut handler = BroadcastHandler::new(BroadcastConfig::default());
+        let msg = |payload: &'static [u8]| {
+            Message::Broadcast(topic, Extensions::default(), Bytes::from_static(payload))
+        };
+        handler.inject_event(HandlerIn::Send(Message::Batch(vec![msg(b"a"), msg(b"b")])));
+        assert_eq!(handler.queued_broadcasts, 2);
+        assert_eq!(handler.next_batch(Version::V1_0), Some(msg(b"a")));
+        assert_eq!(handler.next_batch(Version::V1_0), Some(msg(b"b")));
+
+        handler.inject_event(HandlerIn::Send(Message::Batch(vec![msg(b"a"), msg(b"b")])));
+        assert_eq!(
+            handler.next_batch(Version::V1_1),
+            Some(Message::Batch(vec![msg(b"a"), msg(b"b")]))
+        );
+    }
+
+    #[test]
+    fn test_flush() {
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut handler = BroadcastHandler::new(BroadcastConfig::default());
+        handler.inject_event(HandlerIn::Send(Message::Subscribe(Topic::new(b"topic"))));
+        handler.inject_event(HandlerIn::Flush);
+        assert!(matches!(
+            handler.poll(&mut cx),
+            Poll::Ready(ConnectionHandlerEvent::OutboundSubstreamRequest { .. })
+        ));
+        assert!(handler.poll(&mut cx).is_pending());
+
+        // A failed upgrade drops the queued messages.
+        handler.inject_dial_upgrade_error(
+            Plane::Data,
+            ConnectionHandlerUpgrErr::Upgrade(UpgradeError::Select(NegotiationError::Failed)),
+        );
+        assert!(matches!(
+            handler.poll(&mut cx),
+            Poll::Ready(ConnectionHandlerEvent::Custom(HandlerEvent::Flushed))
+        ));
+        assert!(handler.poll(&mut cx).is_pending());
+    }
+
+    #[test]
+    fn test_timeout() {
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let config = BroadcastConfig::default().substream_timeout(Duration::from_secs(3));
+        let mut handler = BroadcastHandler::new(config);
+        assert_eq!(*handler.listen_protocol().timeout(), Duration::from_secs(3));
+
+        handler.inject_event(HandlerIn::Send(Message::Subscribe(Topic::new(b"topic"))));
+        assert!(matches!(
+            handler.poll(&mut cx),
+            Poll::Ready(ConnectionHandlerEvent::OutboundSubstreamRequest { .. })
+        ));
+        handler.inject_dial_upgrade_error(Plane::Data, ConnectionHandlerUpgrErr::Timeout);
+        assert!(matches!(
+            handler.poll(&mut cx),
+            Poll::Ready(ConnectionHandlerEvent::Custom(HandlerEvent::TimedOut))
+        ));
+    }
+
+    #[test]
+    fn test_control_substream() {
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let topic = Topic::new(b"topic");
+        let mut handler = BroadcastHandler::new(BroadcastConfig::default().control_substream());
+        let broadcast =
+            Message::Broadcast(topic, Extensions::default(), Bytes::from_static(b"msg"));
+        handler.inject_event(HandlerIn::Send(broadcast.clone()));
+        handler.inject_event(HandlerIn::Send(Message::Subscribe(topic)));
+        assert_eq!(handler.send_queue.len(), 1);
+        assert_eq!(handler.control_queue.len(), 1);
+
+        for plane in [Plane::Control, Plane::Data] {
+            match handler.poll(&mut cx) {
+                Poll::Ready(ConnectionHandlerEvent::OutboundSubstreamRequest { protocol }) => {
+                    assert_eq!(*protocol.info(), plane)
+                }
+                _ => panic!("expected a substream request"),
+            }
+        }
+        assert!(handler.poll(&mut cx).is_pending());
+
+        // Control messages fall back to the data substream.
+        handler.inject_dial_upgrade_error(Plane::Control, ConnectionHandlerUpgrErr::Timeout);
+        assert!(handler.control.is_none());
+        assert_eq!(
+            handler.next_batch(Version::V1_1),
+            Some(Message::Batch(vec![Message::Subscribe(topic), broadcast]))
+        );
+    }
+
+    #[test]
+    fn test_tracked() {
+        let topic = Topic::new(b"topic");
+        let mut deliveries = crate::delivery::Deliveries::default();
+        let (first, second) = (deliveries.start(), deliveries.start());
+        let mut handler = BroadcastHandler::new(BroadcastConfig::default().max_send_queue_len(1));
+        let msg = Message::Broadcast(topic, Extensions::default(), Bytes::from_static(b"msg"));
+        handler.inject_event(HandlerIn::SendTracked(first, msg.clone()));
+        handler.inject_event(HandlerIn::SendTracked(second, msg.clone()));
+        assert!(matches!(
+            handler.events.pop_front(),
+            Some(HandlerEvent::Dropped(_))
+        ));
+        assert!(matches!(
+            handler.events.pop_front(),
+            Some(HandlerEvent::SendFailed(id, DeliveryError::QueueFull)) if id == second
+        ));
+        assert_eq!(handler.next_batch(Version::V1_1), Some(msg.clone()));
+        assert_eq!(handler.writing, vec![(msg, Some(first), Priority::Normal)]);
+    }
+
+    #[test]
+    fn test_priority() {
+        let topic = Topic::new(b"topic");
+        let msg = |payload: &[u8]| {
+            Message::Broadcast(
+                topic,
+                Extensions::default(),
+                Bytes::copy_from_slice(payload),
+            )
+        };
+        let mut handler = BroadcastHandler::new(BroadcastConfig::default());
+        handler.inject_event(HandlerIn::SendWithPriority(
+            Priority::Low,
+            None,
+            msg(b"low"),
+        ));
+        handler.inject_event(HandlerIn::Send(msg(b"normal")));
+        handler.inject_event(HandlerIn::Send(Message::Subscribe(topic)));
+        handler.inject_event(HandlerIn::SendWithPriority(
+            Priority::High,
+            None,
+            msg(b"high"),
+        ));
+        handler.inject_event(HandlerIn::SendShared(
+            Priority::Normal,
+            None,
+            Arc::new(msg(b"shared")),
+        ));
+        let order: Vec<_> = std::iter::from_fn(|| handler.next_message(Version::V1_1)).collect();
+        assert_eq!(
+            order,
+            vec![
+                Message::Subscribe(topic),
+                msg(b"high"),
+                msg(b"normal"),
+                msg(b"shared"),
+                msg(b"low")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fragments() {
+        let topic = Topic::new(b"topic");
+        let payload: Bytes = (0..100).collect::<Vec<u8>>().into();
+        let msg = Message::Broadcast(topic, Extensions::default(), payload);
+        let config = BroadcastConfig::default().fragment_size(30);
+        let mut sender = BroadcastHandler::new(config.clone());
+        let mut receiver = BroadcastHandler::new(config);
+        sender.inject_event(HandlerIn::Send(msg.clone()));
+
+        let fragments: Vec<_> = std::iter::from_fn(|| sender.next_message(Version::V1_1)).collect();
+        assert_eq!(fragments.len(), 4);
+        let mut events = fragments
+            .into_iter()
+            .filter_map(|msg| receiver.reassemble(HandlerEvent::Rx(msg)));
+        assert!(matches!(events.next(), Some(HandlerEvent::Rx(rx)) if rx == msg));
+        assert!(events.next().is_none());
+
+        // Peers not speaking `Version::V1_1` get the whole message.
+        sender.inject_event(HandlerIn::Send(msg.clone()));
+        assert_eq!(sender.next_message(Version::V1_0), Some(msg));
+    }
+
+    #[test]
+    fn test_inbound_limits() {
+        let config = BroadcastConfig::default()
+            .max_inbound_streams(2)
+            .max_inbound_streams_per_peer(3);
+        let count = Arc::new(AtomicUsize::new(0));
+        let mut a = BroadcastHandler::new(config.clone());
+        let mut b = BroadcastHandler::new(config);
+        assert!(a.accept_inbound());
+        a.inbound.push(futures::future::pending().boxed());
+        // Substreams accepted before the peer's count was known are counted.
+        a.inject_event(HandlerIn::InboundStreams(count.clone()));
+        b.inject_event(HandlerIn::InboundStreams(count.clone()));
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+        assert!(a.accept_inbound());
+        a.inbound.push(futures::future::pending().boxed());
+        assert!(!a.accept_inbound());
+        assert!(b.accept_inbound());
+        b.inbound.push(futures::future::pending().boxed());
+        assert!(!b.accept_inbound());
+        assert_eq!(count.load(Ordering::Relaxed), 3);
+
+        a.remove_inbound(0);
+        drop(b);
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_pending_sends() {
+        let topic = Topic::new(b"topic");
+        let msg = Message::Broadcast(topic, Extensions::default(), Bytes::from_static(b"msg"));
+        let config = BroadcastConfig::default().backlog_threshold(2);
+        let pending = Arc::new(PendingSends::default());
+        let mut a = BroadcastHandler::new(config.clone());
+        let mut b = BroadcastHandler::new(config);
+        // Messages queued before the peer's counts were known are counted.
+        a.inject_event(HandlerIn::Send(msg.clone()));
+        a.report_pending();
+        a.inject_event(HandlerIn::PendingSends(pending.clone()));
+        b.inject_event(HandlerIn::PendingSends(pending.clone()));
+        b.inject_event(HandlerIn::Send(msg.clone()));
+        b.report_pending();
+        assert_eq!((pending.messages(), pending.bytes()), (2, 6));
+
+        // Messages being written are still pending.
+        a.inject_event(HandlerIn::Send(msg));
+        assert!(a.next_batch(Version::V1_1).is_some());
+        a.report_pending();
+        assert_eq!((pending.messages(), pending.bytes()), (3, 9));
+        assert!(matches!(
+            a.events.pop_front(),
+            Some(HandlerEvent::Backlogged)
+        ));
+        a.report_pending();
+        assert!(a.events.is_empty());
+
+        a.writing.clear();
+        a.report_pending();
+        assert_eq!((pending.messages(), pending.bytes()), (1, 3));
+        assert!(!a.backlogged);
+        assert!(a.events.is_empty());
+        // Queues below the threshold aren't reported.
+        b.next_batch(Version::V1_1);
+        b.writing.clear();
+        b.report_pending();
+        assert!(b.events.is_empty());
+
+        a.inject_event(HandlerIn::Send(Message::Subscribe(topic)));
+        a.report_pending();
+        drop(a);
+        assert_eq!((pending.messages(), pending.bytes()), (0, 0));
+    }
+
+    #[test]
+    fn test_retry_sends() {
+        let topic = Topic::new(b"topic");
+        let config = BroadcastConfig::default().retry_sends(1, Duration::from_millis(10));
+        let mut handler = BroadcastHandler::new(config);
+        let msg = Message::Broadcast(topic, Extensions::default(), Bytes::from_static(b"msg"));
+        handler.inject_event(HandlerIn::Send(msg.clone()));
+        let error = DeliveryError::Io(io::ErrorKind::BrokenPipe);
+
+        // The first failure requeues the message.
+        assert_eq!(handler.next_batch(Version::V1_1), Some(msg.clone()));
+        handler.write_failed(error);
+        assert!(handler.events.is_empty());
+        assert!(handler.retry.is_some());
+        assert_eq!(handler.queued_broadcasts, 1);
+
+        // Once the retries are used up it is reported as lost.
+        assert_eq!(handler.next_batch(Version::V1_1), Some(msg));
+        handler.write_failed(error);
+        assert!(matches!(
+            handler.events.pop_front(),
+            Some(HandlerEvent::SendError(t)) if t == topic
+        ));
+        assert!(handler.send_queue.is_empty());
+        assert_eq!(handler.failures, 0);
+    }
+}
@@ -0,0 +1,91 @@
+use crate::protocol::Topic;
+use fnv::FnvHashMap;
+use futures::FutureExt;
+use futures_timer::Delay;
+use libp2p::PeerId;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// Heartbeats missed by the peers on the topics we share with them, see
+/// `BroadcastConfig::topic_heartbeat`.
+#[derive(Default)]
+pub(crate) struct Heartbeats {
+    interval: Duration,
+    max_missed: u32,
+    missed: FnvHashMap<(PeerId, Topic), u32>,
+    timer: Option<Delay>,
+}
+
+impl Heartbeats {
+    pub fn new(heartbeat: Option<(Duration, u32)>) -> Self {
+        match heartbeat {
+            Some((interval, max_missed)) => Self {
+                interval,
+                max_missed,
+                missed: Default::default(),
+                timer: Some(Delay::new(interval)),
+            },
+            None => Self::default(),
+        }
+    }
+
+    /// The peer sent a heartbeat for the topic.
+    pub fn received(&mut self, peer: PeerId, topic: Topic) {
+        self.missed.insert((peer, topic), 0);
+    }
+
+    pub fn remove_peer(&mut self, peer: &PeerId) {
+        self.missed.retain(|(p, _), _| p != peer);
+    }
+
+    /// Counts a missed heartbeat for each of the `shared` topics, returning
+    /// the ones that just reached the limit.
+    pub fn tick(&mut self, shared: Vec<(PeerId, Topic)>) -> Vec<(PeerId, Topic)> {
+        let mut missed = FnvHashMap::default();
+        let mut unresponsive = Vec::new();
+        for key in shared {
+            let count = self.missed.get(&key).copied().unwrap_or_default() + 1;
+            if count == self.max_missed {
+                unresponsive.push(key);
+            }
+            missed.insert(key, count);
+        }
+        self.missed = missed;
+        unresponsive
+    }
+
+    /// Ready when the next heartbeats are due.
+    pub fn poll_tick(&mut self, cx: &mut Context) -> Poll<()> {
+        if let Some(timer) = &mut self.timer {
+            if timer.poll_unpin(cx).is_ready() {
+                timer.reset(self.interval);
+                return Poll::Ready(());
+            }
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heartbeats() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let topic = Topic::new(b"topic");
+        let mut heartbeats = Heartbeats::new(Some((Duration::from_secs(1), 2)));
+        let shared = vec![(a, topic), (b, topic)];
+        assert!(heartbeats.tick(shared.clone()).is_empty());
+        heartbeats.received(a, topic);
+        assert_eq!(heartbeats.tick(shared.clone()), vec![(b, topic)]);
+        // Reported once.
+        heartbeats.received(a, topic);
+        assert!(heartbeats.tick(shared).is_empty());
+
+        // Topics no longer shared are forgotten.
+        heartbeats.received(a, topic);
+        heartbeats.tick(vec![]);
+        assert!(heartbeats.missed.is_empty());
+    }
+}
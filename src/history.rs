@@ -0,0 +1,131 @@
+use crate::protocol::{Extensions, MessageId, ReplaySince, Topic};
+use bytes::Bytes;
+use fnv::FnvHashMap;
+use instant::Instant;
+use std::collections::VecDeque;
+
+type Entry = (MessageId, Extensions, Bytes, Instant);
+
+/// The last messages of each topic, offered to peers that (re)subscribe and
+/// replayed on request.
+#[derive(Debug, Default)]
+pub(crate) struct MessageHistory {
+    capacity: usize,
+    topics: FnvHashMap<Topic, VecDeque<Entry>>,
+}
+
+impl MessageHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ..Default::default()
+        }
+    }
+
+    pub fn push(&mut self, topic: &Topic, id: MessageId, ext: Extensions, msg: Bytes) {
+        if self.capacity == 0 {
+            return;
+        }
+        let history = self.topics.entry(*topic).or_default();
+        if history.len() >= self.capacity {
+            history.pop_front();
+        }
+        history.push_back((id, ext, msg, Instant::now()));
+    }
+
+    /// Topics with a history matching `filter`.
+    pub fn topics(&self, filter: impl Fn(&Topic) -> bool) -> Vec<Topic> {
+        self.topics
+            .keys()
+            .filter(|topic| filter(topic))
+            .copied()
+            .collect()
+    }
+
+    /// Ids of the unexpired messages of `topic`, oldest first.
+    pub fn ids(&self, topic: &Topic) -> Vec<MessageId> {
+        self.topics
+            .get(topic)
+            .into_iter()
+            .flatten()
+            .filter(|(_, ext, _, _)| !ext.is_expired())
+            .map(|(id, _, _, _)| *id)
+            .collect()
+    }
+
+    pub fn get(&self, topic: &Topic, id: &MessageId) -> Option<(Extensions, Bytes)> {
+        self.topics
+            .get(topic)?
+            .iter()
+            .find(|(id2, ext, _, _)| id2 == id && !ext.is_expired())
+            .map(|(_, ext, msg, _)| (ext.clone(), msg.clone()))
+    }
+
+    /// The unexpired messages of `topic` since a message or time, oldest
+    /// first.
+    pub fn since(&self, topic: &Topic, since: ReplaySince) -> Vec<(MessageId, Extensions, Bytes)> {
+        let history = match self.topics.get(topic) {
+            Some(history) => history,
+            None => return Vec::new(),
+        };
+        let start = match since {
+            ReplaySince::Message(id) => history
+                .iter()
+                .position(|(id2, _, _, _)| *id2 == id)
+                .map_or(0, |i| i + 1),
+            ReplaySince::Time(time) => history.partition_point(|(_, _, _, at)| *at < time),
+            ReplaySince::All => 0,
+        };
+        history
+            .range(start..)
+            .filter(|(_, ext, _, _)| !ext.is_expired())
+            .map(|(id, ext, msg, _)| (*id, ext.clone(), msg.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history() {
+        let topic = Topic::new(b"topic");
+        let start = Instant::now();
+        let mut history = MessageHistory::new(2);
+        for payload in [&b"a"[..], b"b", b"c"] {
+            let id = MessageId::new(&topic, payload);
+            history.push(&topic, id, Extensions::default(), payload.into());
+        }
+        let ids = history.ids(&topic);
+        assert_eq!(
+            ids,
+            vec![MessageId::new(&topic, b"b"), MessageId::new(&topic, b"c")]
+        );
+        assert_eq!(history.get(&topic, &ids[1]).unwrap().1[..], b"c"[..]);
+        assert!(history.get(&topic, &MessageId::new(&topic, b"a")).is_none());
+        assert_eq!(history.topics(|_| true), vec![topic]);
+
+        let replayed = history.since(&topic, ReplaySince::Message(ids[0]));
+        assert_eq!(replayed.len(), 1);
+        assert_eq!(replayed[0].0, ids[1]);
+        let unknown = MessageId::new(&topic, b"a");
+        assert_eq!(
+            history.since(&topic, ReplaySince::Message(unknown)).len(),
+            2
+        );
+        let later = Instant::now() + std::time::Duration::from_secs(1);
+        assert!(history.since(&topic, ReplaySince::Time(later)).is_empty());
+        assert_eq!(history.since(&topic, ReplaySince::Time(start)).len(), 2);
+        assert_eq!(history.since(&topic, ReplaySince::All).len(), 2);
+
+        let mut disabled = MessageHistory::new(0);
+        disabled.push(
+            &topic,
+            ids[0],
+            Extensions::default(),
+            Bytes::from_static(b"b"),
+        );
+        assert!(disabled.ids(&topic).is_empty());
+    }
+}
@@ -0,0 +1,117 @@
+use crate::protocol::Topic;
+use bytes::Bytes;
+use fnv::{FnvHashMap, FnvHashSet};
+use libp2p::PeerId;
+
+/// Keys of the messages of a topic a subscriber is interested in, see
+/// `Broadcast::set_key_filter`. Messages without a key always match, an
+/// empty filter matches all keys.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct KeyFilter {
+    keys: FnvHashSet<Bytes>,
+}
+
+impl KeyFilter {
+    pub fn new<K: Into<Bytes>>(keys: impl IntoIterator<Item = K>) -> Self {
+        Self {
+            keys: keys.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &Bytes> + '_ {
+        self.keys.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn matches(&self, key: Option<&[u8]>) -> bool {
+        match key {
+            Some(key) => self.keys.is_empty() || self.keys.contains(key),
+            None => true,
+        }
+    }
+}
+
+/// Our key filters and the ones announced by peers.
+#[derive(Debug, Default)]
+pub(crate) struct KeyFilters {
+    local: FnvHashMap<Topic, KeyFilter>,
+    remote: FnvHashMap<PeerId, FnvHashMap<Topic, KeyFilter>>,
+}
+
+impl KeyFilters {
+    pub fn local(&self, topic: &Topic) -> Option<&KeyFilter> {
+        self.local.get(topic)
+    }
+
+    /// Sets our filter of `topic`, removing it if it is empty.
+    pub fn set_local(&mut self, topic: Topic, filter: KeyFilter) {
+        if filter.is_empty() {
+            self.local.remove(&topic);
+        } else {
+            self.local.insert(topic, filter);
+        }
+    }
+
+    pub fn set_remote(&mut self, peer: PeerId, topic: Topic, filter: KeyFilter) {
+        let filters = self.remote.entry(peer).or_default();
+        if filter.is_empty() {
+            filters.remove(&topic);
+            if filters.is_empty() {
+                self.remote.remove(&peer);
+            }
+        } else {
+            filters.insert(topic, filter);
+        }
+    }
+
+    /// Whether we deliver messages with `key`.
+    pub fn wants(&self, topic: &Topic, key: Option<&[u8]>) -> bool {
+        match self.local.get(topic) {
+            Some(filter) => filter.matches(key),
+            None => true,
+        }
+    }
+
+    /// Whether `peer` asked for messages with `key`.
+    pub fn peer_wants(&self, peer: &PeerId, topic: &Topic, key: Option<&[u8]>) -> bool {
+        match self.remote.get(peer).and_then(|filters| filters.get(topic)) {
+            Some(filter) => filter.matches(key),
+            None => true,
+        }
+    }
+
+    pub fn remove_topic(&mut self, peer: &PeerId, topic: &Topic) {
+        self.set_remote(*peer, *topic, KeyFilter::default());
+    }
+
+    pub fn remove_peer(&mut self, peer: &PeerId) {
+        self.remote.remove(peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_filters() {
+        let topic = Topic::new(b"topic");
+        let peer = PeerId::random();
+        let mut filters = KeyFilters::default();
+        assert!(filters.wants(&topic, Some(b"a")));
+
+        filters.set_local(topic, KeyFilter::new([&b"a"[..]]));
+        assert!(filters.wants(&topic, Some(b"a")));
+        assert!(!filters.wants(&topic, Some(b"b")));
+        assert!(filters.wants(&topic, None));
+
+        filters.set_remote(peer, topic, KeyFilter::new([&b"b"[..]]));
+        assert!(!filters.peer_wants(&peer, &topic, Some(b"a")));
+        assert!(filters.peer_wants(&peer, &Topic::new(b"other"), Some(b"a")));
+        filters.remove_topic(&peer, &topic);
+        assert!(filters.peer_wants(&peer, &topic, Some(b"a")));
+    }
+}
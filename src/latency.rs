@@ -0,0 +1,70 @@
+use crate::protocol::Topic;
+use fnv::FnvHashMap;
+use libp2p::PeerId;
+use std::time::Duration;
+
+/// Round trip times to the connected peers, measured with acknowledged
+/// broadcasts and smoothed like TCP's, see `Broadcast::peer_latency`.
+#[derive(Default)]
+pub(crate) struct Latencies {
+    peers: FnvHashMap<PeerId, Duration>,
+    topics: FnvHashMap<(PeerId, Topic), Duration>,
+}
+
+/// Moves the estimate an eighth of the way towards the sample.
+fn smooth(estimate: &mut Duration, rtt: Duration) {
+    *estimate = (*estimate * 7 + rtt) / 8;
+}
+
+impl Latencies {
+    pub fn record(&mut self, peer: PeerId, topic: Topic, rtt: Duration) {
+        smooth(self.peers.entry(peer).or_insert(rtt), rtt);
+        smooth(self.topics.entry((peer, topic)).or_insert(rtt), rtt);
+    }
+
+    pub fn peer(&self, peer: &PeerId) -> Option<Duration> {
+        self.peers.get(peer).copied()
+    }
+
+    pub fn topic(&self, peer: &PeerId, topic: &Topic) -> Option<Duration> {
+        self.topics.get(&(*peer, *topic)).copied()
+    }
+
+    pub fn remove_peer(&mut self, peer: &PeerId) {
+        self.peers.remove(peer);
+        self.topics.retain(|(other, _), _| other != peer);
+    }
+
+    /// Relative chance of a peer to be sampled for the fanout, peers with
+    /// a latency of 10ms are picked half as often as unmeasured ones.
+    pub fn weight(&self, peer: &PeerId) -> f64 {
+        match self.peer(peer) {
+            Some(latency) => 1.0 / (1.0 + latency.as_secs_f64() * 100.0),
+            None => 1.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latencies() {
+        let topic = Topic::new(b"topic");
+        let peer = PeerId::random();
+        let mut latencies = Latencies::default();
+        assert_eq!(latencies.weight(&peer), 1.0);
+        latencies.record(peer, topic, Duration::from_millis(80));
+        assert_eq!(latencies.peer(&peer), Some(Duration::from_millis(80)));
+        latencies.record(peer, topic, Duration::from_millis(0));
+        assert_eq!(
+            latencies.topic(&peer, &topic),
+            Some(Duration::from_millis(70))
+        );
+        assert!(latencies.weight(&peer) < 0.5);
+        latencies.remove_peer(&peer);
+        assert_eq!(latencies.peer(&peer), None);
+        assert_eq!(latencies.topic(&peer, &topic), None);
+    }
+}
@@ -0,0 +1,83 @@
+use crate::protocol::{Extensions, MessageId, Topic};
+use bytes::Bytes;
+use fnv::{FnvHashMap, FnvHashSet};
+use std::collections::VecDeque;
+
+/// Number of offered and requested messages remembered.
+const CAPACITY: usize = 256;
+
+type Offer = (Topic, Extensions, Bytes);
+
+/// Large broadcasts offered to peers with `Message::IHave` instead of being
+/// pushed, see `BroadcastConfig::lazy_push`, and the ids requested from
+/// peers offering them.
+#[derive(Debug, Default)]
+pub(crate) struct LazyPush {
+    offered: FnvHashMap<MessageId, Offer>,
+    offered_order: VecDeque<MessageId>,
+    requested: FnvHashSet<MessageId>,
+    requested_order: VecDeque<MessageId>,
+}
+
+impl LazyPush {
+    /// Keeps a broadcast until it is requested, dropping the oldest one if
+    /// too many are kept.
+    pub fn offer(&mut self, id: MessageId, topic: Topic, ext: Extensions, msg: Bytes) {
+        if self.offered.insert(id, (topic, ext, msg)).is_some() {
+            return;
+        }
+        self.offered_order.push_back(id);
+        if self.offered_order.len() > CAPACITY {
+            let oldest = self.offered_order.pop_front().unwrap();
+            self.offered.remove(&oldest);
+        }
+    }
+
+    pub fn get(&self, topic: &Topic, id: &MessageId) -> Option<(Extensions, Bytes)> {
+        match self.offered.get(id) {
+            Some((offered, ext, msg)) if offered == topic => Some((ext.clone(), msg.clone())),
+            _ => None,
+        }
+    }
+
+    /// Returns `true` the first time a message is requested, so messages
+    /// offered by several peers are only requested from one of them.
+    pub fn request(&mut self, id: MessageId) -> bool {
+        if !self.requested.insert(id) {
+            return false;
+        }
+        self.requested_order.push_back(id);
+        if self.requested_order.len() > CAPACITY {
+            let oldest = self.requested_order.pop_front().unwrap();
+            self.requested.remove(&oldest);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lazy_push() {
+        let topic = Topic::new(b"topic");
+        let mut lazy = LazyPush::default();
+        for i in 0..=CAPACITY {
+            let msg = Bytes::from(i.to_string());
+            lazy.offer(
+                MessageId::new(&topic, &msg),
+                topic,
+                Extensions::default(),
+                msg,
+            );
+        }
+        assert!(lazy.get(&topic, &MessageId::new(&topic, b"0")).is_none());
+        let id = MessageId::new(&topic, b"1");
+        assert_eq!(lazy.get(&topic, &id).unwrap().1, Bytes::from_static(b"1"));
+        assert!(lazy.get(&Topic::new(b"other"), &id).is_none());
+
+        assert!(lazy.request(id));
+        assert!(!lazy.request(id));
+    }
+}
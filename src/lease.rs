@@ -0,0 +1,108 @@
+use crate::protocol::Topic;
+use fnv::FnvHashMap;
+use futures::FutureExt;
+use futures_timer::Delay;
+use instant::Instant;
+use libp2p::PeerId;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// Leased subscriptions of remote peers and the renewal of our own.
+#[derive(Default)]
+pub(crate) struct Leases {
+    /// Lease of our subscriptions, renewed after half of it passed.
+    ttl: Option<Duration>,
+    renewal: Option<Delay>,
+    expiries: FnvHashMap<(PeerId, Topic), Instant>,
+    timer: Option<Delay>,
+}
+
+impl Leases {
+    pub fn new(ttl: Option<Duration>) -> Self {
+        Self {
+            ttl,
+            renewal: ttl.map(|ttl| Delay::new(ttl / 2)),
+            ..Default::default()
+        }
+    }
+
+    /// Lease announced with our subscriptions.
+    pub fn ttl(&self) -> Option<Duration> {
+        self.ttl
+    }
+
+    /// Starts or renews the lease of a peer's subscription. Leases too long
+    /// to represent never expire.
+    pub fn renew(&mut self, peer: PeerId, topic: Topic, ttl: Duration) {
+        match Instant::now().checked_add(ttl) {
+            Some(expiry) => self.expiries.insert((peer, topic), expiry),
+            None => self.expiries.remove(&(peer, topic)),
+        };
+        self.timer = None;
+    }
+
+    /// Removes the lease of a subscription that was dropped or made
+    /// permanent, returning whether it had one.
+    pub fn remove(&mut self, peer: &PeerId, topic: &Topic) -> bool {
+        self.expiries.remove(&(*peer, *topic)).is_some()
+    }
+
+    pub fn remove_peer(&mut self, peer: &PeerId) {
+        self.expiries.retain(|(p, _), _| p != peer);
+    }
+
+    /// Returns the next subscription whose lease expired.
+    pub fn poll_expired(&mut self, cx: &mut Context) -> Poll<(PeerId, Topic)> {
+        let now = Instant::now();
+        let expired = self
+            .expiries
+            .iter()
+            .find(|(_, expiry)| **expiry <= now)
+            .map(|(key, _)| *key);
+        if let Some(key) = expired {
+            self.expiries.remove(&key);
+            return Poll::Ready(key);
+        }
+        if let Some(next) = self.expiries.values().min() {
+            let timer = self.timer.get_or_insert_with(|| Delay::new(*next - now));
+            if timer.poll_unpin(cx).is_ready() {
+                self.timer = None;
+                cx.waker().wake_by_ref();
+            }
+        }
+        Poll::Pending
+    }
+
+    /// Ready when our subscriptions need to be renewed.
+    pub fn poll_renew(&mut self, cx: &mut Context) -> Poll<()> {
+        if let (Some(renewal), Some(ttl)) = (&mut self.renewal, self.ttl) {
+            if renewal.poll_unpin(cx).is_ready() {
+                renewal.reset(ttl / 2);
+                return Poll::Ready(());
+            }
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker_ref;
+
+    #[test]
+    fn test_leases() {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let topic = Topic::new(b"topic");
+        let mut leases = Leases::new(None);
+        leases.renew(a, topic, Duration::ZERO);
+        leases.renew(b, topic, Duration::from_secs(60));
+        leases.renew(b, Topic::new(b"other"), Duration::MAX);
+        assert_eq!(leases.poll_expired(&mut cx), Poll::Ready((a, topic)));
+        assert_eq!(leases.poll_expired(&mut cx), Poll::Pending);
+        assert!(leases.remove(&b, &topic));
+        assert!(!leases.remove(&a, &topic));
+        assert_eq!(leases.poll_renew(&mut cx), Poll::Pending);
+    }
+}
@@ -1,34 +1,468 @@
-use crate::protocol::Message;
+use crate::ack::PendingAcks;
+use crate::cache::SeenCache;
+use crate::connections::Connections;
+use crate::delivery::Deliveries;
+use crate::discovery::TopicDiscovery;
+use crate::events::{queued_len, EventQueue};
+use crate::explicit::ExplicitPeers;
+use crate::gc::TopicGc;
+use crate::handle::{Command, Commands};
+use crate::handler::unshare;
+use crate::heartbeat::Heartbeats;
+use crate::history::MessageHistory;
+use crate::keys::KeyFilters;
+use crate::latency::Latencies;
+use crate::lazy::LazyPush;
+use crate::lease::Leases;
+use crate::offline::OfflineQueues;
+use crate::rate_limit::{Admission, RateLimiter};
+use crate::redial::FormerSubscribers;
+use crate::reliable::{Due, ReliableBroadcasts};
+use crate::reorder::ReorderBuffer;
+use crate::replay::ReplayWindow;
+use crate::request::{Replies, Requests};
+use crate::score::PeerScores;
+use crate::sequencer::Sequencers;
+use crate::stats::Stats;
+use crate::sync::TopicSync;
+use crate::unrouted::Unrouted;
+use bytes::Bytes;
 use fnv::{FnvHashMap, FnvHashSet};
+use futures::channel::{mpsc, oneshot};
+use futures::{Future, FutureExt, Stream, StreamExt};
 use libp2p::core::connection::ConnectionId;
+use libp2p::swarm::dial_opts::{DialOpts, PeerCondition};
 use libp2p::swarm::{
-    NetworkBehaviour, NetworkBehaviourAction, NotifyHandler, OneShotHandler, PollParameters,
+    CloseConnection, DialError, NetworkBehaviour, NetworkBehaviourAction, NotifyHandler,
+    PollParameters,
 };
 use libp2p::{Multiaddr, PeerId};
+use rand::seq::SliceRandom;
 use std::collections::VecDeque;
 use std::fmt;
+use std::io;
+use std::ops::Range;
+use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Duration;
 
+#[macro_use]
+mod trace;
+
+mod ack;
+#[cfg(feature = "admin")]
+pub mod admin;
+mod cache;
+mod compression;
+mod connections;
+mod delivery;
+mod discovery;
+#[cfg(feature = "encryption")]
+mod encryption;
+mod events;
+mod explicit;
+mod fragment;
+mod gc;
+mod handle;
+mod handler;
+mod heartbeat;
+mod history;
+mod keys;
+mod latency;
+mod lazy;
+mod lease;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod offline;
 mod protocol;
+mod queue;
+mod rate_limit;
+mod redial;
+mod reliable;
+mod reorder;
+mod replay;
+mod request;
+mod score;
+mod sequencer;
+mod snapshot;
+#[cfg(feature = "stamps")]
+mod stamps;
+mod state;
+mod stats;
+mod store;
+mod summary;
+mod sync;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+mod typed;
+mod unrouted;
+
+pub use compression::Compression;
+pub use connections::ConnectionInfo;
+pub use delivery::{BroadcastId, DeliveryError};
+pub use discovery::Discovery;
+#[cfg(feature = "kad")]
+pub use discovery::KademliaDiscovery;
+#[cfg(feature = "encryption")]
+pub use encryption::TopicKey;
+pub use handle::{BroadcastClient, SubscriptionHandle};
+pub use handler::{BroadcastHandler, HandlerEvent, HandlerIn, PendingSends};
+pub use keys::KeyFilter;
+pub use offline::OfflineQueue;
+pub use protocol::{
+    AccessPolicy, BroadcastConfig, ConnectionPolicy, DefaultCodec, DeliveryMode, EvictionPolicy,
+    Extensions, FloodsubId, Message, MessageCodec, MessageId, NoPeersPolicy, PublishPolicy,
+    RejectReason, RelayMode, RelayStamp, ReplaySince, Signature, Topic, TopicRepresentation,
+    UnsolicitedPolicy, ValidationMode, ValidationResult, Version, MAX_PATH_LENGTH,
+};
+pub use queue::{AdaptiveBatching, Priority};
+pub use rate_limit::RateLimit;
+pub use request::ReplyTo;
+pub use score::PeerScoreParams;
+pub use snapshot::{BroadcastState, PeerState, TopicState};
+#[cfg(feature = "stamps")]
+pub use stamps::RelayKey;
+pub use state::SnapshotProvider;
+pub use stats::{BroadcastStats, PeerStats};
+pub use store::{FileStore, StoredState, SubscriptionStore};
+pub use summary::TopicSummary;
+#[cfg(feature = "bincode")]
+pub use typed::Bincode;
+#[cfg(feature = "cbor")]
+pub use typed::Cbor;
+#[cfg(feature = "json")]
+pub use typed::Json;
+pub use typed::{CodecError, PayloadCodec, PublishError, TypedBroadcast, TypedEvent};
+
+/// Maximum number of topics announced in a single frame on connection
+/// establishment.
+const SUBSCRIBE_BATCH_SIZE: usize = 64;
+
+/// Maximum number of addresses kept of those a peer attached to its
+/// subscriptions.
+const MAX_ADDRESS_HINTS: usize = 8;
+
+/// Number of messages buffered by a topic stream before further ones are
+/// dropped.
+const TOPIC_STREAM_CAPACITY: usize = 256;
+
+/// Number of acked broadcasts with sequence numbers remembered to drop their
+/// retransmissions.
+const ACKED_CACHE_SIZE: usize = 4096;
+
+type TopicSender = mpsc::Sender<(PeerId, Bytes)>;
 
-pub use protocol::{BroadcastConfig, Topic};
+type Validator = Box<dyn Fn(&PeerId, &[u8]) -> ValidationResult + Send>;
+
+/// How `Broadcast::publish` sends a message.
+#[derive(Default)]
+struct PublishOptions<'a> {
+    /// Sent only to the peers whose `KeyFilter` of the topic matches it.
+    key: Option<Bytes>,
+    excluded: &'a [PeerId],
+    /// Retransmitted until the quorum acknowledged it or the deadline
+    /// passed.
+    reliable: Option<(usize, Duration)>,
+    ack: bool,
+    reply_to: Option<u64>,
+    tracked: Option<BroadcastId>,
+    priority: Priority,
+}
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum BroadcastEvent {
     Subscribed(PeerId, Topic),
     Unsubscribed(PeerId, Topic),
-    Received(PeerId, Topic, Arc<[u8]>),
+    /// A message was received.
+    ///
+    /// The peer is the publisher for signed messages, otherwise the peer the
+    /// message was received from. The id is computed with
+    /// `BroadcastConfig::message_id_fn`.
+    Received(PeerId, Topic, MessageId, Bytes),
+    /// A message published with `Broadcast::request`, received instead of a
+    /// `BroadcastEvent::Received`. Answered with `Broadcast::reply`.
+    Request {
+        source: PeerId,
+        topic: Topic,
+        id: MessageId,
+        reply_to: ReplyTo,
+        msg: Bytes,
+    },
+    /// The state of a topic sent by a subscriber in response to
+    /// `Broadcast::subscribe_with_snapshot`.
+    Snapshot(PeerId, Topic, Bytes),
+    /// A message received from the peer was rejected.
+    InvalidMessage(PeerId, Topic, RejectReason),
+    /// A message to the peer was dropped because its send queue is full.
+    OutboundDropped(PeerId, Topic),
+    /// The peer acknowledged a message sent with `broadcast_with_ack`.
+    Acked(PeerId, MessageId),
+    /// The peer didn't acknowledge a message within the ack timeout.
+    AckTimeout(PeerId, MessageId),
+    /// A message sent with `broadcast_reliable` was acknowledged by its
+    /// quorum or its deadline passed.
+    ReliableBroadcastResult {
+        id: MessageId,
+        delivered: usize,
+        quorum_met: bool,
+    },
+    /// The peers a received message passed through, starting with its
+    /// publisher. Emitted before the message is delivered, see
+    /// `BroadcastConfig::trace_paths`.
+    Traced(MessageId, Vec<PeerId>),
+    /// The peer subscribed to all topics below a prefix.
+    SubscribedPrefix(PeerId, Topic),
+    UnsubscribedPrefix(PeerId, Topic),
+    /// The protocol version spoken with the peer changed.
+    PeerProtocolVersion(PeerId, Version),
+    /// A broadcast to the peer was delayed by the rate limit.
+    RateLimited(PeerId, Topic),
+    /// Broadcasts buffered while the peer was disconnected were sent after
+    /// it reconnected.
+    Replayed(PeerId, Topic, usize),
+    /// A message from or to the peer was dropped because its ttl expired.
+    Expired(PeerId, Topic),
+    /// Saving the subscription state to the store failed.
+    StoreFailed(io::ErrorKind),
+    /// We subscribed to the topic. Emitted after the subscription was handed
+    /// to the handlers of all connected peers.
+    LocalSubscribed(Topic),
+    /// We unsubscribed from the topic, emitted after notifying the peers.
+    LocalUnsubscribed(Topic),
+    /// A subscription change on the topic was handed to that many peers.
+    NotifiedPeers(Topic, usize),
+    /// Messages with these sequence numbers published by the peer on the
+    /// topic were missed.
+    GapDetected(PeerId, Topic, Range<u64>),
+    /// A message of that many bytes was dropped to stay within
+    /// `BroadcastConfig::max_buffered_bytes`. The peer is `None` for retained
+    /// messages.
+    Evicted(Option<PeerId>, Topic, usize),
+    /// The peer's subscription to the topic or prefix was refused by the
+    /// `BroadcastConfig::topic_filter` or the access policy.
+    SubscriptionFiltered(PeerId, Topic),
+    /// The peer doesn't track our subscription to the topic or prefix and
+    /// won't send us its broadcasts.
+    SubscribeRejected(PeerId, Topic),
+    /// A message sent with `broadcast_tracked` was written to the peer.
+    Delivered {
+        id: BroadcastId,
+        peer: PeerId,
+    },
+    /// A message sent with `broadcast_tracked` couldn't be written to the
+    /// peer.
+    SendFailed {
+        id: BroadcastId,
+        peer: PeerId,
+        error: DeliveryError,
+    },
+    /// The peer's leased subscription to the topic wasn't renewed in time.
+    SubscriptionExpired(PeerId, Topic),
+    /// Subscribers of the topic suggested by a peer after we subscribed,
+    /// see `BroadcastConfig::peer_exchange`. Connected peers are left out.
+    PeersSuggested(Topic, Vec<(PeerId, Vec<Multiaddr>)>),
+    /// External addresses the peer attached to its subscriptions, see
+    /// `BroadcastConfig::address_hints`. Used to redial the peer.
+    PeerAddresses(PeerId, Vec<Multiaddr>),
+    /// A broadcast on the topic couldn't be written to the peer, after the
+    /// retries configured with `BroadcastConfig::retry_sends`.
+    SendError(PeerId, Topic),
+    /// Negotiating or writing to a substream to the peer timed out, see
+    /// `BroadcastConfig::substream_timeout` and
+    /// `BroadcastConfig::send_timeout`.
+    SendTimeout(PeerId),
+    /// The peer granted no more credits, broadcasts to it are queued until
+    /// it does, see `BroadcastConfig::flow_control`.
+    PeerBackpressured(PeerId),
+    /// Everything queued for a peer that was backpressured or reached
+    /// `BroadcastConfig::backlog_threshold` was written.
+    QueueDrained(PeerId),
+    /// The peer missed `BroadcastConfig::topic_heartbeat` heartbeats on a
+    /// topic we share while staying connected.
+    PeerUnresponsive(PeerId, Topic),
+    /// The peer sent a broadcast on a topic we aren't subscribed to, see
+    /// `UnsolicitedPolicy::Report`.
+    UnsolicitedMessage(PeerId, Topic),
+    /// The sequencer of a topic with `BroadcastConfig::total_order` changed,
+    /// `None` if there are no subscribers left.
+    SequencerChanged(Topic, Option<PeerId>),
+    /// No peer was subscribed to the topic for the retention of
+    /// `BroadcastConfig::topic_gc`, so it was forgotten.
+    TopicForgotten(Topic),
+    /// Summary of the topics a batch of subscription updates from the peer
+    /// added and removed, following their individual events.
+    PeerTopicsChanged {
+        peer: PeerId,
+        added: Vec<Topic>,
+        removed: Vec<Topic>,
+    },
+    /// All peers a message sent with `broadcast_tracked` was sent to were
+    /// reported.
+    DeliveryComplete {
+        id: BroadcastId,
+        delivered: usize,
+        failed: usize,
+    },
+    /// The first connection to the peer was established.
+    PeerConnected(PeerId),
+    /// The last connection to the peer was closed, with the topics it was
+    /// subscribed to.
+    PeerDisconnected(PeerId, Vec<Topic>),
+    /// A signed message received from the peer was stamped outside the
+    /// `BroadcastConfig::replay_window` or was seen before within it.
+    ReplayRejected(PeerId, MessageId),
+    /// A message received on the topic was dropped by that many topic
+    /// streams whose buffer of 256 messages was full, see
+    /// `Broadcast::topic_stream`.
+    StreamLagged(Topic, usize),
+}
+
+/// Reason a subscription couldn't be changed or a message couldn't be sent.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BroadcastError {
+    /// Not subscribed to the topic.
+    NotSubscribed,
+    /// No connected or offline peer is subscribed to the topic.
+    NoPeers,
+    /// The message exceeds the global or per-topic size limit.
+    MessageTooLarge,
+    /// The send queues of all subscribed peers are full.
+    QueueFull,
+    /// Signing the message with the configured keypair failed.
+    SigningFailed,
+    /// Encrypting the message with the key of the topic failed.
+    Encryption,
+    /// The behaviour is shutting down.
+    ShuttingDown,
+    /// The raw topic name, including the namespace, is
+    /// `Topic::MAX_TOPIC_LENGTH` bytes or longer.
+    InvalidTopic,
+}
+
+impl fmt::Display for BroadcastError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            Self::NotSubscribed => "not subscribed to the topic",
+            Self::NoPeers => "no peer is subscribed to the topic",
+            Self::MessageTooLarge => "message too large",
+            Self::QueueFull => "send queues are full",
+            Self::SigningFailed => "signing the message failed",
+            Self::Encryption => "encrypting the message failed",
+            Self::ShuttingDown => "shutting down",
+            Self::InvalidTopic => "topic name too long",
+        };
+        f.write_str(msg)
+    }
 }
-type Handler = OneShotHandler<BroadcastConfig, Message, HandlerEvent>;
+
+impl std::error::Error for BroadcastError {}
 
 #[derive(Default)]
 pub struct Broadcast {
     config: BroadcastConfig,
     subscriptions: FnvHashSet<Topic>,
+    prefix_subscriptions: FnvHashSet<Topic>,
     peers: FnvHashMap<PeerId, FnvHashSet<Topic>>,
+    /// Our subscriptions each peer was told about with
+    /// `BroadcastConfig::lazy_subscriptions`.
+    announced: FnvHashMap<PeerId, FnvHashSet<Topic>>,
+    /// Topics whose subscribers are tracked without subscribing.
+    observed: FnvHashSet<Topic>,
+    unrouted: Unrouted,
+    /// Inbound substreams of each peer, shared by its handlers with
+    /// `BroadcastConfig::max_inbound_streams_per_peer`.
+    inbound_streams: FnvHashMap<PeerId, Arc<AtomicUsize>>,
+    /// Messages queued by the handlers of each peer.
+    pending_sends: FnvHashMap<PeerId, Arc<PendingSends>>,
+    /// Peers reported with `BroadcastEvent::QueueDrained` once nothing is
+    /// pending for them anymore.
+    backlogged: FnvHashSet<PeerId>,
+    /// Addresses we dialed connected peers at, shared with peer exchange.
+    addresses: FnvHashMap<PeerId, Vec<Multiaddr>>,
+    /// Our external addresses, attached to subscriptions with
+    /// `BroadcastConfig::address_hints`.
+    external_addresses: Vec<Multiaddr>,
+    /// Addresses peers attached to their subscriptions.
+    address_hints: FnvHashMap<PeerId, Vec<Multiaddr>>,
+    /// Disconnected subscribers, see `BroadcastConfig::dial_on_broadcast`.
+    former: FormerSubscribers,
+    reorder: Option<ReorderBuffer>,
+    heartbeats: Heartbeats,
+    sequencers: Sequencers,
+    gc: TopicGc,
+    stats: Stats,
+    sync: TopicSync,
+    requests: Requests,
+    replay: ReplayWindow,
     topics: FnvHashMap<Topic, FnvHashSet<PeerId>>,
-    events: VecDeque<NetworkBehaviourAction<BroadcastEvent, Handler>>,
+    /// Peers subscribed to all topics below a prefix.
+    prefixes: FnvHashMap<Topic, FnvHashSet<PeerId>>,
+    /// Local subscriptions beyond the first, see
+    /// `BroadcastConfig::counted_subscriptions`.
+    subscription_refs: FnvHashMap<Topic, usize>,
+    /// Tokens of the subscription handles that still own a subscription,
+    /// so that a stale handle doesn't end a newer one when dropped.
+    handle_tokens: FnvHashMap<Topic, FnvHashSet<u64>>,
+    next_token: u64,
+    topic_limits: FnvHashMap<Topic, usize>,
+    validators: FnvHashMap<Topic, Validator>,
+    #[cfg(feature = "encryption")]
+    topic_keys: FnvHashMap<Topic, TopicKey>,
+    #[cfg(feature = "stamps")]
+    relay_keys: FnvHashMap<Topic, RelayKey>,
+    /// Our peer id in relay stamps, derived from `BroadcastConfig::keypair`.
+    #[cfg(feature = "stamps")]
+    relay_id: Option<PeerId>,
+    retained: FnvHashMap<Topic, Bytes>,
+    streams: FnvHashMap<Topic, Vec<TopicSender>>,
+    /// Names of hashed topics created with `topic`.
+    topic_names: FnvHashMap<Topic, String>,
+    versions: FnvHashMap<PeerId, Version>,
+    seen: SeenCache,
+    /// Acked broadcasts with sequence numbers by publisher, topic and
+    /// sequence number.
+    acked: SeenCache,
+    history: MessageHistory,
+    /// Sequence number of the next message published on a topic.
+    next_seqno: FnvHashMap<Topic, u64>,
+    /// Highest sequence number received from a publisher on a topic.
+    last_seqno: FnvHashMap<(PeerId, Topic), u64>,
+    acks: PendingAcks,
+    reliable: ReliableBroadcasts,
+    latencies: Latencies,
+    lazy: LazyPush,
+    key_filters: KeyFilters,
+    leases: Leases,
+    deliveries: Deliveries,
+    rate_limiter: RateLimiter,
+    offline: OfflineQueues,
+    scores: PeerScores,
+    explicit: ExplicitPeers,
+    discovery: TopicDiscovery,
+    store: Option<Box<dyn SubscriptionStore>>,
+    snapshots: Option<Box<dyn SnapshotProvider>>,
+    /// Topics and prefixes of disconnected peers, kept for the store.
+    known_peers: FnvHashMap<PeerId, (FnvHashSet<Topic>, FnvHashSet<Topic>)>,
+    connections: Connections,
+    /// Peers blocked with `block_peer`, whether their connections are
+    /// closed.
+    blocked: FnvHashMap<PeerId, bool>,
+    /// Peers with a pending `CloseConnection`.
+    closing: FnvHashSet<PeerId>,
+    commands: Commands,
+    /// Set by `shutdown`.
+    shutting_down: bool,
+    /// Connections that didn't flush their messages since `shutdown`.
+    flushing: FnvHashSet<(PeerId, ConnectionId)>,
+    flushed: Vec<oneshot::Sender<()>>,
+    events: EventQueue,
+    /// Our broadcasts delivered locally once the local peer id is known.
+    own_messages: VecDeque<(Topic, MessageId, Bytes)>,
+    /// Learned when the behaviour is first polled.
+    local_peer_id: Option<PeerId>,
+    #[cfg(feature = "metrics")]
+    metrics: Option<metrics::Metrics>,
 }
 
 impl fmt::Debug for Broadcast {
@@ -36,8 +470,11 @@ impl fmt::Debug for Broadcast {
         f.debug_struct("Broadcast")
             .field("config", &self.config)
             .field("subscriptions", &self.subscriptions)
+            .field("prefix_subscriptions", &self.prefix_subscriptions)
             .field("peers", &self.peers)
             .field("topics", &self.topics)
+            .field("prefixes", &self.prefixes)
+            .field("versions", &self.versions)
             .finish()
     }
 }
@@ -45,15 +482,207 @@ impl fmt::Debug for Broadcast {
 impl Broadcast {
     pub fn new(config: BroadcastConfig) -> Self {
         Self {
+            seen: SeenCache::new(config.seen_cache_size),
+            acked: SeenCache::new(ACKED_CACHE_SIZE),
+            history: MessageHistory::new(config.history_len),
+            acks: PendingAcks::new(config.ack_timeout),
+            reliable: ReliableBroadcasts::new(config.ack_timeout),
+            leases: Leases::new(config.subscription_lease),
+            rate_limiter: RateLimiter::new(
+                config.peer_rate_limit,
+                config.topic_rate_limit,
+                config.max_send_queue_len,
+            ),
+            offline: OfflineQueues::new(config.offline_queue),
+            unrouted: match config.no_peers_policy {
+                NoPeersPolicy::Buffer { max, ttl } => Unrouted::new(max, ttl),
+                _ => Unrouted::default(),
+            },
+            scores: PeerScores::new(config.peer_score_params.clone()),
+            heartbeats: Heartbeats::new(config.topic_heartbeat),
+            sequencers: Sequencers::new(config.ordered_topics.clone()),
+            gc: TopicGc::new(config.topic_gc),
+            sync: TopicSync::new(config.topic_sync),
+            replay: ReplayWindow::new(config.replay_window),
+            reorder: config
+                .reorder_window
+                .map(|(max_messages, timeout)| ReorderBuffer::new(max_messages, timeout)),
+            connections: Connections::new(
+                config.connection_policy,
+                config.connection_selector.clone(),
+            ),
+            #[cfg(feature = "stamps")]
+            relay_id: config
+                .keypair
+                .as_ref()
+                .map(|keypair| keypair.public().to_peer_id()),
             config,
             ..Default::default()
         }
     }
 
+    /// Creates a behaviour restoring its subscriptions from `store` and
+    /// saving them whenever they change.
+    ///
+    /// Broadcasts for peers subscribed before the restart are buffered when
+    /// `BroadcastConfig::offline_queue` is set.
+    pub fn with_store(
+        config: BroadcastConfig,
+        mut store: impl SubscriptionStore + 'static,
+    ) -> io::Result<Self> {
+        let state = store.load()?;
+        let mut broadcast = Self::new(config);
+        broadcast.subscriptions.extend(state.subscriptions);
+        broadcast
+            .prefix_subscriptions
+            .extend(state.prefix_subscriptions);
+        for (peer, topics, prefixes) in state.peers {
+            let topics: FnvHashSet<_> = topics.into_iter().collect();
+            let prefixes: FnvHashSet<_> = prefixes.into_iter().collect();
+            broadcast
+                .offline
+                .disconnected(peer, topics.clone(), prefixes.clone());
+            broadcast.known_peers.insert(peer, (topics, prefixes));
+        }
+        broadcast.store = Some(Box::new(store));
+        Ok(broadcast)
+    }
+
+    /// Topics the peer subscribed to through a prefix.
+    fn peer_prefixes(&self, peer: &PeerId) -> FnvHashSet<Topic> {
+        self.prefixes
+            .iter()
+            .filter(|(_, peers)| peers.contains(peer))
+            .map(|(prefix, _)| *prefix)
+            .collect()
+    }
+
+    /// Saves the subscription state if a store is configured.
+    fn persist(&mut self) {
+        if self.store.is_none() || self.shutting_down {
+            return;
+        }
+        let mut peers: Vec<_> = self
+            .known_peers
+            .iter()
+            .map(|(peer, (topics, prefixes))| {
+                let topics = topics.iter().copied().collect();
+                (*peer, topics, prefixes.iter().copied().collect())
+            })
+            .collect();
+        for (peer, topics) in &self.peers {
+            let topics = topics.iter().copied().collect();
+            peers.push((
+                *peer,
+                topics,
+                self.peer_prefixes(peer).into_iter().collect(),
+            ));
+        }
+        let state = StoredState {
+            subscriptions: self.subscriptions.iter().copied().collect(),
+            prefix_subscriptions: self.prefix_subscriptions.iter().copied().collect(),
+            peers,
+        };
+        if let Some(Err(err)) = self.store.as_mut().map(|store| store.save(&state)) {
+            self.events.push_back(NetworkBehaviourAction::GenerateEvent(
+                BroadcastEvent::StoreFailed(err.kind()),
+            ));
+        }
+    }
+
+    /// Registers the behaviour's metrics with a prometheus registry.
+    #[cfg(feature = "metrics")]
+    pub fn register_metrics(&mut self, registry: &mut prometheus_client::registry::Registry) {
+        self.metrics = Some(metrics::Metrics::new(registry));
+    }
+
     pub fn subscribed(&self) -> impl Iterator<Item = &Topic> + '_ {
         self.subscriptions.iter()
     }
 
+    pub fn subscribed_prefixes(&self) -> impl Iterator<Item = &Topic> + '_ {
+        self.prefix_subscriptions.iter()
+    }
+
+    /// Whether we are subscribed to `topic` directly or through a prefix.
+    pub fn is_subscribed(&self, topic: &Topic) -> bool {
+        self.subscriptions.contains(topic)
+            || self
+                .prefix_subscriptions
+                .iter()
+                .any(|prefix| topic.has_prefix(prefix))
+    }
+
+    /// Score of `peer`, `None` if peer scoring is disabled.
+    pub fn peer_score(&self, peer: &PeerId) -> Option<f64> {
+        self.scores.score(peer)
+    }
+
+    /// Counters of the broadcasts exchanged with `peer`, `None` if it isn't
+    /// connected.
+    pub fn peer_stats(&self, peer: &PeerId) -> Option<PeerStats> {
+        self.stats.peer(peer)
+    }
+
+    /// Smoothed round trip time to `peer`, measured with the broadcasts it
+    /// acknowledged. `None` until a broadcast sent with `broadcast_with_ack`
+    /// was acknowledged by the peer.
+    pub fn peer_latency(&self, peer: &PeerId) -> Option<Duration> {
+        self.latencies.peer(peer)
+    }
+
+    /// Smoothed round trip time to `peer` for broadcasts on `topic`, see
+    /// `Broadcast::peer_latency`.
+    pub fn peer_topic_latency(&self, peer: &PeerId, topic: &Topic) -> Option<Duration> {
+        self.latencies.topic(peer, topic)
+    }
+
+    /// Counters of the broadcasts exchanged with all peers.
+    pub fn stats(&self) -> BroadcastStats {
+        self.stats.total()
+    }
+
+    /// Messages queued for `peer` but not yet written, including the ones
+    /// waiting to be handed to its connections.
+    pub fn pending_to_peer(&self, peer: &PeerId) -> usize {
+        let queued = self
+            .events
+            .iter()
+            .filter(|action| match action {
+                NetworkBehaviourAction::NotifyHandler { peer_id, event, .. } => {
+                    peer_id == peer && event.message().is_some()
+                }
+                _ => false,
+            })
+            .count();
+        let pending = self
+            .pending_sends
+            .get(peer)
+            .map_or(0, |pending| pending.messages());
+        queued + pending
+    }
+
+    /// Payload bytes of the messages counted by `pending_to_peer`.
+    pub fn pending_bytes_to_peer(&self, peer: &PeerId) -> usize {
+        let queued: usize = self
+            .events
+            .iter()
+            .filter_map(queued_len)
+            .filter(|(peer_id, _)| peer_id == peer)
+            .map(|(_, len)| len)
+            .sum();
+        let pending = self
+            .pending_sends
+            .get(peer)
+            .map_or(0, |pending| pending.bytes());
+        queued + pending
+    }
+
+    /// Protocol version spoken with `peer`, if a substream was negotiated.
+    pub fn protocol_version(&self, peer: &PeerId) -> Option<Version> {
+        self.versions.get(peer).copied()
+    }
+
     pub fn peers(&self, topic: &Topic) -> Option<impl Iterator<Item = &PeerId> + '_> {
         self.topics.get(topic).map(|peers| peers.iter())
     }
@@ -62,311 +691,6003 @@ impl Broadcast {
         self.peers.get(peer).map(|topics| topics.iter())
     }
 
-    pub fn subscribe(&mut self, topic: Topic) {
-        self.subscriptions.insert(topic);
-        let msg = Message::Subscribe(topic);
-        for peer in self.peers.keys() {
-            self.events
-                .push_back(NetworkBehaviourAction::NotifyHandler {
-                    peer_id: *peer,
-                    event: msg.clone(),
-                    handler: NotifyHandler::Any,
-                });
-        }
+    /// Topics connected peers are subscribed to, whether or not we are, with
+    /// their number of subscribers.
+    pub fn all_topics(&self) -> impl Iterator<Item = (&Topic, usize)> + '_ {
+        self.topics
+            .iter()
+            .filter(|(_, peers)| !peers.is_empty())
+            .map(move |(topic, _)| (topic, self.subscriber_count(topic)))
     }
 
-    pub fn unsubscribe(&mut self, topic: &Topic) {
-        self.subscriptions.remove(topic);
-        let msg = Message::Unsubscribe(*topic);
-        if let Some(peers) = self.topics.get(topic) {
-            for peer in peers {
-                self.events
-                    .push_back(NetworkBehaviourAction::NotifyHandler {
-                        peer_id: *peer,
-                        event: msg.clone(),
-                        handler: NotifyHandler::Any,
-                    });
+    /// Number of connected peers subscribed to `topic`, directly or through
+    /// a prefix.
+    pub fn subscriber_count(&self, topic: &Topic) -> usize {
+        let mut peers: FnvHashSet<&PeerId> = self.topics.get(topic).into_iter().flatten().collect();
+        for (prefix, prefix_peers) in &self.prefixes {
+            if topic.has_prefix(prefix) {
+                peers.extend(prefix_peers);
             }
         }
+        peers.len()
     }
 
-    pub fn broadcast(&mut self, topic: &Topic, msg: Arc<[u8]>) {
-        let msg = Message::Broadcast(*topic, msg);
-        if let Some(peers) = self.topics.get(topic) {
-            for peer in peers {
-                self.events
-                    .push_back(NetworkBehaviourAction::NotifyHandler {
-                        peer_id: *peer,
-                        event: msg.clone(),
-                        handler: NotifyHandler::Any,
-                    });
+    /// The subscriptions, peers and queues of the behaviour, for debugging.
+    pub fn snapshot(&self) -> BroadcastState {
+        let mut queued = FnvHashMap::<PeerId, usize>::default();
+        for action in self.events.iter() {
+            if let NetworkBehaviourAction::NotifyHandler { peer_id, .. } = action {
+                *queued.entry(*peer_id).or_default() += 1;
             }
         }
+        let sorted = |topics: &mut dyn Iterator<Item = Topic>| {
+            let mut topics: Vec<_> = topics.collect();
+            topics.sort();
+            topics
+        };
+        let mut peers: Vec<_> = self
+            .peers
+            .iter()
+            .map(|(peer, topics)| PeerState {
+                peer: *peer,
+                topics: sorted(&mut topics.iter().copied()),
+                prefixes: sorted(&mut self.peer_prefixes(peer).into_iter()),
+                version: self.versions.get(peer).copied(),
+                connections: self.connections.count(peer),
+                queued: queued.get(peer).copied().unwrap_or_default(),
+                delayed: self.rate_limiter.delayed(peer),
+            })
+            .collect();
+        peers.sort_by_key(|state| state.peer);
+        let mut topics: Vec<_> = self
+            .topics
+            .iter()
+            .filter(|(_, peers)| !peers.is_empty())
+            .map(|(topic, peers)| TopicState {
+                topic: *topic,
+                peers: peers.len(),
+            })
+            .collect();
+        topics.sort_by_key(|state| state.topic);
+        let mut offline = self.offline.buffered();
+        offline.sort();
+        BroadcastState {
+            subscriptions: sorted(&mut self.subscriptions.iter().copied()),
+            prefix_subscriptions: sorted(&mut self.prefix_subscriptions.iter().copied()),
+            peers,
+            topics,
+            offline,
+            queued_bytes: self.events.bytes(),
+            buffered_bytes: self.offline.bytes(),
+        }
     }
 
-    fn inject_connected(&mut self, peer: &PeerId) {
-        self.peers.insert(*peer, FnvHashSet::default());
-        for topic in &self.subscriptions {
-            self.events
-                .push_back(NetworkBehaviourAction::NotifyHandler {
-                    peer_id: *peer,
-                    event: Message::Subscribe(*topic),
-                    handler: NotifyHandler::Any,
-                });
+    /// Limits the payload size of messages received on `topic` below the
+    /// global `BroadcastConfig::max_message_size`.
+    pub fn set_topic_limit(&mut self, topic: Topic, size: usize) {
+        self.topic_limits.insert(topic, size);
+    }
+
+    pub fn remove_topic_limit(&mut self, topic: &Topic) {
+        self.topic_limits.remove(topic);
+    }
+
+    /// Validates messages received on `topic` before they are delivered or
+    /// relayed.
+    ///
+    /// The validator is called with the publisher of the message. Rejected
+    /// messages are reported as `BroadcastEvent::InvalidMessage` and count
+    /// against the score of the peer that sent them.
+    pub fn set_validator(
+        &mut self,
+        topic: Topic,
+        validator: impl Fn(&PeerId, &[u8]) -> ValidationResult + Send + 'static,
+    ) {
+        self.validators.insert(topic, Box::new(validator));
+    }
+
+    pub fn remove_validator(&mut self, topic: &Topic) {
+        self.validators.remove(topic);
+    }
+
+    /// Encrypts the payloads broadcast on `topic` with `key` and decrypts the
+    /// received ones, rejecting those that fail to decrypt.
+    ///
+    /// Peers without the key still relay the encrypted payloads.
+    #[cfg(feature = "encryption")]
+    pub fn set_topic_key(&mut self, topic: Topic, key: TopicKey) {
+        self.topic_keys.insert(topic, key);
+    }
+
+    #[cfg(feature = "encryption")]
+    pub fn remove_topic_key(&mut self, topic: &Topic) {
+        self.topic_keys.remove(topic);
+    }
+
+    /// Stamps the broadcasts sent on `topic` with `key` and rejects received
+    /// ones unless every relay they passed through stamped them, the last
+    /// one being the peer they were received from.
+    ///
+    /// Stamps identify us by the peer id of `BroadcastConfig::keypair`,
+    /// which has to be set.
+    #[cfg(feature = "stamps")]
+    pub fn set_relay_key(&mut self, topic: Topic, key: RelayKey) {
+        assert!(
+            self.relay_id.is_some(),
+            "relay stamps require BroadcastConfig::keypair"
+        );
+        self.relay_keys.insert(topic, key);
+    }
+
+    #[cfg(feature = "stamps")]
+    pub fn remove_relay_key(&mut self, topic: &Topic) {
+        self.relay_keys.remove(topic);
+    }
+
+    /// The topic for `name` according to the configured
+    /// `TopicRepresentation` and `BroadcastConfig::namespace`. Fails with
+    /// `BroadcastError::InvalidTopic` if a raw name, including the
+    /// namespace, is `Topic::MAX_TOPIC_LENGTH` bytes or longer.
+    ///
+    /// Names of hashed topics are remembered for `topic_name`.
+    pub fn topic(&mut self, name: &str) -> Result<Topic, BroadcastError> {
+        let representation = self.config.topic_representation;
+        let topic = match &self.config.namespace {
+            Some(namespace) => representation.topic_in(namespace, name)?,
+            None => representation.topic(name)?,
+        };
+        if self.config.topic_representation == TopicRepresentation::Hashed {
+            self.topic_names.insert(topic, name.to_string());
         }
+        Ok(topic)
     }
 
-    fn inject_disconnected(&mut self, peer: &PeerId) {
-        if let Some(topics) = self.peers.remove(peer) {
-            for topic in topics {
-                if let Some(peers) = self.topics.get_mut(&topic) {
-                    peers.remove(peer);
+    /// The name of `topic` for log output, if known. Raw names are
+    /// returned without our namespace.
+    pub fn topic_name<'a>(&'a self, topic: &'a Topic) -> Option<&'a str> {
+        match self.topic_names.get(topic) {
+            Some(name) => Some(name),
+            None if self.config.topic_representation == TopicRepresentation::Raw => {
+                let name = topic.name()?;
+                match &self.config.namespace {
+                    Some(namespace) => name.strip_prefix(namespace.as_str())?.strip_prefix('/'),
+                    None => Some(name),
                 }
             }
+            None => None,
         }
     }
-}
-
-impl NetworkBehaviour for Broadcast {
-    type ConnectionHandler = OneShotHandler<BroadcastConfig, Message, HandlerEvent>;
-    type OutEvent = BroadcastEvent;
 
-    fn new_handler(&mut self) -> Self::ConnectionHandler {
-        Default::default()
+    /// Subscribes to `topic`, returning a handle that unsubscribes from it
+    /// when dropped. The handle tells the number of local subscriptions to
+    /// the topic, see `BroadcastConfig::counted_subscriptions`.
+    pub fn subscribe(&mut self, topic: Topic) -> Result<SubscriptionHandle, BroadcastError> {
+        self.subscribe_inner(topic, false)
     }
 
-    fn addresses_of_peer(&mut self, _peer: &PeerId) -> Vec<Multiaddr> {
-        Vec::new()
+    /// Subscribes to `topic`, asking the connected subscribers for the
+    /// current state of the topic. Their answers are reported as
+    /// `BroadcastEvent::Snapshot`, see `Broadcast::set_snapshot_provider`.
+    pub fn subscribe_with_snapshot(
+        &mut self,
+        topic: Topic,
+    ) -> Result<SubscriptionHandle, BroadcastError> {
+        self.subscribe_inner(topic, true)
     }
 
-    fn inject_connection_established(
+    fn subscribe_inner(
         &mut self,
-        peer: &PeerId,
-        _connection_id: &ConnectionId,
-        _endpoint: &libp2p::core::ConnectedPoint,
-        _failed_addresses: Option<&Vec<Multiaddr>>,
-        other_established: usize,
-    ) {
-        if other_established == 0 {
-            self.inject_connected(peer)
+        topic: Topic,
+        snapshot: bool,
+    ) -> Result<SubscriptionHandle, BroadcastError> {
+        if self.shutting_down {
+            return Err(BroadcastError::ShuttingDown);
+        }
+        if self.config.counted_subscriptions && self.subscriptions.contains(&topic) {
+            let extra = self.subscription_refs.entry(topic).or_default();
+            *extra += 1;
+            let count = *extra + 1;
+            if snapshot {
+                let peers: Vec<_> = self.peers.keys().copied().collect();
+                for peer in peers {
+                    self.notify(peer, Message::SubscribeWithSnapshot(topic));
+                }
+            }
+            let token = self.handle_token(topic, false);
+            return Ok(SubscriptionHandle::new(
+                topic,
+                count,
+                token,
+                self.commands.tx.clone(),
+            ));
+        }
+        self.subscriptions.insert(topic);
+        let msg = self.subscribe_message(topic);
+        let peers: Vec<_> = self.peers.keys().copied().collect();
+        let notified = peers.len();
+        for peer in peers {
+            if self.config.lazy_subscriptions {
+                self.announced.entry(peer).or_default().insert(topic);
+            }
+            if snapshot {
+                self.notify(peer, Message::SubscribeWithSnapshot(topic));
+            }
+            // A lease is renewed once the peer tracks the subscription.
+            if !snapshot || self.leases.ttl().is_some() {
+                self.notify(peer, msg.clone());
+            }
         }
+        event!(debug, "subscription", topic = ?topic, peers = notified, "subscribed");
+        self.notified(BroadcastEvent::LocalSubscribed(topic), topic, notified);
+        self.discover_peers(&topic);
+        self.persist();
+        let token = self.handle_token(topic, true);
+        Ok(SubscriptionHandle::new(
+            topic,
+            1,
+            token,
+            self.commands.tx.clone(),
+        ))
     }
 
-    fn inject_connection_closed(
-        &mut self,
-        peer: &PeerId,
-        _: &ConnectionId,
-        _: &libp2p::core::ConnectedPoint,
-        _: <Self::ConnectionHandler as libp2p::swarm::IntoConnectionHandler>::Handler,
-        remaining_established: usize,
-    ) {
-        if remaining_established == 0 {
-            self.inject_disconnected(peer)
+    /// Issues the token of a new subscription handle. Uncounted
+    /// subscriptions are owned by the latest handle only.
+    fn handle_token(&mut self, topic: Topic, replace: bool) -> u64 {
+        let token = self.next_token;
+        self.next_token += 1;
+        let tokens = self.handle_tokens.entry(topic).or_default();
+        if replace {
+            tokens.clear();
         }
+        tokens.insert(token);
+        token
     }
 
-    fn inject_event(&mut self, peer: PeerId, _: ConnectionId, msg: HandlerEvent) {
-        use HandlerEvent::*;
-        use Message::*;
-        let ev = match msg {
-            Rx(Subscribe(topic)) => {
-                let peers = self.topics.entry(topic).or_default();
-                self.peers.get_mut(&peer).unwrap().insert(topic);
-                peers.insert(peer);
-                BroadcastEvent::Subscribed(peer, topic)
+    /// Announces a subscription, leased if configured.
+    fn subscribe_message(&self, topic: Topic) -> Message {
+        match self.leases.ttl() {
+            Some(ttl) => Message::SubscribeLease(topic, ttl),
+            None if !self.external_addresses.is_empty() => {
+                Message::SubscribeWithAddresses(topic, self.external_addresses.clone())
             }
-            Rx(Broadcast(topic, msg)) => BroadcastEvent::Received(peer, topic, msg),
-            Rx(Unsubscribe(topic)) => {
-                self.peers.get_mut(&peer).unwrap().remove(&topic);
-                if let Some(peers) = self.topics.get_mut(&topic) {
-                    peers.remove(&peer);
-                }
-                BroadcastEvent::Unsubscribed(peer, topic)
+            None => Message::Subscribe(topic),
+        }
+    }
+
+    /// Renews the leases of our subscriptions with all peers.
+    fn renew_subscriptions(&mut self) {
+        let peers: Vec<_> = self.peers.keys().copied().collect();
+        for peer in peers {
+            let topics: Vec<_> = match self.announced.get(&peer) {
+                Some(announced) => announced.iter().copied().collect(),
+                None if self.config.lazy_subscriptions => continue,
+                None => self.subscriptions.iter().copied().collect(),
+            };
+            for topic in topics {
+                self.notify(peer, self.subscribe_message(topic));
             }
-            Tx => {
-                return;
+        }
+    }
+
+    /// Sends heartbeats for the topics shared with peers that understand
+    /// them, reporting the peers that stopped sending theirs.
+    fn send_heartbeats(&mut self) {
+        let mut shared = Vec::new();
+        for (peer, topics) in &self.peers {
+            if matches!(
+                self.versions.get(peer),
+                Some(Version::V1_0 | Version::Floodsub)
+            ) {
+                continue;
             }
-        };
-        self.events
-            .push_back(NetworkBehaviourAction::GenerateEvent(ev));
+            let topics = topics.intersection(&self.subscriptions);
+            shared.extend(topics.map(|topic| (*peer, *topic)));
+        }
+        for (peer, topic) in &shared {
+            self.notify(*peer, Message::Heartbeat(*topic));
+        }
+        for (peer, topic) in self.heartbeats.tick(shared) {
+            event!(debug, "subscription", peer_id = %peer, topic = ?topic, "peer unresponsive");
+            self.events.push_back(NetworkBehaviourAction::GenerateEvent(
+                BroadcastEvent::PeerUnresponsive(peer, topic),
+            ));
+        }
     }
 
-    fn poll(
-        &mut self,
-        _: &mut Context,
-        _: &mut impl PollParameters,
-    ) -> Poll<NetworkBehaviourAction<BroadcastEvent, Handler>> {
-        if let Some(event) = self.events.pop_front() {
-            Poll::Ready(event)
+    /// Tells the peer about our subscriptions to `topics`, skipping those
+    /// it was told about already with `BroadcastConfig::lazy_subscriptions`.
+    fn announce(&mut self, peer: PeerId, mut topics: Vec<Topic>) {
+        if self.config.lazy_subscriptions {
+            let announced = self.announced.entry(peer).or_default();
+            topics.retain(|topic| announced.insert(*topic));
+        }
+        let filters: Vec<_> = topics
+            .iter()
+            .filter_map(|topic| {
+                let filter = self.key_filters.local(topic)?;
+                Some(Message::KeyFilter(*topic, filter.keys().cloned().collect()))
+            })
+            .collect();
+        if self.leases.ttl().is_some() {
+            for topic in topics {
+                self.notify(peer, self.subscribe_message(topic));
+            }
         } else {
-            Poll::Pending
+            // Our addresses are attached to the first subscription only.
+            if !self.external_addresses.is_empty() && !topics.is_empty() {
+                let topic = topics.remove(0);
+                self.notify(peer, self.subscribe_message(topic));
+            }
+            for batch in topics.chunks(SUBSCRIBE_BATCH_SIZE) {
+                self.notify(peer, Message::SubscribeMany(batch.to_vec()));
+            }
+        }
+        for msg in filters {
+            self.notify(peer, msg);
         }
     }
-}
 
-/// Transmission between the `OneShotHandler` and the `BroadcastHandler`.
-#[derive(Debug)]
-pub enum HandlerEvent {
-    /// We received a `Message` from a remote.
-    Rx(Message),
-    /// We successfully sent a `Message`.
-    Tx,
-}
-
-impl From<Message> for HandlerEvent {
-    fn from(message: Message) -> Self {
-        Self::Rx(message)
+    /// Ends a subscription to `topic`, returning the number of local
+    /// subscriptions left. Peers are told once none are left, see
+    /// `BroadcastConfig::counted_subscriptions`.
+    pub fn unsubscribe(&mut self, topic: &Topic) -> Result<usize, BroadcastError> {
+        let left = self.release_subscription(topic)?;
+        if left == 0 {
+            self.handle_tokens.remove(topic);
+        } else if let Some(tokens) = self.handle_tokens.get_mut(topic) {
+            // The oldest handle no longer owns a subscription.
+            if let Some(oldest) = tokens.iter().min().copied() {
+                tokens.remove(&oldest);
+            }
+        }
+        Ok(left)
     }
-}
 
-impl From<()> for HandlerEvent {
-    fn from(_: ()) -> Self {
-        Self::Tx
+    /// Ends the subscription owned by a dropped handle, unless it was
+    /// already ended or replaced.
+    fn release_handle(&mut self, topic: &Topic, token: u64) {
+        let owned = match self.handle_tokens.get_mut(topic) {
+            Some(tokens) => tokens.remove(&token),
+            None => false,
+        };
+        if !owned {
+            return;
+        }
+        if let Ok(0) = self.release_subscription(topic) {
+            self.handle_tokens.remove(topic);
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use libp2p::swarm::AddressRecord;
-    use std::sync::{Arc, Mutex};
+    fn release_subscription(&mut self, topic: &Topic) -> Result<usize, BroadcastError> {
+        if let Some(extra) = self.subscription_refs.get_mut(topic) {
+            *extra -= 1;
+            let count = *extra + 1;
+            if *extra == 0 {
+                self.subscription_refs.remove(topic);
+            }
+            return Ok(count);
+        }
+        if !self.subscriptions.remove(topic) {
+            return Err(BroadcastError::NotSubscribed);
+        }
+        let msg = Message::Unsubscribe(*topic);
+        let peers: Vec<_> = self
+            .topics
+            .get(topic)
+            .into_iter()
+            .flatten()
+            .copied()
+            .collect();
+        let notified = peers.len();
+        for peer in peers {
+            self.notify(peer, msg.clone());
+        }
+        for announced in self.announced.values_mut() {
+            announced.remove(topic);
+        }
+        event!(debug, "subscription", topic = ?topic, peers = notified, "unsubscribed");
+        self.notified(BroadcastEvent::LocalUnsubscribed(*topic), *topic, notified);
+        self.discovery.stop(topic);
+        self.persist();
+        Ok(0)
+    }
 
-    struct DummySwarm {
-        peer_id: PeerId,
-        behaviour: Arc<Mutex<Broadcast>>,
-        connections: FnvHashMap<PeerId, Arc<Mutex<Broadcast>>>,
+    /// Queues a local subscription event behind the notifications of the
+    /// peers.
+    fn notified(&mut self, event: BroadcastEvent, topic: Topic, peers: usize) {
+        self.events
+            .push_back(NetworkBehaviourAction::GenerateEvent(event));
+        self.events.push_back(NetworkBehaviourAction::GenerateEvent(
+            BroadcastEvent::NotifiedPeers(topic, peers),
+        ));
     }
 
-    impl DummySwarm {
-        fn new() -> Self {
-            Self {
-                peer_id: PeerId::random(),
-                behaviour: Default::default(),
-                connections: Default::default(),
-            }
+    /// Subscribes to all topics starting with `prefix`.
+    pub fn subscribe_prefix(&mut self, prefix: Topic) -> Result<(), BroadcastError> {
+        if self.shutting_down {
+            return Err(BroadcastError::ShuttingDown);
         }
-
-        fn peer_id(&self) -> &PeerId {
-            &self.peer_id
+        self.prefix_subscriptions.insert(prefix);
+        event!(debug, "subscription", prefix = ?prefix, "subscribed to prefix");
+        let msg = Message::SubscribePrefix(prefix);
+        let peers: Vec<_> = self.peers.keys().copied().collect();
+        for peer in peers {
+            self.notify(peer, msg.clone());
         }
+        self.persist();
+        Ok(())
+    }
 
-        fn dial(&mut self, other: &mut DummySwarm) {
-            self.behaviour
-                .lock()
-                .unwrap()
-                .inject_connected(other.peer_id());
-            self.connections
-                .insert(*other.peer_id(), other.behaviour.clone());
-            other
-                .behaviour
-                .lock()
-                .unwrap()
-                .inject_connected(self.peer_id());
-            other
-                .connections
-                .insert(*self.peer_id(), self.behaviour.clone());
+    pub fn unsubscribe_prefix(&mut self, prefix: &Topic) -> Result<(), BroadcastError> {
+        if !self.prefix_subscriptions.remove(prefix) {
+            return Err(BroadcastError::NotSubscribed);
+        }
+        event!(debug, "subscription", prefix = ?prefix, "unsubscribed from prefix");
+        let msg = Message::UnsubscribePrefix(*prefix);
+        let peers: Vec<_> = self.peers.keys().copied().collect();
+        for peer in peers {
+            self.notify(peer, msg.clone());
         }
+        self.persist();
+        Ok(())
+    }
 
-        fn next(&self) -> Option<BroadcastEvent> {
-            let waker = futures::task::noop_waker();
-            let mut ctx = Context::from_waker(&waker);
-            let mut me = self.behaviour.lock().unwrap();
-            loop {
-                match me.poll(&mut ctx, &mut DummyPollParameters) {
-                    Poll::Ready(NetworkBehaviourAction::NotifyHandler {
-                        peer_id, event, ..
-                    }) => {
-                        if let Some(other) = self.connections.get(&peer_id) {
-                            let mut other = other.lock().unwrap();
-                            other.inject_event(
-                                *self.peer_id(),
-                                ConnectionId::new(0),
-                                HandlerEvent::Rx(event),
-                            );
-                        }
-                    }
-                    Poll::Ready(NetworkBehaviourAction::GenerateEvent(event)) => {
-                        return Some(event);
-                    }
-                    Poll::Ready(_) => panic!(),
-                    Poll::Pending => {
-                        return None;
-                    }
-                }
+    /// Tracks the subscribers of `topic` without subscribing to it, e.g. for
+    /// monitoring. Subscriptions of peers are reported as
+    /// `BroadcastEvent::Subscribed` and `BroadcastEvent::Unsubscribed`, but
+    /// peers don't learn about our interest and don't send us broadcasts.
+    ///
+    /// Peers announce all their subscriptions anyway unless
+    /// `BroadcastConfig::lazy_subscriptions` is set, in which case they are
+    /// asked for the ones to `topic`.
+    pub fn observe(&mut self, topic: Topic) -> bool {
+        if !self.observed.insert(topic) {
+            return false;
+        }
+        if self.config.lazy_subscriptions {
+            let summary = TopicSummary::new([topic].iter());
+            let peers: Vec<_> = self.peers.keys().copied().collect();
+            for peer in peers {
+                self.notify(peer, Message::TopicSummary(summary.clone()));
             }
         }
+        true
+    }
 
-        fn subscribe(&self, topic: Topic) {
-            let mut me = self.behaviour.lock().unwrap();
-            me.subscribe(topic);
-        }
+    /// Stops observing `topic`. Subscriptions already announced by peers
+    /// are still tracked.
+    pub fn unobserve(&mut self, topic: &Topic) -> bool {
+        self.observed.remove(topic)
+    }
+
+    pub fn observed(&self) -> impl Iterator<Item = &Topic> + '_ {
+        self.observed.iter()
+    }
+
+    /// Keeps a connection to `peer` at `address`, redialing it with
+    /// exponential backoff when disconnected.
+    ///
+    /// Explicit peers receive all broadcasts, whether or not they are
+    /// subscribed.
+    pub fn add_explicit_peer(&mut self, peer: PeerId, address: Multiaddr) {
+        let connected = self.peers.contains_key(&peer);
+        self.explicit.insert(peer, address, connected);
+    }
+
+    /// Stops redialing `peer` and forwarding all broadcasts to it. The
+    /// connection is left open.
+    pub fn remove_explicit_peer(&mut self, peer: &PeerId) -> bool {
+        self.explicit.remove(peer)
+    }
 
-        fn unsubscribe(&self, topic: &Topic) {
-            let mut me = self.behaviour.lock().unwrap();
-            me.unsubscribe(topic);
+    pub fn is_explicit_peer(&self, peer: &PeerId) -> bool {
+        self.explicit.contains(peer)
+    }
+
+    /// Drops all frames received from `peer` and stops sending it anything,
+    /// including the messages already queued for it. Its connections are
+    /// closed, now and whenever it connects again, if `disconnect` is set.
+    pub fn block_peer(&mut self, peer: PeerId, disconnect: bool) {
+        event!(debug, "subscription", peer_id = %peer, disconnect, "blocked peer");
+        self.blocked.insert(peer, disconnect);
+        while let Some(i) = self.events.iter().position(|action| {
+            matches!(action, NetworkBehaviourAction::NotifyHandler { peer_id, .. } if *peer_id == peer)
+        }) {
+            self.remove_queued(i, DeliveryError::Blocked);
+        }
+        if disconnect && self.peers.contains_key(&peer) && self.closing.insert(peer) {
+            self.events
+                .push_back(NetworkBehaviourAction::CloseConnection {
+                    peer_id: peer,
+                    connection: CloseConnection::All,
+                });
         }
+    }
+
+    /// Lets a peer blocked with `block_peer` exchange messages again,
+    /// returning `false` if it wasn't blocked.
+    pub fn unblock_peer(&mut self, peer: &PeerId) -> bool {
+        self.blocked.remove(peer).is_some()
+    }
 
-        fn broadcast(&self, topic: &Topic, msg: Arc<[u8]>) {
-            let mut me = self.behaviour.lock().unwrap();
-            me.broadcast(topic, msg);
+    pub fn is_blocked(&self, peer: &PeerId) -> bool {
+        self.blocked.contains_key(peer)
+    }
+
+    /// Sends all our subscriptions to `peer`, which replaces the ones it
+    /// knows of with them. Peers speaking `Version::V1_0` or floodsub only
+    /// learn of the subscriptions they missed. Returns `false` if the peer
+    /// isn't connected.
+    pub fn resync(&mut self, peer: &PeerId) -> bool {
+        if !self.peers.contains_key(peer) {
+            return false;
         }
+        let mut topics: Vec<_> = match self.announced.get(peer) {
+            Some(announced) => announced.iter().copied().collect(),
+            None if self.config.lazy_subscriptions => Vec::new(),
+            None => self.subscriptions.iter().copied().collect(),
+        };
+        topics.sort();
+        self.notify(*peer, Message::SyncTopics(topics));
+        true
     }
 
-    struct DummyPollParameters;
+    /// Only delivers the messages of `topic` whose key matches `filter`.
+    /// Peers are told about the filter and stop sending us the messages
+    /// with other keys, an empty filter removes it.
+    pub fn set_key_filter(&mut self, topic: Topic, filter: KeyFilter) {
+        let keys: Vec<_> = filter.keys().cloned().collect();
+        self.key_filters.set_local(topic, filter);
+        let peers: Vec<_> = self.peers.keys().copied().collect();
+        for peer in peers {
+            self.notify(peer, Message::KeyFilter(topic, keys.clone()));
+        }
+    }
 
-    impl PollParameters for DummyPollParameters {
-        type SupportedProtocolsIter = std::iter::Empty<Vec<u8>>;
-        type ListenedAddressesIter = std::iter::Empty<Multiaddr>;
-        type ExternalAddressesIter = std::iter::Empty<AddressRecord>;
+    /// Answers peers subscribing with `Broadcast::subscribe_with_snapshot`
+    /// to one of our topics with the state returned by `provider`.
+    pub fn set_snapshot_provider(&mut self, provider: impl SnapshotProvider + 'static) {
+        self.snapshots = Some(Box::new(provider));
+    }
 
-        fn supported_protocols(&self) -> Self::SupportedProtocolsIter {
-            unimplemented!()
+    /// Sends the state of `topic` to a peer that subscribed with
+    /// `Message::SubscribeWithSnapshot`.
+    fn send_snapshot(&mut self, peer: PeerId, topic: Topic) {
+        if !self.subscriptions.contains(&topic) {
+            return;
         }
+        let state = match &mut self.snapshots {
+            Some(provider) => provider.snapshot(&topic),
+            None => None,
+        };
+        if let Some(state) = state.and_then(|state| self.seal(&topic, state)) {
+            self.notify(peer, Message::Snapshot(topic, state));
+        }
+    }
 
-        fn listened_addresses(&self) -> Self::ListenedAddressesIter {
-            unimplemented!()
+    /// Looks up subscribers with `discovery` whenever a subscribed topic has
+    /// fewer than `BroadcastConfig::min_peers` peers, dialing the peers
+    /// found.
+    pub fn set_discovery(&mut self, discovery: impl Discovery + 'static) {
+        self.discovery.set(Box::new(discovery));
+        let topics: Vec<_> = self.subscriptions.iter().copied().collect();
+        for topic in topics {
+            self.discover_peers(&topic);
         }
+    }
 
-        fn external_addresses(&self) -> Self::ExternalAddressesIter {
-            unimplemented!()
+    fn discover_peers(&mut self, topic: &Topic) {
+        if !self.subscriptions.contains(topic) {
+            return;
         }
+        let peers = self.topics.get(topic).map_or(0, |peers| peers.len());
+        if peers < self.config.min_peers {
+            self.discovery.start(topic);
+        }
+    }
 
-        fn local_peer_id(&self) -> &PeerId {
-            unimplemented!()
+    /// Peers subscribed to `topic` directly or through a prefix, and the
+    /// connected explicit peers, leaving out blocked peers.
+    fn recipients(&self, topic: &Topic) -> FnvHashSet<PeerId> {
+        let mut peers = self.topics.get(topic).cloned().unwrap_or_default();
+        peers.extend(self.explicit.connected());
+        for (prefix, prefix_peers) in &self.prefixes {
+            if topic.has_prefix(prefix) {
+                peers.extend(prefix_peers);
+            }
         }
+        peers.retain(|peer| !self.blocked.contains_key(peer));
+        peers
     }
 
-    #[test]
-    fn test_broadcast() {
-        let topic = Topic::new(b"topic");
-        let msg = Arc::new(*b"msg");
-        let mut a = DummySwarm::new();
-        let mut b = DummySwarm::new();
+    /// Broadcasts a message to all peers subscribed to `topic`.
+    ///
+    /// The message is converted into `Bytes` once and shared by the queues
+    /// of all peers without copying.
+    ///
+    /// Fails if the message couldn't be sent to or buffered for any peer.
+    pub fn broadcast(
+        &mut self,
+        topic: &Topic,
+        msg: impl Into<Bytes>,
+    ) -> Result<(), BroadcastError> {
+        self.publish(topic, msg.into(), Default::default())
+            .map(drop)
+    }
 
-        a.subscribe(topic);
-        a.dial(&mut b);
-        assert!(a.next().is_none());
-        assert_eq!(
-            b.next().unwrap(),
-            BroadcastEvent::Subscribed(*a.peer_id(), topic)
-        );
-        b.subscribe(topic);
-        assert!(b.next().is_none());
-        assert_eq!(
-            a.next().unwrap(),
-            BroadcastEvent::Subscribed(*b.peer_id(), topic)
-        );
-        b.broadcast(&topic, msg.clone());
-        assert!(b.next().is_none());
-        assert_eq!(
-            a.next().unwrap(),
-            BroadcastEvent::Received(*b.peer_id(), topic, msg)
-        );
-        a.unsubscribe(&topic);
-        assert!(a.next().is_none());
-        assert_eq!(
-            b.next().unwrap(),
-            BroadcastEvent::Unsubscribed(*a.peer_id(), topic)
-        );
+    /// Broadcasts a message to all subscribed peers except `excluded`.
+    pub fn broadcast_except(
+        &mut self,
+        topic: &Topic,
+        msg: impl Into<Bytes>,
+        excluded: &[PeerId],
+    ) -> Result<(), BroadcastError> {
+        let opts = PublishOptions {
+            excluded,
+            ..Default::default()
+        };
+        self.publish(topic, msg.into(), opts).map(drop)
+    }
+
+    /// Broadcasts a message ahead of or behind the broadcasts of normal
+    /// priority queued for the same peers.
+    pub fn broadcast_with_priority(
+        &mut self,
+        topic: &Topic,
+        msg: impl Into<Bytes>,
+        priority: Priority,
+    ) -> Result<(), BroadcastError> {
+        let opts = PublishOptions {
+            priority,
+            ..Default::default()
+        };
+        self.publish(topic, msg.into(), opts).map(drop)
+    }
+
+    /// Broadcasts messages on several topics at once, writing the ones for
+    /// the same peer as a single batch frame.
+    ///
+    /// All messages are published even if some of them fail, the first
+    /// error is returned. Bundled messages are not evicted to stay within
+    /// `BroadcastConfig::max_buffered_bytes`.
+    pub fn broadcast_many(&mut self, msgs: &[(Topic, Bytes)]) -> Result<(), BroadcastError> {
+        let start = self.events.len();
+        let mut res = Ok(());
+        for (topic, msg) in msgs {
+            let published = self.publish(topic, msg.clone(), Default::default());
+            if let (Ok(()), Err(err)) = (&res, published) {
+                res = Err(err);
+            }
+        }
+        self.bundle(start);
+        res
+    }
+
+    /// Sends a message on `topic` to a single connected peer, whether or not
+    /// it is subscribed. The message is not relayed any further.
+    ///
+    /// Returns `false` if the peer isn't connected.
+    pub fn send_to(&mut self, peer: &PeerId, topic: &Topic, msg: impl Into<Bytes>) -> bool {
+        if !self.peers.contains_key(peer) {
+            return false;
+        }
+        let msg = match self.seal(topic, msg.into()) {
+            Some(msg) => msg,
+            None => return false,
+        };
+        let ext = match self.extensions(topic, &msg, false) {
+            Some(ext) => Extensions { hops: 0, ..ext },
+            None => return false,
+        };
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &self.metrics {
+            metrics.sent(topic, msg.len());
+        }
+        self.send_broadcast(*peer, Message::Broadcast(*topic, ext, msg));
+        true
+    }
+
+    /// Broadcasts a message and asks the receivers to acknowledge it.
+    ///
+    /// Each subscribed peer results in either a `BroadcastEvent::Acked` or a
+    /// `BroadcastEvent::AckTimeout` carrying the returned id.
+    pub fn broadcast_with_ack(
+        &mut self,
+        topic: &Topic,
+        msg: impl Into<Bytes>,
+    ) -> Result<MessageId, BroadcastError> {
+        let opts = PublishOptions {
+            ack: true,
+            ..Default::default()
+        };
+        self.publish(topic, msg.into(), opts)
+    }
+
+    /// Broadcasts a message with a key, sent only to the peers whose
+    /// `KeyFilter` of the topic matches it.
+    pub fn broadcast_with_key(
+        &mut self,
+        topic: &Topic,
+        key: impl Into<Bytes>,
+        msg: impl Into<Bytes>,
+    ) -> Result<MessageId, BroadcastError> {
+        let opts = PublishOptions {
+            key: Some(key.into()),
+            ..Default::default()
+        };
+        self.publish(topic, msg.into(), opts)
+    }
+
+    /// Broadcasts a message and retransmits it to the subscribers that
+    /// didn't acknowledge it, including ones subscribing later, until
+    /// `quorum` peers did or `deadline` passed.
+    ///
+    /// Results in a `BroadcastEvent::ReliableBroadcastResult` carrying the
+    /// returned id. Only peers speaking `Version::V1_1` acknowledge messages.
+    pub fn broadcast_reliable(
+        &mut self,
+        topic: &Topic,
+        msg: impl Into<Bytes>,
+        quorum: usize,
+        deadline: Duration,
+    ) -> Result<MessageId, BroadcastError> {
+        let opts = PublishOptions {
+            ack: quorum > 0,
+            reliable: Some((quorum, deadline)).filter(|_| quorum > 0),
+            ..Default::default()
+        };
+        let id = self.publish(topic, msg.into(), opts)?;
+        if quorum == 0 {
+            self.generate(vec![BroadcastEvent::ReliableBroadcastResult {
+                id,
+                delivered: 0,
+                quorum_met: true,
+            }]);
+        }
+        Ok(id)
+    }
+
+    /// Broadcasts a message, reporting for each peer whether it was written
+    /// with `BroadcastEvent::Delivered` or `BroadcastEvent::SendFailed`,
+    /// followed by a `BroadcastEvent::DeliveryComplete`.
+    ///
+    /// Messages buffered for disconnected peers are not tracked.
+    pub fn broadcast_tracked(
+        &mut self,
+        topic: &Topic,
+        msg: impl Into<Bytes>,
+    ) -> Result<BroadcastId, BroadcastError> {
+        let id = self.deliveries.start();
+        let opts = PublishOptions {
+            tracked: Some(id),
+            ..Default::default()
+        };
+        let res = self.publish(topic, msg.into(), opts);
+        let events = self.deliveries.finish(id);
+        if let Err(err) = res {
+            // The caller never learns the id.
+            self.events.retain(|event| {
+                !matches!(
+                    event,
+                    NetworkBehaviourAction::GenerateEvent(
+                        BroadcastEvent::SendFailed { id: failed, .. }
+                    ) if *failed == id
+                )
+            });
+            return Err(err);
+        }
+        self.generate(events);
+        Ok(id)
+    }
+
+    /// Broadcasts a request and collects the replies for
+    /// `BroadcastConfig::request_timeout`.
+    ///
+    /// The replies are broadcast on a reply topic we are subscribed to until
+    /// the timeout, the receivers get the request as a
+    /// `BroadcastEvent::Request`.
+    pub fn request(
+        &mut self,
+        topic: &Topic,
+        msg: impl Into<Bytes>,
+    ) -> Result<impl Future<Output = Replies>, BroadcastError> {
+        if self.shutting_down {
+            return Err(BroadcastError::ShuttingDown);
+        }
+        let reply_id = self.requests.reply_id();
+        let reply_to = ReplyTo(reply_id).topic();
+        self.subscribe(reply_to)?.detach();
+        let rx = self.requests.insert(reply_to, self.config.request_timeout);
+        let opts = PublishOptions {
+            reply_to: Some(reply_id),
+            ..Default::default()
+        };
+        if let Err(err) = self.publish(topic, msg.into(), opts) {
+            self.requests.remove(&reply_to);
+            self.unsubscribe(&reply_to).ok();
+            return Err(err);
+        }
+        Ok(rx.map(Result::unwrap_or_default))
+    }
+
+    /// Answers a `BroadcastEvent::Request`.
+    pub fn reply(
+        &mut self,
+        reply_to: ReplyTo,
+        msg: impl Into<Bytes>,
+    ) -> Result<(), BroadcastError> {
+        self.broadcast(&reply_to.topic(), msg)
+    }
+
+    /// Stores `msg` as the retained message of `topic` and broadcasts it.
+    ///
+    /// Peers subscribing to `topic` later receive the retained message right
+    /// away. The message is retained even if `BroadcastError::NoPeers` is
+    /// returned.
+    pub fn broadcast_retained(
+        &mut self,
+        topic: &Topic,
+        msg: impl Into<Bytes>,
+    ) -> Result<(), BroadcastError> {
+        let msg = msg.into();
+        self.retained.insert(*topic, msg.clone());
+        self.publish(topic, msg, Default::default()).map(drop)
+    }
+
+    pub fn clear_retained(&mut self, topic: &Topic) {
+        self.retained.remove(topic);
+    }
+
+    /// The messages of `topic` kept for `BroadcastConfig::history_len` since
+    /// a message or time, oldest first.
+    pub fn replay(&self, topic: &Topic, since: impl Into<ReplaySince>) -> Vec<(MessageId, Bytes)> {
+        self.history
+            .since(topic, since.into())
+            .into_iter()
+            .filter_map(|(id, _, msg)| Some((id, self.open(topic, &msg)?)))
+            .collect()
+    }
+
+    /// Asks a connected peer to resend the messages of `topic` it kept since
+    /// a message or time. The ones we didn't see yet are delivered as usual.
+    ///
+    /// Peers only answer subscribers of the topic.
+    pub fn request_replay(
+        &mut self,
+        peer: &PeerId,
+        topic: &Topic,
+        since: impl Into<ReplaySince>,
+    ) -> Result<(), BroadcastError> {
+        if !self.is_subscribed(topic) {
+            return Err(BroadcastError::NotSubscribed);
+        }
+        if !self.peers.contains_key(peer) {
+            return Err(BroadcastError::NoPeers);
+        }
+        self.notify(*peer, Message::Replay(*topic, since.into()));
+        Ok(())
+    }
+
+    /// Unsubscribes from all topics and prefixes and flushes the messages
+    /// queued for all connections, including rate limited ones.
+    ///
+    /// The future resolves once all handlers wrote their messages or were
+    /// closed, the swarm has to be polled meanwhile. Afterwards subscribing
+    /// and broadcasting fail with `BroadcastError::ShuttingDown`, the
+    /// subscription store keeps the subscriptions from before.
+    pub fn shutdown(&mut self) -> impl Future<Output = ()> {
+        self.shutting_down = true;
+        let topics: Vec<_> = self.subscriptions.iter().copied().collect();
+        for topic in topics {
+            self.unsubscribe(&topic).ok();
+        }
+        let prefixes: Vec<_> = self.prefix_subscriptions.iter().copied().collect();
+        for prefix in prefixes {
+            self.unsubscribe_prefix(&prefix).ok();
+        }
+        for (peer, msg, id, priority) in self.rate_limiter.drain() {
+            self.notify_tracked(peer, msg, id, priority);
+        }
+        for (peer, connection) in self.connections.all() {
+            self.events
+                .push_back(NetworkBehaviourAction::NotifyHandler {
+                    peer_id: peer,
+                    handler: NotifyHandler::One(connection),
+                    event: HandlerIn::Flush,
+                });
+            self.flushing.insert((peer, connection));
+        }
+        let (tx, rx) = oneshot::channel();
+        self.flushed.push(tx);
+        self.check_flushed();
+        async move {
+            rx.await.ok();
+        }
+    }
+
+    fn check_flushed(&mut self) {
+        if self.flushing.is_empty() {
+            for tx in self.flushed.drain(..) {
+                tx.send(()).ok();
+            }
+        }
+    }
+
+    /// Returns a cloneable client for using the behaviour from other tasks.
+    pub fn client(&self) -> BroadcastClient {
+        BroadcastClient::new(self.commands.tx.clone())
+    }
+
+    /// Returns a stream of the messages received on `topic`, in addition to
+    /// the `BroadcastEvent::Received` events.
+    ///
+    /// The stream doesn't subscribe to the topic. It buffers up to 256
+    /// messages, further messages are dropped until it is polled and
+    /// reported with `BroadcastEvent::StreamLagged`.
+    pub fn topic_stream(
+        &mut self,
+        topic: Topic,
+    ) -> impl Stream<Item = (PeerId, Bytes)> + Send + Unpin {
+        let (tx, rx) = mpsc::channel(TOPIC_STREAM_CAPACITY);
+        self.streams.entry(topic).or_default().push(tx);
+        rx
+    }
+
+    /// Passes a received message to the streams of its topic, forgetting
+    /// dropped streams and reporting full ones.
+    fn dispatch(&mut self, source: PeerId, topic: &Topic, msg: &Bytes) {
+        let streams = match self.streams.get_mut(topic) {
+            Some(streams) => streams,
+            None => return,
+        };
+        let mut lagged = 0;
+        let mut i = 0;
+        while i < streams.len() {
+            match streams[i].try_send((source, msg.clone())) {
+                Ok(()) => i += 1,
+                Err(err) if err.is_full() => {
+                    lagged += 1;
+                    i += 1;
+                }
+                Err(_) => {
+                    streams.swap_remove(i);
+                }
+            }
+        }
+        if streams.is_empty() {
+            self.streams.remove(topic);
+        }
+        if lagged > 0 {
+            event!(debug, "stream", topic = ?topic, streams = lagged, "stream lagged");
+            self.events.push_back(NetworkBehaviourAction::GenerateEvent(
+                BroadcastEvent::StreamLagged(*topic, lagged),
+            ));
+        }
+    }
+
+    /// Encrypts a payload published on `topic` if the topic has a key,
+    /// `None` if encryption failed.
+    #[cfg(feature = "encryption")]
+    fn seal(&self, topic: &Topic, msg: Bytes) -> Option<Bytes> {
+        match self.topic_keys.get(topic) {
+            Some(key) => key.encrypt(topic, &msg),
+            None => Some(msg),
+        }
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    fn seal(&self, _: &Topic, msg: Bytes) -> Option<Bytes> {
+        Some(msg)
+    }
+
+    /// Decrypts a payload received on `topic` if the topic has a key, `None`
+    /// if decryption failed.
+    #[cfg(feature = "encryption")]
+    fn open(&self, topic: &Topic, msg: &Bytes) -> Option<Bytes> {
+        match self.topic_keys.get(topic) {
+            Some(key) => key.decrypt(topic, msg),
+            None => Some(msg.clone()),
+        }
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    fn open(&self, _: &Topic, msg: &Bytes) -> Option<Bytes> {
+        Some(msg.clone())
+    }
+
+    /// Appends our stamp to a broadcast on a topic with a relay key unless we
+    /// stamped it last, dropping the oldest stamp beyond `MAX_PATH_LENGTH`.
+    #[cfg(feature = "stamps")]
+    fn stamp(&self, mut msg: Arc<Message>) -> Arc<Message> {
+        let (key, relay) = match (&*msg, self.relay_id) {
+            (Message::Broadcast(topic, ext, _), Some(relay))
+                if ext.stamps().last().map(|stamp| stamp.relay) != Some(relay) =>
+            {
+                match self.relay_keys.get(topic) {
+                    Some(key) => (key, relay),
+                    None => return msg,
+                }
+            }
+            _ => return msg,
+        };
+        // Shared broadcasts were stamped before they were shared, so this
+        // doesn't copy them.
+        if let Message::Broadcast(topic, ext, payload) = Arc::make_mut(&mut msg) {
+            let stamps = Arc::make_mut(ext.stamps.get_or_insert_with(Default::default));
+            if stamps.len() >= MAX_PATH_LENGTH {
+                stamps.remove(0);
+            }
+            stamps.push(key.stamp(topic, relay, payload));
+        }
+        msg
+    }
+
+    #[cfg(not(feature = "stamps"))]
+    fn stamp(&self, msg: Arc<Message>) -> Arc<Message> {
+        msg
+    }
+
+    /// Whether a broadcast received from `peer` carries valid stamps if the
+    /// topic has a relay key.
+    #[cfg(feature = "stamps")]
+    fn check_stamps(&self, peer: &PeerId, topic: &Topic, ext: &Extensions, msg: &[u8]) -> bool {
+        match self.relay_keys.get(topic) {
+            Some(key) => key.verify(topic, peer, ext.stamps(), msg),
+            None => true,
+        }
+    }
+
+    #[cfg(not(feature = "stamps"))]
+    fn check_stamps(&self, _: &PeerId, _: &Topic, _: &Extensions, _: &[u8]) -> bool {
+        true
+    }
+
+    /// Extensions of a message published by us, `None` if signing failed.
+    fn extensions(&self, topic: &Topic, msg: &[u8], ack: bool) -> Option<Extensions> {
+        let timestamp = self
+            .config
+            .replay_window
+            .and(self.config.keypair.as_ref())
+            .map(|_| protocol::unix_millis());
+        let signature = match (&self.config.keypair, timestamp) {
+            (Some(keypair), Some(timestamp)) => {
+                Signature::sign_stamped(keypair, topic, timestamp, msg).map(Some)
+            }
+            (Some(keypair), None) => Signature::sign(keypair, topic, msg).map(Some),
+            (None, _) => Ok(None),
+        };
+        // Unsigned messages would be rejected by strict receivers.
+        let signature = signature.ok()?;
+        Some(Extensions {
+            hops: self.config.relay_mode.hops(),
+            signature,
+            ack,
+            compressed: false,
+            expires: self
+                .config
+                .message_ttl
+                .map(|ttl| protocol::unix_millis() + ttl.as_millis() as u64),
+            seqno: None,
+            order: None,
+            reply_to: None,
+            timestamp,
+            path: None,
+            key: None,
+            stamps: None,
+            floodsub: None,
+        })
+    }
+
+    /// Gives a message the publisher and sequence number floodsub peers
+    /// identify it by, once for all of its copies. Messages received over
+    /// floodsub keep theirs.
+    fn identify_for_floodsub(
+        &mut self,
+        topic: &Topic,
+        ext: &mut Extensions,
+        msg: &[u8],
+        from: Option<PeerId>,
+    ) {
+        if ext.floodsub.is_some() || !self.config.versions.contains(&Version::Floodsub) {
+            return;
+        }
+        ext.floodsub = from.or(self.local_peer_id).map(FloodsubId::new);
+        if ext.floodsub.is_some() {
+            // Floodsub peers may send it back under its floodsub id.
+            self.seen.insert(self.config.message_id.id(topic, ext, msg));
+        }
+    }
+
+    /// Publishes a message as described by `opts`.
+    ///
+    /// The result only depends on the remote peers, the copy delivered
+    /// with `BroadcastConfig::deliver_own_messages` doesn't count as sent.
+    fn publish(
+        &mut self,
+        topic: &Topic,
+        msg: Bytes,
+        opts: PublishOptions,
+    ) -> Result<MessageId, BroadcastError> {
+        let PublishOptions {
+            key,
+            excluded,
+            reliable,
+            ack,
+            reply_to,
+            tracked,
+            priority,
+        } = opts;
+        span!(DEBUG, "broadcast", "publish", topic = ?topic, msg_len = msg.len());
+        if self.shutting_down {
+            return Err(BroadcastError::ShuttingDown);
+        }
+        if !self.is_subscribed(topic) {
+            match self.config.publish_policy {
+                PublishPolicy::Allow => {}
+                PublishPolicy::RequireSubscription => return Err(BroadcastError::NotSubscribed),
+                PublishPolicy::AutoSubscribe => self.subscribe_inner(*topic, false)?.detach(),
+            }
+        }
+        let own = (self.config.deliver_own_messages && self.subscriptions.contains(topic))
+            .then(|| msg.clone());
+        let msg = self.seal(topic, msg).ok_or(BroadcastError::Encryption)?;
+        let limit = self.topic_limits.get(topic).copied();
+        if msg.len() > limit.unwrap_or(self.config.max_message_size) {
+            return Err(BroadcastError::MessageTooLarge);
+        }
+        // Retransmitted until acknowledged by all subscribers, which drop the
+        // copies they already received by sequence number.
+        let at_least_once = match self.config.delivery_modes.get(topic) {
+            Some(DeliveryMode::AtLeastOnce { deadline }) if reliable.is_none() => Some(*deadline),
+            _ => None,
+        };
+        let ack = ack || at_least_once.is_some();
+        let mut peers = self.recipients(topic);
+        let subscribers = peers.len();
+        for peer in excluded {
+            peers.remove(peer);
+        }
+        peers.retain(|peer| self.key_filters.peer_wants(peer, topic, key.as_deref()));
+        // Stamped by the sequencer, which sends it to the others.
+        if let Some(sequencer) = self.sequencers.remote(topic) {
+            peers.retain(|peer| *peer == sequencer);
+        }
+        if let Some(min_peers) = self.config.dial_on_broadcast {
+            self.redial(topic, min_peers.saturating_sub(peers.len()));
+        }
+        let peers = self.fanout(peers);
+        let mut ext = self
+            .extensions(topic, &msg, ack)
+            .ok_or(BroadcastError::SigningFailed)?;
+        ext.reply_to = reply_to;
+        ext.key = key.clone();
+        // Numbered only if sent to all subscribers, skipped ones would
+        // report the sequence number as missing.
+        let numbered = self.config.sequence_numbers || at_least_once.is_some();
+        if numbered && peers.len() == subscribers {
+            let seqno = self.next_seqno.entry(*topic).or_default();
+            ext.seqno = Some(*seqno);
+            *seqno += 1;
+        }
+        if self.sequencers.is_local(topic) {
+            ext.order = Some(self.sequencers.stamp(topic));
+        }
+        let id = self.config.message_id.id(topic, &ext, &msg);
+        self.identify_for_floodsub(topic, &mut ext, &msg, None);
+        #[cfg(feature = "metrics")]
+        let len = msg.len();
+        self.history.push(topic, id, ext.clone(), msg.clone());
+        // Shared by the queues of all recipients instead of cloned for each.
+        let msg = self.stamp(Arc::new(Message::Broadcast(*topic, ext, msg)));
+        let reliable = reliable.or_else(|| {
+            let quorum = peers
+                .iter()
+                .filter(|peer| {
+                    !matches!(
+                        self.protocol_version(peer),
+                        Some(Version::V1_0 | Version::Floodsub)
+                    )
+                })
+                .count();
+            at_least_once
+                .filter(|_| quorum > 0)
+                .map(|deadline| (quorum, deadline))
+        });
+        let reliable = reliable.map(|(quorum, deadline)| (quorum, deadline, msg.clone()));
+        let mut sent = 0;
+        for peer in &peers {
+            let peer = *peer;
+            // Only peers speaking `Version::V1_1` acknowledge messages.
+            let version = self.protocol_version(&peer);
+            if ack
+                && reliable.is_none()
+                && !matches!(version, Some(Version::V1_0 | Version::Floodsub))
+            {
+                self.acks.insert(peer, id);
+            }
+            #[cfg(feature = "metrics")]
+            if let Some(metrics) = &self.metrics {
+                metrics.sent(topic, len);
+            }
+            if self.offer(peer, id, &msg) || self.send_tracked(peer, msg.clone(), tracked, priority)
+            {
+                sent += 1;
+            }
+        }
+        let buffered = self.offline.push(topic, &msg);
+        event!(
+            trace,
+            "broadcast",
+            peers = peers.len(),
+            sent,
+            buffered,
+            "published"
+        );
+        self.enforce_budget();
+        let res = match (peers.len(), sent + buffered) {
+            (0, 0) => match self.config.no_peers_policy {
+                NoPeersPolicy::Drop => Ok(id),
+                NoPeersPolicy::Error => Err(BroadcastError::NoPeers),
+                NoPeersPolicy::Buffer { .. } => {
+                    event!(trace, "queue", "buffered until a peer subscribes");
+                    self.unrouted.push(*topic, unshare(msg));
+                    Ok(id)
+                }
+            },
+            (_, 0) => Err(BroadcastError::QueueFull),
+            _ => Ok(id),
+        };
+        if let (Ok(id), Some((quorum, deadline, msg))) = (res, reliable) {
+            self.reliable
+                .insert(id, *topic, unshare(msg), quorum, deadline);
+        }
+        if let (Ok(_), Some(msg)) = (res, own) {
+            self.own_messages.push_back((*topic, id, msg));
+            self.events.wake();
+        }
+        res
+    }
+
+    /// Merges the broadcasts queued from `start` on for the same handler
+    /// into one `Message::Batch`.
+    fn bundle(&mut self, start: usize) {
+        let tail = self.events.split_off(start.min(self.events.len()));
+        let mut batches: FnvHashMap<_, (usize, Vec<Message>)> = FnvHashMap::default();
+        for action in tail {
+            let (peer_id, handler, msg) = match action {
+                NetworkBehaviourAction::NotifyHandler {
+                    peer_id,
+                    handler,
+                    event: HandlerIn::SendShared(Priority::Normal, None, msg),
+                } => (peer_id, handler, unshare(msg)),
+                action => {
+                    self.events.push_back(action);
+                    continue;
+                }
+            };
+            let connection = match handler {
+                NotifyHandler::One(id) => Some(id),
+                NotifyHandler::Any => None,
+            };
+            let next = self.events.len();
+            let (i, msgs) = batches
+                .entry((peer_id, connection))
+                .or_insert((next, Vec::new()));
+            if *i == next {
+                // Filled in once all messages for the handler are known.
+                self.events
+                    .push_back(NetworkBehaviourAction::NotifyHandler {
+                        peer_id,
+                        handler,
+                        event: HandlerIn::Send(Message::Batch(Vec::new())),
+                    });
+            }
+            msgs.push(msg);
+        }
+        for ((peer_id, connection), (i, mut msgs)) in batches {
+            let msg = if msgs.len() == 1 {
+                msgs.pop().unwrap()
+            } else {
+                Message::Batch(msgs)
+            };
+            let handler = match connection {
+                Some(id) => NotifyHandler::One(id),
+                None => NotifyHandler::Any,
+            };
+            self.events.replace(
+                i,
+                NetworkBehaviourAction::NotifyHandler {
+                    peer_id,
+                    handler,
+                    event: HandlerIn::Send(msg),
+                },
+            );
+        }
+    }
+
+    /// Forgets the topics we aren't subscribed to that had no subscribers
+    /// for the retention of `BroadcastConfig::topic_gc`.
+    fn collect_topics(&mut self) {
+        let subscriptions = &self.subscriptions;
+        let empty = self
+            .topics
+            .iter()
+            .filter(|(topic, peers)| peers.is_empty() && !subscriptions.contains(topic))
+            .map(|(topic, _)| *topic);
+        for topic in self.gc.collect(empty) {
+            event!(debug, "subscription", topic = ?topic, "forgot topic");
+            self.topics.remove(&topic);
+            self.last_seqno
+                .retain(|(_, seqno_topic), _| *seqno_topic != topic);
+            self.events.push_back(NetworkBehaviourAction::GenerateEvent(
+                BroadcastEvent::TopicForgotten(topic),
+            ));
+        }
+    }
+
+    /// Elects the subscriber with the lowest peer id as the sequencer of
+    /// each topic with total order.
+    fn elect_sequencers(&mut self, local: PeerId) {
+        let topics: Vec<_> = self.sequencers.topics().copied().collect();
+        for topic in topics {
+            let local_subscriber = if self.subscriptions.contains(&topic) {
+                Some(local)
+            } else {
+                None
+            };
+            let subscribers = self.topics.get(&topic).into_iter().flatten().copied();
+            let candidates = subscribers.chain(local_subscriber);
+            if let Some(sequencer) = self.sequencers.elect(local, topic, candidates) {
+                event!(debug, "broadcast", topic = ?topic, sequencer = ?sequencer, "sequencer changed");
+                self.events.push_back(NetworkBehaviourAction::GenerateEvent(
+                    BroadcastEvent::SequencerChanged(topic, sequencer),
+                ));
+            }
+        }
+    }
+
+    /// Dials up to `amount` peers that were subscribed to `topic`.
+    fn redial(&mut self, topic: &Topic, amount: usize) {
+        if amount == 0 {
+            return;
+        }
+        for (peer, addresses) in self.former.redial(topic, amount) {
+            event!(debug, "broadcast", peer_id = %peer, topic = ?topic, "redialing subscriber");
+            self.discovery.suggest(peer, &addresses);
+        }
+    }
+
+    /// Samples at most `BroadcastConfig::max_fanout` of `peers`, always
+    /// keeping explicit peers.
+    fn fanout(&self, peers: FnvHashSet<PeerId>) -> Vec<PeerId> {
+        let max_fanout = match self.config.max_fanout {
+            Some(max_fanout) if peers.len() > max_fanout => max_fanout,
+            _ => return peers.into_iter().collect(),
+        };
+        let (mut selected, candidates): (Vec<_>, Vec<_>) = peers
+            .into_iter()
+            .partition(|peer| self.explicit.contains(peer));
+        let amount = max_fanout.saturating_sub(selected.len());
+        let mut rng = rand::thread_rng();
+        let by_score = self.config.fanout_weighted_by_score;
+        let by_latency = self.config.fanout_weighted_by_latency;
+        if by_score || by_latency {
+            // Peers with a negative score or a high latency are picked less
+            // often.
+            let weight = |peer: &PeerId| {
+                let mut weight = 1.0;
+                if by_score {
+                    let score = self.scores.score(peer).unwrap_or_default();
+                    weight /= 1.0 - score.min(0.0);
+                }
+                if by_latency {
+                    weight *= self.latencies.weight(peer);
+                }
+                weight
+            };
+            if let Ok(chosen) = candidates.choose_multiple_weighted(&mut rng, amount, weight) {
+                selected.extend(chosen);
+                return selected;
+            }
+        }
+        selected.extend(candidates.choose_multiple(&mut rng, amount));
+        selected
+    }
+
+    /// Checks the signature of a received message according to the
+    /// validation mode, returning the peer that published it.
+    fn verify(
+        &self,
+        peer: &PeerId,
+        topic: &Topic,
+        ext: &Extensions,
+        msg: &[u8],
+    ) -> Result<PeerId, RejectReason> {
+        // Floodsub messages name their publisher, unauthenticated like the
+        // peer an unsigned broadcast is received from.
+        let unsigned = ext.floodsub.as_ref().map_or(*peer, |id| id.from);
+        match (&ext.signature, self.config.validation_mode) {
+            (_, ValidationMode::None) => Ok(unsigned),
+            (Some(signature), _) if verify_signature(signature, topic, ext, msg) => {
+                Ok(signature.origin())
+            }
+            (Some(_), _) => Err(RejectReason::InvalidSignature),
+            (None, ValidationMode::Strict) => Err(RejectReason::MissingSignature),
+            (None, ValidationMode::Permissive) => Ok(unsigned),
+        }
+    }
+
+    /// Relays a received message if it may be relayed any further.
+    fn forward(
+        &mut self,
+        source: &PeerId,
+        topic: &Topic,
+        id: MessageId,
+        mut ext: Extensions,
+        msg: Bytes,
+    ) {
+        let hops = ext.hops.min(self.config.relay_mode.hops());
+        if hops > 0 {
+            ext.hops = hops - 1;
+            ext.ack = false;
+            self.relay(source, topic, id, ext, msg);
+        }
+    }
+
+    /// Forwards a received message to all peers subscribed to `topic` except
+    /// the one it was received from.
+    fn relay(
+        &mut self,
+        source: &PeerId,
+        topic: &Topic,
+        id: MessageId,
+        mut ext: Extensions,
+        msg: Bytes,
+    ) {
+        #[cfg(feature = "metrics")]
+        let len = msg.len();
+        let origin = match (&ext.signature, ext.path().first()) {
+            (Some(signature), _) => signature.origin(),
+            (None, Some(publisher)) => *publisher,
+            (None, None) => *source,
+        };
+        self.identify_for_floodsub(topic, &mut ext, &msg, Some(origin));
+        let key = ext.key.clone();
+        let msg = self.stamp(Arc::new(Message::Broadcast(*topic, ext, msg)));
+        let mut peers = self.recipients(topic);
+        peers.remove(source);
+        peers.retain(|peer| self.key_filters.peer_wants(peer, topic, key.as_deref()));
+        for peer in self.fanout(peers) {
+            #[cfg(feature = "metrics")]
+            if let Some(metrics) = &self.metrics {
+                metrics.sent(topic, len);
+            }
+            if !self.offer(peer, id, &msg) {
+                self.send_broadcast(peer, msg.clone());
+            }
+        }
+    }
+
+    /// Offers a large broadcast to a peer with a `Message::IHave` instead of
+    /// sending it, see `BroadcastConfig::lazy_push`. Returns `false` if it
+    /// has to be sent.
+    fn offer(&mut self, peer: PeerId, id: MessageId, msg: &Message) -> bool {
+        let (topic, ext, payload) = match (msg, self.config.lazy_push) {
+            (Message::Broadcast(topic, ext, payload), Some(threshold))
+                if payload.len() > threshold =>
+            {
+                (topic, ext, payload)
+            }
+            _ => return false,
+        };
+        let version = self.protocol_version(&peer);
+        if matches!(version, Some(Version::V1_0 | Version::Floodsub)) {
+            return false;
+        }
+        self.lazy.offer(id, *topic, ext.clone(), payload.clone());
+        self.notify(peer, Message::IHave(*topic, vec![id]));
+        true
+    }
+
+    /// Sends the retained messages of the topics matching `filter` to a peer
+    /// that just subscribed.
+    fn send_retained(&mut self, peer: PeerId, filter: impl Fn(&Topic) -> bool) {
+        let retained: Vec<_> = self
+            .retained
+            .iter()
+            .filter(|(topic, _)| filter(topic))
+            .map(|(topic, msg)| (*topic, msg.clone()))
+            .collect();
+        for (topic, msg) in retained {
+            let msg = match self.seal(&topic, msg) {
+                Some(msg) => msg,
+                None => continue,
+            };
+            if let Some(mut ext) = self.extensions(&topic, &msg, false) {
+                self.identify_for_floodsub(&topic, &mut ext, &msg, None);
+                self.send_broadcast(peer, Message::Broadcast(topic, ext, msg));
+            }
+        }
+    }
+
+    /// Sends the broadcasts held for the first subscriber of the topics
+    /// matching `filter`.
+    fn send_unrouted(&mut self, peer: PeerId, filter: impl Fn(&Topic) -> bool) {
+        for msg in self.unrouted.take(filter) {
+            self.send_broadcast(peer, msg);
+        }
+    }
+
+    /// Suggests other subscribers of `topic` to a peer that subscribed to it.
+    fn exchange_peers(&mut self, peer: PeerId, topic: Topic) {
+        let max_peers = match self.config.peer_exchange {
+            Some(max_peers) => max_peers,
+            None => return,
+        };
+        let peers: Vec<_> = self
+            .topics
+            .get(&topic)
+            .into_iter()
+            .flatten()
+            .filter(|other| **other != peer)
+            .map(|other| {
+                let mut addresses = self.addresses.get(other).cloned().unwrap_or_default();
+                addresses.extend(self.explicit.addresses(other));
+                (*other, addresses)
+            })
+            .filter(|(_, addresses)| !addresses.is_empty())
+            .take(max_peers)
+            .collect();
+        if peers.is_empty() {
+            return;
+        }
+        let signature = match &self.config.keypair {
+            Some(keypair) => match Signature::sign_peers(keypair, &topic, &peers) {
+                Ok(signature) => Some(signature),
+                Err(_) => return,
+            },
+            None => None,
+        };
+        self.notify(peer, Message::PeerExchange(topic, peers, signature));
+    }
+
+    /// Offers the history of the topics matching `filter` to a peer that just
+    /// subscribed.
+    fn send_ihave(&mut self, peer: PeerId, filter: impl Fn(&Topic) -> bool) {
+        for topic in self.history.topics(filter) {
+            let ids = self.history.ids(&topic);
+            if ids.is_empty() {
+                continue;
+            }
+            self.notify(peer, Message::IHave(topic, ids));
+        }
+    }
+
+    /// Requests the offered messages that haven't been seen yet.
+    fn inject_ihave(&mut self, peer: PeerId, topic: Topic, ids: Vec<MessageId>) {
+        if !self.is_subscribed(&topic) {
+            return;
+        }
+        let (seen, lazy) = (&self.seen, &mut self.lazy);
+        let missing: Vec<_> = ids
+            .into_iter()
+            .filter(|id| !seen.contains(id) && lazy.request(*id))
+            .collect();
+        if missing.is_empty() {
+            return;
+        }
+        self.notify(peer, Message::IWant(topic, missing));
+    }
+
+    /// Sends the requested messages that were offered by `lazy_push`, or
+    /// from the history without relaying them any further.
+    fn inject_iwant(&mut self, peer: PeerId, topic: Topic, ids: Vec<MessageId>) {
+        for id in ids {
+            if let Some((ext, msg)) = self.lazy.get(&topic, &id) {
+                self.send_broadcast(peer, Message::Broadcast(topic, ext, msg));
+            } else if let Some((ext, msg)) = self.history.get(&topic, &id) {
+                let ext = Extensions {
+                    hops: 0,
+                    ack: false,
+                    ..ext
+                };
+                self.send_broadcast(peer, Message::Broadcast(topic, ext, msg));
+            }
+        }
+    }
+
+    /// Sends the pending reliable broadcasts on `topic` to a new subscriber.
+    fn send_reliable(&mut self, peer: PeerId, topic: Topic) {
+        if matches!(
+            self.protocol_version(&peer),
+            Some(Version::V1_0 | Version::Floodsub)
+        ) {
+            return;
+        }
+        for msg in self.reliable.unacked(&peer, &topic) {
+            self.send_broadcast(peer, msg);
+        }
+    }
+
+    /// Resends reliable broadcasts to the subscribers that didn't
+    /// acknowledge them and reports the ones whose deadline passed.
+    fn retransmit(&mut self, due: Vec<Due>) {
+        for due in due {
+            match due {
+                Due::Retransmit(topic, msg, acked) => {
+                    let msg = Arc::new(msg);
+                    for peer in self.recipients(&topic) {
+                        let version = self.protocol_version(&peer);
+                        if acked.contains(&peer)
+                            || matches!(version, Some(Version::V1_0 | Version::Floodsub))
+                        {
+                            continue;
+                        }
+                        self.send_broadcast(peer, msg.clone());
+                    }
+                }
+                Due::Expired(id, delivered) => {
+                    self.generate(vec![BroadcastEvent::ReliableBroadcastResult {
+                        id,
+                        delivered,
+                        quorum_met: false,
+                    }]);
+                }
+            }
+        }
+    }
+
+    /// Resends the messages a subscriber asked for with `Message::Replay`
+    /// without relaying them any further.
+    fn inject_replay(&mut self, peer: PeerId, topic: Topic, since: ReplaySince) {
+        if !self.recipients(&topic).contains(&peer) {
+            return;
+        }
+        for (_, ext, msg) in self.history.since(&topic, since) {
+            if !self
+                .key_filters
+                .peer_wants(&peer, &topic, ext.key.as_deref())
+            {
+                continue;
+            }
+            let ext = Extensions {
+                hops: 0,
+                ack: false,
+                ..ext
+            };
+            self.send_broadcast(peer, Message::Broadcast(topic, ext, msg));
+        }
+    }
+
+    /// Hands a broadcast to the peer's handler unless it is rate limited,
+    /// returning `false` if it was dropped.
+    fn send_broadcast(&mut self, peer: PeerId, msg: impl Into<Arc<Message>>) -> bool {
+        self.send_tracked(peer, msg, None, Priority::Normal)
+    }
+
+    fn send_tracked(
+        &mut self,
+        peer: PeerId,
+        msg: impl Into<Arc<Message>>,
+        id: Option<BroadcastId>,
+        priority: Priority,
+    ) -> bool {
+        let ev = match self.rate_limiter.send(peer, msg.into(), id, priority) {
+            Admission::Send(msg) => {
+                self.notify_tracked(peer, msg, id, priority);
+                return true;
+            }
+            Admission::Delayed(topic) => {
+                if let Some(id) = id {
+                    self.deliveries.sent(id, peer, 0);
+                }
+                BroadcastEvent::RateLimited(peer, topic)
+            }
+            Admission::Dropped(topic) => {
+                self.stats.failed(&peer);
+                if let Some(id) = id {
+                    let events = self.deliveries.fail(id, peer, DeliveryError::QueueFull);
+                    self.generate(events);
+                }
+                BroadcastEvent::OutboundDropped(peer, topic)
+            }
+        };
+        let sent = !matches!(ev, BroadcastEvent::OutboundDropped(..));
+        self.events
+            .push_back(NetworkBehaviourAction::GenerateEvent(ev));
+        sent
+    }
+
+    /// Hands a message to the handlers chosen by the connection policy.
+    fn notify(&mut self, peer: PeerId, msg: Message) {
+        self.notify_tracked(peer, Arc::new(msg), None, Priority::Normal)
+    }
+
+    fn notify_tracked(
+        &mut self,
+        peer: PeerId,
+        msg: Arc<Message>,
+        id: Option<BroadcastId>,
+        priority: Priority,
+    ) {
+        if self.blocked.contains_key(&peer) {
+            if let Some(id) = id {
+                let events = self.deliveries.fail(id, peer, DeliveryError::Blocked);
+                self.generate(events);
+            }
+            return;
+        }
+        let msg = self.stamp(msg);
+        let topic = match &*msg {
+            Message::Broadcast(topic, _, _) => Some(topic),
+            _ => None,
+        };
+        let handlers = self.connections.handlers(&peer, topic);
+        if let Some(id) = id {
+            self.deliveries.sent(id, peer, handlers.len());
+        }
+        if let Message::Broadcast(_, _, payload) = &*msg {
+            if !handlers.is_empty() {
+                self.stats.sent(&peer, payload.len());
+            }
+        }
+        for handler in handlers {
+            let event = match (&*msg, id, priority) {
+                (Message::Broadcast(..), id, priority) => {
+                    HandlerIn::SendShared(priority, id, msg.clone())
+                }
+                (_, id, Priority::High | Priority::Low) => {
+                    HandlerIn::SendWithPriority(priority, id, Message::clone(&msg))
+                }
+                (_, Some(id), Priority::Normal) => HandlerIn::SendTracked(id, Message::clone(&msg)),
+                (_, None, Priority::Normal) => HandlerIn::Send(Message::clone(&msg)),
+            };
+            let action = NetworkBehaviourAction::NotifyHandler {
+                peer_id: peer,
+                event,
+                handler,
+            };
+            self.events.push_back(action);
+        }
+        if matches!(*msg, Message::Broadcast(..)) {
+            self.enforce_budget();
+        }
+    }
+
+    /// Reports the backlogged peers nothing is pending for anymore, across
+    /// all their connections and our own queue.
+    fn report_drained(&mut self) {
+        let drained: Vec<_> = self
+            .backlogged
+            .iter()
+            .copied()
+            .filter(|peer| self.pending_to_peer(peer) == 0)
+            .collect();
+        for peer in drained {
+            self.backlogged.remove(&peer);
+            self.generate(vec![BroadcastEvent::QueueDrained(peer)]);
+        }
+    }
+
+    /// Drops the messages queued for the handlers of a peer that isn't
+    /// connected anymore, failing the tracked broadcasts among them.
+    fn drop_queued(&mut self, peer: &PeerId) {
+        while let Some(i) = self.events.iter().position(|action| {
+            matches!(action, NetworkBehaviourAction::NotifyHandler { peer_id, .. } if peer_id == peer)
+        }) {
+            self.remove_queued(i, DeliveryError::ConnectionClosed);
+        }
+    }
+
+    fn generate(&mut self, events: Vec<BroadcastEvent>) {
+        self.events.extend(
+            events
+                .into_iter()
+                .map(NetworkBehaviourAction::GenerateEvent),
+        );
+    }
+
+    /// Drops buffered messages until they fit into
+    /// `BroadcastConfig::max_buffered_bytes`.
+    fn enforce_budget(&mut self) {
+        let max = match self.config.max_buffered_bytes {
+            Some(max) => max,
+            None => return,
+        };
+        let policy = self.config.eviction_policy;
+        let mut retained: usize = self.retained.values().map(|msg| msg.len()).sum();
+        while self.events.bytes() + self.offline.bytes() + retained > max {
+            let evicted = if let Some((peer, topic, len)) = self.offline.evict(policy) {
+                (Some(peer), topic, len)
+            } else if let Some((peer, topic, len)) = self.evict_queued(policy) {
+                (Some(peer), topic, len)
+            } else {
+                let messages: Vec<_> = self
+                    .retained
+                    .iter()
+                    .map(|(topic, msg)| (*topic, msg.len()))
+                    .collect();
+                let candidates: Vec<_> = messages.iter().map(|(_, len)| (None, *len)).collect();
+                let (topic, len) = match policy.select(&candidates) {
+                    Some(i) => messages[i],
+                    None => break,
+                };
+                self.retained.remove(&topic);
+                retained -= len;
+                (None, topic, len)
+            };
+            let (peer, topic, len) = evicted;
+            self.events.push_back(NetworkBehaviourAction::GenerateEvent(
+                BroadcastEvent::Evicted(peer, topic, len),
+            ));
+        }
+    }
+
+    /// Drops a broadcast queued for a connected peer.
+    fn evict_queued(&mut self, policy: EvictionPolicy) -> Option<(PeerId, Topic, usize)> {
+        let queued: Vec<_> = self
+            .events
+            .iter()
+            .enumerate()
+            .filter_map(|(i, action)| Some((i, queued_len(action)?)))
+            .collect();
+        let candidates: Vec<_> = queued
+            .iter()
+            .map(|(_, (peer, len))| (Some(*peer), *len))
+            .collect();
+        let (i, _) = queued[policy.select(&candidates)?];
+        self.remove_queued(i, DeliveryError::Evicted)
+    }
+
+    /// Removes the queued action at `i`, failing it if it's a tracked
+    /// broadcast. Returns the peer, topic and size of broadcasts.
+    fn remove_queued(&mut self, i: usize, error: DeliveryError) -> Option<(PeerId, Topic, usize)> {
+        let (peer, event) = match self.events.remove(i)? {
+            NetworkBehaviourAction::NotifyHandler { peer_id, event, .. } => (peer_id, event),
+            _ => return None,
+        };
+        let (id, topic, len) = match event {
+            HandlerIn::Send(Message::Broadcast(topic, _, msg)) => (None, topic, msg.len()),
+            HandlerIn::SendTracked(id, Message::Broadcast(topic, _, msg)) => {
+                (Some(id), topic, msg.len())
+            }
+            HandlerIn::SendWithPriority(_, id, Message::Broadcast(topic, _, msg)) => {
+                (id, topic, msg.len())
+            }
+            HandlerIn::SendShared(_, id, msg) => match &*msg {
+                Message::Broadcast(topic, _, msg) => (id, *topic, msg.len()),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        if let Some(id) = id {
+            let events = self.deliveries.report(id, peer, Err(error));
+            self.generate(events);
+        }
+        Some((peer, topic, len))
+    }
+
+    /// Lowers the score of a misbehaving peer, disconnecting it once it
+    /// drops below the disconnect threshold.
+    fn penalize(&mut self, peer: PeerId, penalty: impl Fn(&PeerScoreParams) -> f64) {
+        let params = match self.scores.params() {
+            Some(params) => params,
+            None => return,
+        };
+        let (penalty, threshold) = (penalty(params), params.disconnect_threshold);
+        if self.scores.penalize(&peer, penalty) < threshold && self.closing.insert(peer) {
+            self.events
+                .push_back(NetworkBehaviourAction::CloseConnection {
+                    peer_id: peer,
+                    connection: CloseConnection::All,
+                });
+        }
+    }
+
+    /// Handles a broadcast received from `peer`, returning the event to emit.
+    fn inject_broadcast(
+        &mut self,
+        peer: PeerId,
+        topic: Topic,
+        mut ext: Extensions,
+        msg: Bytes,
+    ) -> Option<BroadcastEvent> {
+        event!(trace, "broadcast", topic = ?topic, msg_len = msg.len(), "received");
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &self.metrics {
+            metrics.received(&topic, msg.len());
+        }
+        if ext.is_expired() {
+            #[cfg(feature = "metrics")]
+            if let Some(metrics) = &self.metrics {
+                metrics.expired(&topic);
+            }
+            return Some(BroadcastEvent::Expired(peer, topic));
+        }
+        let limit = self.topic_limits.get(&topic).copied();
+        if msg.len() > limit.unwrap_or(usize::MAX) {
+            return Some(BroadcastEvent::InvalidMessage(
+                peer,
+                topic,
+                RejectReason::TooLarge,
+            ));
+        }
+        if !self.is_subscribed(&topic) {
+            match self.config.unsolicited_policy {
+                UnsolicitedPolicy::Deliver => {}
+                UnsolicitedPolicy::Drop => return None,
+                UnsolicitedPolicy::Report => {
+                    self.penalize(peer, |params| params.unsubscribed_topic_penalty);
+                    return Some(BroadcastEvent::UnsolicitedMessage(peer, topic));
+                }
+            }
+        }
+        let source = match self.verify(&peer, &topic, &ext, &msg) {
+            Ok(source) => source,
+            Err(reason) => return Some(BroadcastEvent::InvalidMessage(peer, topic, reason)),
+        };
+        if !self.check_stamps(&peer, &topic, &ext, &msg) {
+            return Some(BroadcastEvent::InvalidMessage(
+                peer,
+                topic,
+                RejectReason::InvalidStamp,
+            ));
+        }
+        if let Some(policy) = &self.config.access_policy {
+            if !policy.allow_publish(&source, &topic) {
+                return Some(BroadcastEvent::InvalidMessage(
+                    peer,
+                    topic,
+                    RejectReason::Unauthorized,
+                ));
+            }
+        }
+        // Relayed and deduplicated in encrypted form.
+        let payload = match self.open(&topic, &msg) {
+            Some(payload) => payload,
+            None => {
+                return Some(BroadcastEvent::InvalidMessage(
+                    peer,
+                    topic,
+                    RejectReason::Decryption,
+                ))
+            }
+        };
+        if let Some(validator) = self.validators.get(&topic) {
+            match validator(&source, &payload) {
+                ValidationResult::Accept => {}
+                ValidationResult::Reject => {
+                    return Some(BroadcastEvent::InvalidMessage(
+                        peer,
+                        topic,
+                        RejectReason::Validation,
+                    ))
+                }
+                ValidationResult::Ignore => return None,
+            }
+        }
+        let id = self.config.message_id.id(&topic, &ext, &msg);
+        if ext.ack {
+            self.notify(peer, Message::Ack(topic, id));
+        }
+        // Retransmissions of acked broadcasts are dropped even without a
+        // seen cache, see `DeliveryMode::AtLeastOnce`.
+        if let (true, Some(seqno)) = (ext.ack, ext.seqno) {
+            if !self
+                .acked
+                .insert(MessageId::from_seqno(&topic, &source, seqno))
+            {
+                return None;
+            }
+        }
+        if !self.seen.insert(id) {
+            return None;
+        }
+        if !self.replay.accept(source, &ext, id) {
+            event!(debug, "broadcast", peer_id = %peer, topic = ?topic, "rejected replay");
+            return Some(BroadcastEvent::ReplayRejected(peer, id));
+        }
+        if let Some(max_len) = self.config.trace_paths {
+            if ext.path().len() < max_len {
+                let path = ext.path.get_or_insert_with(Default::default);
+                Arc::make_mut(path).push(peer);
+            }
+            let path = ext.path().to_vec();
+            self.generate(vec![BroadcastEvent::Traced(id, path)]);
+        }
+        if self.requests.contains(&topic) {
+            self.requests.reply(&topic, source, payload);
+            return None;
+        }
+        self.history.push(&topic, id, ext.clone(), msg.clone());
+        let seqno = ext.seqno;
+        if let (Some(seqno), None) = (seqno, &self.reorder) {
+            self.check_seqno(source, topic, seqno);
+        }
+        if !self.key_filters.wants(&topic, ext.key.as_deref()) {
+            self.forward(&peer, &topic, id, ext, msg);
+            return None;
+        }
+        if self.sequencers.is_ordered(&topic) {
+            match ext.order {
+                Some(order) => {
+                    let ev = BroadcastEvent::Received(source, topic, id, payload);
+                    let events = self.sequencers.insert(peer, topic, order, ev);
+                    self.deliver(events);
+                    return None;
+                }
+                None if self.sequencers.is_local(&topic) => {
+                    ext.order = Some(self.sequencers.stamp(&topic));
+                    ext.hops = 0;
+                    ext.ack = false;
+                    let reply_to = ext.reply_to;
+                    self.relay(&peer, &topic, id, ext, msg);
+                    return Some(received(source, topic, id, reply_to, payload));
+                }
+                None => {}
+            }
+        }
+        let reply_to = ext.reply_to;
+        self.forward(&peer, &topic, id, ext, msg);
+        let ev = received(source, topic, id, reply_to, payload);
+        if let (Some(seqno), Some(reorder)) = (seqno, &mut self.reorder) {
+            let events = reorder.insert(source, topic, seqno, ev);
+            self.deliver(events);
+            return None;
+        }
+        Some(ev)
+    }
+
+    /// Emits the events released by the reorder buffer.
+    fn deliver(&mut self, events: Vec<BroadcastEvent>) {
+        for ev in events {
+            if let BroadcastEvent::Received(source, topic, _, msg) = &ev {
+                self.dispatch(*source, topic, msg);
+            }
+            self.events
+                .push_back(NetworkBehaviourAction::GenerateEvent(ev));
+        }
+    }
+
+    /// Reports skipped sequence numbers of a publisher. Messages arriving
+    /// late don't fill gaps that were already reported.
+    fn check_seqno(&mut self, source: PeerId, topic: Topic, seqno: u64) {
+        let expected = match self.last_seqno.get(&(source, topic)) {
+            Some(last) if seqno <= *last => return,
+            Some(last) => last + 1,
+            None => seqno,
+        };
+        self.last_seqno.insert((source, topic), seqno);
+        if seqno > expected {
+            self.events.push_back(NetworkBehaviourAction::GenerateEvent(
+                BroadcastEvent::GapDetected(source, topic, expected..seqno),
+            ));
+        }
+    }
+
+    /// Checks a subscription of the peer against the topic filter, telling
+    /// the peer about rejected ones if configured.
+    fn filter_subscription(&mut self, peer: PeerId, topic: Topic) -> Option<BroadcastEvent> {
+        let allowed = match (&self.config.topic_filter, &self.config.access_policy) {
+            (Some(filter), _) if !filter.allows(&peer, &topic) => false,
+            (_, Some(policy)) => policy.allow_subscribe(&peer, &topic),
+            _ => true,
+        };
+        if allowed {
+            return None;
+        }
+        if self.config.reject_filtered_subscriptions {
+            self.notify(peer, Message::SubscribeRejected(topic));
+        }
+        Some(BroadcastEvent::SubscriptionFiltered(peer, topic))
+    }
+
+    /// Records the addresses a peer attached to a subscription, reporting
+    /// them if they changed.
+    fn inject_address_hints(&mut self, peer: PeerId, mut addresses: Vec<Multiaddr>) {
+        addresses.truncate(MAX_ADDRESS_HINTS);
+        if self.address_hints.get(&peer) == Some(&addresses) {
+            return;
+        }
+        self.address_hints.insert(peer, addresses.clone());
+        self.generate(vec![BroadcastEvent::PeerAddresses(peer, addresses)]);
+    }
+
+    fn inject_subscribe(&mut self, peer: PeerId, topic: Topic) -> BroadcastEvent {
+        // The peer wasn't told about our subscription if it subscribed after
+        // the summaries were exchanged.
+        if self.config.lazy_subscriptions && self.subscriptions.contains(&topic) {
+            self.announce(peer, vec![topic]);
+        }
+        if let Some(ev) = self.filter_subscription(peer, topic) {
+            return ev;
+        }
+        event!(debug, "subscription", peer_id = %peer, topic = ?topic, "peer subscribed");
+        let peers = self.topics.entry(topic).or_default();
+        self.peers.get_mut(&peer).unwrap().insert(topic);
+        peers.insert(peer);
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &self.metrics {
+            metrics.subscribed();
+            metrics.topic_peers(&topic, peers.len());
+        }
+        self.send_retained(peer, |retained| *retained == topic);
+        self.send_unrouted(peer, |unrouted| *unrouted == topic);
+        self.exchange_peers(peer, topic);
+        self.send_ihave(peer, |history| *history == topic);
+        self.send_reliable(peer, topic);
+        BroadcastEvent::Subscribed(peer, topic)
+    }
+
+    fn remove_subscription(&mut self, peer: PeerId, topic: Topic) {
+        event!(debug, "subscription", peer_id = %peer, topic = ?topic, "peer unsubscribed");
+        if let Some(topics) = self.peers.get_mut(&peer) {
+            topics.remove(&topic);
+        }
+        if let Some(peers) = self.topics.get_mut(&topic) {
+            peers.remove(&peer);
+            #[cfg(feature = "metrics")]
+            if let Some(metrics) = &self.metrics {
+                metrics.topic_peers(&topic, peers.len());
+            }
+        }
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &self.metrics {
+            metrics.unsubscribed();
+        }
+        self.discover_peers(&topic);
+    }
+
+    /// Handles an event of the peer's handler, unpacking batches.
+    fn inject_handler_event(&mut self, peer: PeerId, msg: HandlerEvent) {
+        use HandlerEvent::*;
+        use Message::*;
+        span!(TRACE, "handler", "handler_event", peer_id = %peer);
+        if let Rx(rx) = &msg {
+            if self.blocked.contains_key(&peer) || self.scores.is_graylisted(&peer) {
+                return;
+            }
+            let len = match rx {
+                Broadcast(_, _, payload) => payload.len(),
+                _ => 0,
+            };
+            match rx {
+                Broadcast(..) => self.stats.received(&peer, Some(len)),
+                _ => self.stats.received(&peer, None),
+            }
+            if !matches!(rx, Batch(_)) && !self.scores.inbound(&peer, len) {
+                self.penalize(peer, |params| params.rate_limit_penalty);
+            }
+        }
+        let ev = match msg {
+            Rx(Subscribe(topic)) => {
+                self.leases.remove(&peer, &topic);
+                self.inject_subscribe(peer, topic)
+            }
+            Rx(SubscribeLease(topic, ttl)) => {
+                if self
+                    .peers
+                    .get(&peer)
+                    .map_or(false, |topics| topics.contains(&topic))
+                {
+                    self.leases.renew(peer, topic, ttl);
+                    return;
+                }
+                let ev = self.inject_subscribe(peer, topic);
+                if let BroadcastEvent::Subscribed(..) = ev {
+                    self.leases.renew(peer, topic, ttl);
+                }
+                ev
+            }
+            Rx(SubscribeWithAddresses(topic, addresses)) => {
+                self.inject_address_hints(peer, addresses);
+                self.leases.remove(&peer, &topic);
+                self.inject_subscribe(peer, topic)
+            }
+            Rx(SubscribeWithSnapshot(topic)) => {
+                self.leases.remove(&peer, &topic);
+                let ev = self.inject_subscribe(peer, topic);
+                if let BroadcastEvent::Subscribed(..) = ev {
+                    self.send_snapshot(peer, topic);
+                }
+                ev
+            }
+            Rx(Snapshot(topic, state)) => {
+                if !self.subscriptions.contains(&topic) {
+                    return;
+                }
+                match self.open(&topic, &state) {
+                    Some(state) => BroadcastEvent::Snapshot(peer, topic, state),
+                    None => BroadcastEvent::InvalidMessage(peer, topic, RejectReason::Decryption),
+                }
+            }
+            Rx(SubscribeMany(topics)) => {
+                self.inject_batch(peer, topics.into_iter().map(Subscribe).collect());
+                return;
+            }
+            Rx(Broadcast(topic, ext, msg)) => match self.inject_broadcast(peer, topic, ext, msg) {
+                Some(ev) => ev,
+                None => return,
+            },
+            Rx(KeyFilter(topic, keys)) => {
+                let filter = keys::KeyFilter::new(keys);
+                self.key_filters.set_remote(peer, topic, filter);
+                return;
+            }
+            Rx(Unsubscribe(topic)) => {
+                self.key_filters.remove_topic(&peer, &topic);
+                self.leases.remove(&peer, &topic);
+                self.remove_subscription(peer, topic);
+                BroadcastEvent::Unsubscribed(peer, topic)
+            }
+            Rx(SubscribePrefix(prefix)) => match self.filter_subscription(peer, prefix) {
+                Some(ev) => ev,
+                None => {
+                    self.prefixes.entry(prefix).or_default().insert(peer);
+                    self.send_retained(peer, |retained| retained.has_prefix(&prefix));
+                    self.send_unrouted(peer, |unrouted| unrouted.has_prefix(&prefix));
+                    self.send_ihave(peer, |history| history.has_prefix(&prefix));
+                    BroadcastEvent::SubscribedPrefix(peer, prefix)
+                }
+            },
+            Rx(UnsubscribePrefix(prefix)) => {
+                if let Some(peers) = self.prefixes.get_mut(&prefix) {
+                    peers.remove(&peer);
+                }
+                BroadcastEvent::UnsubscribedPrefix(peer, prefix)
+            }
+            Rx(Batch(msgs)) => {
+                self.inject_batch(peer, msgs);
+                return;
+            }
+            Rx(IHave(topic, ids)) => {
+                self.inject_ihave(peer, topic, ids);
+                return;
+            }
+            Rx(IWant(topic, ids)) => {
+                self.inject_iwant(peer, topic, ids);
+                return;
+            }
+            Rx(Replay(topic, since)) => {
+                self.inject_replay(peer, topic, since);
+                return;
+            }
+            Rx(SubscribeRejected(topic)) => BroadcastEvent::SubscribeRejected(peer, topic),
+            Rx(TopicSummary(summary)) => {
+                let topics = self
+                    .subscriptions
+                    .iter()
+                    .filter(|topic| summary.contains(topic))
+                    .copied()
+                    .collect();
+                self.announce(peer, topics);
+                return;
+            }
+            // Reassembled and taken by the handler.
+            Rx(Fragment { .. } | Credit(_)) => return,
+            Rx(Heartbeat(topic)) => {
+                self.heartbeats.received(peer, topic);
+                return;
+            }
+            Rx(SyncTopics(topics)) => {
+                let known = self.peers.get(&peer).cloned().unwrap_or_default();
+                self.inject_batch(peer, sync::diff(&known, &topics));
+                return;
+            }
+            Rx(PeerExchange(topic, peers, signature)) => {
+                let valid = match (&signature, self.config.validation_mode) {
+                    (_, ValidationMode::None) => true,
+                    (Some(signature), _) => signature.verify_peers(&topic, &peers),
+                    (None, ValidationMode::Strict) => false,
+                    (None, ValidationMode::Permissive) => true,
+                };
+                if !valid {
+                    self.penalize(peer, |params| params.invalid_message_penalty);
+                    return;
+                }
+                let peers: Vec<_> = peers
+                    .into_iter()
+                    .filter(|(suggested, _)| !self.peers.contains_key(suggested))
+                    .collect();
+                if peers.is_empty() {
+                    return;
+                }
+                if self.config.dial_suggested_peers && self.is_subscribed(&topic) {
+                    for (suggested, addresses) in &peers {
+                        self.discovery.suggest(*suggested, addresses);
+                    }
+                }
+                BroadcastEvent::PeersSuggested(topic, peers)
+            }
+            Rx(Ack(_, id)) if self.reliable.contains(&id) => match self.reliable.ack(peer, id) {
+                Some(delivered) => BroadcastEvent::ReliableBroadcastResult {
+                    id,
+                    delivered,
+                    quorum_met: true,
+                },
+                None => return,
+            },
+            Rx(Ack(topic, id)) => match self.acks.remove(peer, id) {
+                Some(rtt) => {
+                    self.latencies.record(peer, topic, rtt);
+                    BroadcastEvent::Acked(peer, id)
+                }
+                None => return,
+            },
+            Rejected(topic, reason) => BroadcastEvent::InvalidMessage(peer, topic, reason),
+            SendError(topic) => {
+                self.stats.failed(&peer);
+                BroadcastEvent::SendError(peer, topic)
+            }
+            Dropped(topic) => {
+                self.stats.failed(&peer);
+                #[cfg(feature = "metrics")]
+                if let Some(metrics) = &self.metrics {
+                    metrics.dropped(&topic);
+                }
+                BroadcastEvent::OutboundDropped(peer, topic)
+            }
+            Expired(topic) => {
+                self.stats.failed(&peer);
+                #[cfg(feature = "metrics")]
+                if let Some(metrics) = &self.metrics {
+                    metrics.expired(&topic);
+                }
+                BroadcastEvent::Expired(peer, topic)
+            }
+            HandlerEvent::Version(version) => {
+                if self.versions.insert(peer, version) == Some(version) {
+                    return;
+                }
+                // The peer can't read the summary.
+                if self.config.lazy_subscriptions && version != protocol::Version::V1_1 {
+                    let topics = self.subscriptions.iter().copied().collect();
+                    self.announce(peer, topics);
+                }
+                BroadcastEvent::PeerProtocolVersion(peer, version)
+            }
+            Malformed => {
+                self.penalize(peer, |params| params.invalid_message_penalty);
+                return;
+            }
+            Tx | Flushed => {
+                return;
+            }
+            BatchSent { len, coalesced } => {
+                self.stats.batch_sent(len, coalesced);
+                return;
+            }
+            Delivered(id) => {
+                let events = self.deliveries.report(id, peer, Ok(()));
+                self.generate(events);
+                return;
+            }
+            HandlerEvent::TimedOut => BroadcastEvent::SendTimeout(peer),
+            Backpressured => {
+                self.backlogged.insert(peer);
+                BroadcastEvent::PeerBackpressured(peer)
+            }
+            Backlogged => {
+                self.backlogged.insert(peer);
+                return;
+            }
+            SendFailed(id, error) => {
+                let events = self.deliveries.report(id, peer, Err(error));
+                self.generate(events);
+                return;
+            }
+        };
+        match &ev {
+            BroadcastEvent::InvalidMessage(..) => {
+                self.penalize(peer, |params| params.invalid_message_penalty);
+            }
+            BroadcastEvent::Received(source, topic, _, msg) => self.dispatch(*source, topic, msg),
+            _ => {}
+        }
+        self.events
+            .push_back(NetworkBehaviourAction::GenerateEvent(ev));
+    }
+
+    /// Handles the messages of a batch, summarizing the changes to the
+    /// peer's subscriptions with a `BroadcastEvent::PeerTopicsChanged`.
+    fn inject_batch(&mut self, peer: PeerId, msgs: Vec<Message>) {
+        let before = self.peers.get(&peer).cloned().unwrap_or_default();
+        for msg in msgs {
+            self.inject_handler_event(peer, HandlerEvent::Rx(msg));
+        }
+        let after = match self.peers.get(&peer) {
+            Some(after) => after,
+            None => return,
+        };
+        let mut added: Vec<_> = after.difference(&before).copied().collect();
+        let mut removed: Vec<_> = before.difference(after).copied().collect();
+        if added.is_empty() && removed.is_empty() {
+            return;
+        }
+        added.sort();
+        removed.sort();
+        self.events.push_back(NetworkBehaviourAction::GenerateEvent(
+            BroadcastEvent::PeerTopicsChanged {
+                peer,
+                added,
+                removed,
+            },
+        ));
+    }
+
+    /// Executes a command of a `SubscriptionHandle` or `BroadcastClient`.
+    fn inject_command(&mut self, command: Command) {
+        match command {
+            Command::Subscribe(topic, reply) => {
+                reply.send(self.subscribe(topic)).ok();
+            }
+            Command::Unsubscribe(topic, reply) => {
+                reply.send(self.unsubscribe(&topic)).ok();
+            }
+            Command::Release(topic, token) => self.release_handle(&topic, token),
+            Command::Topic(name, reply) => {
+                reply.send(self.topic(&name)).ok();
+            }
+            Command::Publish(topic, msg, reply) => {
+                let result = self.broadcast(&topic, msg);
+                if let Some(reply) = reply {
+                    reply.send(result).ok();
+                }
+            }
+            Command::Stream(topic, tx) => self.streams.entry(topic).or_default().push(tx),
+            Command::Stats(reply) => {
+                reply.send(Ok(self.stats())).ok();
+            }
+            Command::State(reply) => {
+                reply.send(Ok(self.snapshot())).ok();
+            }
+        }
+    }
+
+    fn inject_connected(&mut self, peer: &PeerId) {
+        self.peers.insert(*peer, FnvHashSet::default());
+        self.events.push_back(NetworkBehaviourAction::GenerateEvent(
+            BroadcastEvent::PeerConnected(*peer),
+        ));
+        self.stats.connected(*peer);
+        self.former.remove(peer);
+        self.explicit.inject_connected(peer);
+        self.discovery.remove(peer);
+        self.known_peers.remove(peer);
+        if self.config.lazy_subscriptions {
+            let topics: FnvHashSet<_> = self.subscriptions.union(&self.observed).collect();
+            let summary = TopicSummary::new(topics.into_iter());
+            self.notify(*peer, Message::TopicSummary(summary));
+        } else {
+            let topics = self.subscriptions.iter().copied().collect();
+            self.announce(*peer, topics);
+        }
+        let prefixes: Vec<_> = self.prefix_subscriptions.iter().copied().collect();
+        for prefix in prefixes {
+            self.notify(*peer, Message::SubscribePrefix(prefix));
+        }
+        let mut replayed = FnvHashMap::<Topic, usize>::default();
+        for msg in self.offline.reconnected(peer) {
+            if let Message::Broadcast(topic, _, _) = &msg {
+                *replayed.entry(*topic).or_default() += 1;
+            }
+            self.send_broadcast(*peer, msg);
+        }
+        for (topic, count) in replayed {
+            self.events.push_back(NetworkBehaviourAction::GenerateEvent(
+                BroadcastEvent::Replayed(*peer, topic, count),
+            ));
+        }
+    }
+
+    fn inject_disconnected(&mut self, peer: &PeerId) {
+        self.stats.disconnected(peer);
+        if let Some(topics) = self.peers.remove(peer) {
+            let mut subscribed: Vec<_> = topics.iter().copied().collect();
+            subscribed.sort();
+            self.events.push_back(NetworkBehaviourAction::GenerateEvent(
+                BroadcastEvent::PeerDisconnected(*peer, subscribed),
+            ));
+            let prefixes = self.peer_prefixes(peer);
+            if self.store.is_some() && !(topics.is_empty() && prefixes.is_empty()) {
+                self.known_peers
+                    .insert(*peer, (topics.clone(), prefixes.clone()));
+            }
+            self.offline.disconnected(*peer, topics.clone(), prefixes);
+            if self.config.dial_on_broadcast.is_some() {
+                let addresses = self.addresses.get(peer).cloned().unwrap_or_default();
+                self.former.disconnected(*peer, topics.clone(), addresses);
+            }
+            for topic in topics {
+                if let Some(peers) = self.topics.get_mut(&topic) {
+                    peers.remove(peer);
+                    #[cfg(feature = "metrics")]
+                    if let Some(metrics) = &self.metrics {
+                        metrics.topic_peers(&topic, peers.len());
+                    }
+                }
+                self.discover_peers(&topic);
+            }
+        }
+        for peers in self.prefixes.values_mut() {
+            peers.remove(peer);
+        }
+        self.versions.remove(peer);
+        self.announced.remove(peer);
+        self.addresses.remove(peer);
+        self.inbound_streams.remove(peer);
+        self.pending_sends.remove(peer);
+        self.backlogged.remove(peer);
+        self.leases.remove_peer(peer);
+        self.heartbeats.remove_peer(peer);
+        self.latencies.remove_peer(peer);
+        self.key_filters.remove_peer(peer);
+        self.drop_queued(peer);
+        self.rate_limiter.remove_peer(peer);
+        let events = self.deliveries.disconnected(peer);
+        self.generate(events);
+        self.scores.remove_peer(peer);
+        self.closing.remove(peer);
+        self.explicit.inject_disconnected(peer);
+        self.persist();
+    }
+}
+
+/// Checks a signature including the timestamp of stamped messages.
+fn verify_signature(signature: &Signature, topic: &Topic, ext: &Extensions, msg: &[u8]) -> bool {
+    match ext.timestamp {
+        Some(timestamp) => signature.verify_stamped(topic, timestamp, msg),
+        None => signature.verify(topic, msg),
+    }
+}
+
+/// The event of a received broadcast, a request if it has a reply topic.
+fn received(
+    source: PeerId,
+    topic: Topic,
+    id: MessageId,
+    reply_to: Option<u64>,
+    msg: Bytes,
+) -> BroadcastEvent {
+    match reply_to {
+        Some(reply_id) => BroadcastEvent::Request {
+            source,
+            topic,
+            id,
+            reply_to: ReplyTo(reply_id),
+            msg,
+        },
+        None => BroadcastEvent::Received(source, topic, id, msg),
+    }
+}
+
+impl NetworkBehaviour for Broadcast {
+    type ConnectionHandler = BroadcastHandler;
+    type OutEvent = BroadcastEvent;
+
+    fn new_handler(&mut self) -> Self::ConnectionHandler {
+        BroadcastHandler::new(self.config.clone())
+    }
+
+    fn addresses_of_peer(&mut self, peer: &PeerId) -> Vec<Multiaddr> {
+        let mut addresses = self.explicit.addresses(peer);
+        addresses.extend(self.discovery.addresses(peer));
+        addresses.extend(self.address_hints.get(peer).into_iter().flatten().cloned());
+        addresses
+    }
+
+    fn inject_connection_established(
+        &mut self,
+        peer: &PeerId,
+        connection_id: &ConnectionId,
+        endpoint: &libp2p::core::ConnectedPoint,
+        _failed_addresses: Option<&Vec<Multiaddr>>,
+        other_established: usize,
+    ) {
+        if self.blocked.get(peer) == Some(&true) {
+            self.events
+                .push_back(NetworkBehaviourAction::CloseConnection {
+                    peer_id: *peer,
+                    connection: CloseConnection::One(*connection_id),
+                });
+        }
+        self.connections
+            .established(*peer, *connection_id, endpoint.get_remote_address().clone());
+        let pending = self.pending_sends.entry(*peer).or_default().clone();
+        self.events
+            .push_back(NetworkBehaviourAction::NotifyHandler {
+                peer_id: *peer,
+                handler: NotifyHandler::One(*connection_id),
+                event: HandlerIn::PendingSends(pending),
+            });
+        if self.config.max_inbound_streams_per_peer.is_some() {
+            let count = self.inbound_streams.entry(*peer).or_default().clone();
+            self.events
+                .push_back(NetworkBehaviourAction::NotifyHandler {
+                    peer_id: *peer,
+                    handler: NotifyHandler::One(*connection_id),
+                    event: HandlerIn::InboundStreams(count),
+                });
+        }
+        if let libp2p::core::ConnectedPoint::Dialer { address, .. } = endpoint {
+            let addresses = self.addresses.entry(*peer).or_default();
+            if !addresses.contains(address) {
+                addresses.push(address.clone());
+            }
+        }
+        if other_established == 0 {
+            self.inject_connected(peer)
+        }
+    }
+
+    fn inject_connection_closed(
+        &mut self,
+        peer: &PeerId,
+        connection_id: &ConnectionId,
+        _: &libp2p::core::ConnectedPoint,
+        _: <Self::ConnectionHandler as libp2p::swarm::IntoConnectionHandler>::Handler,
+        remaining_established: usize,
+    ) {
+        self.connections.closed(peer, connection_id);
+        if self.flushing.remove(&(*peer, *connection_id)) {
+            self.check_flushed();
+        }
+        if remaining_established == 0 {
+            self.inject_disconnected(peer)
+        }
+    }
+
+    fn inject_address_change(
+        &mut self,
+        peer: &PeerId,
+        connection_id: &ConnectionId,
+        old: &libp2p::core::ConnectedPoint,
+        new: &libp2p::core::ConnectedPoint,
+    ) {
+        let address = new.get_remote_address();
+        self.connections
+            .address_changed(peer, connection_id, address.clone());
+        if let libp2p::core::ConnectedPoint::Dialer { address: old, .. } = old {
+            if let Some(addresses) = self.addresses.get_mut(peer) {
+                addresses.retain(|known| known != old);
+                if new.is_dialer() && !addresses.contains(address) {
+                    addresses.push(address.clone());
+                }
+            }
+        }
+        // The connection and the state of both sides survive the change,
+        // re-announcing our subscriptions lets the peer correct a view that
+        // diverged while it migrated.
+        self.resync(peer);
+    }
+
+    fn inject_dial_failure(
+        &mut self,
+        peer: Option<PeerId>,
+        _: Self::ConnectionHandler,
+        error: &DialError,
+    ) {
+        let peer = match peer {
+            Some(peer) => peer,
+            None => return,
+        };
+        self.explicit.inject_dial_failure(&peer);
+        self.discovery.remove(&peer);
+        if self.peers.contains_key(&peer) {
+            return;
+        }
+        event!(debug, "broadcast", peer_id = %peer, "dial failed");
+        self.drop_queued(&peer);
+        if let DialError::Banned = error {
+            self.offline.remove(&peer);
+            self.former.remove(&peer);
+            self.address_hints.remove(&peer);
+        }
+    }
+
+    fn inject_listen_failure(
+        &mut self,
+        _local_addr: &Multiaddr,
+        send_back_addr: &Multiaddr,
+        _: Self::ConnectionHandler,
+    ) {
+        // No state is kept before a connection is established.
+        event!(debug, "broadcast", address = %send_back_addr, "incoming connection failed");
+    }
+
+    fn inject_event(&mut self, peer: PeerId, connection: ConnectionId, msg: HandlerEvent) {
+        if let HandlerEvent::Flushed = msg {
+            self.flushing.remove(&(peer, connection));
+            self.check_flushed();
+            return;
+        }
+        self.inject_handler_event(peer, msg)
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context,
+        params: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<BroadcastEvent, BroadcastHandler>> {
+        self.events.register(cx.waker());
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &self.metrics {
+            metrics.pending_events(self.events.len());
+        }
+        if let Some(max) = self.config.address_hints {
+            self.external_addresses = params
+                .external_addresses()
+                .map(|record| record.addr)
+                .take(max)
+                .collect();
+        }
+        while let Poll::Ready(Some(command)) = self.commands.rx.poll_next_unpin(cx) {
+            self.inject_command(command);
+        }
+        while let Poll::Ready((peer, msg, id, priority)) = self.rate_limiter.poll_ready(cx) {
+            self.notify_tracked(peer, msg, id, priority);
+        }
+        if let Poll::Ready(()) = self.leases.poll_renew(cx) {
+            self.renew_subscriptions();
+        }
+        if let Poll::Ready(()) = self.heartbeats.poll_tick(cx) {
+            self.send_heartbeats();
+        }
+        if let Poll::Ready(()) = self.gc.poll_tick(cx) {
+            self.collect_topics();
+        }
+        while let Poll::Ready(topic) = self.requests.poll_expired(cx) {
+            self.unsubscribe(&topic).ok();
+        }
+        if let Poll::Ready(()) = self.sync.poll_tick(cx) {
+            let peers: Vec<_> = self.peers.keys().copied().collect();
+            for peer in peers {
+                self.resync(&peer);
+            }
+        }
+        let local = *params.local_peer_id();
+        self.local_peer_id = Some(local);
+        for (topic, id, msg) in std::mem::take(&mut self.own_messages) {
+            self.dispatch(local, &topic, &msg);
+            self.events.push_back(NetworkBehaviourAction::GenerateEvent(
+                BroadcastEvent::Received(local, topic, id, msg),
+            ));
+        }
+        if let Some(Poll::Ready(events)) = self
+            .reorder
+            .as_mut()
+            .map(|reorder| reorder.poll_expired(cx))
+        {
+            self.deliver(events);
+        }
+        self.elect_sequencers(local);
+        if let Poll::Ready(events) = self.sequencers.poll_expired(cx) {
+            self.deliver(events);
+        }
+        if let Poll::Ready(due) = self.reliable.poll_due(cx) {
+            self.retransmit(due);
+        }
+        self.report_drained();
+        if let Some(event) = self.events.pop_front() {
+            return Poll::Ready(event);
+        }
+        if let Poll::Ready((peer, id)) = self.acks.poll_expired(cx) {
+            return Poll::Ready(NetworkBehaviourAction::GenerateEvent(
+                BroadcastEvent::AckTimeout(peer, id),
+            ));
+        }
+        if let Poll::Ready((peer, topic)) = self.leases.poll_expired(cx) {
+            self.remove_subscription(peer, topic);
+            return Poll::Ready(NetworkBehaviourAction::GenerateEvent(
+                BroadcastEvent::SubscriptionExpired(peer, topic),
+            ));
+        }
+        if let Poll::Ready(peer) = self.explicit.poll_dial(cx) {
+            return Poll::Ready(NetworkBehaviourAction::Dial {
+                opts: DialOpts::peer_id(peer)
+                    .condition(PeerCondition::Disconnected)
+                    .build(),
+                handler: self.new_handler(),
+            });
+        }
+        while let Poll::Ready(peer) = self.discovery.poll(cx) {
+            if self.peers.contains_key(&peer) {
+                continue;
+            }
+            return Poll::Ready(NetworkBehaviourAction::Dial {
+                opts: DialOpts::peer_id(peer)
+                    .condition(PeerCondition::Disconnected)
+                    .build(),
+                handler: self.new_handler(),
+            });
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestNetwork;
+    use futures::FutureExt;
+    use libp2p::identity::Keypair;
+    use libp2p::swarm::AddressRecord;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    /// The events of `peer` since the last call, without the local and
+    /// connection events tested in `test_local_events` and
+    /// `test_peer_connection_events`.
+    ///
+    /// End-to-end semantics over real connections are tested with swarms in
+    /// `tests/swarm.rs`.
+    fn events_of(net: &mut TestNetwork, peer: &PeerId) -> Vec<BroadcastEvent> {
+        net.events(peer)
+            .into_iter()
+            .filter(|event| {
+                !matches!(
+                    event,
+                    BroadcastEvent::LocalSubscribed(_)
+                        | BroadcastEvent::LocalUnsubscribed(_)
+                        | BroadcastEvent::NotifiedPeers(..)
+                        | BroadcastEvent::PeerConnected(_)
+                        | BroadcastEvent::PeerDisconnected(..)
+                )
+            })
+            .collect()
+    }
+
+    /// Poll parameters of the behaviour of `peer_id`.
+    pub(crate) struct DummyPollParameters(pub(crate) PeerId);
+
+    impl PollParameters for DummyPollParameters {
+        type SupportedProtocolsIter = std::iter::Empty<Vec<u8>>;
+        type ListenedAddressesIter = std::iter::Empty<Multiaddr>;
+        type ExternalAddressesIter = std::iter::Empty<AddressRecord>;
+
+        fn supported_protocols(&self) -> Self::SupportedProtocolsIter {
+            unimplemented!()
+        }
+
+        fn listened_addresses(&self) -> Self::ListenedAddressesIter {
+            unimplemented!()
+        }
+
+        fn external_addresses(&self) -> Self::ExternalAddressesIter {
+            std::iter::empty()
+        }
+
+        fn local_peer_id(&self) -> &PeerId {
+            &self.0
+        }
+    }
+
+    #[test]
+    fn test_broadcast() {
+        let topic = Topic::new(b"topic");
+        let msg = Bytes::from_static(b"msg");
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(BroadcastConfig::default());
+        let b = net.add_node(BroadcastConfig::default());
+
+        net.node(&a).subscribe(topic).unwrap().detach();
+        net.connect(&a, &b);
+        net.run();
+        assert!(events_of(&mut net, &a).is_empty());
+        assert_eq!(
+            events_of(&mut net, &b),
+            vec![
+                BroadcastEvent::Subscribed(a, topic),
+                BroadcastEvent::PeerTopicsChanged {
+                    peer: a,
+                    added: vec![topic],
+                    removed: vec![],
+                },
+            ]
+        );
+        net.node(&b).subscribe(topic).unwrap().detach();
+        net.run();
+        assert!(events_of(&mut net, &b).is_empty());
+        assert_eq!(
+            events_of(&mut net, &a),
+            vec![BroadcastEvent::Subscribed(b, topic)]
+        );
+        net.node(&b).broadcast(&topic, msg.clone()).unwrap();
+        net.run();
+        assert!(events_of(&mut net, &b).is_empty());
+        assert_eq!(
+            events_of(&mut net, &a),
+            vec![BroadcastEvent::Received(
+                b,
+                topic,
+                MessageId::new(&topic, &msg),
+                msg
+            )]
+        );
+        net.node(&a).unsubscribe(&topic).unwrap();
+        net.run();
+        assert!(events_of(&mut net, &a).is_empty());
+        assert_eq!(
+            events_of(&mut net, &b),
+            vec![BroadcastEvent::Unsubscribed(a, topic)]
+        );
+    }
+
+    #[test]
+    fn test_stats() {
+        let topic = Topic::new(b"topic");
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(BroadcastConfig::default());
+        let b = net.add_node(BroadcastConfig::default());
+        net.node(&a).subscribe(topic).unwrap().detach();
+        net.connect(&a, &b);
+        net.run();
+
+        net.node(&b)
+            .broadcast(&topic, Bytes::from_static(b"msg"))
+            .unwrap();
+        net.run();
+        let sent = net.node(&b).peer_stats(&a).unwrap();
+        assert_eq!((sent.messages_sent, sent.bytes_sent), (1, 3));
+        let received = net.node(&a).peer_stats(&b).unwrap();
+        assert_eq!(
+            (received.messages_received, received.bytes_received),
+            (1, 3)
+        );
+        assert!(received.last_seen.is_some());
+        assert_eq!(net.node(&a).stats().peers, 1);
+        assert_eq!(net.node(&a).peer_stats(&a), None);
+    }
+
+    #[test]
+    fn test_resync() {
+        let (topic, other) = (Topic::new(b"topic"), Topic::new(b"other"));
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(BroadcastConfig::default());
+        let b = net.add_node(BroadcastConfig::default());
+        net.node(&a).subscribe(topic).unwrap().detach();
+        net.connect(&a, &b);
+        net.run();
+        events_of(&mut net, &a);
+        events_of(&mut net, &b);
+
+        // b lost the subscription to `topic` and saw one to `other`.
+        let rx = [Message::Unsubscribe(topic), Message::Subscribe(other)];
+        for msg in rx.iter().cloned() {
+            net.node(&b).inject_handler_event(a, HandlerEvent::Rx(msg));
+        }
+        net.run();
+        events_of(&mut net, &b);
+
+        assert!(net.node(&a).resync(&b));
+        assert!(!net.node(&a).resync(&PeerId::random()));
+        net.run();
+        assert!(events_of(&mut net, &a).is_empty());
+        assert_eq!(
+            events_of(&mut net, &b).last(),
+            Some(&BroadcastEvent::PeerTopicsChanged {
+                peer: a,
+                added: vec![topic],
+                removed: vec![other],
+            })
+        );
+        assert!(net.node(&b).peers[&a].contains(&topic));
+    }
+
+    #[test]
+    fn test_request() {
+        let topic = Topic::new(b"service");
+        let config = BroadcastConfig::default().request_timeout(Duration::from_millis(50));
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(config);
+        let b = net.add_node(BroadcastConfig::default());
+        net.node(&b).subscribe(topic).unwrap().detach();
+        net.connect(&a, &b);
+        net.run();
+        events_of(&mut net, &a);
+        events_of(&mut net, &b);
+
+        let replies = net
+            .node(&a)
+            .request(&topic, Bytes::from_static(b"ping"))
+            .unwrap();
+        net.run();
+        assert!(events_of(&mut net, &a).is_empty());
+        let mut events = events_of(&mut net, &b).into_iter();
+        let reply_topic = match events.next().unwrap() {
+            BroadcastEvent::Subscribed(_, reply_topic) => reply_topic,
+            ev => panic!("unexpected event {:?}", ev),
+        };
+        let reply_to = match events.next().unwrap() {
+            BroadcastEvent::Request {
+                source,
+                reply_to,
+                msg,
+                ..
+            } if source == a && msg == Bytes::from_static(b"ping") => reply_to,
+            ev => panic!("unexpected event {:?}", ev),
+        };
+        assert_eq!(reply_to.topic(), reply_topic);
+        net.node(&b)
+            .reply(reply_to, Bytes::from_static(b"pong"))
+            .unwrap();
+        net.run();
+        assert!(events_of(&mut net, &b).is_empty());
+        assert!(events_of(&mut net, &a).is_empty());
+
+        while net.node(&a).requests.contains(&reply_topic) {
+            std::thread::sleep(Duration::from_millis(10));
+            net.run();
+            assert!(events_of(&mut net, &a).is_empty());
+        }
+        assert_eq!(
+            futures::executor::block_on(replies),
+            vec![(b, Bytes::from_static(b"pong"))]
+        );
+        assert!(!net.node(&a).subscriptions.contains(&reply_topic));
+    }
+
+    #[test]
+    fn test_deduplicate() {
+        let topic = Topic::new(b"topic");
+        let msg = Bytes::from_static(b"msg");
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(BroadcastConfig::default().seen_cache_size(16));
+        let b = net.add_node(BroadcastConfig::default());
+        let c = net.add_node(BroadcastConfig::default());
+
+        net.node(&a).subscribe(topic).unwrap().detach();
+        net.connect(&a, &b);
+        net.connect(&a, &c);
+        net.run();
+        assert!(events_of(&mut net, &a).is_empty());
+        events_of(&mut net, &b);
+        events_of(&mut net, &c);
+
+        net.node(&b).broadcast(&topic, msg.clone()).unwrap();
+        net.node(&c).broadcast(&topic, msg.clone()).unwrap();
+        net.run();
+        assert!(events_of(&mut net, &b).is_empty());
+        assert!(events_of(&mut net, &c).is_empty());
+        assert_eq!(
+            events_of(&mut net, &a),
+            vec![BroadcastEvent::Received(
+                b,
+                topic,
+                MessageId::new(&topic, &msg),
+                msg
+            )]
+        );
+    }
+
+    #[test]
+    fn test_message_id_fn() {
+        let topic = Topic::new(b"topic");
+        let msg = Bytes::from_static(b"msg");
+        let config = BroadcastConfig::default().message_id_fn(MessageId::from_origin);
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(
+            config
+                .clone()
+                .sign_messages(Keypair::generate_ed25519())
+                .sequence_numbers(),
+        );
+        let b = net.add_node(config);
+
+        net.node(&b).subscribe(topic).unwrap().detach();
+        net.connect(&b, &a);
+        net.run();
+        events_of(&mut net, &a);
+        events_of(&mut net, &b);
+
+        // Repeated payloads aren't deduplicated.
+        net.node(&a).broadcast(&topic, msg.clone()).unwrap();
+        net.node(&a).broadcast(&topic, msg.clone()).unwrap();
+        net.run();
+        assert!(events_of(&mut net, &a).is_empty());
+        let ids: Vec<_> = events_of(&mut net, &b)
+            .into_iter()
+            .map(|ev| match ev {
+                BroadcastEvent::Received(_, _, id, payload) => {
+                    assert_eq!(payload, msg);
+                    id
+                }
+                ev => panic!("unexpected event {:?}", ev),
+            })
+            .collect();
+        assert_eq!(ids.len(), 2);
+        assert_ne!(ids[0], ids[1]);
+        assert_ne!(ids[0], MessageId::new(&topic, &msg));
+    }
+
+    #[test]
+    fn test_relay() {
+        let topic = Topic::new(b"topic");
+        let msg = Bytes::from_static(b"msg");
+        let config = BroadcastConfig::default()
+            .seen_cache_size(16)
+            .relay_mode(RelayMode::Flood { hops: 1 });
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(config.clone());
+        let b = net.add_node(config.clone());
+        let c = net.add_node(config);
+
+        for peer in [a, b, c] {
+            net.node(&peer).subscribe(topic).unwrap().detach();
+        }
+        net.connect(&a, &b);
+        net.connect(&b, &c);
+        net.run();
+        for peer in [a, b, c] {
+            events_of(&mut net, &peer);
+        }
+
+        net.node(&a).broadcast(&topic, msg.clone()).unwrap();
+        net.run();
+        let id = MessageId::new(&topic, &msg);
+        assert!(events_of(&mut net, &a).is_empty());
+        assert_eq!(
+            events_of(&mut net, &b),
+            vec![BroadcastEvent::Received(a, topic, id, msg.clone())]
+        );
+        assert_eq!(
+            events_of(&mut net, &c),
+            vec![BroadcastEvent::Received(b, topic, id, msg)]
+        );
+    }
+
+    #[test]
+    fn test_lazy_push() {
+        let topic = Topic::new(b"topic");
+        let msg = Bytes::from_static(b"large message");
+        let config = BroadcastConfig::default()
+            .seen_cache_size(16)
+            .relay_mode(RelayMode::Flood { hops: 1 })
+            .lazy_push(8);
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(config.clone());
+        let b = net.add_node(config.clone());
+        let c = net.add_node(config);
+
+        for peer in [a, b, c] {
+            net.node(&peer).subscribe(topic).unwrap().detach();
+        }
+        net.connect(&a, &b);
+        net.connect(&a, &c);
+        net.connect(&b, &c);
+        net.run();
+        for peer in [a, b, c] {
+            events_of(&mut net, &peer);
+        }
+
+        let behaviour = net.node(&a);
+        behaviour.broadcast(&topic, msg.clone()).unwrap();
+        let offers = behaviour
+            .events
+            .iter()
+            .filter(|action| {
+                matches!(
+                    action,
+                    NetworkBehaviourAction::NotifyHandler {
+                        event: HandlerIn::Send(Message::IHave(..)),
+                        ..
+                    }
+                )
+            })
+            .count();
+        assert_eq!(offers, 2);
+
+        // The peers request the payloads they were offered.
+        net.run();
+        let mut received = Vec::new();
+        for peer in [a, b, c] {
+            received.extend(events_of(&mut net, &peer));
+        }
+        let id = MessageId::new(&topic, &msg);
+        assert_eq!(
+            received,
+            vec![
+                BroadcastEvent::Received(a, topic, id, msg.clone()),
+                BroadcastEvent::Received(a, topic, id, msg),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_key_filter() {
+        let topic = Topic::new(b"topic");
+        let msg = Bytes::from_static(b"msg");
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(BroadcastConfig::default());
+        let b = net.add_node(BroadcastConfig::default());
+        net.node(&b)
+            .set_key_filter(topic, KeyFilter::new([&b"a"[..]]));
+        net.node(&b).subscribe(topic).unwrap().detach();
+        net.connect(&a, &b);
+        net.run();
+        assert!(events_of(&mut net, &b).is_empty());
+        assert!(matches!(
+            events_of(&mut net, &a).as_slice(),
+            [
+                BroadcastEvent::Subscribed(..),
+                BroadcastEvent::PeerTopicsChanged { .. }
+            ]
+        ));
+
+        let behaviour = net.node(&a);
+        assert_eq!(
+            behaviour.broadcast_with_key(&topic, &b"b"[..], msg.clone()),
+            Err(BroadcastError::NoPeers)
+        );
+        let id = behaviour
+            .broadcast_with_key(&topic, &b"a"[..], msg.clone())
+            .unwrap();
+        net.run();
+        assert!(events_of(&mut net, &a).is_empty());
+        assert_eq!(
+            events_of(&mut net, &b),
+            vec![BroadcastEvent::Received(a, topic, id, msg.clone())]
+        );
+
+        // Messages from peers ignoring the filter aren't delivered.
+        let ext = Extensions {
+            key: Some(Bytes::from_static(b"b")),
+            ..Default::default()
+        };
+        let rx = Message::Broadcast(topic, ext, msg);
+        net.node(&b).inject_handler_event(a, HandlerEvent::Rx(rx));
+        net.run();
+        assert!(events_of(&mut net, &b).is_empty());
+    }
+
+    #[test]
+    fn test_namespace() {
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(BroadcastConfig::default().namespace("testnet"));
+        let b = net.add_node(BroadcastConfig::default().namespace("mainnet"));
+        let topic = net.node(&a).topic("chat").unwrap();
+        assert_eq!(net.node(&a).topic_name(&topic), Some("chat"));
+        let other = net.node(&b).topic("chat").unwrap();
+        assert_ne!(topic, other);
+        net.node(&a).subscribe(topic).unwrap().detach();
+        net.node(&b).subscribe(other).unwrap().detach();
+        net.connect(&a, &b);
+        net.run();
+
+        let behaviour = net.node(&a);
+        assert!(behaviour.peers(&topic).is_none());
+        assert_eq!(
+            behaviour.broadcast(&topic, Bytes::from_static(b"msg")),
+            Err(BroadcastError::NoPeers)
+        );
+    }
+
+    #[test]
+    fn test_trace_paths() {
+        let topic = Topic::new(b"topic");
+        let msg = Bytes::from_static(b"msg");
+        let config = BroadcastConfig::default()
+            .relay_mode(RelayMode::Flood { hops: 1 })
+            .trace_paths(4);
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(config.clone());
+        let b = net.add_node(config.clone());
+        let c = net.add_node(config);
+
+        for peer in [a, b, c] {
+            net.node(&peer).subscribe(topic).unwrap().detach();
+        }
+        net.connect(&a, &b);
+        net.connect(&b, &c);
+        net.run();
+        for peer in [a, b, c] {
+            events_of(&mut net, &peer);
+        }
+
+        net.node(&a).broadcast(&topic, msg.clone()).unwrap();
+        net.run();
+        assert!(events_of(&mut net, &a).is_empty());
+        let id = MessageId::new(&topic, &msg);
+        let events = events_of(&mut net, &b);
+        assert_eq!(events[0], BroadcastEvent::Traced(id, vec![a]));
+        assert!(matches!(events[1], BroadcastEvent::Received(..)));
+        let events = events_of(&mut net, &c);
+        assert_eq!(events[0], BroadcastEvent::Traced(id, vec![a, b]));
+        assert!(matches!(events[1], BroadcastEvent::Received(..)));
+    }
+
+    #[test]
+    fn test_signing() {
+        let topic = Topic::new(b"topic");
+        let msg = Bytes::from_static(b"msg");
+        let keypair = Keypair::generate_ed25519();
+        let origin = keypair.public().to_peer_id();
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(BroadcastConfig::default().sign_messages(keypair));
+        let b = net.add_node(BroadcastConfig::default().validation_mode(ValidationMode::Strict));
+        let c = net.add_node(BroadcastConfig::default());
+
+        net.node(&b).subscribe(topic).unwrap().detach();
+        net.connect(&b, &a);
+        net.connect(&b, &c);
+        net.run();
+        for peer in [a, b, c] {
+            events_of(&mut net, &peer);
+        }
+
+        net.node(&c).broadcast(&topic, msg.clone()).unwrap();
+        net.run();
+        assert!(events_of(&mut net, &c).is_empty());
+        assert_eq!(
+            events_of(&mut net, &b),
+            vec![BroadcastEvent::InvalidMessage(
+                c,
+                topic,
+                RejectReason::MissingSignature
+            )]
+        );
+
+        net.node(&a).broadcast(&topic, msg.clone()).unwrap();
+        net.run();
+        assert!(events_of(&mut net, &a).is_empty());
+        assert_eq!(
+            events_of(&mut net, &b),
+            vec![BroadcastEvent::Received(
+                origin,
+                topic,
+                MessageId::new(&topic, &msg),
+                msg
+            )]
+        );
+    }
+
+    #[test]
+    fn test_topic_limit() {
+        let topic = Topic::new(b"topic");
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(BroadcastConfig::default());
+        let b = net.add_node(BroadcastConfig::default());
+
+        net.node(&a).subscribe(topic).unwrap().detach();
+        net.node(&a).set_topic_limit(topic, 2);
+        net.connect(&a, &b);
+        net.run();
+        events_of(&mut net, &a);
+        events_of(&mut net, &b);
+
+        net.node(&b)
+            .broadcast(&topic, Bytes::from_static(b"msg"))
+            .unwrap();
+        net.run();
+        assert!(events_of(&mut net, &b).is_empty());
+        assert_eq!(
+            events_of(&mut net, &a),
+            vec![BroadcastEvent::InvalidMessage(
+                b,
+                topic,
+                RejectReason::TooLarge
+            )]
+        );
+        net.node(&b)
+            .broadcast(&topic, Bytes::from_static(b"ms"))
+            .unwrap();
+        net.run();
+        assert!(events_of(&mut net, &b).is_empty());
+        assert_eq!(
+            events_of(&mut net, &a),
+            vec![BroadcastEvent::Received(
+                b,
+                topic,
+                MessageId::new(&topic, b"ms"),
+                Bytes::from_static(b"ms")
+            )]
+        );
+    }
+
+    #[test]
+    fn test_ack() {
+        let topic = Topic::new(b"topic");
+        let msg = Bytes::from_static(b"msg");
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(BroadcastConfig::default());
+        let b = net.add_node(BroadcastConfig::default());
+        let c = net.add_node(BroadcastConfig::default().ack_timeout(Duration::ZERO));
+
+        net.node(&a).subscribe(topic).unwrap().detach();
+        net.connect(&a, &b);
+        net.connect(&a, &c);
+        net.run();
+        for peer in [a, b, c] {
+            events_of(&mut net, &peer);
+        }
+
+        let id = net
+            .node(&b)
+            .broadcast_with_ack(&topic, msg.clone())
+            .unwrap();
+        net.run();
+        assert_eq!(
+            events_of(&mut net, &a),
+            vec![BroadcastEvent::Received(
+                b,
+                topic,
+                MessageId::new(&topic, &msg),
+                msg.clone()
+            )]
+        );
+        assert_eq!(events_of(&mut net, &b), vec![BroadcastEvent::Acked(a, id)]);
+        let behaviour = net.node(&b);
+        assert!(behaviour.peer_latency(&a).is_some());
+        assert!(behaviour.peer_topic_latency(&a, &topic).is_some());
+        assert!(behaviour.peer_latency(&c).is_none());
+
+        // The timeout expires before the ack arrives, which is ignored.
+        let id = net.node(&c).broadcast_with_ack(&topic, msg).unwrap();
+        net.run();
+        assert_eq!(
+            events_of(&mut net, &c),
+            vec![BroadcastEvent::AckTimeout(a, id)]
+        );
+        assert!(!events_of(&mut net, &a).is_empty());
+    }
+
+    #[test]
+    fn test_reliable_broadcast() {
+        let topic = Topic::new(b"topic");
+        let msg = Bytes::from_static(b"msg");
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(BroadcastConfig::default());
+        let b = net.add_node(BroadcastConfig::default());
+        let c = net.add_node(BroadcastConfig::default());
+        let d = net.add_node(BroadcastConfig::default());
+        for peer in [a, b, d] {
+            net.node(&peer).subscribe(topic).unwrap().detach();
+        }
+        net.connect(&c, &a);
+        net.connect(&c, &b);
+        net.run();
+        for peer in [a, b, c] {
+            events_of(&mut net, &peer);
+        }
+
+        let id = net
+            .node(&c)
+            .broadcast_reliable(&topic, msg.clone(), 3, Duration::from_secs(10))
+            .unwrap();
+        net.run();
+        let mut events = vec![];
+        for peer in [a, b, c] {
+            events.extend(events_of(&mut net, &peer));
+        }
+        let received = BroadcastEvent::Received(c, topic, id, msg.clone());
+        assert_eq!(events, vec![received.clone(), received.clone()]);
+
+        // The message is sent to the peer subscribing later.
+        net.connect(&c, &d);
+        net.run();
+        let mut events = events_of(&mut net, &c);
+        events.extend(events_of(&mut net, &d));
+        assert!(events.contains(&received));
+        assert!(events.contains(&BroadcastEvent::ReliableBroadcastResult {
+            id,
+            delivered: 3,
+            quorum_met: true,
+        }));
+
+        let id = net
+            .node(&c)
+            .broadcast_reliable(&topic, Bytes::from_static(b"other"), 4, Duration::ZERO)
+            .unwrap();
+        net.run();
+        assert_eq!(
+            events_of(&mut net, &c)[0],
+            BroadcastEvent::ReliableBroadcastResult {
+                id,
+                delivered: 0,
+                quorum_met: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_delivery_mode() {
+        let reliable = Topic::new(b"reliable");
+        let topic = Topic::new(b"topic");
+        let msg = Bytes::from_static(b"msg");
+        let mode = DeliveryMode::AtLeastOnce {
+            deadline: Duration::from_secs(10),
+        };
+        let config = BroadcastConfig::default()
+            .delivery_mode(reliable, mode)
+            .ack_timeout(Duration::from_millis(20));
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(config);
+        let b = net.add_node(BroadcastConfig::default());
+        let c = net.add_node(BroadcastConfig::default());
+        for peer in [b, c] {
+            net.node(&peer).subscribe(reliable).unwrap().detach();
+            net.node(&peer).subscribe(topic).unwrap().detach();
+        }
+        net.connect(&a, &b);
+        net.connect(&a, &c);
+        net.run();
+        let events = |net: &mut TestNetwork| {
+            let mut events = vec![];
+            for peer in [a, b, c] {
+                events.extend(events_of(net, &peer));
+            }
+            events
+        };
+        events(&mut net);
+
+        // At-most-once broadcasts aren't tracked.
+        net.node(&a).broadcast(&topic, msg.clone()).unwrap();
+        net.run();
+        let received = events(&mut net);
+        assert_eq!(received.len(), 2);
+        assert!(received
+            .iter()
+            .all(|ev| matches!(ev, BroadcastEvent::Received(..))));
+
+        net.node(&a).broadcast(&reliable, msg.clone()).unwrap();
+        net.run();
+        let received = events(&mut net);
+        let ids: Vec<_> = received
+            .iter()
+            .filter_map(|ev| match ev {
+                BroadcastEvent::Received(_, topic, id, payload) => {
+                    assert_eq!((topic, payload), (&reliable, &msg));
+                    Some(*id)
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(ids.len(), 2);
+        assert!(received.contains(&BroadcastEvent::ReliableBroadcastResult {
+            id: ids[0],
+            delivered: 2,
+            quorum_met: true,
+        }));
+
+        // The ack of b is lost, the retransmission is acked but not delivered
+        // again, without a seen cache.
+        net.node(&a).broadcast(&reliable, msg.clone()).unwrap();
+        net.step();
+        assert!(net.drop_in_flight(&b, &a) > 0);
+        net.run();
+        let mut received = events(&mut net);
+        std::thread::sleep(Duration::from_millis(40));
+        net.run();
+        received.extend(events(&mut net));
+        let delivered = received
+            .iter()
+            .filter(|ev| matches!(ev, BroadcastEvent::Received(..)))
+            .count();
+        assert_eq!(delivered, 2);
+        assert!(received.iter().any(|ev| matches!(
+            ev,
+            BroadcastEvent::ReliableBroadcastResult {
+                delivered: 2,
+                quorum_met: true,
+                ..
+            }
+        )));
+    }
+
+    #[test]
+    fn test_topic_filter() {
+        let allowed = Topic::new(b"allowed");
+        let other = Topic::new(b"other");
+        let config = BroadcastConfig::default()
+            .topic_allowlist(vec![allowed])
+            .reject_filtered_subscriptions();
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(config);
+        let b = net.add_node(BroadcastConfig::default());
+        net.connect(&a, &b);
+        net.run();
+
+        net.node(&b).subscribe(allowed).unwrap().detach();
+        net.run();
+        assert!(events_of(&mut net, &b).is_empty());
+        assert_eq!(
+            events_of(&mut net, &a),
+            vec![BroadcastEvent::Subscribed(b, allowed)]
+        );
+
+        net.node(&b).subscribe(other).unwrap().detach();
+        net.run();
+        assert_eq!(
+            events_of(&mut net, &a),
+            vec![BroadcastEvent::SubscriptionFiltered(b, other)]
+        );
+        assert_eq!(
+            events_of(&mut net, &b),
+            vec![BroadcastEvent::SubscribeRejected(a, other)]
+        );
+        assert_eq!(
+            net.node(&a).broadcast(&other, Bytes::from_static(b"msg")),
+            Err(BroadcastError::NoPeers)
+        );
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let topic = Topic::new(b"topic");
+        let msg = Bytes::from_static(b"msg");
+        let keypair = Keypair::generate_ed25519();
+        let origin = keypair.public().to_peer_id();
+        let config = BroadcastConfig::default()
+            .sign_messages(keypair.clone())
+            .replay_window(Duration::from_secs(60));
+        let a = Broadcast::new(config.clone());
+        let ext = a.extensions(&topic, &msg, false).unwrap();
+        let timestamp = ext.timestamp.unwrap();
+        assert!(ext
+            .signature
+            .as_ref()
+            .unwrap()
+            .verify_stamped(&topic, timestamp, &msg));
+
+        let relay = PeerId::random();
+        let mut b = Broadcast::new(config);
+        b.subscribe(topic).unwrap().detach();
+        b.inject_connected(&relay);
+        b.events.clear();
+        let id = MessageId::new(&topic, &msg);
+        let rx = Message::Broadcast(topic, ext.clone(), msg.clone());
+        b.inject_handler_event(relay, HandlerEvent::Rx(rx.clone()));
+        b.inject_handler_event(relay, HandlerEvent::Rx(rx));
+        let stale = Extensions {
+            signature: Signature::sign_stamped(&keypair, &topic, 0, &msg).ok(),
+            timestamp: Some(0),
+            ..ext
+        };
+        let rx = Message::Broadcast(topic, stale, msg.clone());
+        b.inject_handler_event(relay, HandlerEvent::Rx(rx));
+        let events: Vec<_> = std::iter::from_fn(|| b.events.pop_front())
+            .filter_map(|action| match action {
+                NetworkBehaviourAction::GenerateEvent(event) => Some(event),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                BroadcastEvent::Received(origin, topic, id, msg),
+                BroadcastEvent::ReplayRejected(relay, id),
+                BroadcastEvent::ReplayRejected(relay, id),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_access_policy() {
+        #[derive(Debug)]
+        struct Validators(PeerId);
+
+        impl AccessPolicy for Validators {
+            fn allow_subscribe(&self, peer: &PeerId, topic: &Topic) -> bool {
+                *topic != Topic::new(b"private") || *peer == self.0
+            }
+
+            fn allow_publish(&self, peer: &PeerId, _: &Topic) -> bool {
+                *peer == self.0
+            }
+        }
+
+        let topic = Topic::new(b"consensus");
+        let private = Topic::new(b"private");
+        let msg = Bytes::from_static(b"msg");
+        let mut net = TestNetwork::new(0);
+        let b = net.add_node(BroadcastConfig::default());
+        let c = net.add_node(BroadcastConfig::default());
+        let a = net.add_node(BroadcastConfig::default().with_policy(Validators(b)));
+        net.node(&a).subscribe(topic).unwrap().detach();
+        net.connect(&a, &b);
+        net.connect(&a, &c);
+        net.run();
+        for peer in [a, b, c] {
+            events_of(&mut net, &peer);
+        }
+
+        net.node(&b).broadcast(&topic, msg.clone()).unwrap();
+        net.run();
+        assert!(events_of(&mut net, &b).is_empty());
+        assert_eq!(
+            events_of(&mut net, &a),
+            vec![BroadcastEvent::Received(
+                b,
+                topic,
+                MessageId::new(&topic, &msg),
+                msg
+            )]
+        );
+
+        net.node(&c)
+            .broadcast(&topic, Bytes::from_static(b"forged"))
+            .unwrap();
+        net.run();
+        assert!(events_of(&mut net, &c).is_empty());
+        assert_eq!(
+            events_of(&mut net, &a),
+            vec![BroadcastEvent::InvalidMessage(
+                c,
+                topic,
+                RejectReason::Unauthorized
+            )]
+        );
+
+        net.node(&c).subscribe(private).unwrap().detach();
+        net.run();
+        assert!(events_of(&mut net, &c).is_empty());
+        assert_eq!(
+            events_of(&mut net, &a),
+            vec![BroadcastEvent::SubscriptionFiltered(c, private)]
+        );
+    }
+
+    #[test]
+    fn test_prefix_subscription() {
+        let prefix = Topic::new(b"chat/");
+        let topic = Topic::new(b"chat/room/42");
+        let msg = Bytes::from_static(b"msg");
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(BroadcastConfig::default());
+        let b = net.add_node(BroadcastConfig::default());
+
+        net.node(&b).subscribe_prefix(prefix).unwrap();
+        net.connect(&b, &a);
+        net.run();
+        assert!(events_of(&mut net, &b).is_empty());
+        assert_eq!(
+            events_of(&mut net, &a),
+            vec![BroadcastEvent::SubscribedPrefix(b, prefix)]
+        );
+        assert!(net.node(&b).is_subscribed(&topic));
+
+        assert_eq!(
+            net.node(&a).broadcast(&Topic::new(b"news"), msg.clone()),
+            Err(BroadcastError::NoPeers)
+        );
+        net.node(&a).broadcast(&topic, msg.clone()).unwrap();
+        net.run();
+        assert!(events_of(&mut net, &a).is_empty());
+        assert_eq!(
+            events_of(&mut net, &b),
+            vec![BroadcastEvent::Received(
+                a,
+                topic,
+                MessageId::new(&topic, &msg),
+                msg
+            )]
+        );
+
+        net.node(&b).unsubscribe_prefix(&prefix).unwrap();
+        net.run();
+        assert!(events_of(&mut net, &b).is_empty());
+        assert_eq!(
+            events_of(&mut net, &a),
+            vec![BroadcastEvent::UnsubscribedPrefix(b, prefix)]
+        );
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_metrics() {
+        let topic = Topic::new(b"topic");
+        let mut registry = prometheus_client::registry::Registry::default();
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(BroadcastConfig::default());
+        let b = net.add_node(BroadcastConfig::default());
+        net.node(&a).register_metrics(&mut registry);
+
+        net.node(&b).subscribe(topic).unwrap().detach();
+        net.connect(&a, &b);
+        net.run();
+        net.node(&a)
+            .broadcast(&topic, Bytes::from_static(b"msg"))
+            .unwrap();
+        net.run();
+
+        let mut buf = Vec::new();
+        prometheus_client::encoding::text::encode(&mut buf, &registry).unwrap();
+        let text = String::from_utf8(buf).unwrap();
+        assert!(text.contains("broadcast_messages_sent_total{topic=\"topic\"} 1"));
+        assert!(text.contains("broadcast_topic_peers{topic=\"topic\"} 1"));
+    }
+
+    #[test]
+    fn test_peer_scoring() {
+        let topic = Topic::new(b"topic");
+        let peer = PeerId::random();
+        let params = PeerScoreParams {
+            graylist_threshold: -15.0,
+            disconnect_threshold: -25.0,
+            ..Default::default()
+        };
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(BroadcastConfig::default().peer_scoring(params));
+        let b = net.add_node(BroadcastConfig::default());
+        net.node(&a).subscribe(topic).unwrap().detach();
+        net.connect(&a, &b);
+        net.run();
+        assert_eq!(net.node(&a).peer_score(&b), Some(0.0));
+
+        let behaviour = net.node(&a);
+        behaviour.inject_event(peer, ConnectionId::new(0), HandlerEvent::Malformed);
+        behaviour.inject_event(peer, ConnectionId::new(0), HandlerEvent::Malformed);
+        assert!(behaviour.peer_score(&peer).unwrap() < -15.0);
+        let msg = Message::Broadcast(topic, Extensions::default(), Bytes::from_static(b"msg"));
+        behaviour.inject_event(peer, ConnectionId::new(0), HandlerEvent::Rx(msg));
+        assert!(behaviour.events.is_empty());
+
+        behaviour.inject_event(peer, ConnectionId::new(0), HandlerEvent::Malformed);
+        assert!(matches!(
+            behaviour.events.pop_front(),
+            Some(NetworkBehaviourAction::CloseConnection { peer_id, .. }) if peer_id == peer
+        ));
+        behaviour.inject_event(peer, ConnectionId::new(0), HandlerEvent::Malformed);
+        assert!(behaviour.events.is_empty());
+    }
+
+    #[test]
+    fn test_offline_queue() {
+        let topic = Topic::new(b"topic");
+        let msg = Bytes::from_static(b"msg");
+        let config = BroadcastConfig::default().offline_queue(Default::default());
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(config);
+        let b = net.add_node(BroadcastConfig::default());
+
+        net.node(&b).subscribe(topic).unwrap().detach();
+        net.connect(&a, &b);
+        net.run();
+        assert!(events_of(&mut net, &b).is_empty());
+        let events = events_of(&mut net, &a);
+        assert_eq!(events[0], BroadcastEvent::Subscribed(b, topic));
+        assert!(matches!(
+            events[1],
+            BroadcastEvent::PeerTopicsChanged { .. }
+        ));
+        net.disconnect(&a, &b);
+        net.node(&a).broadcast(&topic, msg.clone()).unwrap();
+        assert_eq!(
+            net.node(&a).broadcast(&Topic::new(b"other"), msg.clone()),
+            Err(BroadcastError::NoPeers)
+        );
+        net.run();
+        assert!(events_of(&mut net, &a).is_empty());
+
+        net.connect(&a, &b);
+        net.run();
+        assert_eq!(
+            events_of(&mut net, &a)[0],
+            BroadcastEvent::Replayed(b, topic, 1)
+        );
+        assert_eq!(
+            events_of(&mut net, &b)[0],
+            BroadcastEvent::Received(a, topic, MessageId::new(&topic, &msg), msg)
+        );
+    }
+
+    #[test]
+    fn test_retained() {
+        let topic = Topic::new(b"topic");
+        let msg = Bytes::from_static(b"msg");
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(BroadcastConfig::default());
+        let b = net.add_node(BroadcastConfig::default());
+
+        assert_eq!(
+            net.node(&a).broadcast_retained(&topic, msg.clone()),
+            Err(BroadcastError::NoPeers)
+        );
+        net.connect(&a, &b);
+        net.node(&b).subscribe(topic).unwrap().detach();
+        net.run();
+        assert_eq!(
+            events_of(&mut net, &a),
+            vec![BroadcastEvent::Subscribed(b, topic)]
+        );
+        assert_eq!(
+            events_of(&mut net, &b),
+            vec![BroadcastEvent::Received(
+                a,
+                topic,
+                MessageId::new(&topic, &msg),
+                msg
+            )]
+        );
+
+        net.node(&a).clear_retained(&topic);
+        net.node(&b).unsubscribe(&topic).unwrap();
+        net.node(&b).subscribe(topic).unwrap().detach();
+        net.run();
+        assert!(events_of(&mut net, &b).is_empty());
+    }
+
+    #[test]
+    fn test_subscribe_with_snapshot() {
+        let topic = Topic::new(b"topic");
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(BroadcastConfig::default());
+        let b = net.add_node(BroadcastConfig::default());
+        let c = net.add_node(BroadcastConfig::default());
+        net.connect(&a, &b);
+        net.connect(&a, &c);
+        net.node(&b).subscribe(topic).unwrap().detach();
+        net.run();
+        assert!(events_of(&mut net, &b).is_empty());
+        assert_eq!(
+            events_of(&mut net, &a),
+            vec![BroadcastEvent::Subscribed(b, topic)]
+        );
+        net.node(&b)
+            .set_snapshot_provider(|_: &Topic| Some(Bytes::from_static(b"state")));
+        // Peers that aren't subscribed don't answer.
+        net.node(&c)
+            .set_snapshot_provider(|_: &Topic| Some(Bytes::from_static(b"other")));
+
+        net.node(&a)
+            .subscribe_with_snapshot(topic)
+            .unwrap()
+            .detach();
+        net.run();
+        assert_eq!(
+            events_of(&mut net, &b),
+            vec![BroadcastEvent::Subscribed(a, topic)]
+        );
+        assert_eq!(
+            events_of(&mut net, &c),
+            vec![BroadcastEvent::Subscribed(a, topic)]
+        );
+        assert_eq!(
+            events_of(&mut net, &a),
+            vec![BroadcastEvent::Snapshot(
+                b,
+                topic,
+                Bytes::from_static(b"state")
+            )]
+        );
+    }
+
+    #[test]
+    fn test_address_hints() {
+        let topic = Topic::new(b"topic");
+        let mut params = DummyPollParameters(PeerId::random());
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let peer = PeerId::random();
+        let address: Multiaddr = "/memory/1".parse().unwrap();
+        let mut a = Broadcast::new(BroadcastConfig::default().address_hints(1));
+        a.inject_connected(&peer);
+        a.inject_handler_event(
+            peer,
+            HandlerEvent::Rx(Message::SubscribeWithAddresses(
+                topic,
+                vec![address.clone()],
+            )),
+        );
+        a.inject_handler_event(
+            peer,
+            HandlerEvent::Rx(Message::SubscribeWithAddresses(
+                topic,
+                vec![address.clone()],
+            )),
+        );
+        let mut events = Vec::new();
+        while let Poll::Ready(action) = a.poll(&mut cx, &mut params) {
+            if let NetworkBehaviourAction::GenerateEvent(
+                event @ BroadcastEvent::PeerAddresses(..),
+            ) = action
+            {
+                events.push(event);
+            }
+        }
+        // Unchanged addresses aren't reported again.
+        assert_eq!(
+            events,
+            vec![BroadcastEvent::PeerAddresses(peer, vec![address.clone()])]
+        );
+        assert_eq!(a.addresses_of_peer(&peer), vec![address.clone()]);
+
+        // Set from the poll parameters.
+        a.external_addresses = vec![address.clone()];
+        a.subscribe(topic).unwrap().detach();
+        assert!(a.events.iter().any(|action| matches!(
+            action,
+            NetworkBehaviourAction::NotifyHandler {
+                event: HandlerIn::Send(Message::SubscribeWithAddresses(_, addresses)),
+                ..
+            } if *addresses == [address.clone()]
+        )));
+    }
+
+    #[test]
+    fn test_targeted_send() {
+        let topic = Topic::new(b"topic");
+        let msg = Bytes::from_static(b"msg");
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(BroadcastConfig::default());
+        let b = net.add_node(BroadcastConfig::default());
+        let c = net.add_node(BroadcastConfig::default());
+
+        net.connect(&a, &b);
+        net.connect(&a, &c);
+        net.node(&b).subscribe(topic).unwrap().detach();
+        net.node(&c).subscribe(topic).unwrap().detach();
+        net.run();
+        assert!(events_of(&mut net, &b).is_empty());
+        assert!(events_of(&mut net, &c).is_empty());
+        events_of(&mut net, &a);
+
+        net.node(&a)
+            .broadcast_except(&topic, msg.clone(), &[b])
+            .unwrap();
+        net.run();
+        assert!(events_of(&mut net, &a).is_empty());
+        assert!(events_of(&mut net, &b).is_empty());
+        assert_eq!(
+            events_of(&mut net, &c),
+            vec![BroadcastEvent::Received(
+                a,
+                topic,
+                MessageId::new(&topic, &msg),
+                msg.clone()
+            )]
+        );
+
+        let me = net.node(&a);
+        assert!(me.send_to(&b, &topic, msg.clone()));
+        assert!(!me.send_to(&PeerId::random(), &topic, msg.clone()));
+        net.run();
+        assert!(events_of(&mut net, &a).is_empty());
+        assert!(events_of(&mut net, &c).is_empty());
+        assert_eq!(
+            events_of(&mut net, &b),
+            vec![BroadcastEvent::Received(
+                a,
+                topic,
+                MessageId::new(&topic, &msg),
+                msg
+            )]
+        );
+    }
+
+    #[test]
+    fn test_unsolicited_messages() {
+        let topic = Topic::new(b"topic");
+        let msg = Bytes::from_static(b"msg");
+        for policy in [
+            UnsolicitedPolicy::Deliver,
+            UnsolicitedPolicy::Drop,
+            UnsolicitedPolicy::Report,
+        ] {
+            let mut net = TestNetwork::new(0);
+            let a = net.add_node(BroadcastConfig::default());
+            let b = net.add_node(
+                BroadcastConfig::default()
+                    .unsolicited_messages(policy)
+                    .peer_scoring(PeerScoreParams::default()),
+            );
+            net.connect(&a, &b);
+            assert!(net.node(&a).send_to(&b, &topic, msg.clone()));
+            net.run();
+            assert!(events_of(&mut net, &a).is_empty());
+            let ev = events_of(&mut net, &b).into_iter().next();
+            let score = net.node(&b).peer_score(&a);
+            match policy {
+                UnsolicitedPolicy::Deliver => assert_eq!(
+                    ev,
+                    Some(BroadcastEvent::Received(
+                        a,
+                        topic,
+                        MessageId::new(&topic, &msg),
+                        msg.clone()
+                    ))
+                ),
+                UnsolicitedPolicy::Drop => assert_eq!(ev, None),
+                UnsolicitedPolicy::Report => {
+                    assert_eq!(ev, Some(BroadcastEvent::UnsolicitedMessage(a, topic)))
+                }
+            }
+            assert_eq!(score.unwrap() < 0.0, policy == UnsolicitedPolicy::Report);
+        }
+    }
+
+    #[test]
+    fn test_peer_topics_changed() {
+        let (t1, t2) = (Topic::new(b"t1"), Topic::new(b"t2"));
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(BroadcastConfig::default());
+        let b = net.add_node(BroadcastConfig::default());
+        net.connect(&a, &b);
+
+        let batch = Message::Batch(vec![Message::Subscribe(t1), Message::Subscribe(t2)]);
+        net.node(&b)
+            .inject_handler_event(a, HandlerEvent::Rx(batch));
+        net.run();
+        assert_eq!(
+            events_of(&mut net, &b),
+            vec![
+                BroadcastEvent::Subscribed(a, t1),
+                BroadcastEvent::Subscribed(a, t2),
+                BroadcastEvent::PeerTopicsChanged {
+                    peer: a,
+                    added: vec![t1, t2],
+                    removed: vec![],
+                },
+            ]
+        );
+
+        let batch = Message::Batch(vec![Message::Unsubscribe(t1), Message::Subscribe(t2)]);
+        net.node(&b)
+            .inject_handler_event(a, HandlerEvent::Rx(batch));
+        net.run();
+        assert_eq!(
+            events_of(&mut net, &b),
+            vec![
+                BroadcastEvent::Unsubscribed(a, t1),
+                BroadcastEvent::Subscribed(a, t2),
+                BroadcastEvent::PeerTopicsChanged {
+                    peer: a,
+                    added: vec![],
+                    removed: vec![t1],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_block_peer() {
+        let topic = Topic::new(b"topic");
+        let msg = Bytes::from_static(b"msg");
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(BroadcastConfig::default());
+        let b = net.add_node(BroadcastConfig::default());
+        net.node(&a).subscribe(topic).unwrap().detach();
+        net.node(&b).subscribe(topic).unwrap().detach();
+        net.connect(&a, &b);
+        net.run();
+        events_of(&mut net, &a);
+        events_of(&mut net, &b);
+
+        net.node(&a).block_peer(b, false);
+        net.node(&b).broadcast(&topic, msg.clone()).unwrap();
+        net.run();
+        assert!(events_of(&mut net, &b).is_empty());
+        assert!(events_of(&mut net, &a).is_empty());
+        assert_eq!(
+            net.node(&a).broadcast(&topic, msg.clone()),
+            Err(BroadcastError::NoPeers)
+        );
+
+        assert!(net.node(&a).unblock_peer(&b));
+        net.node(&b).broadcast(&topic, msg.clone()).unwrap();
+        net.run();
+        assert!(events_of(&mut net, &b).is_empty());
+        assert_eq!(
+            events_of(&mut net, &a),
+            vec![BroadcastEvent::Received(
+                b,
+                topic,
+                MessageId::new(&topic, &msg),
+                msg
+            )]
+        );
+
+        // Blocking drops the queued messages and closes the connections.
+        let mut params = DummyPollParameters(PeerId::random());
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let peer = PeerId::random();
+        let mut a = Broadcast::new(Default::default());
+        a.inject_connected(&peer);
+        a.subscribe(topic).unwrap().detach();
+        a.block_peer(peer, true);
+        let mut closed = false;
+        while let Poll::Ready(action) = a.poll(&mut cx, &mut params) {
+            match action {
+                NetworkBehaviourAction::CloseConnection { peer_id, .. } => {
+                    closed = peer_id == peer;
+                }
+                NetworkBehaviourAction::NotifyHandler { .. } => panic!(),
+                _ => {}
+            }
+        }
+        assert!(closed);
+    }
+
+    #[test]
+    fn test_message_ttl() {
+        let topic = Topic::new(b"topic");
+        let msg = Bytes::from_static(b"msg");
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(BroadcastConfig::default().message_ttl(Duration::ZERO));
+        let b = net.add_node(BroadcastConfig::default().message_ttl(Duration::from_secs(60)));
+
+        net.connect(&a, &b);
+        net.node(&a).subscribe(topic).unwrap().detach();
+        net.node(&b).subscribe(topic).unwrap().detach();
+        net.run();
+        assert_eq!(
+            events_of(&mut net, &a),
+            vec![BroadcastEvent::Subscribed(b, topic)]
+        );
+        assert_eq!(
+            events_of(&mut net, &b),
+            vec![BroadcastEvent::Subscribed(a, topic)]
+        );
+
+        net.node(&a).broadcast(&topic, msg.clone()).unwrap();
+        net.run();
+        assert!(events_of(&mut net, &a).is_empty());
+        assert_eq!(
+            events_of(&mut net, &b),
+            vec![BroadcastEvent::Expired(a, topic)]
+        );
+        net.node(&b).broadcast(&topic, msg.clone()).unwrap();
+        net.run();
+        assert!(events_of(&mut net, &b).is_empty());
+        assert_eq!(
+            events_of(&mut net, &a),
+            vec![BroadcastEvent::Received(
+                b,
+                topic,
+                MessageId::new(&topic, &msg),
+                msg
+            )]
+        );
+    }
+
+    #[test]
+    fn test_topic_stream() {
+        use futures::{FutureExt, StreamExt};
+        let topic = Topic::new(b"topic");
+        let other = Topic::new(b"other");
+        let msg = Bytes::from_static(b"msg");
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(BroadcastConfig::default());
+        let b = net.add_node(BroadcastConfig::default());
+
+        let mut stream = net.node(&a).topic_stream(topic);
+        net.node(&a).subscribe(topic).unwrap().detach();
+        net.node(&a).subscribe(other).unwrap().detach();
+        net.connect(&a, &b);
+        net.run();
+        assert!(events_of(&mut net, &a).is_empty());
+        events_of(&mut net, &b);
+
+        net.node(&b).broadcast(&other, msg.clone()).unwrap();
+        net.node(&b).broadcast(&topic, msg.clone()).unwrap();
+        net.run();
+        assert!(events_of(&mut net, &b).is_empty());
+        events_of(&mut net, &a);
+        assert_eq!(stream.next().now_or_never(), Some(Some((b, msg))));
+        assert!(stream.next().now_or_never().is_none());
+
+        drop(stream);
+        net.node(&b)
+            .broadcast(&topic, Bytes::from_static(b"msg2"))
+            .unwrap();
+        net.run();
+        assert!(events_of(&mut net, &b).is_empty());
+        assert!(!events_of(&mut net, &a).is_empty());
+        assert!(net.node(&a).streams.is_empty());
+    }
+
+    #[test]
+    fn test_topic_stream_lagged() {
+        use futures::{FutureExt, StreamExt};
+        let topic = Topic::new(b"topic");
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(BroadcastConfig::default());
+        let b = net.add_node(BroadcastConfig::default());
+
+        let mut stream = net.node(&a).topic_stream(topic);
+        net.node(&a).subscribe(topic).unwrap().detach();
+        net.connect(&a, &b);
+        net.run();
+        events_of(&mut net, &a);
+        events_of(&mut net, &b);
+
+        let mut lagged = 0;
+        for i in 0..TOPIC_STREAM_CAPACITY + 2 {
+            net.node(&b)
+                .broadcast(&topic, Bytes::from(i.to_string()))
+                .unwrap();
+            net.run();
+            for ev in events_of(&mut net, &a) {
+                if let BroadcastEvent::StreamLagged(lagged_topic, streams) = ev {
+                    assert_eq!((lagged_topic, streams), (topic, 1));
+                    lagged += 1;
+                }
+            }
+        }
+        // The channel holds one message per sender beyond its capacity.
+        assert_eq!(lagged, 1);
+        let mut received = 0;
+        while let Some(Some(_)) = stream.next().now_or_never() {
+            received += 1;
+        }
+        assert_eq!(received, TOPIC_STREAM_CAPACITY + 1);
+    }
+
+    #[test]
+    fn test_explicit_peer() {
+        let topic = Topic::new(b"topic");
+        let msg = Bytes::from_static(b"msg");
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(BroadcastConfig::default());
+        let b = net.add_node(BroadcastConfig::default());
+        let address: Multiaddr = "/memory/1".parse().unwrap();
+
+        net.connect(&a, &b);
+        net.node(&a).add_explicit_peer(b, address.clone());
+        assert_eq!(net.node(&a).addresses_of_peer(&b), vec![address]);
+        net.node(&a).broadcast(&topic, msg.clone()).unwrap();
+        net.run();
+        assert!(events_of(&mut net, &a).is_empty());
+        assert_eq!(
+            events_of(&mut net, &b),
+            vec![BroadcastEvent::Received(
+                a,
+                topic,
+                MessageId::new(&topic, &msg),
+                msg
+            )]
+        );
+
+        net.disconnect(&a, &b);
+        assert!(net.node(&a).remove_explicit_peer(&b));
+        net.run();
+        assert!(events_of(&mut net, &a).is_empty());
+    }
+
+    #[test]
+    fn test_validator() {
+        let topic = Topic::new(b"topic");
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(BroadcastConfig::default());
+        let b = net.add_node(BroadcastConfig::default());
+
+        net.node(&a).subscribe(topic).unwrap().detach();
+        net.connect(&a, &b);
+        net.run();
+        assert!(events_of(&mut net, &a).is_empty());
+        events_of(&mut net, &b);
+        net.node(&a).set_validator(topic, |_, msg| match msg {
+            b"ok" => ValidationResult::Accept,
+            b"bad" => ValidationResult::Reject,
+            _ => ValidationResult::Ignore,
+        });
+
+        for msg in [&b"ignored"[..], b"bad", b"ok"] {
+            net.node(&b).broadcast(&topic, msg).unwrap();
+        }
+        net.run();
+        assert!(events_of(&mut net, &b).is_empty());
+        assert_eq!(
+            events_of(&mut net, &a),
+            vec![
+                BroadcastEvent::InvalidMessage(b, topic, RejectReason::Validation),
+                BroadcastEvent::Received(
+                    b,
+                    topic,
+                    MessageId::new(&topic, b"ok"),
+                    Bytes::from_static(b"ok")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_subscription_store() {
+        #[derive(Clone, Default)]
+        struct MemoryStore(Arc<Mutex<StoredState>>);
+
+        impl SubscriptionStore for MemoryStore {
+            fn load(&mut self) -> io::Result<StoredState> {
+                Ok(self.0.lock().unwrap().clone())
+            }
+
+            fn save(&mut self, state: &StoredState) -> io::Result<()> {
+                *self.0.lock().unwrap() = state.clone();
+                Ok(())
+            }
+        }
+
+        let topic = Topic::new(b"topic");
+        let store = MemoryStore::default();
+        let mut a = Broadcast::with_store(BroadcastConfig::default(), store.clone()).unwrap();
+        a.subscribe(topic).unwrap().detach();
+        a.subscribe_prefix(Topic::new(b"chat/")).unwrap();
+        let peer = PeerId::random();
+        a.inject_connected(&peer);
+        a.inject_event(
+            peer,
+            ConnectionId::new(0),
+            HandlerEvent::Rx(Message::Subscribe(topic)),
+        );
+        a.inject_disconnected(&peer);
+
+        let a = Broadcast::with_store(BroadcastConfig::default(), store).unwrap();
+        assert!(a.is_subscribed(&topic));
+        assert!(a.is_subscribed(&Topic::new(b"chat/room")));
+        assert_eq!(
+            a.known_peers[&peer].0,
+            std::iter::once(topic).collect::<FnvHashSet<_>>()
+        );
+    }
+
+    #[test]
+    fn test_max_fanout() {
+        let topic = Topic::new(b"topic");
+        let msg = Bytes::from_static(b"msg");
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(BroadcastConfig::default().max_fanout(2));
+        let peers: Vec<_> = (0..4)
+            .map(|_| net.add_node(BroadcastConfig::default()))
+            .collect();
+        for peer in &peers {
+            net.connect(&a, peer);
+            net.node(peer).subscribe(topic).unwrap().detach();
+        }
+        net.run();
+        for peer in &peers {
+            assert!(events_of(&mut net, peer).is_empty());
+        }
+        events_of(&mut net, &a);
+
+        net.node(&a).broadcast(&topic, msg).unwrap();
+        net.run();
+        assert!(events_of(&mut net, &a).is_empty());
+        let received = peers
+            .iter()
+            .filter(|peer| !events_of(&mut net, peer).is_empty())
+            .count();
+        assert_eq!(received, 2);
+    }
+
+    #[test]
+    fn test_history() {
+        let topic = Topic::new(b"topic");
+        let config = BroadcastConfig::default()
+            .seen_cache_size(16)
+            .history_len(8);
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(config.clone());
+        let b = net.add_node(config);
+        net.node(&a).subscribe(topic).unwrap().detach();
+        net.node(&b).subscribe(topic).unwrap().detach();
+        net.connect(&a, &b);
+        net.run();
+        events_of(&mut net, &a);
+        events_of(&mut net, &b);
+
+        net.node(&a)
+            .broadcast(&topic, Bytes::from_static(b"first"))
+            .unwrap();
+        net.run();
+        assert!(events_of(&mut net, &a).is_empty());
+        assert!(matches!(
+            events_of(&mut net, &b).as_slice(),
+            [BroadcastEvent::Received(..)]
+        ));
+
+        net.disconnect(&a, &b);
+        let _ = net
+            .node(&a)
+            .broadcast(&topic, Bytes::from_static(b"second"));
+        net.run();
+        assert!(events_of(&mut net, &a).is_empty());
+
+        // b resubscribes, is offered both messages and requests the missed one.
+        net.connect(&a, &b);
+        net.run();
+        let received: Vec<_> = events_of(&mut net, &b)
+            .into_iter()
+            .filter_map(|ev| match ev {
+                BroadcastEvent::Received(_, _, _, msg) => Some(msg),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(received, vec![Bytes::from_static(b"second")]);
+    }
+
+    #[test]
+    fn test_replay() {
+        let topic = Topic::new(b"topic");
+        let first = Bytes::from_static(b"first");
+        let second = Bytes::from_static(b"second");
+        let start = instant::Instant::now();
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(BroadcastConfig::default().history_len(8));
+        let b = net.add_node(BroadcastConfig::default());
+        net.node(&a).subscribe(topic).unwrap().detach();
+        net.node(&b).subscribe(topic).unwrap().detach();
+        net.connect(&a, &b);
+        net.run();
+        events_of(&mut net, &a);
+        events_of(&mut net, &b);
+        net.node(&a).broadcast(&topic, first.clone()).unwrap();
+        net.node(&a).broadcast(&topic, second.clone()).unwrap();
+        net.run();
+        assert!(events_of(&mut net, &a).is_empty());
+        assert_eq!(events_of(&mut net, &b).len(), 2, "b receives both messages");
+
+        let first_id = MessageId::new(&topic, &first);
+        let second_id = MessageId::new(&topic, &second);
+        let behaviour = net.node(&a);
+        assert_eq!(
+            behaviour.replay(&topic, first_id),
+            vec![(second_id, second.clone())]
+        );
+        assert_eq!(behaviour.replay(&topic, start).len(), 2);
+
+        // b doesn't deduplicate and receives the replayed messages again.
+        let behaviour = net.node(&b);
+        behaviour.request_replay(&a, &topic, first_id).unwrap();
+        assert_eq!(
+            behaviour.request_replay(&a, &Topic::new(b"other"), start),
+            Err(BroadcastError::NotSubscribed)
+        );
+        assert_eq!(
+            behaviour.request_replay(&PeerId::random(), &topic, start),
+            Err(BroadcastError::NoPeers)
+        );
+        net.run();
+        let mut received = Vec::new();
+        for peer in [a, b] {
+            for ev in events_of(&mut net, &peer) {
+                if let BroadcastEvent::Received(_, _, _, msg) = ev {
+                    received.push(msg);
+                }
+            }
+        }
+        assert_eq!(received, vec![second]);
+    }
+
+    #[test]
+    fn test_broadcast_error() {
+        let topic = Topic::new(b"topic");
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(BroadcastConfig::default().max_message_size(4));
+        let b = net.add_node(BroadcastConfig::default());
+        let behaviour = net.node(&a);
+        assert_eq!(
+            behaviour.unsubscribe(&topic),
+            Err(BroadcastError::NotSubscribed)
+        );
+        assert_eq!(
+            behaviour.broadcast(&topic, Bytes::from_static(b"msg")),
+            Err(BroadcastError::NoPeers)
+        );
+
+        net.connect(&a, &b);
+        net.node(&b).subscribe(topic).unwrap().detach();
+        net.run();
+        assert!(events_of(&mut net, &b).is_empty());
+        let behaviour = net.node(&a);
+        assert_eq!(
+            behaviour.broadcast(&topic, Bytes::from_static(b"message")),
+            Err(BroadcastError::MessageTooLarge)
+        );
+        assert_eq!(
+            behaviour.broadcast(&topic, Bytes::from_static(b"msg")),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_publish_policy() {
+        let topic = Topic::new(b"topic");
+        let msg = Bytes::from_static(b"msg");
+        let config = BroadcastConfig::default().publish_policy(PublishPolicy::RequireSubscription);
+        let mut behaviour = Broadcast::new(config);
+        assert_eq!(
+            behaviour.broadcast(&topic, msg.clone()),
+            Err(BroadcastError::NotSubscribed)
+        );
+        behaviour.subscribe(topic).unwrap().detach();
+        assert_eq!(
+            behaviour.broadcast(&topic, msg.clone()),
+            Err(BroadcastError::NoPeers)
+        );
+
+        let config = BroadcastConfig::default().publish_policy(PublishPolicy::AutoSubscribe);
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(config);
+        let b = net.add_node(BroadcastConfig::default());
+        net.connect(&a, &b);
+        net.node(&b).subscribe(topic).unwrap().detach();
+        net.run();
+        assert!(events_of(&mut net, &b).is_empty());
+        assert!(matches!(
+            events_of(&mut net, &a).as_slice(),
+            [BroadcastEvent::Subscribed(..)]
+        ));
+        net.node(&a).broadcast(&topic, msg).unwrap();
+        assert!(net.node(&a).is_subscribed(&topic));
+        net.run();
+        assert!(events_of(&mut net, &a).is_empty());
+        let events = events_of(&mut net, &b);
+        assert_eq!(events[0], BroadcastEvent::Subscribed(a, topic));
+        assert!(matches!(events[1], BroadcastEvent::Received(..)));
+    }
+
+    #[test]
+    fn test_subscription_handle() {
+        let topic = Topic::new(b"topic");
+        let msg = Bytes::from_static(b"msg");
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(BroadcastConfig::default());
+        let b = net.add_node(BroadcastConfig::default());
+        net.connect(&a, &b);
+        net.node(&b).subscribe(topic).unwrap().detach();
+        let handle = net.node(&a).subscribe(topic).unwrap();
+        assert_eq!(handle.topic(), &topic);
+        net.run();
+        assert!(matches!(
+            events_of(&mut net, &a).as_slice(),
+            [BroadcastEvent::Subscribed(..)]
+        ));
+        assert!(matches!(
+            events_of(&mut net, &b).as_slice(),
+            [BroadcastEvent::Subscribed(..)]
+        ));
+
+        handle.publish(msg.clone()).unwrap();
+        net.run();
+        assert!(events_of(&mut net, &a).is_empty());
+        assert_eq!(
+            events_of(&mut net, &b),
+            vec![BroadcastEvent::Received(
+                a,
+                topic,
+                MessageId::new(&topic, &msg),
+                msg
+            )]
+        );
+
+        drop(handle);
+        net.run();
+        assert!(events_of(&mut net, &a).is_empty());
+        assert!(!net.node(&a).is_subscribed(&topic));
+        assert_eq!(
+            events_of(&mut net, &b),
+            vec![BroadcastEvent::Unsubscribed(a, topic)]
+        );
+    }
+
+    #[test]
+    fn test_counted_subscriptions() {
+        let topic = Topic::new(b"topic");
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(BroadcastConfig::default().counted_subscriptions());
+        let b = net.add_node(BroadcastConfig::default());
+        net.connect(&a, &b);
+        net.node(&b).subscribe(topic).unwrap().detach();
+        let first = net.node(&a).subscribe(topic).unwrap();
+        let second = net.node(&a).subscribe(topic).unwrap();
+        assert_eq!((first.count(), second.count()), (1, 2));
+        net.run();
+        assert_eq!(
+            events_of(&mut net, &a),
+            vec![BroadcastEvent::Subscribed(b, topic)]
+        );
+        assert_eq!(
+            events_of(&mut net, &b),
+            vec![BroadcastEvent::Subscribed(a, topic)]
+        );
+
+        drop(first);
+        net.run();
+        assert!(events_of(&mut net, &a).is_empty());
+        assert!(net.node(&a).is_subscribed(&topic));
+        assert!(events_of(&mut net, &b).is_empty());
+        second.detach();
+        assert_eq!(net.node(&a).unsubscribe(&topic), Ok(0));
+        net.run();
+        assert!(events_of(&mut net, &a).is_empty());
+        assert_eq!(
+            events_of(&mut net, &b),
+            vec![BroadcastEvent::Unsubscribed(a, topic)]
+        );
+    }
+
+    #[test]
+    fn test_stale_subscription_handle() {
+        let topic = Topic::new(b"topic");
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(BroadcastConfig::default());
+        let stale = net.node(&a).subscribe(topic).unwrap();
+        assert_eq!(net.node(&a).unsubscribe(&topic), Ok(0));
+        let current = net.node(&a).subscribe(topic).unwrap();
+        drop(stale);
+        net.run();
+        assert!(net.node(&a).is_subscribed(&topic));
+
+        let replaced = current;
+        let current = net.node(&a).subscribe(topic).unwrap();
+        drop(replaced);
+        net.run();
+        assert!(net.node(&a).is_subscribed(&topic));
+        drop(current);
+        net.run();
+        assert!(!net.node(&a).is_subscribed(&topic));
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_topic_key() {
+        let topic = Topic::new(b"topic");
+        let msg = Bytes::from_static(b"msg");
+        let key = TopicKey::generate();
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(BroadcastConfig::default());
+        let b = net.add_node(BroadcastConfig::default());
+        let c = net.add_node(BroadcastConfig::default());
+        net.node(&a).set_topic_key(topic, key.clone());
+        net.node(&b).set_topic_key(topic, key);
+        net.node(&c).set_topic_key(topic, TopicKey::generate());
+        net.connect(&a, &b);
+        net.connect(&a, &c);
+        net.node(&b).subscribe(topic).unwrap().detach();
+        net.node(&c).subscribe(topic).unwrap().detach();
+        net.run();
+        assert!(events_of(&mut net, &b).is_empty());
+        assert!(events_of(&mut net, &c).is_empty());
+        events_of(&mut net, &a);
+
+        net.node(&a).broadcast(&topic, msg.clone()).unwrap();
+        net.run();
+        assert!(events_of(&mut net, &a).is_empty());
+        // The id is computed from the encrypted payload.
+        assert!(matches!(
+            events_of(&mut net, &b).as_slice(),
+            [BroadcastEvent::Received(peer, _, _, payload)] if *peer == a && *payload == msg
+        ));
+        assert_eq!(
+            events_of(&mut net, &c),
+            vec![BroadcastEvent::InvalidMessage(
+                a,
+                topic,
+                RejectReason::Decryption
+            )]
+        );
+    }
+
+    #[cfg(feature = "stamps")]
+    #[test]
+    fn test_relay_stamps() {
+        let topic = Topic::new(b"topic");
+        let msg = Bytes::from_static(b"msg");
+        let key = RelayKey::generate();
+        let stamped = |net: &mut TestNetwork| {
+            let config = BroadcastConfig::default()
+                .relay_mode(RelayMode::Flood { hops: 1 })
+                .sign_messages(Keypair::generate_ed25519());
+            let peer = net.add_node(config);
+            net.node(&peer).set_relay_key(topic, key.clone());
+            peer
+        };
+        let mut net = TestNetwork::new(0);
+        let a = stamped(&mut net);
+        let b = stamped(&mut net);
+        let c = stamped(&mut net);
+        let d = net.add_node(BroadcastConfig::default());
+
+        for peer in [a, b, c, d] {
+            net.node(&peer).subscribe(topic).unwrap().detach();
+        }
+        net.connect(&a, &b);
+        net.connect(&b, &c);
+        net.connect(&d, &c);
+        net.run();
+        for peer in [a, b, c, d] {
+            events_of(&mut net, &peer);
+        }
+
+        net.node(&a).broadcast(&topic, msg.clone()).unwrap();
+        net.run();
+        assert!(events_of(&mut net, &a).is_empty());
+        assert!(matches!(
+            events_of(&mut net, &b).as_slice(),
+            [BroadcastEvent::Received(..)]
+        ));
+        assert!(matches!(
+            events_of(&mut net, &c).as_slice(),
+            [BroadcastEvent::Received(peer, _, _, payload)] if *peer == a && *payload == msg
+        ));
+
+        events_of(&mut net, &d);
+
+        net.node(&d).broadcast(&topic, msg).unwrap();
+        net.run();
+        assert!(events_of(&mut net, &d).is_empty());
+        assert_eq!(
+            events_of(&mut net, &c),
+            vec![BroadcastEvent::InvalidMessage(
+                d,
+                topic,
+                RejectReason::InvalidStamp
+            )]
+        );
+    }
+
+    #[test]
+    fn test_local_events() {
+        let topic = Topic::new(b"topic");
+        let mut params = DummyPollParameters(PeerId::random());
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut a = Broadcast::new(BroadcastConfig::default());
+        let peer = PeerId::random();
+        a.inject_connected(&peer);
+        while a.poll(&mut cx, &mut params).is_ready() {}
+
+        a.subscribe(topic).unwrap().detach();
+        assert!(matches!(
+            a.poll(&mut cx, &mut params),
+            Poll::Ready(NetworkBehaviourAction::NotifyHandler {
+                event: HandlerIn::Send(Message::Subscribe(_)),
+                ..
+            })
+        ));
+        for expected in [
+            BroadcastEvent::LocalSubscribed(topic),
+            BroadcastEvent::NotifiedPeers(topic, 1),
+        ] {
+            match a.poll(&mut cx, &mut params) {
+                Poll::Ready(NetworkBehaviourAction::GenerateEvent(event)) => {
+                    assert_eq!(event, expected)
+                }
+                _ => panic!("expected {:?}", expected),
+            }
+        }
+
+        // The peer isn't subscribed and isn't notified.
+        a.unsubscribe(&topic).unwrap();
+        for expected in [
+            BroadcastEvent::LocalUnsubscribed(topic),
+            BroadcastEvent::NotifiedPeers(topic, 0),
+        ] {
+            match a.poll(&mut cx, &mut params) {
+                Poll::Ready(NetworkBehaviourAction::GenerateEvent(event)) => {
+                    assert_eq!(event, expected)
+                }
+                _ => panic!("expected {:?}", expected),
+            }
+        }
+    }
+
+    #[test]
+    fn test_waker() {
+        let mut params = DummyPollParameters(PeerId::random());
+        struct CountingWaker(std::sync::atomic::AtomicUsize);
+
+        impl futures::task::ArcWake for CountingWaker {
+            fn wake_by_ref(arc_self: &Arc<Self>) {
+                arc_self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            }
+        }
+
+        let topic = Topic::new(b"topic");
+        let counter = Arc::new(CountingWaker(Default::default()));
+        let waker = futures::task::waker(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+        let woken = || counter.0.swap(0, std::sync::atomic::Ordering::SeqCst);
+        let mut a = Broadcast::new(BroadcastConfig::default());
+        let client = a.client();
+        let peer = PeerId::random();
+        a.inject_connected(&peer);
+        while a.poll(&mut cx, &mut params).is_ready() {}
+        woken();
+
+        a.subscribe(topic).unwrap().detach();
+        assert_eq!(woken(), 1);
+        // Woken once until the behaviour is polled again.
+        a.inject_event(
+            peer,
+            ConnectionId::new(0),
+            HandlerEvent::Rx(Message::Subscribe(topic)),
+        );
+        assert_eq!(woken(), 0);
+        while a.poll(&mut cx, &mut params).is_ready() {}
+
+        a.inject_event(
+            peer,
+            ConnectionId::new(0),
+            HandlerEvent::Rx(Message::Unsubscribe(topic)),
+        );
+        assert_eq!(woken(), 1);
+        while a.poll(&mut cx, &mut params).is_ready() {}
+
+        std::thread::spawn(move || {
+            let waker = futures::task::noop_waker();
+            let mut cx = Context::from_waker(&waker);
+            let mut unsubscribe = Box::pin(client.unsubscribe(topic));
+            assert!(unsubscribe.poll_unpin(&mut cx).is_pending());
+        })
+        .join()
+        .unwrap();
+        assert_eq!(woken(), 1);
+        assert!(a.poll(&mut cx, &mut params).is_ready());
+    }
+
+    #[test]
+    fn test_client() {
+        let topic = Topic::new(b"topic");
+        let msg = Bytes::from_static(b"msg");
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(BroadcastConfig::default());
+        let b = net.add_node(BroadcastConfig::default());
+        net.connect(&a, &b);
+        net.node(&b).subscribe(topic).unwrap().detach();
+        net.run();
+        assert!(events_of(&mut net, &b).is_empty());
+        let client_a = net.node(&a).client();
+        let client_b = net.node(&b).client();
+        let mut messages = client_b.messages(topic);
+        // Registers the stream.
+        net.run();
+        assert!(events_of(&mut net, &b).is_empty());
+
+        let mut subscribe = Box::pin(client_a.subscribe(topic));
+        assert!(subscribe.poll_unpin(&mut cx).is_pending());
+        net.run();
+        match subscribe.poll_unpin(&mut cx) {
+            Poll::Ready(Ok(handle)) => handle.detach(),
+            _ => panic!("expected subscription handle"),
+        }
+        events_of(&mut net, &a);
+
+        let client = client_a.clone();
+        let mut broadcast = Box::pin(client.broadcast(topic, msg.clone()));
+        assert!(broadcast.poll_unpin(&mut cx).is_pending());
+        net.run();
+        assert!(events_of(&mut net, &a).is_empty());
+        assert_eq!(broadcast.poll_unpin(&mut cx), Poll::Ready(Ok(())));
+        events_of(&mut net, &b);
+        assert_eq!(
+            messages.poll_next_unpin(&mut cx),
+            Poll::Ready(Some((a, msg)))
+        );
+
+        drop(net);
+        let mut unsubscribe = Box::pin(client_a.unsubscribe(topic));
+        assert_eq!(
+            unsubscribe.poll_unpin(&mut cx),
+            Poll::Ready(Err(BroadcastError::ShuttingDown))
+        );
+    }
+
+    #[test]
+    fn test_gap_detection() {
+        let topic = Topic::new(b"topic");
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(BroadcastConfig::default().sequence_numbers());
+        let b = net.add_node(BroadcastConfig::default());
+        net.connect(&a, &b);
+        net.node(&b).subscribe(topic).unwrap().detach();
+        net.run();
+        assert!(events_of(&mut net, &b).is_empty());
+        events_of(&mut net, &a);
+
+        net.node(&a)
+            .broadcast(&topic, Bytes::from_static(b"0"))
+            .unwrap();
+        net.run();
+        assert!(events_of(&mut net, &a).is_empty());
+        assert!(matches!(
+            events_of(&mut net, &b).as_slice(),
+            [BroadcastEvent::Received(..)]
+        ));
+
+        // Messages 1 and 2 are published while b is disconnected.
+        net.disconnect(&a, &b);
+        let _ = net.node(&a).broadcast(&topic, Bytes::from_static(b"1"));
+        let _ = net.node(&a).broadcast(&topic, Bytes::from_static(b"2"));
+        net.connect(&a, &b);
+        net.run();
+        assert!(events_of(&mut net, &b).is_empty());
+        events_of(&mut net, &a);
+        net.node(&a)
+            .broadcast(&topic, Bytes::from_static(b"3"))
+            .unwrap();
+        net.run();
+        assert!(events_of(&mut net, &a).is_empty());
+        let events = events_of(&mut net, &b);
+        assert_eq!(events[0], BroadcastEvent::GapDetected(a, topic, 1..3));
+        assert!(matches!(events[1], BroadcastEvent::Received(..)));
+    }
+
+    #[test]
+    fn test_sequence_numbers_with_fanout() {
+        let topic = Topic::new(b"topic");
+        let config = BroadcastConfig::default().sequence_numbers().max_fanout(1);
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(config);
+        let peers: Vec<_> = (0..3)
+            .map(|_| net.add_node(BroadcastConfig::default()))
+            .collect();
+        for peer in &peers {
+            net.connect(&a, peer);
+            net.node(peer).subscribe(topic).unwrap().detach();
+        }
+        net.run();
+        for peer in &peers {
+            assert!(events_of(&mut net, peer).is_empty());
+        }
+        events_of(&mut net, &a);
+
+        // Each message reaches one peer, none of them sees a gap.
+        for i in 0..10u8 {
+            net.node(&a)
+                .broadcast(&topic, Bytes::from(vec![i]))
+                .unwrap();
+        }
+        net.run();
+        assert!(events_of(&mut net, &a).is_empty());
+        let mut received = 0;
+        for peer in &peers {
+            for ev in events_of(&mut net, peer) {
+                assert!(matches!(ev, BroadcastEvent::Received(..)), "{:?}", ev);
+                received += 1;
+            }
+        }
+        assert_eq!(received, 10);
+    }
+
+    #[test]
+    fn test_reorder_window() {
+        let topic = Topic::new(b"topic");
+        let mut params = DummyPollParameters(PeerId::random());
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let peer = PeerId::random();
+        let config = BroadcastConfig::default().reorder_window(4, Duration::from_secs(60));
+        let mut a = Broadcast::new(config);
+        a.subscribe(topic).unwrap().detach();
+        a.inject_connected(&peer);
+        for seqno in [0, 2, 1] {
+            let ext = Extensions {
+                seqno: Some(seqno),
+                ..Default::default()
+            };
+            let msg = Message::Broadcast(topic, ext, Bytes::from(vec![seqno as u8]));
+            a.inject_handler_event(peer, HandlerEvent::Rx(msg));
+        }
+        let mut received = Vec::new();
+        while let Poll::Ready(action) = a.poll(&mut cx, &mut params) {
+            if let NetworkBehaviourAction::GenerateEvent(BroadcastEvent::Received(_, _, _, msg)) =
+                action
+            {
+                received.push(msg[0]);
+            }
+        }
+        assert_eq!(received, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_total_order() {
+        let topic = Topic::new(b"topic");
+        let mut params = DummyPollParameters(PeerId::random());
+        let local = *params.local_peer_id();
+        let peer = |lower: bool| loop {
+            let peer = PeerId::random();
+            if (peer < local) == lower {
+                return peer;
+            }
+        };
+        let new = |peers: &[PeerId]| {
+            let mut a = Broadcast::new(BroadcastConfig::default().total_order(topic));
+            a.subscribe(topic).unwrap().detach();
+            for peer in peers {
+                a.inject_connected(peer);
+                a.inject_handler_event(*peer, HandlerEvent::Rx(Message::Subscribe(topic)));
+            }
+            a
+        };
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut poll = |a: &mut Broadcast| {
+            let (mut sent, mut events) = (Vec::new(), Vec::new());
+            while let Poll::Ready(action) = a.poll(&mut cx, &mut params) {
+                match action {
+                    NetworkBehaviourAction::NotifyHandler { peer_id, event, .. } => {
+                        if let Some(Message::Broadcast(_, ext, msg)) = event.message() {
+                            sent.push((peer_id, ext.order, msg.clone()));
+                        }
+                    }
+                    NetworkBehaviourAction::GenerateEvent(ev) => events.push(ev),
+                    _ => {}
+                }
+            }
+            sent.sort();
+            (sent, events)
+        };
+        let received = |events: &[BroadcastEvent]| -> Vec<Bytes> {
+            events
+                .iter()
+                .filter_map(|ev| match ev {
+                    BroadcastEvent::Received(_, _, _, msg) => Some(msg.clone()),
+                    _ => None,
+                })
+                .collect()
+        };
+        let (first, second) = (Bytes::from_static(b"first"), Bytes::from_static(b"second"));
+
+        // We stamp our messages and those of the other subscribers.
+        let (b, c) = (peer(false), peer(false));
+        let mut a = new(&[b, c]);
+        let (_, events) = poll(&mut a);
+        assert!(events.contains(&BroadcastEvent::SequencerChanged(topic, Some(local))));
+        a.broadcast(&topic, first.clone()).unwrap();
+        let msg = Message::Broadcast(topic, Extensions::default(), second.clone());
+        a.inject_handler_event(b, HandlerEvent::Rx(msg));
+        let (sent, events) = poll(&mut a);
+        let mut expected = vec![
+            (b, Some(0), first.clone()),
+            (c, Some(0), first.clone()),
+            (c, Some(1), second.clone()),
+        ];
+        expected.sort();
+        assert_eq!(sent, expected);
+        assert_eq!(received(&events), vec![second.clone()]);
+
+        // Our messages are sent to the sequencer, whose are delivered in order.
+        let (sequencer, c) = (peer(true), peer(false));
+        let mut a = new(&[sequencer, c]);
+        let (_, events) = poll(&mut a);
+        assert!(events.contains(&BroadcastEvent::SequencerChanged(topic, Some(sequencer))));
+        a.broadcast(&topic, first.clone()).unwrap();
+        let (sent, _) = poll(&mut a);
+        assert_eq!(sent, vec![(sequencer, None, first.clone())]);
+        let third = Bytes::from_static(b"third");
+        for (order, msg) in [(0, &first), (2, &third), (1, &second)] {
+            let ext = Extensions {
+                order: Some(order),
+                ..Default::default()
+            };
+            let msg = Message::Broadcast(topic, ext, msg.clone());
+            a.inject_handler_event(sequencer, HandlerEvent::Rx(msg));
+        }
+        let (sent, events) = poll(&mut a);
+        assert!(sent.is_empty());
+        assert_eq!(received(&events), vec![first, second, third]);
+    }
+
+    #[test]
+    fn test_topic_gc() {
+        let (topic, other) = (Topic::new(b"topic"), Topic::new(b"other"));
+        let mut params = DummyPollParameters(PeerId::random());
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let peer = PeerId::random();
+        let config = BroadcastConfig::default().topic_gc(Duration::from_secs(60), Duration::ZERO);
+        let mut a = Broadcast::new(config);
+        a.subscribe(other).unwrap().detach();
+        a.inject_connected(&peer);
+        for msg in [
+            Message::Subscribe(topic),
+            Message::Subscribe(other),
+            Message::Unsubscribe(topic),
+            Message::Unsubscribe(other),
+        ] {
+            a.inject_handler_event(peer, HandlerEvent::Rx(msg));
+        }
+        // Not waiting for the timer to fire.
+        a.collect_topics();
+        let mut forgotten = Vec::new();
+        while let Poll::Ready(action) = a.poll(&mut cx, &mut params) {
+            if let NetworkBehaviourAction::GenerateEvent(BroadcastEvent::TopicForgotten(topic)) =
+                action
+            {
+                forgotten.push(topic);
+            }
+        }
+        // Topics we're subscribed to are kept.
+        assert_eq!(forgotten, vec![topic]);
+        assert!(!a.topics.contains_key(&topic));
+        assert!(a.topics.contains_key(&other));
+    }
+
+    #[test]
+    fn test_topic_heartbeat() {
+        let topic = Topic::new(b"topic");
+        let config = BroadcastConfig::default().topic_heartbeat(Duration::from_secs(60), 2);
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(config.clone());
+        let b = net.add_node(config);
+        net.node(&a).subscribe(topic).unwrap().detach();
+        net.node(&b).subscribe(topic).unwrap().detach();
+        net.connect(&a, &b);
+        net.run();
+        events_of(&mut net, &a);
+        events_of(&mut net, &b);
+
+        for _ in 0..3 {
+            net.node(&a).send_heartbeats();
+            net.node(&b).send_heartbeats();
+            net.run();
+            assert!(events_of(&mut net, &a).is_empty());
+            assert!(events_of(&mut net, &b).is_empty());
+        }
+
+        // b stops sending heartbeats.
+        net.node(&a).send_heartbeats();
+        net.run();
+        assert!(events_of(&mut net, &a).is_empty());
+        net.node(&a).send_heartbeats();
+        net.run();
+        assert_eq!(
+            events_of(&mut net, &a),
+            vec![BroadcastEvent::PeerUnresponsive(b, topic)]
+        );
+    }
+
+    #[test]
+    fn test_all_topics() {
+        let (topic, other, prefix) = (
+            Topic::new(b"topic"),
+            Topic::new(b"other"),
+            Topic::new(b"oth"),
+        );
+        let (b, c) = (PeerId::random(), PeerId::random());
+        let mut a = Broadcast::new(BroadcastConfig::default());
+        a.inject_connected(&b);
+        a.inject_connected(&c);
+        a.inject_handler_event(b, HandlerEvent::Rx(Message::Subscribe(topic)));
+        a.inject_handler_event(c, HandlerEvent::Rx(Message::Subscribe(topic)));
+        a.inject_handler_event(b, HandlerEvent::Rx(Message::Subscribe(other)));
+        a.inject_handler_event(c, HandlerEvent::Rx(Message::SubscribePrefix(prefix)));
+        let mut topics: Vec<_> = a.all_topics().map(|(topic, n)| (*topic, n)).collect();
+        topics.sort();
+        let mut expected = vec![(topic, 2), (other, 2)];
+        expected.sort();
+        assert_eq!(topics, expected);
+        assert_eq!(a.subscriber_count(&Topic::new(b"other2")), 1);
+
+        a.inject_handler_event(b, HandlerEvent::Rx(Message::Unsubscribe(other)));
+        a.inject_handler_event(c, HandlerEvent::Rx(Message::UnsubscribePrefix(prefix)));
+        assert_eq!(a.all_topics().collect::<Vec<_>>(), vec![(&topic, 2)]);
+        assert_eq!(a.subscriber_count(&other), 0);
+    }
+
+    #[test]
+    fn test_discovery() {
+        struct MockDiscovery {
+            lookups: Arc<Mutex<Vec<Topic>>>,
+            peers: Vec<(PeerId, Multiaddr)>,
+        }
+
+        impl Discovery for MockDiscovery {
+            fn discover(
+                &mut self,
+                topic: &Topic,
+            ) -> futures::stream::BoxStream<'static, (PeerId, Multiaddr)> {
+                self.lookups.lock().unwrap().push(*topic);
+                futures::stream::iter(self.peers.clone()).boxed()
+            }
+        }
+
+        let topic = Topic::new(b"topic");
+        let mut params = DummyPollParameters(PeerId::random());
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let (found, connected) = (PeerId::random(), PeerId::random());
+        let address: Multiaddr = "/memory/1".parse().unwrap();
+        let lookups = Arc::new(Mutex::new(Vec::new()));
+        let mut a = Broadcast::new(BroadcastConfig::default().min_peers(1));
+        a.inject_connected(&connected);
+        a.set_discovery(MockDiscovery {
+            lookups: lookups.clone(),
+            peers: vec![(found, address.clone()), (connected, address.clone())],
+        });
+
+        a.subscribe(topic).unwrap().detach();
+        let mut dialed = Vec::new();
+        while let Poll::Ready(action) = a.poll(&mut cx, &mut params) {
+            if let NetworkBehaviourAction::Dial { opts, .. } = action {
+                dialed.extend(opts.get_peer_id());
+            }
+        }
+        assert_eq!(*lookups.lock().unwrap(), vec![topic]);
+        assert_eq!(dialed, vec![found]);
+        assert_eq!(a.addresses_of_peer(&found), vec![address]);
+
+        // Once connected the address is forgotten, a lookup starts again
+        // when the topic loses its last subscriber.
+        a.inject_connected(&found);
+        assert!(a.addresses_of_peer(&found).is_empty());
+        a.inject_handler_event(found, HandlerEvent::Rx(Message::Subscribe(topic)));
+        a.inject_handler_event(found, HandlerEvent::Rx(Message::Unsubscribe(topic)));
+        assert_eq!(*lookups.lock().unwrap(), vec![topic, topic]);
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let topic = Topic::new(b"topic");
+        let mut params = DummyPollParameters(PeerId::random());
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let peer = PeerId::random();
+        let mut a = Broadcast::new(BroadcastConfig::default().max_buffered_bytes(4));
+        a.inject_connected(&peer);
+        a.inject_handler_event(peer, HandlerEvent::Rx(Message::Subscribe(topic)));
+        while a.poll(&mut cx, &mut params).is_ready() {}
+
+        let mut drain = |a: &mut Broadcast| {
+            let mut sent = Vec::new();
+            let mut evicted = Vec::new();
+            while let Poll::Ready(action) = a.poll(&mut cx, &mut params) {
+                match action {
+                    NetworkBehaviourAction::NotifyHandler { event, .. } => {
+                        if let Some(Message::Broadcast(_, _, msg)) = event.message() {
+                            sent.push(msg.clone());
+                        }
+                    }
+                    NetworkBehaviourAction::GenerateEvent(BroadcastEvent::Evicted(
+                        peer,
+                        _,
+                        len,
+                    )) => evicted.push((peer, len)),
+                    _ => {}
+                }
+            }
+            (sent, evicted)
+        };
+
+        for msg in [&b"ab"[..], b"cd", b"ef"] {
+            a.broadcast(&topic, msg).unwrap();
+        }
+        let (sent, evicted) = drain(&mut a);
+        assert_eq!(
+            sent,
+            vec![Bytes::from_static(b"cd"), Bytes::from_static(b"ef")]
+        );
+        assert_eq!(evicted, vec![(Some(peer), 2)]);
+
+        // Queued messages are dropped before retained ones.
+        a.broadcast(&topic, Bytes::from_static(b"gh")).unwrap();
+        a.broadcast_retained(&Topic::new(b"other"), Bytes::from_static(b"ijklm"))
+            .unwrap_err();
+        let (sent, evicted) = drain(&mut a);
+        assert!(sent.is_empty());
+        assert_eq!(evicted, vec![(Some(peer), 2), (None, 5)]);
+        assert_eq!(a.events.bytes(), 0);
+    }
+
+    #[test]
+    fn test_peer_connection_events() {
+        let (t1, t2) = (Topic::new(b"t1"), Topic::new(b"t2"));
+        let mut params = DummyPollParameters(PeerId::random());
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let peer = PeerId::random();
+        let mut a = Broadcast::new(BroadcastConfig::default());
+        let mut events = |a: &mut Broadcast| {
+            let mut events = Vec::new();
+            while let Poll::Ready(action) = a.poll(&mut cx, &mut params) {
+                if let NetworkBehaviourAction::GenerateEvent(
+                    event @ (BroadcastEvent::PeerConnected(_)
+                    | BroadcastEvent::PeerDisconnected(..)),
+                ) = action
+                {
+                    events.push(event);
+                }
+            }
+            events
+        };
+
+        a.inject_connected(&peer);
+        a.inject_handler_event(peer, HandlerEvent::Rx(Message::SubscribeMany(vec![t2, t1])));
+        assert_eq!(events(&mut a), vec![BroadcastEvent::PeerConnected(peer)]);
+        a.inject_disconnected(&peer);
+        assert_eq!(
+            events(&mut a),
+            vec![BroadcastEvent::PeerDisconnected(peer, vec![t1, t2])]
+        );
+    }
+
+    #[test]
+    fn test_broadcast_many() {
+        let (t1, t2) = (Topic::new(b"t1"), Topic::new(b"t2"));
+        let mut params = DummyPollParameters(PeerId::random());
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let (p1, p2) = (PeerId::random(), PeerId::random());
+        let mut a = Broadcast::new(BroadcastConfig::default());
+        a.inject_connected(&p1);
+        a.inject_connected(&p2);
+        a.inject_handler_event(p1, HandlerEvent::Rx(Message::SubscribeMany(vec![t1, t2])));
+        a.inject_handler_event(p2, HandlerEvent::Rx(Message::Subscribe(t2)));
+        while a.poll(&mut cx, &mut params).is_ready() {}
+
+        let msgs = [
+            (t1, Bytes::from_static(b"a")),
+            (t2, Bytes::from_static(b"b")),
+        ];
+        a.broadcast_many(&msgs).unwrap();
+        let mut sent = FnvHashMap::default();
+        while let Poll::Ready(action) = a.poll(&mut cx, &mut params) {
+            if let NetworkBehaviourAction::NotifyHandler {
+                peer_id,
+                event: HandlerIn::Send(msg),
+                ..
+            } = action
+            {
+                assert!(sent.insert(peer_id, msg).is_none());
+            }
+        }
+        let payloads = |msg: &Message| {
+            let msgs = match msg {
+                Message::Batch(msgs) => msgs.clone(),
+                msg => vec![msg.clone()],
+            };
+            msgs.into_iter()
+                .map(|msg| match msg {
+                    Message::Broadcast(_, _, payload) => payload,
+                    _ => panic!(),
+                })
+                .collect::<Vec<_>>()
+        };
+        assert!(matches!(sent[&p1], Message::Batch(_)));
+        assert_eq!(payloads(&sent[&p1]), vec![&b"a"[..], b"b"]);
+        assert_eq!(payloads(&sent[&p2]), vec![&b"b"[..]]);
+        assert_eq!(a.events.bytes(), 0);
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let topic = Topic::new(b"topic");
+        let mut params = DummyPollParameters(PeerId::random());
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let peer = PeerId::random();
+        let endpoint = libp2p::core::ConnectedPoint::Listener {
+            local_addr: "/memory/1".parse().unwrap(),
+            send_back_addr: "/memory/2".parse().unwrap(),
+        };
+        let (first, second) = (ConnectionId::new(1), ConnectionId::new(2));
+        let mut a = Broadcast::new(BroadcastConfig::default());
+        a.inject_connection_established(&peer, &first, &endpoint, None, 0);
+        a.inject_connection_established(&peer, &second, &endpoint, None, 1);
+        a.inject_handler_event(peer, HandlerEvent::Rx(Message::Subscribe(topic)));
+        a.subscribe(topic).unwrap().detach();
+        while a.poll(&mut cx, &mut params).is_ready() {}
+
+        let mut shutdown = Box::pin(a.shutdown());
+        let mut unsubscribed = false;
+        let mut flushing = Vec::new();
+        while let Poll::Ready(action) = a.poll(&mut cx, &mut params) {
+            match action {
+                NetworkBehaviourAction::NotifyHandler {
+                    event: HandlerIn::Send(Message::Unsubscribe(_)),
+                    ..
+                } => unsubscribed = true,
+                NetworkBehaviourAction::NotifyHandler {
+                    event: HandlerIn::Flush,
+                    handler: NotifyHandler::One(connection),
+                    ..
+                } => flushing.push(connection),
+                _ => {}
+            }
+        }
+        assert!(unsubscribed);
+        assert_eq!(flushing.len(), 2);
+        assert!(!a.is_subscribed(&topic));
+        assert_eq!(a.subscribe(topic).err(), Some(BroadcastError::ShuttingDown));
+        assert_eq!(
+            a.broadcast(&topic, Bytes::from_static(b"msg")),
+            Err(BroadcastError::ShuttingDown)
+        );
+
+        a.inject_event(peer, first, HandlerEvent::Flushed);
+        assert!(shutdown.poll_unpin(&mut cx).is_pending());
+        let handler = a.new_handler();
+        a.inject_connection_closed(&peer, &second, &endpoint, handler, 1);
+        assert!(shutdown.poll_unpin(&mut cx).is_ready());
+    }
+
+    #[test]
+    fn test_pending_to_peer() {
+        let topic = Topic::new(b"topic");
+        let mut params = DummyPollParameters(PeerId::random());
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let peer = PeerId::random();
+        let endpoint = libp2p::core::ConnectedPoint::Listener {
+            local_addr: "/memory/1".parse().unwrap(),
+            send_back_addr: "/memory/2".parse().unwrap(),
+        };
+        let connection = ConnectionId::new(1);
+        let mut a = Broadcast::new(BroadcastConfig::default());
+        a.inject_connection_established(&peer, &connection, &endpoint, None, 0);
+        a.inject_handler_event(peer, HandlerEvent::Rx(Message::Subscribe(topic)));
+        while a.poll(&mut cx, &mut params).is_ready() {}
+        assert_eq!(a.pending_to_peer(&peer), 0);
+
+        // Counted until handed to the handler.
+        a.broadcast(&topic, Bytes::from_static(b"msg")).unwrap();
+        assert_eq!(a.pending_to_peer(&peer), 1);
+        assert_eq!(a.pending_bytes_to_peer(&peer), 3);
+        assert_eq!(a.pending_to_peer(&PeerId::random()), 0);
+        while a.poll(&mut cx, &mut params).is_ready() {}
+        assert_eq!(a.pending_to_peer(&peer), 0);
+
+        // Reported once nothing is pending anymore, even if we kept
+        // publishing after the handler became backlogged.
+        a.inject_event(peer, connection, HandlerEvent::Backlogged);
+        a.broadcast(&topic, Bytes::from_static(b"more")).unwrap();
+        let mut actions = vec![];
+        while let Poll::Ready(action) = a.poll(&mut cx, &mut params) {
+            actions.push(action);
+        }
+        let sent = actions.iter().position(|action| {
+            matches!(action, NetworkBehaviourAction::NotifyHandler { peer_id, .. } if *peer_id == peer)
+        });
+        let drained = actions.iter().position(|action| {
+            matches!(
+                action,
+                NetworkBehaviourAction::GenerateEvent(BroadcastEvent::QueueDrained(drained))
+                    if *drained == peer
+            )
+        });
+        assert!(sent.unwrap() < drained.unwrap());
+        assert!(a.backlogged.is_empty());
+    }
+
+    #[test]
+    fn test_broadcast_tracked() {
+        let topic = Topic::new(b"topic");
+        let mut params = DummyPollParameters(PeerId::random());
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let (b, c) = (PeerId::random(), PeerId::random());
+        let mut a = Broadcast::new(BroadcastConfig::default());
+        for peer in [b, c] {
+            a.inject_connected(&peer);
+            a.inject_handler_event(peer, HandlerEvent::Rx(Message::Subscribe(topic)));
+        }
+        while a.poll(&mut cx, &mut params).is_ready() {}
+
+        let id = a
+            .broadcast_tracked(&topic, Bytes::from_static(b"msg"))
+            .unwrap();
+        let mut tracked = 0;
+        while let Poll::Ready(action) = a.poll(&mut cx, &mut params) {
+            if let NetworkBehaviourAction::NotifyHandler {
+                event: HandlerIn::SendShared(_, Some(sent), _),
+                ..
+            } = action
+            {
+                assert_eq!(sent, id);
+                tracked += 1;
+            }
+        }
+        assert_eq!(tracked, 2);
+
+        let error = DeliveryError::Io(io::ErrorKind::BrokenPipe);
+        a.inject_event(b, ConnectionId::new(0), HandlerEvent::Delivered(id));
+        a.inject_event(c, ConnectionId::new(0), HandlerEvent::SendFailed(id, error));
+        let mut events = Vec::new();
+        while let Poll::Ready(NetworkBehaviourAction::GenerateEvent(event)) =
+            a.poll(&mut cx, &mut params)
+        {
+            events.push(event);
+        }
+        assert_eq!(
+            events,
+            vec![
+                BroadcastEvent::Delivered { id, peer: b },
+                BroadcastEvent::SendFailed { id, peer: c, error },
+                BroadcastEvent::DeliveryComplete {
+                    id,
+                    delivered: 1,
+                    failed: 1
+                },
+            ]
+        );
+
+        assert_eq!(
+            a.broadcast_tracked(&Topic::new(b"other"), Bytes::from_static(b"msg")),
+            Err(BroadcastError::NoPeers)
+        );
+    }
+
+    #[test]
+    fn test_subscription_lease() {
+        let topic = Topic::new(b"topic");
+        let config = BroadcastConfig::default().subscription_lease(Duration::from_secs(60));
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(BroadcastConfig::default());
+        let b = net.add_node(config);
+        net.connect(&a, &b);
+        net.run();
+
+        net.node(&b).subscribe(topic).unwrap().detach();
+        net.run();
+        assert!(events_of(&mut net, &b).is_empty());
+        assert_eq!(
+            events_of(&mut net, &a),
+            vec![BroadcastEvent::Subscribed(b, topic)]
+        );
+        let a = net.node(&a);
+        assert!(a.leases.remove(&b, &topic));
+
+        // Renewals don't generate events, expired leases drop the peer.
+        let lease = |ttl| HandlerEvent::Rx(Message::SubscribeLease(topic, ttl));
+        a.inject_handler_event(b, lease(Duration::from_secs(60)));
+        a.inject_handler_event(b, lease(Duration::ZERO));
+        let mut params = DummyPollParameters(PeerId::random());
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        assert!(matches!(
+            a.poll(&mut cx, &mut params),
+            Poll::Ready(NetworkBehaviourAction::GenerateEvent(
+                BroadcastEvent::SubscriptionExpired(p, t)
+            )) if p == b && t == topic
+        ));
+        assert_eq!(
+            a.broadcast(&topic, Bytes::from_static(b"msg")),
+            Err(BroadcastError::NoPeers)
+        );
+    }
+
+    #[test]
+    fn test_deliver_own_messages() {
+        let topic = Topic::new(b"topic");
+        let msg = Bytes::from_static(b"msg");
+        let config = BroadcastConfig::default().deliver_own_messages(true);
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(config);
+        let b = net.add_node(BroadcastConfig::default());
+        net.connect(&a, &b);
+        net.run();
+
+        // Not subscribed and no peers.
+        assert_eq!(
+            net.node(&a).broadcast(&topic, msg.clone()),
+            Err(BroadcastError::NoPeers)
+        );
+        // The local copy doesn't count as sent.
+        net.node(&a).subscribe(topic).unwrap().detach();
+        net.run();
+        events_of(&mut net, &a);
+        events_of(&mut net, &b);
+        assert_eq!(
+            net.node(&a).broadcast(&topic, msg.clone()),
+            Err(BroadcastError::NoPeers)
+        );
+        net.run();
+        assert!(events_of(&mut net, &a).is_empty());
+
+        net.node(&b).subscribe(topic).unwrap().detach();
+        net.run();
+        events_of(&mut net, &a);
+        events_of(&mut net, &b);
+        let mut stream = net.node(&a).topic_stream(topic);
+        net.node(&a).broadcast(&topic, msg.clone()).unwrap();
+        net.run();
+        let received =
+            BroadcastEvent::Received(a, topic, MessageId::new(&topic, &msg), msg.clone());
+        assert_eq!(events_of(&mut net, &a), vec![received.clone()]);
+        assert_eq!(stream.next().now_or_never(), Some(Some((a, msg))));
+        assert_eq!(events_of(&mut net, &b), vec![received]);
+    }
+
+    #[test]
+    fn test_no_peers_policy() {
+        let topic = Topic::new(b"topic");
+        let msg = |i: u8| -> Bytes { Bytes::from(vec![i]) };
+        let policy = NoPeersPolicy::Buffer {
+            max: 2,
+            ttl: Duration::from_secs(60),
+        };
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(BroadcastConfig::default().no_peers_policy(policy));
+        let b = net.add_node(BroadcastConfig::default());
+        net.connect(&a, &b);
+        net.run();
+        for i in 0..3 {
+            net.node(&a).broadcast(&topic, msg(i)).unwrap();
+        }
+
+        // The first subscriber receives the latest buffered messages.
+        net.node(&b).subscribe(topic).unwrap().detach();
+        net.run();
+        let received: Vec<_> = events_of(&mut net, &b)
+            .into_iter()
+            .filter_map(|event| match event {
+                BroadcastEvent::Received(_, _, _, msg) => Some(msg),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(received, vec![msg(1), msg(2)]);
+
+        let mut c = Broadcast::new(BroadcastConfig::default().no_peers_policy(NoPeersPolicy::Drop));
+        assert_eq!(c.broadcast(&topic, msg(0)), Ok(()));
+    }
+
+    #[test]
+    fn test_peer_exchange() {
+        let topic = Topic::new(b"topic");
+        let address: Multiaddr = "/memory/1".parse().unwrap();
+        let keypair = Keypair::generate_ed25519();
+        let config = BroadcastConfig::default()
+            .peer_exchange(8)
+            .sign_messages(keypair)
+            .validation_mode(ValidationMode::Strict);
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(config);
+        let b = net.add_node(BroadcastConfig::default().validation_mode(ValidationMode::Strict));
+        let c = net.add_node(BroadcastConfig::default());
+        net.node(&c).subscribe(topic).unwrap().detach();
+        net.connect(&a, &c);
+        net.node(&a).addresses.insert(c, vec![address.clone()]);
+        net.connect(&a, &b);
+        net.run();
+
+        net.node(&b).subscribe(topic).unwrap().detach();
+        net.run();
+        let suggested = events_of(&mut net, &b)
+            .into_iter()
+            .find(|event| matches!(event, BroadcastEvent::PeersSuggested(..)));
+        assert_eq!(
+            suggested,
+            Some(BroadcastEvent::PeersSuggested(
+                topic,
+                vec![(c, vec![address.clone()])]
+            ))
+        );
+
+        // Suggested peers are dialed for topics we're subscribed to.
+        let mut params = DummyPollParameters(PeerId::random());
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let (peer, suggested) = (PeerId::random(), PeerId::random());
+        let mut d = Broadcast::new(BroadcastConfig::default().dial_suggested_peers(true));
+        d.subscribe(topic).unwrap().detach();
+        d.inject_connected(&peer);
+        let peers = vec![(suggested, vec![address.clone()])];
+        d.inject_handler_event(
+            peer,
+            HandlerEvent::Rx(Message::PeerExchange(topic, peers, None)),
+        );
+        let mut dialed = Vec::new();
+        while let Poll::Ready(action) = d.poll(&mut cx, &mut params) {
+            if let NetworkBehaviourAction::Dial { opts, .. } = action {
+                dialed.extend(opts.get_peer_id());
+            }
+        }
+        assert_eq!(dialed, vec![suggested]);
+        assert_eq!(d.addresses_of_peer(&suggested), vec![address]);
+    }
+
+    #[test]
+    fn test_dial_failure() {
+        let topic = Topic::new(b"topic");
+        let msg = Bytes::from_static(b"msg");
+        let peer = PeerId::random();
+        let config = BroadcastConfig::default().offline_queue(Default::default());
+        let mut a = Broadcast::new(config);
+        a.inject_connected(&peer);
+        a.inject_handler_event(peer, HandlerEvent::Rx(Message::Subscribe(topic)));
+        a.inject_disconnected(&peer);
+        assert_eq!(a.broadcast(&topic, msg), Ok(()));
+        let queued = |a: &Broadcast| {
+            a.events.iter().any(|action| {
+                matches!(
+                    action,
+                    NetworkBehaviourAction::NotifyHandler { peer_id, .. } if *peer_id == peer
+                )
+            })
+        };
+        a.notify(peer, Message::Heartbeat(topic));
+        assert!(queued(&a));
+
+        let handler = a.new_handler();
+        a.inject_dial_failure(Some(peer), handler, &DialError::NoAddresses);
+        assert!(!queued(&a));
+
+        // Messages buffered for banned peers are dropped.
+        let handler = a.new_handler();
+        a.inject_dial_failure(Some(peer), handler, &DialError::Banned);
+        a.inject_connected(&peer);
+        assert!(!a.events.iter().any(|action| matches!(
+            action,
+            NetworkBehaviourAction::GenerateEvent(BroadcastEvent::Replayed(..))
+        )));
+    }
+
+    #[test]
+    fn test_drop_queued() {
+        let topic = Topic::new(b"topic");
+        let msg = Bytes::from_static(b"msg");
+        let peer = PeerId::random();
+        let mut a = Broadcast::new(BroadcastConfig::default());
+        a.inject_connected(&peer);
+        a.inject_handler_event(peer, HandlerEvent::Rx(Message::Subscribe(topic)));
+        let id = a.broadcast_tracked(&topic, msg.clone()).unwrap();
+        assert_eq!(a.events.bytes(), 3);
+        a.inject_disconnected(&peer);
+        assert_eq!(a.events.bytes(), 0);
+        assert!(a.events.iter().any(|action| matches!(
+            action,
+            NetworkBehaviourAction::GenerateEvent(BroadcastEvent::SendFailed {
+                id: failed,
+                error: DeliveryError::ConnectionClosed,
+                ..
+            }) if *failed == id
+        )));
+
+        // Queued while dialing the peer.
+        a.send_broadcast(peer, Message::Broadcast(topic, Default::default(), msg));
+        assert_eq!(a.events.bytes(), 3);
+        let handler = a.new_handler();
+        a.inject_dial_failure(Some(peer), handler, &DialError::NoAddresses);
+        assert_eq!(a.events.bytes(), 0);
+    }
+
+    #[test]
+    fn test_address_change() {
+        let topic = Topic::new(b"topic");
+        let peer = PeerId::random();
+        let connection = ConnectionId::new(0);
+        let dialer = |address: &str| libp2p::core::ConnectedPoint::Dialer {
+            address: address.parse().unwrap(),
+            role_override: libp2p::core::Endpoint::Dialer,
+        };
+        let (old, new) = (dialer("/memory/1"), dialer("/memory/2"));
+        let mut a = Broadcast::new(BroadcastConfig::default());
+        a.subscribe(topic).unwrap().detach();
+        a.inject_connection_established(&peer, &connection, &old, None, 0);
+        a.events.clear();
+
+        a.inject_address_change(&peer, &connection, &old, &new);
+        assert_eq!(a.addresses[&peer], vec![new.get_remote_address().clone()]);
+        assert!(a.events.iter().any(|action| matches!(
+            action,
+            NetworkBehaviourAction::NotifyHandler {
+                event: HandlerIn::Send(Message::SyncTopics(topics)),
+                ..
+            } if topics == &vec![topic]
+        )));
+    }
+
+    #[test]
+    fn test_dial_on_broadcast() {
+        let topic = Topic::new(b"topic");
+        let mut params = DummyPollParameters(PeerId::random());
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let peer = PeerId::random();
+        let address: Multiaddr = "/memory/1".parse().unwrap();
+        let endpoint = libp2p::core::ConnectedPoint::Dialer {
+            address: address.clone(),
+            role_override: libp2p::core::Endpoint::Dialer,
+        };
+        let mut a = Broadcast::new(BroadcastConfig::default().dial_on_broadcast(1));
+        a.inject_connection_established(&peer, &ConnectionId::new(0), &endpoint, None, 0);
+        a.inject_connected(&peer);
+        a.inject_handler_event(peer, HandlerEvent::Rx(Message::Subscribe(topic)));
+        a.inject_disconnected(&peer);
+        let mut dialed = |a: &mut Broadcast| {
+            let mut dialed = Vec::new();
+            while let Poll::Ready(action) = a.poll(&mut cx, &mut params) {
+                if let NetworkBehaviourAction::Dial { opts, .. } = action {
+                    dialed.extend(opts.get_peer_id());
+                }
+            }
+            dialed
+        };
+        assert!(dialed(&mut a).is_empty());
+
+        let msg = Bytes::from_static(b"msg");
+        assert_eq!(
+            a.broadcast(&topic, msg.clone()),
+            Err(BroadcastError::NoPeers)
+        );
+        assert_eq!(dialed(&mut a), vec![peer]);
+        assert_eq!(a.addresses_of_peer(&peer), vec![address]);
+
+        // Not redialed right away.
+        assert!(a.broadcast(&topic, msg).is_err());
+        assert!(dialed(&mut a).is_empty());
+    }
+
+    #[test]
+    fn test_lazy_subscriptions() {
+        let (shared, only_a, later) = (
+            Topic::new(b"shared"),
+            Topic::new(b"only_a"),
+            Topic::new(b"later"),
+        );
+        let config = BroadcastConfig::default().lazy_subscriptions();
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(config.clone());
+        let b = net.add_node(config);
+        for topic in [shared, only_a, later] {
+            net.node(&a).subscribe(topic).unwrap().detach();
+        }
+        net.node(&b).subscribe(shared).unwrap().detach();
+        net.connect(&a, &b);
+        net.run();
+        let topics = |net: &mut TestNetwork, peer: &PeerId, other: &PeerId| {
+            let mut topics: Vec<_> = net.node(peer).peers[other].iter().copied().collect();
+            topics.sort();
+            topics
+        };
+        assert_eq!(topics(&mut net, &a, &b), vec![shared]);
+        assert_eq!(topics(&mut net, &b, &a), vec![shared]);
+
+        // Subscribing after the exchange announces the topic to everyone,
+        // peers subscribed to it answer with their subscription.
+        net.node(&b).subscribe(later).unwrap().detach();
+        net.run();
+        assert_eq!(topics(&mut net, &a, &b), vec![later, shared]);
+        assert_eq!(topics(&mut net, &b, &a), vec![later, shared]);
+    }
+
+    #[test]
+    fn test_observe() {
+        let (topic, other) = (Topic::new(b"topic"), Topic::new(b"other"));
+        let config = BroadcastConfig::default().lazy_subscriptions();
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(config.clone());
+        let b = net.add_node(config.clone());
+        let c = net.add_node(config);
+        net.node(&b).subscribe(topic).unwrap().detach();
+        net.node(&b).subscribe(other).unwrap().detach();
+        net.node(&a).observe(topic);
+        net.connect(&a, &b);
+        net.run();
+        let topics: Vec<_> = net.node(&a).peers[&b].iter().copied().collect();
+        assert_eq!(topics, vec![topic]);
+        // `b` wasn't told about any interest of `a`.
+        assert!(net.node(&b).peers[&a].is_empty());
+
+        // Observing a topic later asks connected peers for it.
+        net.node(&c).subscribe(other).unwrap().detach();
+        net.connect(&a, &c);
+        net.run();
+        net.node(&a).observe(other);
+        net.run();
+        let me = net.node(&a);
+        assert!(me.peers[&b].contains(&other));
+        assert!(me.peers[&c].contains(&other));
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let topic = Topic::new(b"topic");
+        let mut net = TestNetwork::new(0);
+        let a = net.add_node(BroadcastConfig::default());
+        let b = net.add_node(BroadcastConfig::default());
+        net.node(&a).subscribe(topic).unwrap().detach();
+        net.connect(&a, &b);
+        net.node(&b).subscribe(topic).unwrap().detach();
+        net.run();
+
+        let state = net.node(&a).snapshot();
+        assert_eq!(state.subscriptions, vec![topic]);
+        assert_eq!(state.topics, vec![TopicState { topic, peers: 1 }]);
+        assert_eq!(state.peers.len(), 1);
+        assert_eq!(state.peers[0].peer, b);
+        assert_eq!(state.peers[0].topics, vec![topic]);
+        assert_eq!(state.peers[0].queued, 0);
+
+        #[cfg(feature = "json")]
+        {
+            let json = serde_json::to_value(&state).unwrap();
+            assert_eq!(json["subscriptions"][0], "topic");
+            assert_eq!(json["peers"][0]["peer"], b.to_base58());
+        }
     }
 }
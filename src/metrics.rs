@@ -0,0 +1,142 @@
+use crate::Topic;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::registry::Registry;
+
+type TopicLabel = Vec<(String, String)>;
+
+fn label(topic: &Topic) -> TopicLabel {
+    vec![(
+        "topic".to_string(),
+        String::from_utf8_lossy(topic).into_owned(),
+    )]
+}
+
+/// Prometheus metrics recorded by the `Broadcast` behaviour.
+pub(crate) struct Metrics {
+    messages_sent: Family<TopicLabel, Counter>,
+    messages_received: Family<TopicLabel, Counter>,
+    messages_dropped: Family<TopicLabel, Counter>,
+    messages_expired: Family<TopicLabel, Counter>,
+    bytes_sent: Counter,
+    bytes_received: Counter,
+    message_size: Histogram,
+    subscriptions: Counter,
+    unsubscriptions: Counter,
+    topic_peers: Family<TopicLabel, Gauge>,
+    pending_events: Gauge,
+}
+
+impl Metrics {
+    pub fn new(registry: &mut Registry) -> Self {
+        let registry = registry.sub_registry_with_prefix("broadcast");
+        let metrics = Self {
+            messages_sent: Default::default(),
+            messages_received: Default::default(),
+            messages_dropped: Default::default(),
+            messages_expired: Default::default(),
+            bytes_sent: Default::default(),
+            bytes_received: Default::default(),
+            message_size: Histogram::new(exponential_buckets(64.0, 4.0, 10)),
+            subscriptions: Default::default(),
+            unsubscriptions: Default::default(),
+            topic_peers: Default::default(),
+            pending_events: Default::default(),
+        };
+        registry.register(
+            "messages_sent",
+            "Number of messages sent to peers per topic",
+            Box::new(metrics.messages_sent.clone()),
+        );
+        registry.register(
+            "messages_received",
+            "Number of messages received from peers per topic",
+            Box::new(metrics.messages_received.clone()),
+        );
+        registry.register(
+            "messages_dropped",
+            "Number of messages dropped because a send queue was full",
+            Box::new(metrics.messages_dropped.clone()),
+        );
+        registry.register(
+            "messages_expired",
+            "Number of messages dropped because their ttl expired",
+            Box::new(metrics.messages_expired.clone()),
+        );
+        registry.register(
+            "bytes_sent",
+            "Payload bytes sent to peers",
+            Box::new(metrics.bytes_sent.clone()),
+        );
+        registry.register(
+            "bytes_received",
+            "Payload bytes received from peers",
+            Box::new(metrics.bytes_received.clone()),
+        );
+        registry.register(
+            "message_size",
+            "Size of received payloads in bytes",
+            Box::new(metrics.message_size.clone()),
+        );
+        registry.register(
+            "subscriptions",
+            "Number of subscriptions received from peers",
+            Box::new(metrics.subscriptions.clone()),
+        );
+        registry.register(
+            "unsubscriptions",
+            "Number of unsubscriptions received from peers",
+            Box::new(metrics.unsubscriptions.clone()),
+        );
+        registry.register(
+            "topic_peers",
+            "Number of peers subscribed to a topic",
+            Box::new(metrics.topic_peers.clone()),
+        );
+        registry.register(
+            "pending_events",
+            "Number of actions queued in the behaviour",
+            Box::new(metrics.pending_events.clone()),
+        );
+        metrics
+    }
+
+    pub fn sent(&self, topic: &Topic, len: usize) {
+        self.messages_sent.get_or_create(&label(topic)).inc();
+        self.bytes_sent.inc_by(len as u64);
+    }
+
+    pub fn received(&self, topic: &Topic, len: usize) {
+        self.messages_received.get_or_create(&label(topic)).inc();
+        self.bytes_received.inc_by(len as u64);
+        self.message_size.observe(len as f64);
+    }
+
+    pub fn dropped(&self, topic: &Topic) {
+        self.messages_dropped.get_or_create(&label(topic)).inc();
+    }
+
+    pub fn expired(&self, topic: &Topic) {
+        self.messages_expired.get_or_create(&label(topic)).inc();
+    }
+
+    pub fn subscribed(&self) {
+        self.subscriptions.inc();
+    }
+
+    pub fn unsubscribed(&self) {
+        self.unsubscriptions.inc();
+    }
+
+    pub fn topic_peers(&self, topic: &Topic, peers: usize) {
+        self.topic_peers
+            .get_or_create(&label(topic))
+            .set(peers as u64);
+    }
+
+    pub fn pending_events(&self, len: usize) {
+        self.pending_events.set(len as u64);
+    }
+}
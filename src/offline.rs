@@ -0,0 +1,245 @@
+use crate::protocol::{EvictionPolicy, Message, Topic};
+use fnv::{FnvHashMap, FnvHashSet};
+use instant::Instant;
+use libp2p::PeerId;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Limits of the messages buffered for each disconnected peer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct OfflineQueue {
+    pub max_messages: usize,
+    pub max_bytes: usize,
+    /// Messages older than this are not replayed, peers disconnected for
+    /// longer are forgotten.
+    pub max_age: Duration,
+}
+
+impl Default for OfflineQueue {
+    fn default() -> Self {
+        Self {
+            max_messages: 128,
+            max_bytes: 1024 * 1024,
+            max_age: Duration::from_secs(60),
+        }
+    }
+}
+
+struct OfflinePeer {
+    disconnected: Instant,
+    topics: FnvHashSet<Topic>,
+    prefixes: FnvHashSet<Topic>,
+    messages: VecDeque<(Instant, Message)>,
+    bytes: usize,
+}
+
+impl OfflinePeer {
+    fn is_subscribed(&self, topic: &Topic) -> bool {
+        self.topics.contains(topic) || self.prefixes.iter().any(|prefix| topic.has_prefix(prefix))
+    }
+}
+
+fn payload_len(msg: &Message) -> usize {
+    match msg {
+        Message::Broadcast(_, _, payload) => payload.len(),
+        _ => 0,
+    }
+}
+
+/// Broadcasts buffered for recently disconnected peers.
+#[derive(Default)]
+pub(crate) struct OfflineQueues {
+    limits: Option<OfflineQueue>,
+    peers: FnvHashMap<PeerId, OfflinePeer>,
+}
+
+impl OfflineQueues {
+    pub fn new(limits: Option<OfflineQueue>) -> Self {
+        Self {
+            limits,
+            ..Default::default()
+        }
+    }
+
+    /// Starts buffering broadcasts for a peer that disconnected while
+    /// subscribed to `topics` or `prefixes`.
+    pub fn disconnected(
+        &mut self,
+        peer: PeerId,
+        topics: FnvHashSet<Topic>,
+        prefixes: FnvHashSet<Topic>,
+    ) {
+        if self.limits.is_none() || topics.is_empty() && prefixes.is_empty() {
+            return;
+        }
+        self.peers.insert(
+            peer,
+            OfflinePeer {
+                disconnected: Instant::now(),
+                topics,
+                prefixes,
+                messages: Default::default(),
+                bytes: 0,
+            },
+        );
+    }
+
+    /// Buffers a broadcast for all offline peers subscribed to `topic`,
+    /// evicting the oldest messages when a queue is full. Returns the number
+    /// of peers it was buffered for.
+    pub fn push(&mut self, topic: &Topic, msg: &Message) -> usize {
+        let limits = match self.limits {
+            Some(limits) => limits,
+            None => return 0,
+        };
+        let now = Instant::now();
+        self.peers
+            .retain(|_, peer| now.saturating_duration_since(peer.disconnected) <= limits.max_age);
+        let len = payload_len(msg);
+        if len > limits.max_bytes || limits.max_messages == 0 {
+            return 0;
+        }
+        let mut buffered = 0;
+        for peer in self.peers.values_mut() {
+            if !peer.is_subscribed(topic) {
+                continue;
+            }
+            while peer.messages.len() >= limits.max_messages || peer.bytes + len > limits.max_bytes
+            {
+                if let Some((_, msg)) = peer.messages.pop_front() {
+                    peer.bytes -= payload_len(&msg);
+                }
+            }
+            peer.messages.push_back((now, msg.clone()));
+            peer.bytes += len;
+            buffered += 1;
+        }
+        buffered
+    }
+
+    /// Payload bytes buffered for all peers.
+    pub fn bytes(&self) -> usize {
+        self.peers.values().map(|peer| peer.bytes).sum()
+    }
+
+    /// Number of messages buffered for each offline peer.
+    pub fn buffered(&self) -> Vec<(PeerId, usize)> {
+        self.peers
+            .iter()
+            .map(|(peer, queue)| (*peer, queue.messages.len()))
+            .collect()
+    }
+
+    /// Drops the message chosen by `policy`, returning its peer, topic and
+    /// payload size.
+    pub fn evict(&mut self, policy: EvictionPolicy) -> Option<(PeerId, Topic, usize)> {
+        let mut messages: Vec<_> = self
+            .peers
+            .iter()
+            .flat_map(|(peer_id, peer)| {
+                peer.messages
+                    .iter()
+                    .enumerate()
+                    .map(move |(i, (time, msg))| (*time, *peer_id, i, payload_len(msg)))
+            })
+            .collect();
+        messages.sort_by_key(|(time, ..)| *time);
+        let candidates: Vec<_> = messages
+            .iter()
+            .map(|(_, peer, _, len)| (Some(*peer), *len))
+            .collect();
+        let (_, peer_id, i, len) = messages[policy.select(&candidates)?];
+        let peer = self.peers.get_mut(&peer_id)?;
+        let (_, msg) = peer.messages.remove(i)?;
+        peer.bytes -= len;
+        match msg {
+            Message::Broadcast(topic, _, _) => Some((peer_id, topic, len)),
+            _ => None,
+        }
+    }
+
+    /// Drops the messages buffered for a peer that won't reconnect.
+    pub fn remove(&mut self, peer: &PeerId) {
+        self.peers.remove(peer);
+    }
+
+    /// Returns the unexpired messages buffered for a reconnected peer.
+    ///
+    /// Messages whose ttl expired are dropped as well.
+    pub fn reconnected(&mut self, peer: &PeerId) -> Vec<Message> {
+        let (limits, peer) = match (self.limits, self.peers.remove(peer)) {
+            (Some(limits), Some(peer)) => (limits, peer),
+            _ => return Vec::new(),
+        };
+        let now = Instant::now();
+        peer.messages
+            .into_iter()
+            .filter(|(time, _)| now.saturating_duration_since(*time) <= limits.max_age)
+            .map(|(_, msg)| msg)
+            .filter(|msg| !matches!(msg, Message::Broadcast(_, ext, _) if ext.is_expired()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Extensions;
+    use bytes::Bytes;
+
+    #[test]
+    fn test_offline_queue_limits() {
+        let topic = Topic::new(b"topic");
+        let peer = PeerId::random();
+        let limits = OfflineQueue {
+            max_messages: 2,
+            max_bytes: 5,
+            ..Default::default()
+        };
+        let mut queues = OfflineQueues::new(Some(limits));
+        queues.disconnected(peer, std::iter::once(topic).collect(), Default::default());
+        let msg = |payload: &[u8]| {
+            Message::Broadcast(
+                topic,
+                Extensions::default(),
+                Bytes::copy_from_slice(payload),
+            )
+        };
+        for payload in [&b"a"[..], b"b", b"c", b"toolong", b"defg"] {
+            queues.push(&topic, &msg(payload));
+        }
+        queues.push(&Topic::new(b"other"), &msg(b"h"));
+        assert_eq!(queues.reconnected(&peer), vec![msg(b"c"), msg(b"defg")]);
+        assert!(queues.reconnected(&peer).is_empty());
+    }
+
+    #[test]
+    fn test_offline_queue_evict() {
+        let topic = Topic::new(b"topic");
+        let peer = PeerId::random();
+        let mut queues = OfflineQueues::new(Some(OfflineQueue::default()));
+        queues.disconnected(peer, std::iter::once(topic).collect(), Default::default());
+        let msg = |payload: &[u8]| {
+            Message::Broadcast(
+                topic,
+                Extensions::default(),
+                Bytes::copy_from_slice(payload),
+            )
+        };
+        for payload in [&b"a"[..], b"bcd", b"ef"] {
+            queues.push(&topic, &msg(payload));
+        }
+        assert_eq!(queues.bytes(), 6);
+        assert_eq!(
+            queues.evict(EvictionPolicy::DropLargest),
+            Some((peer, topic, 3))
+        );
+        assert_eq!(
+            queues.evict(EvictionPolicy::DropOldest),
+            Some((peer, topic, 1))
+        );
+        assert_eq!(queues.bytes(), 2);
+        assert_eq!(queues.reconnected(&peer), vec![msg(b"ef")]);
+        assert_eq!(queues.evict(EvictionPolicy::DropOldest), None);
+    }
+}
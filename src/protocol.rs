@@ -1,167 +1,2711 @@
-use futures::future::BoxFuture;
-use futures::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use crate::compression::{Codec, Compression};
+use crate::connections::ConnectionInfo;
+use crate::offline::OfflineQueue;
+use crate::queue::AdaptiveBatching;
+use crate::rate_limit::RateLimit;
+use crate::score::PeerScoreParams;
+use crate::summary::TopicSummary;
+use crate::BroadcastError;
+use bytes::Bytes;
+use fnv::{FnvHashMap, FnvHashSet, FnvHasher};
+use futures::future;
+use futures::io::{self, AsyncRead, AsyncReadExt, AsyncWrite};
+use instant::{Instant, SystemTime};
+use libp2p::core::connection::ConnectionId;
 use libp2p::core::{upgrade, InboundUpgrade, OutboundUpgrade, UpgradeInfo};
+use libp2p::identity::{Keypair, PublicKey, SigningError};
+use libp2p::{Multiaddr, PeerId};
+use sha2::{Digest, Sha256};
+use std::convert::TryFrom;
+use std::hash::Hasher;
 use std::io::{Error, ErrorKind, Result};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
 const PROTOCOL_INFO: &[u8] = b"/ax/broadcast/1.0.0";
+const STREAM_PROTOCOL_INFO: &[u8] = b"/ax/broadcast/1.1.0";
+const FLOODSUB_PROTOCOL_INFO: &[u8] = b"/floodsub/1.0.0";
 
-#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+/// Frame type of frames whose kind is given by the byte following the header.
+const EXTENDED: u8 = 0b11;
+const KIND_BROADCAST: u8 = 0;
+const KIND_ACK: u8 = 1;
+const KIND_SUBSCRIBE_PREFIX: u8 = 2;
+const KIND_UNSUBSCRIBE_PREFIX: u8 = 3;
+const KIND_SUBSCRIBE_MANY: u8 = 4;
+const KIND_BATCH: u8 = 5;
+const KIND_IHAVE: u8 = 6;
+const KIND_IWANT: u8 = 7;
+const KIND_SUBSCRIBE_REJECTED: u8 = 8;
+const KIND_SUBSCRIBE_LEASE: u8 = 9;
+const KIND_TOPIC_SUMMARY: u8 = 10;
+const KIND_FRAGMENT: u8 = 11;
+const KIND_PEER_EXCHANGE: u8 = 12;
+const KIND_HEARTBEAT: u8 = 13;
+const KIND_SYNC_TOPICS: u8 = 14;
+const KIND_CREDIT: u8 = 15;
+const KIND_SUBSCRIBE_WITH_SNAPSHOT: u8 = 16;
+const KIND_SNAPSHOT: u8 = 17;
+const KIND_KEY_FILTER: u8 = 18;
+const KIND_REPLAY: u8 = 19;
+
+const EXT_HOPS: u8 = 0b0000_0001;
+const EXT_SIGNATURE: u8 = 0b0000_0010;
+const EXT_ACK: u8 = 0b0000_0100;
+const EXT_COMPRESSED: u8 = 0b0000_1000;
+const EXT_EXPIRES: u8 = 0b0001_0000;
+const EXT_SEQNO: u8 = 0b0010_0000;
+const EXT_ORDER: u8 = 0b0100_0000;
+/// A second byte of extension flags follows.
+const EXT_MORE: u8 = 0b1000_0000;
+
+// Flags of the second extension byte.
+const EXT_REPLY_TO: u8 = 0b0000_0001;
+const EXT_TIMESTAMP: u8 = 0b0000_0010;
+const EXT_PATH: u8 = 0b0000_0100;
+const EXT_KEY: u8 = 0b0000_1000;
+const EXT_STAMPS: u8 = 0b0001_0000;
+
+/// Upper bound of the relays recorded in `Extensions::path`.
+pub const MAX_PATH_LENGTH: usize = 16;
+
+/// Upper bound of the header, topic and extensions of a frame.
+pub(crate) const MAX_FRAME_OVERHEAD: usize = 4096;
+/// Length of the header and the longest topic.
+const MAX_HEADER_LEN: usize = 2 + 63;
+
+/// Domain separation prefix of signed broadcasts.
+const SIGNING_PREFIX: &[u8] = b"libp2p-broadcast:";
+/// Domain separation prefix of signed broadcasts with a timestamp.
+const STAMPED_SIGNING_PREFIX: &[u8] = b"libp2p-broadcast-ts:";
+/// Domain separation prefix of signed peer exchanges.
+const PEER_EXCHANGE_SIGNING_PREFIX: &[u8] = b"libp2p-broadcast-px:";
+/// Prefix of the bytes signed by pubsub implementations.
+const FLOODSUB_SIGNING_PREFIX: &[u8] = b"libp2p-pubsub:";
+
+#[derive(Clone, Copy, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct Topic {
     len: u8,
     bytes: [u8; 64],
 }
 
-impl Topic {
-    pub const MAX_TOPIC_LENGTH: usize = 64;
+impl Topic {
+    /// Topics are shorter than this many bytes.
+    pub const MAX_TOPIC_LENGTH: usize = 64;
+
+    /// # Panics
+    ///
+    /// If `topic` is `MAX_TOPIC_LENGTH` bytes or longer, see `try_new`.
+    pub fn new(topic: &[u8]) -> Self {
+        Self::try_new(topic).expect("topic too long")
+    }
+
+    /// The topic of `topic`, `None` if it is `MAX_TOPIC_LENGTH` bytes or
+    /// longer.
+    pub fn try_new(topic: &[u8]) -> Option<Self> {
+        if topic.len() >= Self::MAX_TOPIC_LENGTH {
+            return None;
+        }
+        let mut bytes = [0u8; 64];
+        bytes[..topic.len()].copy_from_slice(topic);
+        Some(Self {
+            len: topic.len() as _,
+            bytes,
+        })
+    }
+}
+
+impl Topic {
+    /// Whether this topic lies below `prefix`, e.g. `chat/room/42` below
+    /// `chat/room/`.
+    pub fn has_prefix(&self, prefix: &Topic) -> bool {
+        self.starts_with(prefix)
+    }
+
+    /// The `/` separated segments of a hierarchical topic name.
+    pub fn segments(&self) -> impl Iterator<Item = &[u8]> + '_ {
+        self.split(|byte| *byte == b'/')
+    }
+
+    /// Topic identified by a hash of `name`, for names of any length.
+    pub fn hashed(name: &str) -> Self {
+        let mut hasher = FnvHasher::default();
+        hasher.write(name.as_bytes());
+        Self::new(format!("#{:016x}", hasher.finish()).as_bytes())
+    }
+
+    /// The topic as UTF-8 name, `None` if it isn't valid UTF-8.
+    pub fn name(&self) -> Option<&str> {
+        std::str::from_utf8(self).ok()
+    }
+}
+
+impl std::fmt::Debug for Topic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.name() {
+            Some(name) => f.debug_tuple("Topic").field(&name).finish(),
+            None => f.debug_tuple("Topic").field(&self.as_ref()).finish(),
+        }
+    }
+}
+
+/// How topic names are represented on the wire.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TopicRepresentation {
+    /// The UTF-8 name is sent as is, limiting names to shorter than
+    /// `Topic::MAX_TOPIC_LENGTH` bytes.
+    Raw,
+    /// A hash of the name is sent. Topics can't be matched by prefix.
+    Hashed,
+}
+
+impl Default for TopicRepresentation {
+    fn default() -> Self {
+        Self::Raw
+    }
+}
+
+impl TopicRepresentation {
+    /// The topic for `name`. Fails with `BroadcastError::InvalidTopic` if a
+    /// raw name is `Topic::MAX_TOPIC_LENGTH` bytes or longer.
+    pub fn topic(&self, name: &str) -> std::result::Result<Topic, BroadcastError> {
+        match self {
+            Self::Raw => Topic::try_new(name.as_bytes()).ok_or(BroadcastError::InvalidTopic),
+            Self::Hashed => Ok(Topic::hashed(name)),
+        }
+    }
+
+    /// The topic for `name` within `namespace`, see
+    /// `BroadcastConfig::namespace`. Raw names are prefixed with the
+    /// namespace and a `/`, which count towards the limit of `topic`.
+    pub fn topic_in(
+        &self,
+        namespace: &str,
+        name: &str,
+    ) -> std::result::Result<Topic, BroadcastError> {
+        self.topic(&format!("{}/{}", namespace, name))
+    }
+}
+
+/// Serialized as string if the topic is valid UTF-8, as bytes otherwise.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Topic {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        match self.name() {
+            Some(name) => serializer.serialize_str(name),
+            None => serializer.serialize_bytes(self),
+        }
+    }
+}
+
+impl std::ops::Deref for Topic {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        self.as_ref()
+    }
+}
+
+impl AsRef<[u8]> for Topic {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes[..(self.len as usize)]
+    }
+}
+
+/// Identifies a broadcast message, computed by the function configured with
+/// `BroadcastConfig::message_id_fn`. Defaults to `MessageId::new`, except for
+/// messages received over floodsub, which are identified by their
+/// `Extensions::floodsub`.
+///
+/// Ids are sent to peers in acks and replay requests, so they are the first
+/// 8 bytes of a SHA-256 hash rather than a per-process keyed hash. Finding a
+/// message with the id of another one takes about 2^64 hashes.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct MessageId(u64);
+
+impl MessageId {
+    /// Identifies a message by its topic and payload.
+    pub fn new(topic: &Topic, payload: &[u8]) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update([topic.len() as u8]);
+        hasher.update(topic);
+        hasher.update(payload);
+        Self::from_digest(&hasher.finalize())
+    }
+
+    /// Identifies a message by its topic, publisher and sequence number, so
+    /// repeated payloads are told apart. Messages without a signature or
+    /// sequence number fall back to `MessageId::new`.
+    ///
+    /// Requires `BroadcastConfig::sequence_numbers` and signed messages on the
+    /// publishers.
+    pub fn from_origin(topic: &Topic, ext: &Extensions, payload: &[u8]) -> Self {
+        match (&ext.signature, ext.seqno) {
+            (Some(signature), Some(seqno)) => Self::from_seqno(topic, &signature.origin(), seqno),
+            _ => Self::new(topic, payload),
+        }
+    }
+
+    /// Identifies a message received over floodsub by its topic, publisher
+    /// and floodsub sequence number.
+    pub(crate) fn from_floodsub(topic: &Topic, id: &FloodsubId) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update([topic.len() as u8]);
+        hasher.update(topic);
+        hasher.update(id.from.to_bytes());
+        hasher.update(&id.seqno);
+        Self::from_digest(&hasher.finalize())
+    }
+
+    /// Identifies a message by its topic, publisher and sequence number.
+    pub(crate) fn from_seqno(topic: &Topic, origin: &PeerId, seqno: u64) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update([topic.len() as u8]);
+        hasher.update(topic);
+        hasher.update(origin.to_bytes());
+        hasher.update(seqno.to_be_bytes());
+        Self::from_digest(&hasher.finalize())
+    }
+
+    fn from_digest(digest: &[u8]) -> Self {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&digest[..8]);
+        Self(u64::from_be_bytes(bytes))
+    }
+}
+
+/// Where a replay of the messages retained for a topic starts, see
+/// `Broadcast::replay`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReplaySince {
+    /// The messages retained after this one, all of them if it isn't
+    /// retained.
+    Message(MessageId),
+    /// The messages received or published since then. Sent to peers as the
+    /// time elapsed since.
+    Time(Instant),
+    /// All retained messages. Requests for times before the local clock
+    /// started are decoded as this.
+    All,
+}
+
+impl From<MessageId> for ReplaySince {
+    fn from(id: MessageId) -> Self {
+        Self::Message(id)
+    }
+}
+
+impl From<Instant> for ReplaySince {
+    fn from(time: Instant) -> Self {
+        Self::Time(time)
+    }
+}
+
+/// Optional fields of a broadcast frame.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Extensions {
+    /// Number of times the message may still be relayed.
+    pub hops: u8,
+    /// Signature of the peer that published the message.
+    pub signature: Option<Signature>,
+    /// Whether the receiver should reply with an `Ack`.
+    pub ack: bool,
+    /// Whether the payload is compressed with the algorithm negotiated on
+    /// the substream.
+    pub compressed: bool,
+    /// Time after which the message is dropped instead of delivered, in
+    /// milliseconds since the Unix epoch.
+    pub expires: Option<u64>,
+    /// Position of the message among those the publisher broadcast on the
+    /// topic.
+    pub seqno: Option<u64>,
+    /// Position of the message among all messages on the topic, stamped by
+    /// its sequencer, see `BroadcastConfig::total_order`.
+    pub order: Option<u64>,
+    /// Identifies the topic the publisher expects replies on, see
+    /// `Broadcast::request`.
+    pub reply_to: Option<u64>,
+    /// Time the message was signed at, in milliseconds since the Unix
+    /// epoch, see `BroadcastConfig::replay_window`.
+    pub timestamp: Option<u64>,
+    /// Peers the message passed through, starting with its publisher. Each
+    /// peer is added by the one it sent the message to, see
+    /// `BroadcastConfig::trace_paths`. Shared by the copies of a relayed
+    /// broadcast, `None` if empty.
+    pub path: Option<Arc<Vec<PeerId>>>,
+    /// Key of the message within its topic, see `Broadcast::set_key_filter`.
+    pub key: Option<Bytes>,
+    /// Stamps of the relays the message passed through, see
+    /// `Broadcast::set_relay_key`. Shared like `path`, `None` if empty.
+    pub stamps: Option<Arc<Vec<RelayStamp>>>,
+    /// Publisher and sequence number of a message sent or received over
+    /// floodsub. Only sent to floodsub peers.
+    pub floodsub: Option<FloodsubId>,
+}
+
+/// Identifies a message over floodsub, where the publisher isn't
+/// authenticated unless the message is signed.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct FloodsubId {
+    pub from: PeerId,
+    pub seqno: Bytes,
+}
+
+impl FloodsubId {
+    /// Identifies a message published by `from` with a sequence number
+    /// unique to this process.
+    pub(crate) fn new(from: PeerId) -> Self {
+        Self {
+            from,
+            seqno: Bytes::copy_from_slice(&next_seqno().to_be_bytes()),
+        }
+    }
+}
+
+impl Extensions {
+    fn flags(&self) -> u8 {
+        let mut flags = 0;
+        if self.hops > 0 {
+            flags |= EXT_HOPS;
+        }
+        if self.signature.is_some() {
+            flags |= EXT_SIGNATURE;
+        }
+        if self.ack {
+            flags |= EXT_ACK;
+        }
+        if self.compressed {
+            flags |= EXT_COMPRESSED;
+        }
+        if self.expires.is_some() {
+            flags |= EXT_EXPIRES;
+        }
+        if self.seqno.is_some() {
+            flags |= EXT_SEQNO;
+        }
+        if self.order.is_some() {
+            flags |= EXT_ORDER;
+        }
+        if self.more_flags() != 0 {
+            flags |= EXT_MORE;
+        }
+        flags
+    }
+
+    fn more_flags(&self) -> u8 {
+        let mut flags = 0;
+        if self.reply_to.is_some() {
+            flags |= EXT_REPLY_TO;
+        }
+        if self.timestamp.is_some() {
+            flags |= EXT_TIMESTAMP;
+        }
+        if !self.path().is_empty() {
+            flags |= EXT_PATH;
+        }
+        if self.key.is_some() {
+            flags |= EXT_KEY;
+        }
+        if !self.stamps().is_empty() {
+            flags |= EXT_STAMPS;
+        }
+        flags
+    }
+
+    /// The peers the message passed through, see `Extensions::path`.
+    pub fn path(&self) -> &[PeerId] {
+        self.path.as_deref().map_or(&[], Vec::as_slice)
+    }
+
+    /// The relay stamps of the message, see `Extensions::stamps`.
+    pub fn stamps(&self) -> &[RelayStamp] {
+        self.stamps.as_deref().map_or(&[], Vec::as_slice)
+    }
+
+    pub(crate) fn is_expired(&self) -> bool {
+        matches!(self.expires, Some(expires) if expires <= unix_millis())
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(self.flags());
+        if self.more_flags() != 0 {
+            buf.push(self.more_flags());
+        }
+        if self.hops > 0 {
+            buf.push(self.hops);
+        }
+        if let Some(signature) = &self.signature {
+            write_bytes(buf, &signature.key.to_protobuf_encoding());
+            write_bytes(buf, &signature.bytes);
+        }
+        if let Some(expires) = self.expires {
+            buf.extend_from_slice(&expires.to_be_bytes());
+        }
+        if let Some(seqno) = self.seqno {
+            buf.extend_from_slice(&seqno.to_be_bytes());
+        }
+        if let Some(order) = self.order {
+            buf.extend_from_slice(&order.to_be_bytes());
+        }
+        if let Some(reply_to) = self.reply_to {
+            buf.extend_from_slice(&reply_to.to_be_bytes());
+        }
+        if let Some(timestamp) = self.timestamp {
+            buf.extend_from_slice(&timestamp.to_be_bytes());
+        }
+        if !self.path().is_empty() {
+            write_varint(buf, self.path().len());
+            for peer in self.path() {
+                write_bytes(buf, &peer.to_bytes());
+            }
+        }
+        if let Some(key) = &self.key {
+            write_bytes(buf, key);
+        }
+        if !self.stamps().is_empty() {
+            write_varint(buf, self.stamps().len());
+            for stamp in self.stamps() {
+                write_bytes(buf, &stamp.relay.to_bytes());
+                buf.extend_from_slice(&stamp.tag);
+            }
+        }
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        let flags = reader.u8()?;
+        let known = EXT_HOPS
+            | EXT_SIGNATURE
+            | EXT_ACK
+            | EXT_COMPRESSED
+            | EXT_EXPIRES
+            | EXT_SEQNO
+            | EXT_ORDER
+            | EXT_MORE;
+        if flags & !known != 0 {
+            return Err(Error::new(ErrorKind::InvalidData, "unknown extension"));
+        }
+        let more = match flags & EXT_MORE {
+            0 => 0,
+            _ => reader.u8()?,
+        };
+        if more & !(EXT_REPLY_TO | EXT_TIMESTAMP | EXT_PATH | EXT_KEY | EXT_STAMPS) != 0 {
+            return Err(Error::new(ErrorKind::InvalidData, "unknown extension"));
+        }
+        let mut ext = Self::default();
+        if flags & EXT_HOPS != 0 {
+            ext.hops = reader.u8()?;
+        }
+        if flags & EXT_SIGNATURE != 0 {
+            let key = PublicKey::from_protobuf_encoding(reader.bytes()?)
+                .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+            let bytes = Bytes::copy_from_slice(reader.bytes()?);
+            ext.signature = Some(Signature { key, bytes });
+        }
+        if flags & EXT_EXPIRES != 0 {
+            ext.expires = Some(reader.u64()?);
+        }
+        if flags & EXT_SEQNO != 0 {
+            ext.seqno = Some(reader.u64()?);
+        }
+        if flags & EXT_ORDER != 0 {
+            ext.order = Some(reader.u64()?);
+        }
+        if more & EXT_REPLY_TO != 0 {
+            ext.reply_to = Some(reader.u64()?);
+        }
+        if more & EXT_TIMESTAMP != 0 {
+            ext.timestamp = Some(reader.u64()?);
+        }
+        if more & EXT_PATH != 0 {
+            let len = reader.varint()?;
+            if len > MAX_PATH_LENGTH {
+                return Err(Error::new(ErrorKind::InvalidData, "path too long"));
+            }
+            let mut path = Vec::with_capacity(len);
+            for _ in 0..len {
+                let peer = PeerId::from_bytes(reader.bytes()?)
+                    .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+                path.push(peer);
+            }
+            ext.path = Some(Arc::new(path));
+        }
+        if more & EXT_KEY != 0 {
+            ext.key = Some(Bytes::copy_from_slice(reader.bytes()?));
+        }
+        if more & EXT_STAMPS != 0 {
+            let len = reader.varint()?;
+            if len > MAX_PATH_LENGTH {
+                return Err(Error::new(ErrorKind::InvalidData, "too many stamps"));
+            }
+            let mut stamps = Vec::with_capacity(len);
+            for _ in 0..len {
+                let relay = PeerId::from_bytes(reader.bytes()?)
+                    .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+                let mut tag = [0; STAMP_LEN];
+                for byte in &mut tag {
+                    *byte = reader.u8()?;
+                }
+                stamps.push(RelayStamp { relay, tag });
+            }
+            ext.stamps = Some(Arc::new(stamps));
+        }
+        ext.ack = flags & EXT_ACK != 0;
+        ext.compressed = flags & EXT_COMPRESSED != 0;
+        Ok(ext)
+    }
+}
+
+/// Length of the tag of a `RelayStamp`.
+pub const STAMP_LEN: usize = 16;
+
+/// Proof that a relay holding the relay key of the topic sent a broadcast,
+/// see `Broadcast::set_relay_key`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RelayStamp {
+    pub relay: PeerId,
+    pub tag: [u8; STAMP_LEN],
+}
+
+/// Signature of a broadcast by the peer that published it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Signature {
+    pub key: PublicKey,
+    /// Shared by the copies of a broadcast queued for each peer.
+    pub bytes: Bytes,
+}
+
+impl Signature {
+    pub fn sign(
+        keypair: &Keypair,
+        topic: &Topic,
+        payload: &[u8],
+    ) -> std::result::Result<Self, SigningError> {
+        Ok(Self {
+            key: keypair.public(),
+            bytes: keypair
+                .sign(&Self::signed_bytes(SIGNING_PREFIX, topic, payload))?
+                .into(),
+        })
+    }
+
+    pub fn verify(&self, topic: &Topic, payload: &[u8]) -> bool {
+        self.key.verify(
+            &Self::signed_bytes(SIGNING_PREFIX, topic, payload),
+            &self.bytes,
+        )
+    }
+
+    /// Signs a broadcast together with the time it was published at, see
+    /// `BroadcastConfig::replay_window`.
+    pub fn sign_stamped(
+        keypair: &Keypair,
+        topic: &Topic,
+        timestamp: u64,
+        payload: &[u8],
+    ) -> std::result::Result<Self, SigningError> {
+        let signed = Self::stamped_bytes(topic, timestamp, payload);
+        Ok(Self {
+            key: keypair.public(),
+            bytes: keypair.sign(&signed)?.into(),
+        })
+    }
+
+    pub fn verify_stamped(&self, topic: &Topic, timestamp: u64, payload: &[u8]) -> bool {
+        self.key
+            .verify(&Self::stamped_bytes(topic, timestamp, payload), &self.bytes)
+    }
+
+    /// Signs the peers suggested in a `Message::PeerExchange`.
+    pub fn sign_peers(
+        keypair: &Keypair,
+        topic: &Topic,
+        peers: &[(PeerId, Vec<Multiaddr>)],
+    ) -> std::result::Result<Self, SigningError> {
+        let peers = encode_peers(peers);
+        Ok(Self {
+            key: keypair.public(),
+            bytes: keypair
+                .sign(&Self::signed_bytes(
+                    PEER_EXCHANGE_SIGNING_PREFIX,
+                    topic,
+                    &peers,
+                ))?
+                .into(),
+        })
+    }
+
+    pub fn verify_peers(&self, topic: &Topic, peers: &[(PeerId, Vec<Multiaddr>)]) -> bool {
+        let peers = encode_peers(peers);
+        self.key.verify(
+            &Self::signed_bytes(PEER_EXCHANGE_SIGNING_PREFIX, topic, &peers),
+            &self.bytes,
+        )
+    }
+
+    /// The peer that published the message.
+    pub fn origin(&self) -> PeerId {
+        PeerId::from_public_key(&self.key)
+    }
+
+    fn signed_bytes(prefix: &[u8], topic: &Topic, payload: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(prefix.len() + topic.len() + payload.len() + 1);
+        buf.extend_from_slice(prefix);
+        buf.push(topic.len() as u8);
+        buf.extend_from_slice(topic);
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    fn stamped_bytes(topic: &Topic, timestamp: u64, payload: &[u8]) -> Vec<u8> {
+        let mut stamped = Vec::with_capacity(payload.len() + 8);
+        stamped.extend_from_slice(&timestamp.to_be_bytes());
+        stamped.extend_from_slice(payload);
+        Self::signed_bytes(STAMPED_SIGNING_PREFIX, topic, &stamped)
+    }
+}
+
+/// Why a received message was rejected.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RejectReason {
+    /// The payload exceeds the global or per-topic size limit.
+    TooLarge,
+    /// The message is unsigned but signatures are required.
+    MissingSignature,
+    /// The signature doesn't match the message.
+    InvalidSignature,
+    /// The topic validator rejected the message.
+    Validation,
+    /// The payload couldn't be decrypted with the topic key.
+    Decryption,
+    /// The access policy doesn't allow the source to publish on the topic.
+    Unauthorized,
+    /// The relay stamps are missing or don't match the relay key of the
+    /// topic.
+    InvalidStamp,
+}
+
+/// A frame read from a substream.
+#[derive(Debug)]
+// Frames are moved rather than stored, boxing them would allocate for every
+// broadcast read.
+#[allow(clippy::large_enum_variant)]
+pub enum Frame {
+    Message(Message),
+    /// A broadcast whose payload exceeds the size limit was skipped.
+    TooLarge(Topic),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    Subscribe(Topic),
+    Broadcast(Topic, Extensions, Bytes),
+    Unsubscribe(Topic),
+    Ack(Topic, MessageId),
+    SubscribePrefix(Topic),
+    UnsubscribePrefix(Topic),
+    /// Subscriptions announced in a single frame.
+    SubscribeMany(Vec<Topic>),
+    /// Messages written to the substream at once, never nested.
+    Batch(Vec<Message>),
+    /// Ids of the recent messages of a topic the sender can provide.
+    IHave(Topic, Vec<MessageId>),
+    /// Requests the messages with the given ids from the sender's history.
+    IWant(Topic, Vec<MessageId>),
+    /// The sender doesn't track the subscription to the topic or prefix.
+    SubscribeRejected(Topic),
+    /// Subscription that expires unless it is renewed within the duration.
+    SubscribeLease(Topic, Duration),
+    /// Summary of the sender's subscriptions, answered with the
+    /// subscriptions to the topics it may contain.
+    TopicSummary(TopicSummary),
+    /// Part `index` of the `total` parts of a frame too large to be written
+    /// at once, see `BroadcastConfig::fragment_size`.
+    Fragment {
+        id: u64,
+        index: u32,
+        total: u32,
+        data: Bytes,
+    },
+    /// Other subscribers of the topic with their addresses, sent to a peer
+    /// that subscribed with `BroadcastConfig::peer_exchange`.
+    PeerExchange(Topic, Vec<(PeerId, Vec<Multiaddr>)>, Option<Signature>),
+    /// The sender is subscribed to the topic and responsive, see
+    /// `BroadcastConfig::topic_heartbeat`.
+    Heartbeat(Topic),
+    /// All topics the sender is subscribed to, replacing the subscriptions
+    /// known to the receiver, see `Broadcast::resync`.
+    SyncTopics(Vec<Topic>),
+    /// Additional broadcasts the receiver may send on its substream, see
+    /// `BroadcastConfig::flow_control`.
+    Credit(u32),
+    /// Subscription asking the receiver for the current state of the topic,
+    /// see `Broadcast::subscribe_with_snapshot`.
+    SubscribeWithSnapshot(Topic),
+    /// The current state of a topic, sent in response to a
+    /// `Message::SubscribeWithSnapshot`.
+    Snapshot(Topic, Bytes),
+    /// Subscription carrying external addresses of the sender, see
+    /// `BroadcastConfig::address_hints`. Encoded as a `Message::Subscribe`
+    /// with a body older receivers ignore.
+    SubscribeWithAddresses(Topic, Vec<Multiaddr>),
+    /// Keys of the topic's messages the sender is interested in, all of them
+    /// if empty, see `Broadcast::set_key_filter`.
+    KeyFilter(Topic, Vec<Bytes>),
+    /// Requests the messages the receiver retained for the topic, see
+    /// `Broadcast::request_replay`.
+    Replay(Topic, ReplaySince),
+}
+
+/// Encodes the messages exchanged on `Version::V1_0` and `Version::V1_1`
+/// substreams. Frames are length-prefixed by the protocol.
+pub trait MessageCodec: std::fmt::Debug + Send + Sync {
+    fn encode(&self, msg: &Message) -> Vec<u8>;
+
+    fn decode(&self, frame: &[u8]) -> Result<Message>;
+
+    /// The topic of a frame from its first bytes, used to report skipped
+    /// frames exceeding the size limit. By default oversized frames fail
+    /// the substream.
+    fn topic(&self, header: &[u8]) -> Result<Topic> {
+        let _ = header;
+        Err(Error::new(ErrorKind::InvalidData, "frame too large"))
+    }
+}
+
+/// The native wire format of the broadcast protocol.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultCodec;
+
+impl MessageCodec for DefaultCodec {
+    fn encode(&self, msg: &Message) -> Vec<u8> {
+        msg.to_bytes()
+    }
+
+    fn decode(&self, frame: &[u8]) -> Result<Message> {
+        Message::from_bytes(frame)
+    }
+
+    fn topic(&self, header: &[u8]) -> Result<Topic> {
+        Ok(Message::split(header)?.1)
+    }
+}
+
+impl Message {
+    /// Reads a length-prefixed frame from `socket`.
+    ///
+    /// Broadcasts with a payload larger than `max_message_size` are skipped
+    /// without buffering them.
+    pub(crate) async fn read<S: AsyncRead + Unpin>(
+        socket: &mut S,
+        max_message_size: usize,
+        codec: &dyn MessageCodec,
+    ) -> Result<Frame> {
+        let len = upgrade::read_varint(socket).await?;
+        if len > max_message_size + MAX_FRAME_OVERHEAD {
+            let mut header = vec![0; len.min(MAX_HEADER_LEN)];
+            socket.read_exact(&mut header).await?;
+            let topic = codec.topic(&header)?;
+            let remaining = (len - header.len()) as u64;
+            let skipped = io::copy((&mut *socket).take(remaining), &mut io::sink()).await?;
+            if skipped < remaining {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            return Ok(Frame::TooLarge(topic));
+        }
+        let mut packet = vec![0; len];
+        socket.read_exact(&mut packet).await?;
+        Ok(match codec.decode(&packet)? {
+            Message::Broadcast(topic, _, payload) if payload.len() > max_message_size => {
+                Frame::TooLarge(topic)
+            }
+            msg => Frame::Message(msg),
+        })
+    }
+
+    /// Writes a length-prefixed message to `socket` and flushes it.
+    pub(crate) async fn write<S: AsyncWrite + Unpin>(
+        &self,
+        socket: &mut S,
+        codec: &dyn MessageCodec,
+    ) -> Result<()> {
+        upgrade::write_length_prefixed(socket, codec.encode(self)).await
+    }
+
+    /// Reads a length-prefixed floodsub RPC, which carries any number of
+    /// messages.
+    pub(crate) async fn read_rpc<S: AsyncRead + Unpin>(
+        socket: &mut S,
+        max_message_size: usize,
+    ) -> Result<Vec<Frame>> {
+        let packet =
+            upgrade::read_length_prefixed(socket, max_message_size + MAX_FRAME_OVERHEAD).await?;
+        Self::from_rpc(&packet, max_message_size)
+    }
+
+    /// Writes the message as a length-prefixed floodsub RPC.
+    ///
+    /// Broadcasts are signed the way pubsub implementations expect when a
+    /// keypair is given.
+    pub(crate) async fn write_rpc<S: AsyncWrite + Unpin>(
+        &self,
+        socket: &mut S,
+        keypair: Option<&Keypair>,
+    ) -> Result<()> {
+        let rpc = self
+            .to_rpc(keypair)
+            .map_err(|err| Error::new(ErrorKind::Other, err))?;
+        upgrade::write_length_prefixed(socket, rpc).await
+    }
+
+    fn from_rpc(bytes: &[u8], max_message_size: usize) -> Result<Vec<Frame>> {
+        let mut frames = Vec::new();
+        let mut rpc = Reader(bytes);
+        while let Some((field, value)) = rpc.proto_field()? {
+            match (field, value) {
+                (1, ProtoValue::Bytes(bytes)) => {
+                    let mut sub = Reader(bytes);
+                    let mut subscribe = false;
+                    let mut topic = None;
+                    while let Some((field, value)) = sub.proto_field()? {
+                        match (field, value) {
+                            (1, ProtoValue::Varint(value)) => subscribe = value != 0,
+                            (2, ProtoValue::Bytes(bytes)) => topic = floodsub_topic(bytes),
+                            _ => {}
+                        }
+                    }
+                    if let Some(topic) = topic {
+                        frames.push(Frame::Message(if subscribe {
+                            Message::Subscribe(topic)
+                        } else {
+                            Message::Unsubscribe(topic)
+                        }));
+                    }
+                }
+                (2, ProtoValue::Bytes(bytes)) => {
+                    let mut publish = Reader(bytes);
+                    let mut from = None;
+                    let mut data: &[u8] = &[];
+                    let mut seqno: &[u8] = &[];
+                    let mut topics = Vec::new();
+                    while let Some((field, value)) = publish.proto_field()? {
+                        match (field, value) {
+                            (1, ProtoValue::Bytes(bytes)) => from = PeerId::from_bytes(bytes).ok(),
+                            (2, ProtoValue::Bytes(bytes)) => data = bytes,
+                            (3, ProtoValue::Bytes(bytes)) => seqno = bytes,
+                            (4, ProtoValue::Bytes(bytes)) => topics.extend(floodsub_topic(bytes)),
+                            _ => {}
+                        }
+                    }
+                    let ext = Extensions {
+                        floodsub: from.map(|from| FloodsubId {
+                            from,
+                            seqno: Bytes::copy_from_slice(seqno),
+                        }),
+                        ..Default::default()
+                    };
+                    let data = Bytes::copy_from_slice(data);
+                    for topic in topics {
+                        frames.push(if data.len() > max_message_size {
+                            Frame::TooLarge(topic)
+                        } else {
+                            Frame::Message(Message::Broadcast(topic, ext.clone(), data.clone()))
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(frames)
+    }
+
+    /// Encodes the message as a floodsub RPC. Messages without a floodsub
+    /// equivalent encode as an empty RPC.
+    fn to_rpc(&self, keypair: Option<&Keypair>) -> std::result::Result<Vec<u8>, SigningError> {
+        let mut rpc = Vec::new();
+        match self {
+            Message::Subscribe(topic) | Message::Unsubscribe(topic) => {
+                let mut sub = Vec::new();
+                let subscribe = matches!(self, Message::Subscribe(_));
+                write_proto_varint(&mut sub, 1, subscribe as usize);
+                write_proto_bytes(&mut sub, 2, topic);
+                write_proto_bytes(&mut rpc, 1, &sub);
+            }
+            Message::SubscribeMany(topics) => {
+                for topic in topics {
+                    let mut sub = Vec::new();
+                    write_proto_varint(&mut sub, 1, 1);
+                    write_proto_bytes(&mut sub, 2, topic);
+                    write_proto_bytes(&mut rpc, 1, &sub);
+                }
+            }
+            // Concatenated protobuf messages merge their repeated fields.
+            Message::Batch(msgs) => {
+                for msg in msgs {
+                    rpc.extend(msg.to_rpc(keypair)?);
+                }
+            }
+            Message::Broadcast(topic, ext, data) => {
+                // Floodsub peers tell messages apart by their publisher and
+                // sequence number, so every copy of a message has to carry
+                // the same ones.
+                let id = ext.floodsub.clone().or_else(|| {
+                    keypair.map(|keypair| FloodsubId::new(keypair.public().to_peer_id()))
+                });
+                let mut publish = Vec::new();
+                if let Some(id) = &id {
+                    write_proto_bytes(&mut publish, 1, &id.from.to_bytes());
+                }
+                write_proto_bytes(&mut publish, 2, data);
+                let seqno = match &id {
+                    Some(id) => id.seqno.clone(),
+                    None => Bytes::copy_from_slice(&next_seqno().to_be_bytes()),
+                };
+                write_proto_bytes(&mut publish, 3, &seqno);
+                write_proto_bytes(&mut publish, 4, topic);
+                // Messages of other publishers are relayed unsigned.
+                let keypair = keypair.filter(|keypair| {
+                    id.as_ref()
+                        .map_or(false, |id| id.from == keypair.public().to_peer_id())
+                });
+                if let Some(keypair) = keypair {
+                    let mut signed = FLOODSUB_SIGNING_PREFIX.to_vec();
+                    signed.extend_from_slice(&publish);
+                    let signature = keypair.sign(&signed)?;
+                    write_proto_bytes(&mut publish, 5, &signature);
+                    write_proto_bytes(&mut publish, 6, &keypair.public().to_protobuf_encoding());
+                }
+                write_proto_bytes(&mut rpc, 2, &publish);
+            }
+            _ => {}
+        }
+        Ok(rpc)
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let (frame_type, topic, body) = Self::split(bytes)?;
+        Ok(match frame_type {
+            0b00 if body.is_empty() => Message::Subscribe(topic),
+            0b00 => {
+                let mut reader = Reader(body);
+                let mut addresses = Vec::new();
+                while !reader.0.is_empty() {
+                    let address = Multiaddr::try_from(reader.bytes()?.to_vec())
+                        .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+                    addresses.push(address);
+                }
+                Message::SubscribeWithAddresses(topic, addresses)
+            }
+            0b10 => Message::Unsubscribe(topic),
+            0b01 => Message::Broadcast(topic, Extensions::default(), Bytes::copy_from_slice(body)),
+            _ => return Self::from_extended(bytes[1], topic, body),
+        })
+    }
+
+    /// Splits a frame into its type, topic and body.
+    fn split(bytes: &[u8]) -> Result<(u8, Topic, &[u8])> {
+        if bytes.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidData, "empty message"));
+        }
+        let frame_type = bytes[0] & 0b11;
+        let start = if frame_type == EXTENDED { 2 } else { 1 };
+        let topic_len = (bytes[0] >> 2) as usize;
+        if bytes.len() < topic_len + start {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "topic length out of range",
+            ));
+        }
+        let topic = Topic::new(&bytes[start..topic_len + start]);
+        Ok((frame_type, topic, &bytes[(topic_len + start)..]))
+    }
+
+    fn from_extended(kind: u8, topic: Topic, body: &[u8]) -> Result<Self> {
+        let mut reader = Reader(body);
+        match kind {
+            KIND_BROADCAST => {
+                let ext = Extensions::decode(&mut reader)?;
+                Ok(Message::Broadcast(
+                    topic,
+                    ext,
+                    Bytes::copy_from_slice(reader.0),
+                ))
+            }
+            KIND_ACK => Ok(Message::Ack(topic, MessageId(reader.u64()?))),
+            KIND_SUBSCRIBE_PREFIX => Ok(Message::SubscribePrefix(topic)),
+            KIND_UNSUBSCRIBE_PREFIX => Ok(Message::UnsubscribePrefix(topic)),
+            KIND_SUBSCRIBE_REJECTED => Ok(Message::SubscribeRejected(topic)),
+            KIND_HEARTBEAT => Ok(Message::Heartbeat(topic)),
+            KIND_SUBSCRIBE_WITH_SNAPSHOT => Ok(Message::SubscribeWithSnapshot(topic)),
+            KIND_SNAPSHOT => Ok(Message::Snapshot(topic, Bytes::copy_from_slice(body))),
+            KIND_SUBSCRIBE_LEASE => Ok(Message::SubscribeLease(
+                topic,
+                Duration::from_millis(reader.u64()?),
+            )),
+            KIND_TOPIC_SUMMARY => {
+                let hashes = reader.u8()?;
+                TopicSummary::from_parts(hashes, reader.0.to_vec())
+                    .map(Message::TopicSummary)
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidData, "invalid topic summary"))
+            }
+            KIND_FRAGMENT => {
+                let id = reader.u64()?;
+                let index = reader.varint()?;
+                let total = reader.varint()?;
+                match (u32::try_from(index), u32::try_from(total)) {
+                    (Ok(index), Ok(total)) if index < total => Ok(Message::Fragment {
+                        id,
+                        index,
+                        total,
+                        data: Bytes::copy_from_slice(reader.0),
+                    }),
+                    _ => Err(Error::new(ErrorKind::InvalidData, "invalid fragment")),
+                }
+            }
+            KIND_KEY_FILTER => {
+                let mut keys = Vec::new();
+                while !reader.0.is_empty() {
+                    keys.push(Bytes::copy_from_slice(reader.bytes()?));
+                }
+                Ok(Message::KeyFilter(topic, keys))
+            }
+            KIND_REPLAY => match reader.u8()? {
+                0 => Ok(Message::Replay(
+                    topic,
+                    ReplaySince::Message(MessageId(reader.u64()?)),
+                )),
+                1 => {
+                    let elapsed = Duration::from_millis(reader.u64()?);
+                    let now = Instant::now();
+                    let since = now
+                        .checked_sub(elapsed)
+                        .map_or(ReplaySince::All, ReplaySince::Time);
+                    Ok(Message::Replay(topic, since))
+                }
+                _ => Err(Error::new(ErrorKind::InvalidData, "invalid replay")),
+            },
+            KIND_CREDIT => u32::try_from(reader.varint()?)
+                .map(Message::Credit)
+                .map_err(|_| Error::new(ErrorKind::InvalidData, "invalid credit")),
+            KIND_PEER_EXCHANGE => {
+                let mut peers = Vec::new();
+                for _ in 0..reader.varint()? {
+                    let peer = PeerId::from_bytes(reader.bytes()?)
+                        .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+                    let mut addresses = Vec::new();
+                    for _ in 0..reader.varint()? {
+                        let address = Multiaddr::try_from(reader.bytes()?.to_vec())
+                            .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+                        addresses.push(address);
+                    }
+                    peers.push((peer, addresses));
+                }
+                let signature = if reader.0.is_empty() {
+                    None
+                } else {
+                    let key = PublicKey::from_protobuf_encoding(reader.bytes()?)
+                        .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+                    let bytes = Bytes::copy_from_slice(reader.bytes()?);
+                    Some(Signature { key, bytes })
+                };
+                Ok(Message::PeerExchange(topic, peers, signature))
+            }
+            KIND_SUBSCRIBE_MANY | KIND_SYNC_TOPICS => {
+                let mut topics = Vec::new();
+                while !reader.0.is_empty() {
+                    let topic = Topic::try_new(reader.bytes()?)
+                        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "topic too long"))?;
+                    topics.push(topic);
+                }
+                Ok(match kind {
+                    KIND_SUBSCRIBE_MANY => Message::SubscribeMany(topics),
+                    _ => Message::SyncTopics(topics),
+                })
+            }
+            KIND_BATCH => {
+                let mut msgs = Vec::new();
+                while !reader.0.is_empty() {
+                    match Self::from_bytes(reader.bytes()?)? {
+                        Message::Batch(_) => {
+                            return Err(Error::new(ErrorKind::InvalidData, "nested batch"))
+                        }
+                        msg => msgs.push(msg),
+                    }
+                }
+                Ok(Message::Batch(msgs))
+            }
+            KIND_IHAVE | KIND_IWANT => {
+                let mut ids = Vec::new();
+                while !reader.0.is_empty() {
+                    ids.push(MessageId(reader.u64()?));
+                }
+                Ok(match kind {
+                    KIND_IHAVE => Message::IHave(topic, ids),
+                    _ => Message::IWant(topic, ids),
+                })
+            }
+            _ => Err(Error::new(ErrorKind::InvalidData, "unknown frame kind")),
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        use Message::*;
+        match self {
+            Subscribe(topic) => {
+                let mut buf = Vec::with_capacity(topic.len() + 1);
+                buf.push((topic.len() as u8) << 2);
+                buf.extend_from_slice(topic);
+                buf
+            }
+            SubscribeWithAddresses(topic, addresses) => {
+                let mut buf = Vec::with_capacity(topic.len() + addresses.len() * 32 + 1);
+                buf.push((topic.len() as u8) << 2);
+                buf.extend_from_slice(topic);
+                for address in addresses {
+                    write_bytes(&mut buf, &address.to_vec());
+                }
+                buf
+            }
+            Unsubscribe(topic) => {
+                let mut buf = Vec::with_capacity(topic.len() + 1);
+                buf.push((topic.len() as u8) << 2 | 0b10);
+                buf.extend_from_slice(topic);
+                buf
+            }
+            Broadcast(topic, ext, msg) if ext.flags() == 0 => {
+                let mut buf = Vec::with_capacity(topic.len() + msg.len() + 1);
+                buf.push((topic.len() as u8) << 2 | 0b01);
+                buf.extend_from_slice(topic);
+                buf.extend_from_slice(msg);
+                buf
+            }
+            Broadcast(topic, ext, msg) => {
+                let mut buf = Vec::with_capacity(topic.len() + msg.len() + 4);
+                buf.push((topic.len() as u8) << 2 | EXTENDED);
+                buf.push(KIND_BROADCAST);
+                buf.extend_from_slice(topic);
+                ext.encode(&mut buf);
+                buf.extend_from_slice(msg);
+                buf
+            }
+            Ack(topic, id) => {
+                let mut buf = Vec::with_capacity(topic.len() + 10);
+                buf.push((topic.len() as u8) << 2 | EXTENDED);
+                buf.push(KIND_ACK);
+                buf.extend_from_slice(topic);
+                buf.extend_from_slice(&id.0.to_be_bytes());
+                buf
+            }
+            SubscribePrefix(topic)
+            | UnsubscribePrefix(topic)
+            | SubscribeRejected(topic)
+            | SubscribeWithSnapshot(topic)
+            | Heartbeat(topic) => {
+                let kind = match self {
+                    SubscribePrefix(_) => KIND_SUBSCRIBE_PREFIX,
+                    UnsubscribePrefix(_) => KIND_UNSUBSCRIBE_PREFIX,
+                    SubscribeRejected(_) => KIND_SUBSCRIBE_REJECTED,
+                    SubscribeWithSnapshot(_) => KIND_SUBSCRIBE_WITH_SNAPSHOT,
+                    _ => KIND_HEARTBEAT,
+                };
+                let mut buf = Vec::with_capacity(topic.len() + 2);
+                buf.push((topic.len() as u8) << 2 | EXTENDED);
+                buf.push(kind);
+                buf.extend_from_slice(topic);
+                buf
+            }
+            Snapshot(topic, state) => {
+                let mut buf = Vec::with_capacity(topic.len() + state.len() + 2);
+                buf.push((topic.len() as u8) << 2 | EXTENDED);
+                buf.push(KIND_SNAPSHOT);
+                buf.extend_from_slice(topic);
+                buf.extend_from_slice(state);
+                buf
+            }
+            KeyFilter(topic, keys) => {
+                let mut buf = vec![(topic.len() as u8) << 2 | EXTENDED, KIND_KEY_FILTER];
+                buf.extend_from_slice(topic);
+                for key in keys {
+                    write_bytes(&mut buf, key);
+                }
+                buf
+            }
+            Replay(topic, since) => {
+                let mut buf = Vec::with_capacity(topic.len() + 11);
+                buf.push((topic.len() as u8) << 2 | EXTENDED);
+                buf.push(KIND_REPLAY);
+                buf.extend_from_slice(topic);
+                match since {
+                    ReplaySince::Message(id) => {
+                        buf.push(0);
+                        buf.extend_from_slice(&id.0.to_be_bytes());
+                    }
+                    ReplaySince::Time(time) => {
+                        buf.push(1);
+                        buf.extend_from_slice(&(time.elapsed().as_millis() as u64).to_be_bytes());
+                    }
+                    ReplaySince::All => {
+                        buf.push(1);
+                        buf.extend_from_slice(&u64::MAX.to_be_bytes());
+                    }
+                }
+                buf
+            }
+            SubscribeLease(topic, ttl) => {
+                let mut buf = Vec::with_capacity(topic.len() + 10);
+                buf.push((topic.len() as u8) << 2 | EXTENDED);
+                buf.push(KIND_SUBSCRIBE_LEASE);
+                buf.extend_from_slice(topic);
+                buf.extend_from_slice(&(ttl.as_millis() as u64).to_be_bytes());
+                buf
+            }
+            TopicSummary(summary) => {
+                let mut buf = Vec::with_capacity(summary.bits().len() + 3);
+                buf.push(EXTENDED);
+                buf.push(KIND_TOPIC_SUMMARY);
+                buf.push(summary.hashes());
+                buf.extend_from_slice(summary.bits());
+                buf
+            }
+            Fragment {
+                id,
+                index,
+                total,
+                data,
+            } => {
+                let mut buf = Vec::with_capacity(data.len() + 20);
+                buf.push(EXTENDED);
+                buf.push(KIND_FRAGMENT);
+                buf.extend_from_slice(&id.to_be_bytes());
+                write_varint(&mut buf, *index as usize);
+                write_varint(&mut buf, *total as usize);
+                buf.extend_from_slice(data);
+                buf
+            }
+            Credit(credits) => {
+                let mut buf = vec![EXTENDED, KIND_CREDIT];
+                write_varint(&mut buf, *credits as usize);
+                buf
+            }
+            PeerExchange(topic, peers, signature) => {
+                let mut buf = vec![(topic.len() as u8) << 2 | EXTENDED, KIND_PEER_EXCHANGE];
+                buf.extend_from_slice(topic);
+                buf.extend_from_slice(&encode_peers(peers));
+                if let Some(signature) = signature {
+                    write_bytes(&mut buf, &signature.key.to_protobuf_encoding());
+                    write_bytes(&mut buf, &signature.bytes);
+                }
+                buf
+            }
+            SubscribeMany(topics) | SyncTopics(topics) => {
+                let kind = match self {
+                    SubscribeMany(_) => KIND_SUBSCRIBE_MANY,
+                    _ => KIND_SYNC_TOPICS,
+                };
+                let len = topics.iter().map(|topic| topic.len() + 1).sum::<usize>();
+                let mut buf = Vec::with_capacity(len + 2);
+                buf.push(EXTENDED);
+                buf.push(kind);
+                for topic in topics {
+                    write_varint(&mut buf, topic.len());
+                    buf.extend_from_slice(topic);
+                }
+                buf
+            }
+            Batch(msgs) => {
+                let mut buf = vec![EXTENDED, KIND_BATCH];
+                for msg in msgs {
+                    write_bytes(&mut buf, &msg.to_bytes());
+                }
+                buf
+            }
+            IHave(topic, ids) | IWant(topic, ids) => {
+                let kind = match self {
+                    IHave(..) => KIND_IHAVE,
+                    _ => KIND_IWANT,
+                };
+                let mut buf = Vec::with_capacity(topic.len() + ids.len() * 8 + 2);
+                buf.push((topic.len() as u8) << 2 | EXTENDED);
+                buf.push(kind);
+                buf.extend_from_slice(topic);
+                for id in ids {
+                    buf.extend_from_slice(&id.0.to_be_bytes());
+                }
+                buf
+            }
+        }
+    }
+}
+
+/// Floodsub topics longer than a `Topic` can hold are ignored.
+fn floodsub_topic(bytes: &[u8]) -> Option<Topic> {
+    Topic::try_new(bytes)
+}
+
+/// Milliseconds since the Unix epoch.
+pub(crate) fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|time| time.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Sequence numbers of published floodsub messages, unique per process.
+fn next_seqno() -> u64 {
+    static SEQNO: AtomicU64 = AtomicU64::new(0);
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|time| time.as_nanos() as u64)
+        .unwrap_or_default();
+    let prev = SEQNO.fetch_max(now, Ordering::Relaxed);
+    if prev < now {
+        now
+    } else {
+        SEQNO.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+/// Value of a protobuf field.
+enum ProtoValue<'a> {
+    Varint(usize),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+/// Cursor over the body of an extended frame or a protobuf message.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn u8(&mut self) -> Result<u8> {
+        let (byte, rest) = self
+            .0
+            .split_first()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "frame truncated"))?;
+        self.0 = rest;
+        Ok(*byte)
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        let mut bytes = [0; 8];
+        for byte in &mut bytes {
+            *byte = self.u8()?;
+        }
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    fn varint(&mut self) -> Result<usize> {
+        let mut value = 0usize;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7f) as usize) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(Error::new(ErrorKind::InvalidData, "varint overflow"))
+    }
+
+    /// Reads a varint length-prefixed byte string.
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.varint()?;
+        if len > self.0.len() {
+            return Err(Error::new(ErrorKind::InvalidData, "frame truncated"));
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    /// Reads the next protobuf field number and value.
+    fn proto_field(&mut self) -> Result<Option<(usize, ProtoValue<'a>)>> {
+        if self.0.is_empty() {
+            return Ok(None);
+        }
+        let key = self.varint()?;
+        let value = match key & 0b111 {
+            0 => ProtoValue::Varint(self.varint()?),
+            1 => {
+                self.u64()?;
+                ProtoValue::Fixed
+            }
+            2 => ProtoValue::Bytes(self.bytes()?),
+            5 => {
+                for _ in 0..4 {
+                    self.u8()?;
+                }
+                ProtoValue::Fixed
+            }
+            _ => return Err(Error::new(ErrorKind::InvalidData, "unknown wire type")),
+        };
+        Ok(Some((key >> 3, value)))
+    }
+}
+
+/// Encodes the peers of a `Message::PeerExchange`.
+fn encode_peers(peers: &[(PeerId, Vec<Multiaddr>)]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    write_varint(&mut buf, peers.len());
+    for (peer, addresses) in peers {
+        write_bytes(&mut buf, &peer.to_bytes());
+        write_varint(&mut buf, addresses.len());
+        for address in addresses {
+            write_bytes(&mut buf, &address.to_vec());
+        }
+    }
+    buf
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// Writes a varint length-prefixed byte string.
+fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    write_varint(buf, bytes.len());
+    buf.extend_from_slice(bytes);
+}
+
+fn write_proto_varint(buf: &mut Vec<u8>, field: usize, value: usize) {
+    write_varint(buf, field << 3);
+    write_varint(buf, value);
+}
+
+fn write_proto_bytes(buf: &mut Vec<u8>, field: usize, bytes: &[u8]) {
+    write_varint(buf, field << 3 | 2);
+    write_bytes(buf, bytes);
+}
+
+/// Whether received broadcasts are forwarded to other subscribed peers.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RelayMode {
+    /// Messages are only delivered to directly connected peers.
+    Disabled,
+    /// Messages are relayed at most `hops` times.
+    ///
+    /// Relaying should be combined with a seen cache, otherwise messages
+    /// bounce between relays until the hop count is exhausted.
+    Flood { hops: u8 },
+}
+
+impl Default for RelayMode {
+    fn default() -> Self {
+        Self::Disabled
+    }
+}
+
+impl RelayMode {
+    pub(crate) fn hops(&self) -> u8 {
+        match self {
+            Self::Disabled => 0,
+            Self::Flood { hops } => *hops,
+        }
+    }
+}
+
+/// Which connection receives the messages to a peer with several
+/// connections.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConnectionPolicy {
+    /// Any connection, chosen by the swarm.
+    Any,
+    /// The most recently established connection.
+    PreferNewest,
+    /// The connections in turn.
+    RoundRobin,
+    /// All connections. Receivers need a seen cache to drop the duplicates.
+    All,
+}
+
+impl Default for ConnectionPolicy {
+    fn default() -> Self {
+        Self::Any
+    }
+}
+
+type FilterFn = dyn Fn(&PeerId, &Topic) -> bool + Send + Sync;
+
+type SelectorFn = dyn Fn(&PeerId, &Topic, &[ConnectionInfo]) -> Option<ConnectionId> + Send + Sync;
+
+/// Picks the connection broadcasts on a topic are sent over, letting bulk
+/// and latency sensitive topics use separate connections. Returning `None`
+/// leaves the choice to the `ConnectionPolicy`.
+#[derive(Clone)]
+pub(crate) struct ConnectionSelector(Arc<SelectorFn>);
+
+impl ConnectionSelector {
+    pub fn new(
+        f: impl Fn(&PeerId, &Topic, &[ConnectionInfo]) -> Option<ConnectionId> + Send + Sync + 'static,
+    ) -> Self {
+        Self(Arc::new(f))
+    }
+
+    pub fn select(
+        &self,
+        peer: &PeerId,
+        topic: &Topic,
+        connections: &[ConnectionInfo],
+    ) -> Option<ConnectionId> {
+        (self.0)(peer, topic, connections)
+    }
+}
+
+impl std::fmt::Debug for ConnectionSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ConnectionSelector(..)")
+    }
+}
+
+type IdFn = dyn Fn(&Topic, &Extensions, &[u8]) -> MessageId + Send + Sync;
+
+/// Computes the ids of published and received messages.
+#[derive(Clone)]
+pub(crate) struct MessageIdFn(Arc<IdFn>);
+
+impl MessageIdFn {
+    pub fn id(&self, topic: &Topic, ext: &Extensions, payload: &[u8]) -> MessageId {
+        (self.0)(topic, ext, payload)
+    }
+}
+
+impl Default for MessageIdFn {
+    fn default() -> Self {
+        Self(Arc::new(|topic, ext, payload| match &ext.floodsub {
+            Some(id) => MessageId::from_floodsub(topic, id),
+            None => MessageId::new(topic, payload),
+        }))
+    }
+}
+
+impl std::fmt::Debug for MessageIdFn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("MessageIdFn(..)")
+    }
+}
+
+/// Decides which subscriptions of remote peers are tracked.
+#[derive(Clone)]
+pub(crate) struct TopicFilter(Arc<FilterFn>);
+
+impl TopicFilter {
+    pub fn allows(&self, peer: &PeerId, topic: &Topic) -> bool {
+        (self.0)(peer, topic)
+    }
+}
+
+impl std::fmt::Debug for TopicFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("TopicFilter(..)")
+    }
+}
+
+/// Authorizes what remote peers may do on a topic, see
+/// `BroadcastConfig::with_policy`. Everything is allowed by default.
+pub trait AccessPolicy: std::fmt::Debug + Send + Sync {
+    /// Whether the subscription of `peer` to a topic or prefix is tracked.
+    fn allow_subscribe(&self, peer: &PeerId, topic: &Topic) -> bool {
+        let _ = (peer, topic);
+        true
+    }
+
+    /// Whether broadcasts on `topic` published by `peer` are accepted. The
+    /// publisher is the signer of signed broadcasts and the sending peer
+    /// otherwise.
+    fn allow_publish(&self, peer: &PeerId, topic: &Topic) -> bool {
+        let _ = (peer, topic);
+        true
+    }
+}
+
+/// Which buffered message is dropped when `BroadcastConfig::max_buffered_bytes`
+/// is exceeded.
+///
+/// Messages buffered for disconnected peers are dropped first, then the
+/// messages queued for connected peers and retained messages last.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EvictionPolicy {
+    DropOldest,
+    DropLargest,
+    /// Drops the oldest message of the peer with the most buffered bytes.
+    PerPeerFair,
+}
+
+impl EvictionPolicy {
+    /// Index of the message to drop among `(peer, bytes)` pairs, oldest
+    /// first.
+    pub(crate) fn select(self, messages: &[(Option<PeerId>, usize)]) -> Option<usize> {
+        match self {
+            Self::DropOldest if messages.is_empty() => None,
+            Self::DropOldest => Some(0),
+            Self::DropLargest => messages
+                .iter()
+                .enumerate()
+                .rev()
+                .max_by_key(|(_, (_, bytes))| *bytes)
+                .map(|(i, _)| i),
+            Self::PerPeerFair => {
+                let mut peers = FnvHashMap::<Option<PeerId>, usize>::default();
+                for (peer, bytes) in messages {
+                    *peers.entry(*peer).or_default() += bytes;
+                }
+                let (peer, _) = peers.into_iter().max_by_key(|(_, bytes)| *bytes)?;
+                messages.iter().position(|(peer2, _)| *peer2 == peer)
+            }
+        }
+    }
+}
+
+impl Default for EvictionPolicy {
+    fn default() -> Self {
+        Self::DropOldest
+    }
+}
+
+/// What `Broadcast::broadcast` does with a message on a topic without
+/// subscribed peers.
+///
+/// `Error` is the default, so `Broadcast::broadcast` keeps returning
+/// `BroadcastError::NoPeers` as it did before the policy existed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NoPeersPolicy {
+    /// The message is dropped silently.
+    Drop,
+    /// The message is dropped and `BroadcastError::NoPeers` returned.
+    Error,
+    /// Up to `max` messages per topic are held for at most `ttl` and sent to
+    /// the first peer subscribing to the topic.
+    Buffer { max: usize, ttl: Duration },
+}
+
+impl Default for NoPeersPolicy {
+    fn default() -> Self {
+        Self::Error
+    }
+}
+
+/// What `Broadcast::broadcast` does with a message on a topic we aren't
+/// subscribed to.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PublishPolicy {
+    /// The message is published without subscribing.
+    Allow,
+    /// The message is dropped and `BroadcastError::NotSubscribed` returned.
+    RequireSubscription,
+    /// We subscribe to the topic before publishing the message. The
+    /// subscription lasts until `Broadcast::unsubscribe` is called.
+    AutoSubscribe,
+}
+
+impl Default for PublishPolicy {
+    fn default() -> Self {
+        Self::Allow
+    }
+}
+
+/// Delivery guarantee of the broadcasts published on a topic, see
+/// `BroadcastConfig::delivery_mode`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DeliveryMode {
+    /// Each broadcast is sent once.
+    AtMostOnce,
+    /// Broadcasts carry sequence numbers and are retransmitted like the
+    /// ones sent with `Broadcast::broadcast_reliable` until all subscribers
+    /// acknowledged them or `deadline` passed. Receivers drop retransmissions
+    /// by publisher and sequence number, regardless of
+    /// `BroadcastConfig::seen_cache_size`. Broadcasts that don't go to all
+    /// subscribers aren't numbered, see `BroadcastConfig::sequence_numbers`,
+    /// their retransmissions are only dropped by the seen cache.
+    AtLeastOnce { deadline: Duration },
+}
+
+impl Default for DeliveryMode {
+    fn default() -> Self {
+        Self::AtMostOnce
+    }
+}
+
+/// What happens to broadcasts received on topics we aren't subscribed to,
+/// like the ones sent with `Broadcast::send_to`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum UnsolicitedPolicy {
+    /// The message is delivered like any other.
+    Deliver,
+    /// The message is dropped silently.
+    Drop,
+    /// The message is dropped, the sender penalized by
+    /// `PeerScoreParams::unsubscribed_topic_penalty` and
+    /// `BroadcastEvent::UnsolicitedMessage` emitted.
+    Report,
+}
+
+impl Default for UnsolicitedPolicy {
+    fn default() -> Self {
+        Self::Deliver
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BroadcastConfig {
+    pub(crate) max_message_size: usize,
+    pub(crate) max_send_queue_len: usize,
+    pub(crate) backlog_threshold: usize,
+    pub(crate) seen_cache_size: usize,
+    pub(crate) relay_mode: RelayMode,
+    pub(crate) keypair: Option<Keypair>,
+    pub(crate) replay_window: Option<Duration>,
+    pub(crate) validation_mode: ValidationMode,
+    pub(crate) ack_timeout: Duration,
+    pub(crate) versions: Vec<Version>,
+    pub(crate) protocol_name: Option<Bytes>,
+    pub(crate) namespace: Option<String>,
+    pub(crate) peer_rate_limit: Option<RateLimit>,
+    pub(crate) topic_rate_limit: Option<RateLimit>,
+    pub(crate) peer_score_params: Option<PeerScoreParams>,
+    pub(crate) offline_queue: Option<OfflineQueue>,
+    pub(crate) compression: Compression,
+    pub(crate) compression_threshold: usize,
+    pub(crate) message_ttl: Option<Duration>,
+    pub(crate) topic_representation: TopicRepresentation,
+    pub(crate) keep_alive_shared_topics: bool,
+    pub(crate) max_batch_len: usize,
+    pub(crate) batch_window: Duration,
+    pub(crate) adaptive_batching: Option<AdaptiveBatching>,
+    pub(crate) max_fanout: Option<usize>,
+    pub(crate) fanout_weighted_by_score: bool,
+    pub(crate) fanout_weighted_by_latency: bool,
+    pub(crate) history_len: usize,
+    pub(crate) lazy_push: Option<usize>,
+    pub(crate) connection_policy: ConnectionPolicy,
+    pub(crate) connection_selector: Option<ConnectionSelector>,
+    pub(crate) sequence_numbers: bool,
+    pub(crate) min_peers: usize,
+    pub(crate) max_buffered_bytes: Option<usize>,
+    pub(crate) eviction_policy: EvictionPolicy,
+    pub(crate) topic_filter: Option<TopicFilter>,
+    pub(crate) access_policy: Option<Arc<dyn AccessPolicy>>,
+    pub(crate) reject_filtered_subscriptions: bool,
+    pub(crate) send_retries: u32,
+    pub(crate) retry_backoff: Duration,
+    pub(crate) subscription_lease: Option<Duration>,
+    pub(crate) codec: Arc<dyn MessageCodec>,
+    pub(crate) message_id: MessageIdFn,
+    pub(crate) deliver_own_messages: bool,
+    pub(crate) lazy_subscriptions: bool,
+    pub(crate) counted_subscriptions: bool,
+    pub(crate) fragment_size: Option<usize>,
+    pub(crate) no_peers_policy: NoPeersPolicy,
+    pub(crate) publish_policy: PublishPolicy,
+    pub(crate) unsolicited_policy: UnsolicitedPolicy,
+    pub(crate) peer_exchange: Option<usize>,
+    pub(crate) address_hints: Option<usize>,
+    pub(crate) trace_paths: Option<usize>,
+    pub(crate) dial_suggested_peers: bool,
+    pub(crate) dial_on_broadcast: Option<usize>,
+    pub(crate) reorder_window: Option<(usize, Duration)>,
+    pub(crate) topic_heartbeat: Option<(Duration, u32)>,
+    pub(crate) ordered_topics: FnvHashSet<Topic>,
+    pub(crate) delivery_modes: FnvHashMap<Topic, DeliveryMode>,
+    pub(crate) topic_gc: Option<(Duration, Duration)>,
+    pub(crate) topic_sync: Option<Duration>,
+    pub(crate) request_timeout: Duration,
+    pub(crate) substream_timeout: Duration,
+    pub(crate) send_timeout: Option<Duration>,
+    pub(crate) flow_control: Option<u32>,
+    pub(crate) max_inbound_streams: usize,
+    pub(crate) control_substream: bool,
+    pub(crate) max_inbound_streams_per_peer: Option<usize>,
+    pub(crate) max_reassembly_bytes: usize,
+    pub(crate) reassembly_timeout: Duration,
+}
+
+impl BroadcastConfig {
+    /// Maximum payload size of received messages, larger ones are rejected.
+    /// Defaults to 4 MiB.
+    pub fn max_message_size(mut self, size: usize) -> Self {
+        self.max_message_size = size;
+        self
+    }
+
+    /// Maximum number of broadcasts queued per connection, further ones are
+    /// dropped and reported as `BroadcastEvent::OutboundDropped`. Defaults to
+    /// `1024`.
+    pub fn max_send_queue_len(mut self, len: usize) -> Self {
+        self.max_send_queue_len = len;
+        self
+    }
+
+    /// Number of messages queued for a connection from which on the peer
+    /// counts as backlogged, reported with `BroadcastEvent::QueueDrained`
+    /// once everything queued for it was written. Defaults to `256`.
+    pub fn backlog_threshold(mut self, len: usize) -> Self {
+        self.backlog_threshold = len;
+        self
+    }
+
+    /// Number of recently received message ids remembered for deduplication.
+    ///
+    /// A message already in the cache, e.g. because it arrived over another
+    /// path, does not generate a second `BroadcastEvent::Received`. Defaults
+    /// to `0`, which disables deduplication.
+    pub fn seen_cache_size(mut self, size: usize) -> Self {
+        self.seen_cache_size = size;
+        self
+    }
+
+    /// Sets whether received broadcasts are relayed. Defaults to
+    /// `RelayMode::Disabled`.
+    pub fn relay_mode(mut self, mode: RelayMode) -> Self {
+        self.relay_mode = mode;
+        self
+    }
+
+    /// Signs published messages with `keypair`.
+    ///
+    /// Receivers learn the publishing peer of a signed message even when it
+    /// was relayed.
+    pub fn sign_messages(mut self, keypair: Keypair) -> Self {
+        self.keypair = Some(keypair);
+        self
+    }
+
+    /// Signs the time a message was published at along with it and rejects
+    /// signed messages stamped more than `window` ago or ahead, or seen
+    /// before within it, with `BroadcastEvent::ReplayRejected`.
+    ///
+    /// Signed messages without a timestamp are rejected as well, so all
+    /// publishers need to enable it. Clocks of the peers must not drift
+    /// apart by more than `window`.
+    pub fn replay_window(mut self, window: Duration) -> Self {
+        self.replay_window = Some(window);
+        self
+    }
+
+    /// Sets how signatures of received messages are checked. Defaults to
+    /// `ValidationMode::Permissive`.
+    pub fn validation_mode(mut self, mode: ValidationMode) -> Self {
+        self.validation_mode = mode;
+        self
+    }
+
+    /// How long to wait for acknowledgements of messages sent with
+    /// `Broadcast::broadcast_with_ack`. Defaults to 10 seconds.
+    pub fn ack_timeout(mut self, timeout: Duration) -> Self {
+        self.ack_timeout = timeout;
+        self
+    }
+
+    /// Protocol versions offered to peers in order of preference. Defaults
+    /// to `Version::V1_1` and `Version::V1_0`.
+    pub fn protocol_versions(mut self, versions: Vec<Version>) -> Self {
+        self.versions = versions;
+        self
+    }
+
+    /// Additionally speaks `/floodsub/1.0.0` with peers that don't support
+    /// the broadcast protocol.
+    ///
+    /// Floodsub messages are identified by their publisher and sequence
+    /// number, see `Extensions::floodsub`, and received ones are reported
+    /// with their publisher as source. Pubsub implementations requiring
+    /// signed messages only accept broadcasts when `sign_messages` is set.
+    /// Their signatures are not checked.
+    pub fn floodsub_compat(mut self) -> Self {
+        if !self.versions.contains(&Version::Floodsub) {
+            self.versions.push(Version::Floodsub);
+        }
+        self
+    }
+
+    /// Speaks `Version::V1_1` as `name`, like `/myapp/broadcast/1.0.0`,
+    /// instead of `/ax/broadcast/1.1.0` so unrelated deployments don't
+    /// negotiate with each other. Compressed variants append the algorithm,
+    /// like `/myapp/broadcast/1.0.0/zstd`, and `Version::V1_0` isn't offered.
+    pub fn protocol_name(mut self, name: impl Into<String>) -> Self {
+        self.protocol_name = Some(Bytes::from(name.into()));
+        self
+    }
+
+    /// Separates the topics returned by `Broadcast::topic` from the ones of
+    /// other namespaces, so deployments like testnet and mainnet can share
+    /// peers and relays without exchanging messages. Unlike
+    /// `protocol_name`, peers of different namespaces still connect.
+    ///
+    /// Panics if the namespace contains a `/`.
+    pub fn namespace(mut self, namespace: impl Into<String>) -> Self {
+        let namespace = namespace.into();
+        assert!(!namespace.contains('/'), "namespace contains a '/'");
+        self.namespace = Some(namespace);
+        self
+    }
+
+    /// Limits the rate of broadcasts sent to each peer.
+    ///
+    /// Broadcasts exceeding the limit are delayed and reported as
+    /// `BroadcastEvent::RateLimited`, at most `max_send_queue_len` per peer.
+    pub fn peer_rate_limit(mut self, limit: RateLimit) -> Self {
+        self.peer_rate_limit = Some(limit);
+        self
+    }
+
+    /// Limits the rate of broadcasts sent on each topic, summed over all
+    /// peers.
+    pub fn topic_rate_limit(mut self, limit: RateLimit) -> Self {
+        self.topic_rate_limit = Some(limit);
+        self
+    }
+
+    /// Enables scoring of peers, ignoring or disconnecting peers that
+    /// misbehave.
+    pub fn peer_scoring(mut self, params: PeerScoreParams) -> Self {
+        self.peer_score_params = Some(params);
+        self
+    }
 
-    pub fn new(topic: &[u8]) -> Self {
-        let mut bytes = [0u8; 64];
-        bytes[..topic.len()].copy_from_slice(topic);
+    /// Buffers broadcasts for subscribed peers that disconnected and sends
+    /// them when the peer reconnects.
+    pub fn offline_queue(mut self, limits: OfflineQueue) -> Self {
+        self.offline_queue = Some(limits);
+        self
+    }
+
+    /// Compresses broadcast payloads sent to peers supporting the
+    /// algorithm. Defaults to `Compression::None`.
+    ///
+    /// Received payloads are decompressed with any algorithm enabled by a
+    /// crate feature, up to `max_message_size` bytes.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Payloads smaller than this are sent uncompressed. Defaults to 1 KiB.
+    pub fn compression_threshold(mut self, size: usize) -> Self {
+        self.compression_threshold = size;
+        self
+    }
+
+    /// Drops published messages that haven't been delivered within `ttl`,
+    /// whether they are queued, buffered for offline peers or relayed.
+    ///
+    /// Expiry is checked against the local clock, so clocks of the peers
+    /// need to be roughly in sync.
+    pub fn message_ttl(mut self, ttl: Duration) -> Self {
+        self.message_ttl = Some(ttl);
+        self
+    }
+
+    /// Sets how names passed to `Broadcast::topic` are represented on the
+    /// wire. Defaults to `TopicRepresentation::Raw`.
+    pub fn topic_representation(mut self, representation: TopicRepresentation) -> Self {
+        self.topic_representation = representation;
+        self
+    }
+
+    /// Keeps connections to peers sharing at least one topic with us alive,
+    /// while connections without shared topics close once idle. Defaults to
+    /// `false`.
+    pub fn keep_alive_shared_topics(mut self, keep_alive: bool) -> Self {
+        self.keep_alive_shared_topics = keep_alive;
+        self
+    }
+
+    /// Maximum number of queued messages written to a `Version::V1_1`
+    /// substream as a single batch frame. Defaults to `64`, `1` disables
+    /// batching.
+    pub fn max_batch_len(mut self, len: usize) -> Self {
+        self.max_batch_len = len;
+        self
+    }
+
+    /// How long to wait for further messages before writing a batch that
+    /// isn't full. Defaults to zero, batching only the messages queued while
+    /// the previous write was in progress.
+    pub fn batch_window(mut self, window: Duration) -> Self {
+        self.batch_window = window;
+        self
+    }
+
+    /// Writes queued messages right away while a peer's send queue is
+    /// shallow, and only waits for the `batch_window` and coalesces larger
+    /// batches once it is congested.
+    pub fn adaptive_batching(mut self, batching: AdaptiveBatching) -> Self {
+        self.adaptive_batching = Some(batching);
+        self
+    }
+
+    /// Sends each published or relayed broadcast to at most `fanout`
+    /// randomly chosen subscribers, in addition to the explicit peers.
+    ///
+    /// Combined with `relay_mode` messages still reach all subscribers with
+    /// high probability.
+    pub fn max_fanout(mut self, fanout: usize) -> Self {
+        self.max_fanout = Some(fanout);
+        self
+    }
+
+    /// Prefers peers with a higher score when sampling the fanout. Requires
+    /// `peer_scoring`.
+    pub fn fanout_weighted_by_score(mut self) -> Self {
+        self.fanout_weighted_by_score = true;
+        self
+    }
+
+    /// Prefers peers with a lower latency when sampling the fanout, see
+    /// `Broadcast::peer_latency`. Peers without a measurement are preferred
+    /// until they are measured.
+    pub fn fanout_weighted_by_latency(mut self) -> Self {
+        self.fanout_weighted_by_latency = true;
+        self
+    }
+
+    /// Keeps the last `len` messages of each topic. Peers (re)subscribing to
+    /// a topic are offered their ids and can request the ones they missed,
+    /// see also `Broadcast::replay`.
+    ///
+    /// Missed messages are detected with the seen cache, so
+    /// `seen_cache_size` should be set as well. Defaults to 0.
+    pub fn history_len(mut self, len: usize) -> Self {
+        self.history_len = len;
+        self
+    }
+
+    /// Offers broadcasts with payloads larger than `threshold` bytes with a
+    /// `Message::IHave` instead of sending them, so peers receiving them
+    /// from several relays only download them once. The payload is sent to
+    /// the peers answering with a `Message::IWant`.
+    ///
+    /// Trades a round trip for bandwidth, peers speaking older versions
+    /// receive the payload right away. Requires `seen_cache_size` on the
+    /// receivers.
+    pub fn lazy_push(mut self, threshold: usize) -> Self {
+        self.lazy_push = Some(threshold);
+        self
+    }
+
+    /// Which connection receives the messages to a peer with several
+    /// connections. Defaults to `ConnectionPolicy::Any`.
+    pub fn connection_policy(mut self, policy: ConnectionPolicy) -> Self {
+        self.connection_policy = policy;
+        self
+    }
+
+    /// Sends the broadcasts on a topic over the connection returned by
+    /// `selector`, for example to keep bulk topics from delaying others
+    /// behind them. Other messages and broadcasts for which it returns
+    /// `None` use the `connection_policy`.
+    pub fn connection_selector(
+        mut self,
+        selector: impl Fn(&PeerId, &Topic, &[ConnectionInfo]) -> Option<ConnectionId>
+            + Send
+            + Sync
+            + 'static,
+    ) -> Self {
+        self.connection_selector = Some(ConnectionSelector::new(selector));
+        self
+    }
+
+    /// Numbers the messages broadcast on each topic, letting receivers
+    /// report missed messages with `BroadcastEvent::GapDetected`.
+    ///
+    /// Broadcasts that don't go to all subscribers, because of
+    /// `Broadcast::broadcast_except`, key filters, `max_fanout` or the
+    /// sequencer of a `total_order` topic, aren't numbered.
+    pub fn sequence_numbers(mut self) -> Self {
+        self.sequence_numbers = true;
+        self
+    }
+
+    /// Looks for more subscribers of a topic with fewer than `peers` peers
+    /// through `Broadcast::set_discovery`. Defaults to 0.
+    pub fn min_peers(mut self, peers: usize) -> Self {
+        self.min_peers = peers;
+        self
+    }
+
+    /// Limits the payload bytes of the broadcasts queued for connected
+    /// peers, buffered for disconnected peers and retained, dropping
+    /// messages with the `eviction_policy` and reporting them as
+    /// `BroadcastEvent::Evicted`. Unlimited by default.
+    pub fn max_buffered_bytes(mut self, bytes: usize) -> Self {
+        self.max_buffered_bytes = Some(bytes);
+        self
+    }
+
+    /// Which message is dropped once `max_buffered_bytes` is exceeded.
+    /// Defaults to `EvictionPolicy::DropOldest`.
+    pub fn eviction_policy(mut self, policy: EvictionPolicy) -> Self {
+        self.eviction_policy = policy;
+        self
+    }
+
+    /// Only tracks the subscriptions to topics and prefixes for which
+    /// `filter` returns true. Others are reported as
+    /// `BroadcastEvent::SubscriptionFiltered` and the peer doesn't receive
+    /// broadcasts on them.
+    pub fn topic_filter(
+        mut self,
+        filter: impl Fn(&PeerId, &Topic) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.topic_filter = Some(TopicFilter(Arc::new(filter)));
+        self
+    }
+
+    /// Computes the ids used to deduplicate, acknowledge and request
+    /// messages, for example `MessageId::from_origin`. All peers need to
+    /// use the same function. Defaults to `MessageId::new`.
+    pub fn message_id_fn(
+        mut self,
+        f: impl Fn(&Topic, &Extensions, &[u8]) -> MessageId + Send + Sync + 'static,
+    ) -> Self {
+        self.message_id = MessageIdFn(Arc::new(f));
+        self
+    }
+
+    /// Only tracks the subscriptions to `topics`, see `topic_filter`.
+    pub fn topic_allowlist(self, topics: impl IntoIterator<Item = Topic>) -> Self {
+        let topics: FnvHashSet<Topic> = topics.into_iter().collect();
+        self.topic_filter(move |_, topic| topics.contains(topic))
+    }
+
+    /// Checks the subscriptions and broadcasts of remote peers with `policy`.
+    /// Denied subscriptions are treated like those rejected by the
+    /// `topic_filter`, denied broadcasts are dropped and reported as
+    /// `BroadcastEvent::InvalidMessage`.
+    pub fn with_policy(mut self, policy: impl AccessPolicy + 'static) -> Self {
+        self.access_policy = Some(Arc::new(policy));
+        self
+    }
+
+    /// Tells peers about filtered subscriptions, reported to them as
+    /// `BroadcastEvent::SubscribeRejected`.
+    pub fn reject_filtered_subscriptions(mut self) -> Self {
+        self.reject_filtered_subscriptions = true;
+        self
+    }
+
+    /// Retries writing messages up to `retries` times when opening or
+    /// writing a substream fails, waiting `backoff` before the first retry
+    /// and doubling it after each one. Broadcasts still not written are
+    /// reported as `BroadcastEvent::SendError`. No retries by default.
+    pub fn retry_sends(mut self, retries: u32, backoff: Duration) -> Self {
+        self.send_retries = retries;
+        self.retry_backoff = backoff;
+        self
+    }
+
+    /// Announces our subscriptions with a lease of `ttl`, renewed after
+    /// half of it passed. Peers drop the subscriptions when the lease
+    /// expires, in case the connection died without being closed. Peers
+    /// speaking `Version::V1_0` or floodsub get permanent subscriptions.
+    pub fn subscription_lease(mut self, ttl: Duration) -> Self {
+        self.subscription_lease = Some(ttl);
+        self
+    }
+
+    /// Encodes messages with `codec` instead of the `DefaultCodec`, for
+    /// embedding the protocol into an existing wire format. Floodsub
+    /// substreams keep using the pubsub format.
+    pub fn with_codec(mut self, codec: impl MessageCodec + 'static) -> Self {
+        self.codec = Arc::new(codec);
+        self
+    }
+
+    /// Also reports messages we broadcast on a topic we're subscribed to as
+    /// `BroadcastEvent::Received` from the local peer. Defaults to false.
+    ///
+    /// The local copy doesn't count as sent: a broadcast without remote
+    /// recipients still follows `NoPeersPolicy`, and the local copy is only
+    /// delivered if the broadcast succeeds.
+    pub fn deliver_own_messages(mut self, deliver: bool) -> Self {
+        self.deliver_own_messages = deliver;
+        self
+    }
+
+    /// Sends a `TopicSummary` of our subscriptions to new peers instead of
+    /// the subscriptions themselves. Peers only announce their
+    /// subscriptions to topics the summary may contain, so a peer isn't
+    /// told about our subscriptions to topics it isn't subscribed to until
+    /// it subscribes. Peers not speaking `Version::V1_1` get all
+    /// subscriptions.
+    pub fn lazy_subscriptions(mut self) -> Self {
+        self.lazy_subscriptions = true;
+        self
+    }
+
+    /// Counts the local subscriptions to a topic, so independent components
+    /// can subscribe to it. Peers are told about the subscription once and
+    /// the unsubscription when the last one ends. By default subscribing
+    /// again does nothing and a single unsubscription ends the subscription.
+    pub fn counted_subscriptions(mut self) -> Self {
+        self.counted_subscriptions = true;
+        self
+    }
+
+    /// Splits broadcasts with a payload larger than `size` into fragments
+    /// written one at a time, so other messages to the peer aren't stuck
+    /// behind them. Only used with peers speaking `Version::V1_1`, other
+    /// peers get the whole message. Fragmented broadcasts are not
+    /// compressed. Disabled by default.
+    pub fn fragment_size(mut self, size: usize) -> Self {
+        self.fragment_size = Some(size.max(1));
+        self
+    }
+
+    /// What to do with broadcasts on topics without subscribed peers.
+    /// Defaults to `NoPeersPolicy::Error`, see `NoPeersPolicy`.
+    pub fn no_peers_policy(mut self, policy: NoPeersPolicy) -> Self {
+        self.no_peers_policy = policy;
+        self
+    }
+
+    /// What to do with our own broadcasts on topics we aren't subscribed
+    /// to. Defaults to `PublishPolicy::Allow`.
+    pub fn publish_policy(mut self, policy: PublishPolicy) -> Self {
+        self.publish_policy = policy;
+        self
+    }
+
+    /// What to do with broadcasts on topics we aren't subscribed to.
+    /// Defaults to `UnsolicitedPolicy::Deliver`.
+    pub fn unsolicited_messages(mut self, policy: UnsolicitedPolicy) -> Self {
+        self.unsolicited_policy = policy;
+        self
+    }
+
+    /// Answers subscriptions of peers with up to `max_peers` other
+    /// subscribers of the topic whose addresses we know, signed with the
+    /// keypair of `sign_messages`. Receivers report them as
+    /// `BroadcastEvent::PeersSuggested`.
+    pub fn peer_exchange(mut self, max_peers: usize) -> Self {
+        self.peer_exchange = Some(max_peers);
+        self
+    }
+
+    /// Attaches up to `max_addresses` of our external addresses to the
+    /// subscriptions we announce, unless they are leased. Receivers report
+    /// them as `BroadcastEvent::PeerAddresses` and dial them to reconnect.
+    pub fn address_hints(mut self, max_addresses: usize) -> Self {
+        self.address_hints = Some(max_addresses);
+        self
+    }
+
+    /// Records the peers a broadcast passed through in `Extensions::path`,
+    /// up to `max_len` of them and at most `MAX_PATH_LENGTH`. Each peer is
+    /// added by the one it sent the broadcast to, so relays can't leave
+    /// themselves out. Peers without the option relay the path unchanged.
+    /// Received broadcasts are reported with their path as
+    /// `BroadcastEvent::Traced`.
+    pub fn trace_paths(mut self, max_len: usize) -> Self {
+        self.trace_paths = Some(max_len.min(MAX_PATH_LENGTH));
+        self
+    }
+
+    /// Dials the peers suggested for topics we're subscribed to. Defaults to
+    /// false.
+    pub fn dial_suggested_peers(mut self, dial: bool) -> Self {
+        self.dial_suggested_peers = dial;
+        self
+    }
+
+    /// Holds back up to `max_messages` received broadcasts per publisher and
+    /// topic for at most `timeout` until the ones published before them
+    /// arrived, delivering them in order. Missing messages are reported as
+    /// `BroadcastEvent::GapDetected` once skipped. Requires
+    /// `sequence_numbers` on the publishers.
+    pub fn reorder_window(mut self, max_messages: usize, timeout: Duration) -> Self {
+        self.reorder_window = Some((max_messages, timeout));
+        self
+    }
+
+    /// Sends a heartbeat every `interval` for each topic we share with a
+    /// peer, reporting peers that missed `max_missed` heartbeats in a row as
+    /// `BroadcastEvent::PeerUnresponsive`.
+    pub fn topic_heartbeat(mut self, interval: Duration, max_missed: u32) -> Self {
+        self.topic_heartbeat = Some((interval, max_missed));
+        self
+    }
+
+    /// Experimental: delivers the broadcasts on `topic` in the same order to
+    /// all subscribers. The subscriber with the lowest peer id, including us,
+    /// is elected sequencer and stamps the messages published by the others,
+    /// which are sent to it only. Stamped messages are held back until the
+    /// ones before them arrived, reporting those missing for too long with
+    /// `BroadcastEvent::GapDetected`. Meant for small groups whose members
+    /// are all connected to each other.
+    pub fn total_order(mut self, topic: Topic) -> Self {
+        self.ordered_topics.insert(topic);
+        self
+    }
+
+    /// Sets the delivery guarantee of the broadcasts we publish on `topic`,
+    /// `DeliveryMode::AtMostOnce` by default. Results of at-least-once
+    /// broadcasts sent to peers speaking `Version::V1_1` are reported as
+    /// `BroadcastEvent::ReliableBroadcastResult`.
+    pub fn delivery_mode(mut self, topic: Topic, mode: DeliveryMode) -> Self {
+        self.delivery_modes.insert(topic, mode);
+        self
+    }
+
+    /// Checks every `interval` for topics without subscribers, forgetting
+    /// those that had none for `retention` and reporting them as
+    /// `BroadcastEvent::TopicForgotten`. Defaults to checking every minute
+    /// with a retention of ten minutes.
+    pub fn topic_gc(mut self, interval: Duration, retention: Duration) -> Self {
+        self.topic_gc = Some((interval, retention));
+        self
+    }
+
+    /// Time allowed for negotiating the protocol on a new substream.
+    /// Defaults to ten seconds, which may be too short for high latency
+    /// links.
+    pub fn substream_timeout(mut self, timeout: Duration) -> Self {
+        self.substream_timeout = timeout;
+        self
+    }
+
+    /// Fails writes to a substream that take longer than `timeout`, like
+    /// other write errors. Writes don't time out by default.
+    pub fn send_timeout(mut self, timeout: Duration) -> Self {
+        self.send_timeout = Some(timeout);
+        self
+    }
+
+    /// Allows each peer to write `credits` broadcasts to a connection before
+    /// it has to wait for more, granted as their events are passed on. Peers
+    /// out of credits queue their broadcasts and emit
+    /// `BroadcastEvent::PeerBackpressured`. Only `Version::V1_1` substreams
+    /// carry credits.
+    pub fn flow_control(mut self, credits: u32) -> Self {
+        self.flow_control = Some(credits);
+        self
+    }
+
+    /// How long `Broadcast::request` collects replies. Defaults to five
+    /// seconds.
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// Sends all our subscriptions to each peer every `interval`, repairing
+    /// the peers' view of them after lost subscription frames. See
+    /// `Broadcast::resync`.
+    pub fn topic_sync(mut self, interval: Duration) -> Self {
+        self.topic_sync = Some(interval);
+        self
+    }
+
+    /// Redials peers that were subscribed to a topic before disconnecting
+    /// when it is broadcast on with fewer than `min_peers` connected
+    /// subscribers. The broadcast isn't delayed, combine with
+    /// `NoPeersPolicy::Buffer` or `offline_queue` to deliver it once they
+    /// reconnect.
+    pub fn dial_on_broadcast(mut self, min_peers: usize) -> Self {
+        self.dial_on_broadcast = Some(min_peers);
+        self
+    }
+
+    /// Maximum number of concurrently open inbound substreams per
+    /// connection, further ones are closed right away. Peers speaking
+    /// `Version::V1_1` need a single one, two with `control_substream`.
+    /// Defaults to 16.
+    pub fn max_inbound_streams(mut self, max: usize) -> Self {
+        self.max_inbound_streams = max;
+        self
+    }
+
+    /// Writes control messages like subscriptions, acks and heartbeats to a
+    /// second substream, so they aren't delayed by large broadcasts. Costs an
+    /// extra substream per connection, the order of control messages and
+    /// broadcasts isn't kept.
+    ///
+    /// Control messages are written to the broadcast substream if the second
+    /// one fails or the peer speaks `Version::V1_0`. Defaults to `false`.
+    pub fn control_substream(mut self) -> Self {
+        self.control_substream = true;
+        self
+    }
+
+    /// Maximum number of concurrently open inbound substreams over all
+    /// connections to a peer. Unlimited by default.
+    pub fn max_inbound_streams_per_peer(mut self, max: usize) -> Self {
+        self.max_inbound_streams_per_peer = Some(max);
+        self
+    }
+
+    /// Limits the bytes of incomplete fragmented messages buffered per
+    /// connection, dropping the oldest ones first, and drops messages that
+    /// weren't completed within `timeout`. Defaults to 16 MiB and 30
+    /// seconds.
+    pub fn reassembly_limits(mut self, max_bytes: usize, timeout: Duration) -> Self {
+        self.max_reassembly_bytes = max_bytes;
+        self.reassembly_timeout = timeout;
+        self
+    }
+}
+
+impl Default for BroadcastConfig {
+    fn default() -> Self {
         Self {
-            len: topic.len() as _,
-            bytes,
+            max_message_size: 1024 * 1024 * 4,
+            max_send_queue_len: 1024,
+            backlog_threshold: 256,
+            seen_cache_size: 0,
+            relay_mode: RelayMode::Disabled,
+            keypair: None,
+            replay_window: None,
+            validation_mode: ValidationMode::Permissive,
+            ack_timeout: Duration::from_secs(10),
+            versions: vec![Version::V1_1, Version::V1_0],
+            protocol_name: None,
+            namespace: None,
+            peer_rate_limit: None,
+            topic_rate_limit: None,
+            peer_score_params: None,
+            offline_queue: None,
+            compression: Compression::None,
+            compression_threshold: 1024,
+            message_ttl: None,
+            topic_representation: TopicRepresentation::Raw,
+            keep_alive_shared_topics: false,
+            max_batch_len: 64,
+            batch_window: Duration::ZERO,
+            adaptive_batching: None,
+            max_fanout: None,
+            fanout_weighted_by_score: false,
+            fanout_weighted_by_latency: false,
+            history_len: 0,
+            lazy_push: None,
+            connection_policy: ConnectionPolicy::Any,
+            connection_selector: None,
+            sequence_numbers: false,
+            min_peers: 0,
+            max_buffered_bytes: None,
+            eviction_policy: EvictionPolicy::DropOldest,
+            topic_filter: None,
+            access_policy: None,
+            reject_filtered_subscriptions: false,
+            send_retries: 0,
+            retry_backoff: Duration::from_millis(100),
+            subscription_lease: None,
+            codec: Arc::new(DefaultCodec),
+            message_id: MessageIdFn::default(),
+            deliver_own_messages: false,
+            lazy_subscriptions: false,
+            counted_subscriptions: false,
+            fragment_size: None,
+            no_peers_policy: NoPeersPolicy::Error,
+            publish_policy: PublishPolicy::Allow,
+            unsolicited_policy: UnsolicitedPolicy::Deliver,
+            peer_exchange: None,
+            address_hints: None,
+            trace_paths: None,
+            dial_suggested_peers: false,
+            dial_on_broadcast: None,
+            reorder_window: None,
+            topic_heartbeat: None,
+            ordered_topics: FnvHashSet::default(),
+            delivery_modes: FnvHashMap::default(),
+            topic_gc: Some((Duration::from_secs(60), Duration::from_secs(600))),
+            topic_sync: None,
+            request_timeout: Duration::from_secs(5),
+            substream_timeout: Duration::from_secs(10),
+            send_timeout: None,
+            flow_control: None,
+            max_inbound_streams: 16,
+            control_substream: false,
+            max_inbound_streams_per_peer: None,
+            max_reassembly_bytes: 1024 * 1024 * 16,
+            reassembly_timeout: Duration::from_secs(30),
         }
     }
 }
 
-impl std::ops::Deref for Topic {
-    type Target = [u8];
+/// Which received broadcasts are checked for a valid signature.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ValidationMode {
+    /// Only signed messages with a valid signature are accepted.
+    Strict,
+    /// Signatures are verified when present, unsigned messages are accepted.
+    Permissive,
+    /// Signatures are ignored.
+    None,
+}
 
-    fn deref(&self) -> &Self::Target {
-        self.as_ref()
+impl Default for ValidationMode {
+    fn default() -> Self {
+        Self::Permissive
     }
 }
 
-impl AsRef<[u8]> for Topic {
-    fn as_ref(&self) -> &[u8] {
-        &self.bytes[..(self.len as usize)]
-    }
+/// Outcome of validating a received message with a topic validator.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ValidationResult {
+    /// The message is delivered and relayed.
+    Accept,
+    /// The message is dropped and the sender penalized.
+    Reject,
+    /// The message is dropped silently.
+    Ignore,
 }
 
-#[derive(Clone, Debug, PartialEq)]
-pub enum Message {
-    Subscribe(Topic),
-    Broadcast(Topic, Arc<[u8]>),
-    Unsubscribe(Topic),
+/// Version of the broadcast protocol spoken on a substream.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum Version {
+    /// `/ax/broadcast/1.0.0`, a single message per substream and no
+    /// extensions.
+    V1_0,
+    /// `/ax/broadcast/1.1.0`, any number of length-prefixed messages per
+    /// substream including extended frames.
+    V1_1,
+    /// `/floodsub/1.0.0`, for exchanging messages with pubsub
+    /// implementations. Topics are mapped to and from topic strings.
+    Floodsub,
 }
 
-impl Message {
-    fn from_bytes(bytes: &[u8]) -> Result<Self> {
-        if bytes.is_empty() {
-            return Err(Error::new(ErrorKind::InvalidData, "empty message"));
-        }
-        let topic_len = (bytes[0] >> 2) as usize;
-        if bytes.len() < topic_len + 1 {
-            return Err(Error::new(
-                ErrorKind::InvalidData,
-                "topic length out of range",
-            ));
+impl Version {
+    pub fn protocol_name(&self) -> &'static [u8] {
+        match self {
+            Self::V1_0 => PROTOCOL_INFO,
+            Self::V1_1 => STREAM_PROTOCOL_INFO,
+            Self::Floodsub => FLOODSUB_PROTOCOL_INFO,
         }
-        let msg_len = bytes.len() - topic_len - 1;
-        let topic = Topic::new(&bytes[1..topic_len + 1]);
-        Ok(match bytes[0] & 0b11 {
-            0b00 => Message::Subscribe(topic),
-            0b10 => Message::Unsubscribe(topic),
-            0b01 => {
-                let mut msg = Vec::with_capacity(msg_len);
-                msg.extend_from_slice(&bytes[(topic_len + 1)..]);
-                Message::Broadcast(topic, msg.into())
-            }
-            _ => return Err(Error::new(ErrorKind::InvalidData, "invalid header")),
-        })
     }
 
-    fn to_bytes(&self) -> Vec<u8> {
-        use Message::*;
-        match self {
-            Subscribe(topic) => {
-                let mut buf = Vec::with_capacity(topic.len() + 1);
-                buf.push((topic.len() as u8) << 2);
-                buf.extend_from_slice(topic);
-                buf
-            }
-            Unsubscribe(topic) => {
-                let mut buf = Vec::with_capacity(topic.len() + 1);
-                buf.push((topic.len() as u8) << 2 | 0b10);
-                buf.extend_from_slice(topic);
-                buf
-            }
-            Broadcast(topic, msg) => {
-                let mut buf = Vec::with_capacity(topic.len() + msg.len() + 1);
-                buf.push((topic.len() as u8) << 2 | 0b01);
-                buf.extend_from_slice(topic);
-                buf.extend_from_slice(msg);
-                buf
+    /// Whether a substream carries more than one message.
+    pub(crate) fn is_stream(&self) -> bool {
+        *self == Self::V1_1
+    }
+
+    /// Converts `msg` into a form this version can encode, `None` if it has
+    /// no equivalent.
+    pub(crate) fn encodable(&self, msg: Message) -> Option<Message> {
+        match (self, msg) {
+            (Self::V1_1, msg) => Some(msg),
+            (Self::Floodsub, Message::Broadcast(topic, ext, msg)) => Some(Message::Broadcast(
+                topic,
+                Extensions {
+                    floodsub: ext.floodsub,
+                    ..Default::default()
+                },
+                msg,
+            )),
+            (_, Message::Broadcast(topic, _, msg)) => {
+                Some(Message::Broadcast(topic, Extensions::default(), msg))
             }
+            (_, msg @ (Message::Subscribe(_) | Message::Unsubscribe(_))) => Some(msg),
+            (
+                _,
+                Message::SubscribeLease(topic, _)
+                | Message::SubscribeWithSnapshot(topic)
+                | Message::SubscribeWithAddresses(topic, _),
+            ) => Some(Message::Subscribe(topic)),
+            (Self::Floodsub, msg @ Message::SubscribeMany(_)) => Some(msg),
+            (Self::Floodsub, Message::SyncTopics(topics)) => Some(Message::SubscribeMany(topics)),
+            _ => None,
         }
     }
 }
 
+/// Upgrade negotiating the broadcast protocol on a substream.
+///
+/// The protocols are offered in order of preference, compressed variants of
+/// `Version::V1_1` before the uncompressed one.
 #[derive(Clone, Debug)]
-pub struct BroadcastConfig {
-    max_buf_size: usize,
+pub struct BroadcastProtocol {
+    protocols: Vec<(Bytes, Version, Option<Codec>)>,
 }
 
-impl Default for BroadcastConfig {
-    fn default() -> Self {
-        Self {
-            max_buf_size: 1024 * 1024 * 4,
+impl BroadcastProtocol {
+    /// Offers `versions`, naming `Version::V1_1` `name` if set, see
+    /// `BroadcastConfig::protocol_name`.
+    pub fn new(versions: &[Version], codecs: &[Codec], name: Option<&Bytes>) -> Self {
+        let mut protocols = Vec::new();
+        for version in versions {
+            match (version, name) {
+                (Version::V1_0, Some(_)) => continue,
+                (Version::V1_1, Some(name)) => {
+                    for codec in codecs {
+                        let mut codec_name = name.to_vec();
+                        codec_name.extend_from_slice(b"/");
+                        codec_name.extend_from_slice(codec.name().as_bytes());
+                        protocols.push((Bytes::from(codec_name), *version, Some(*codec)));
+                    }
+                    protocols.push((name.clone(), *version, None));
+                    continue;
+                }
+                (Version::V1_1, None) => {
+                    for codec in codecs {
+                        let codec_name = Bytes::from_static(codec.protocol_name());
+                        protocols.push((codec_name, *version, Some(*codec)));
+                    }
+                }
+                _ => {}
+            }
+            protocols.push((Bytes::from_static(version.protocol_name()), *version, None));
         }
+        Self { protocols }
+    }
+
+    fn negotiated(&self, info: &[u8]) -> (Version, Option<Codec>) {
+        self.protocols
+            .iter()
+            .find(|(name, _, _)| name[..] == *info)
+            .map(|(_, version, codec)| (*version, *codec))
+            .unwrap_or((Version::V1_0, None))
+    }
+}
+
+impl Default for BroadcastProtocol {
+    fn default() -> Self {
+        Self::new(&[Version::V1_1, Version::V1_0], &[], None)
     }
 }
 
-impl UpgradeInfo for BroadcastConfig {
-    type Info = &'static [u8];
-    type InfoIter = std::iter::Once<Self::Info>;
+impl UpgradeInfo for BroadcastProtocol {
+    type Info = Bytes;
+    type InfoIter = Vec<Self::Info>;
 
     fn protocol_info(&self) -> Self::InfoIter {
-        std::iter::once(PROTOCOL_INFO)
+        self.protocols
+            .iter()
+            .map(|(name, _, _)| name.clone())
+            .collect()
     }
 }
 
-impl<TSocket> InboundUpgrade<TSocket> for BroadcastConfig
+impl<TSocket> InboundUpgrade<TSocket> for BroadcastProtocol
 where
     TSocket: AsyncRead + AsyncWrite + Send + Unpin + 'static,
 {
-    type Output = Message;
+    type Output = (TSocket, Version, Option<Codec>);
     type Error = Error;
-    type Future = BoxFuture<'static, Result<Self::Output>>;
-
-    fn upgrade_inbound(self, mut socket: TSocket, _info: Self::Info) -> Self::Future {
-        Box::pin(async move {
-            let packet = upgrade::read_length_prefixed(&mut socket, self.max_buf_size).await?;
-            socket.close().await?;
-            let request = Message::from_bytes(&packet)?;
-            Ok(request)
-        })
-    }
-}
+    type Future = future::Ready<Result<Self::Output>>;
 
-impl UpgradeInfo for Message {
-    type Info = &'static [u8];
-    type InfoIter = std::iter::Once<Self::Info>;
-
-    fn protocol_info(&self) -> Self::InfoIter {
-        std::iter::once(PROTOCOL_INFO)
+    fn upgrade_inbound(self, socket: TSocket, info: Self::Info) -> Self::Future {
+        let (version, codec) = self.negotiated(&info);
+        future::ok((socket, version, codec))
     }
 }
 
-impl<TSocket> OutboundUpgrade<TSocket> for Message
+impl<TSocket> OutboundUpgrade<TSocket> for BroadcastProtocol
 where
     TSocket: AsyncRead + AsyncWrite + Send + Unpin + 'static,
 {
-    type Output = ();
+    type Output = (TSocket, Version, Option<Codec>);
     type Error = Error;
-    type Future = BoxFuture<'static, Result<Self::Output>>;
-
-    fn upgrade_outbound(self, mut socket: TSocket, _info: Self::Info) -> Self::Future {
-        Box::pin(async move {
-            let bytes = self.to_bytes();
-            upgrade::write_length_prefixed(&mut socket, bytes).await?;
-            socket.close().await?;
-            Ok(())
-        })
+    type Future = future::Ready<Result<Self::Output>>;
+
+    fn upgrade_outbound(self, socket: TSocket, info: Self::Info) -> Self::Future {
+        let (version, codec) = self.negotiated(&info);
+        future::ok((socket, version, codec))
     }
 }
 
@@ -172,16 +2716,352 @@ mod tests {
     #[test]
     fn test_roundtrip() {
         let topic = Topic::new(b"topic");
+        let keypair = Keypair::generate_ed25519();
         let msgs = [
-            Message::Broadcast(Topic::new(b""), Arc::new(*b"")),
+            Message::Broadcast(
+                Topic::new(b""),
+                Extensions::default(),
+                Bytes::from_static(b""),
+            ),
             Message::Subscribe(topic),
             Message::Unsubscribe(topic),
-            Message::Broadcast(topic, Arc::new(*b"content")),
+            Message::Ack(topic, MessageId::new(&topic, b"content")),
+            Message::SubscribePrefix(topic),
+            Message::UnsubscribePrefix(topic),
+            Message::SubscribeMany(vec![topic, Topic::new(b""), Topic::new(b"other")]),
+            Message::SubscribeMany(vec![]),
+            Message::SyncTopics(vec![topic, Topic::new(b"other")]),
+            Message::SyncTopics(vec![]),
+            Message::IHave(
+                topic,
+                vec![MessageId::new(&topic, b"a"), MessageId::new(&topic, b"b")],
+            ),
+            Message::IWant(topic, vec![MessageId::new(&topic, b"a")]),
+            Message::IWant(topic, vec![]),
+            Message::SubscribeRejected(topic),
+            Message::Heartbeat(topic),
+            Message::SubscribeLease(topic, Duration::from_secs(30)),
+            Message::TopicSummary(TopicSummary::new([topic].iter())),
+            Message::Fragment {
+                id: u64::MAX,
+                index: 300,
+                total: 301,
+                data: Bytes::from_static(b"data"),
+            },
+            Message::Credit(u32::MAX),
+            Message::SubscribeWithSnapshot(topic),
+            Message::SubscribeWithAddresses(
+                topic,
+                vec!["/memory/1".parse().unwrap(), "/memory/2".parse().unwrap()],
+            ),
+            Message::Snapshot(topic, Bytes::from_static(b"state")),
+            Message::Snapshot(Topic::new(b""), Bytes::new()),
+            Message::KeyFilter(topic, vec![Bytes::from_static(b"a"), Bytes::new()]),
+            Message::KeyFilter(topic, vec![]),
+            Message::Replay(topic, ReplaySince::Message(MessageId::new(&topic, b"a"))),
+            Message::PeerExchange(topic, vec![], None),
+            Message::PeerExchange(
+                topic,
+                vec![
+                    (PeerId::random(), vec!["/memory/1".parse().unwrap()]),
+                    (PeerId::random(), vec![]),
+                ],
+                Some(Signature::sign_peers(&keypair, &topic, &[]).unwrap()),
+            ),
+            Message::Batch(vec![
+                Message::Subscribe(topic),
+                Message::Broadcast(
+                    topic,
+                    Extensions {
+                        hops: 2,
+                        ..Default::default()
+                    },
+                    Bytes::from_static(b"content"),
+                ),
+                Message::Broadcast(topic, Extensions::default(), Bytes::from_static(b"")),
+            ]),
+            Message::Broadcast(topic, Extensions::default(), Bytes::from_static(b"content")),
+            Message::Broadcast(
+                topic,
+                Extensions {
+                    ack: true,
+                    ..Default::default()
+                },
+                Bytes::from_static(b"content"),
+            ),
+            Message::Broadcast(
+                topic,
+                Extensions {
+                    hops: 3,
+                    ..Default::default()
+                },
+                Bytes::from_static(b"content"),
+            ),
+            Message::Broadcast(
+                topic,
+                Extensions {
+                    path: Some(Arc::new(vec![PeerId::random(), PeerId::random()])),
+                    key: Some(Bytes::from_static(b"key")),
+                    stamps: Some(Arc::new(vec![RelayStamp {
+                        relay: PeerId::random(),
+                        tag: [7; STAMP_LEN],
+                    }])),
+                    ..Default::default()
+                },
+                Bytes::from_static(b"content"),
+            ),
+            Message::Broadcast(
+                topic,
+                Extensions {
+                    compressed: true,
+                    ..Default::default()
+                },
+                Bytes::from_static(b"content"),
+            ),
+            Message::Broadcast(
+                topic,
+                Extensions {
+                    hops: 1,
+                    expires: Some(unix_millis()),
+                    seqno: Some(7),
+                    order: Some(3),
+                    reply_to: Some(u64::MAX),
+                    timestamp: Some(unix_millis()),
+                    ..Default::default()
+                },
+                Bytes::from_static(b"content"),
+            ),
+            Message::Broadcast(
+                topic,
+                Extensions {
+                    signature: Some(Signature::sign(&keypair, &topic, b"content").unwrap()),
+                    ..Default::default()
+                },
+                Bytes::from_static(b"content"),
+            ),
         ];
         for msg in &msgs {
             let msg2 = Message::from_bytes(&msg.to_bytes()).unwrap();
             assert_eq!(msg, &msg2);
         }
+
+        // Times are sent as the time elapsed since.
+        let since = Instant::now() - Duration::from_secs(60);
+        let msg = Message::Replay(topic, ReplaySince::Time(since));
+        match Message::from_bytes(&msg.to_bytes()).unwrap() {
+            Message::Replay(topic2, ReplaySince::Time(since2)) => {
+                assert_eq!(topic2, topic);
+                assert!(since2 >= since && since2 <= since + Duration::from_secs(1));
+            }
+            msg => panic!("unexpected {:?}", msg),
+        }
+
+        // Times before the local clock started replay everything.
+        let mut bytes = Message::Replay(topic, ReplaySince::All).to_bytes();
+        let len = bytes.len();
+        assert_eq!(bytes[len - 8..], u64::MAX.to_be_bytes());
+        bytes[len - 8..].copy_from_slice(&(u64::MAX / 2).to_be_bytes());
+        assert_eq!(
+            Message::from_bytes(&bytes).unwrap(),
+            Message::Replay(topic, ReplaySince::All)
+        );
+    }
+
+    #[test]
+    fn test_topic_prefix() {
+        let topic = Topic::new(b"chat/room/42");
+        assert!(topic.has_prefix(&Topic::new(b"chat/room/")));
+        assert!(topic.has_prefix(&Topic::new(b"")));
+        assert!(!topic.has_prefix(&Topic::new(b"chat/lobby/")));
+        let segments: Vec<&[u8]> = topic.segments().collect();
+        assert_eq!(segments, [&b"chat"[..], b"room", b"42"]);
+    }
+
+    #[test]
+    fn test_topic_representation() {
+        let name = "a/topic/name/longer/than/the/sixty/three/bytes/a/raw/topic/can/hold";
+        assert_eq!(
+            TopicRepresentation::Raw.topic(name),
+            Err(BroadcastError::InvalidTopic)
+        );
+        let topic = TopicRepresentation::Hashed.topic(name).unwrap();
+        assert_eq!(topic, Topic::hashed(name));
+        assert_ne!(topic, Topic::hashed("other"));
+        assert_eq!(topic.len(), 17);
+        let topic = TopicRepresentation::Raw.topic("chat").unwrap();
+        assert_eq!(topic.name(), Some("chat"));
+        assert_eq!(format!("{:?}", topic), "Topic(\"chat\")");
+        assert_eq!(Topic::new(&[0xff]).name(), None);
+
+        let topic = TopicRepresentation::Raw.topic_in("testnet", "chat");
+        assert_eq!(topic.unwrap().name(), Some("testnet/chat"));
+        let topic = TopicRepresentation::Hashed.topic_in("testnet", "chat");
+        assert_ne!(topic, TopicRepresentation::Hashed.topic("chat"));
+
+        // The longest raw topic.
+        let name = "a".repeat(Topic::MAX_TOPIC_LENGTH - 1);
+        assert!(TopicRepresentation::Raw.topic(&name).is_ok());
+        assert!(Topic::try_new(name.as_bytes()).is_some());
+        assert!(Topic::try_new(format!("{}a", name).as_bytes()).is_none());
+    }
+
+    #[test]
+    fn test_signature() {
+        let topic = Topic::new(b"topic");
+        let keypair = Keypair::generate_ed25519();
+        let signature = Signature::sign(&keypair, &topic, b"content").unwrap();
+        assert!(signature.verify(&topic, b"content"));
+        assert!(!signature.verify(&topic, b"forged"));
+        assert!(!signature.verify(&Topic::new(b"other"), b"content"));
+        assert_eq!(signature.origin(), keypair.public().to_peer_id());
+
+        let peers = vec![(PeerId::random(), vec!["/memory/1".parse().unwrap()])];
+        let signature = Signature::sign_peers(&keypair, &topic, &peers).unwrap();
+        assert!(signature.verify_peers(&topic, &peers));
+        assert!(!signature.verify_peers(&topic, &[]));
+        // Signatures of peer exchanges and broadcasts can't be mixed up.
+        assert!(!signature.verify(&topic, &encode_peers(&peers)));
+
+        let signature = Signature::sign_stamped(&keypair, &topic, 7, b"content").unwrap();
+        assert!(signature.verify_stamped(&topic, 7, b"content"));
+        assert!(!signature.verify_stamped(&topic, 8, b"content"));
+        assert!(!signature.verify(&topic, b"content"));
+    }
+
+    #[test]
+    fn test_stream_framing() {
+        let topic = Topic::new(b"topic");
+        let msgs = [
+            Message::Subscribe(topic),
+            Message::Broadcast(topic, Extensions::default(), Bytes::from_static(b"content")),
+        ];
+        let mut socket = futures::io::Cursor::new(Vec::new());
+        futures::executor::block_on(async {
+            for msg in &msgs {
+                msg.write(&mut socket, &DefaultCodec).await.unwrap();
+            }
+            socket.set_position(0);
+            for msg in &msgs {
+                match Message::read(&mut socket, 1024, &DefaultCodec)
+                    .await
+                    .unwrap()
+                {
+                    Frame::Message(msg2) => assert_eq!(&msg2, msg),
+                    frame => panic!("unexpected frame {:?}", frame),
+                }
+            }
+            assert!(Message::read(&mut socket, 1024, &DefaultCodec)
+                .await
+                .is_err());
+        });
+    }
+
+    #[test]
+    fn test_version_encodable() {
+        let topic = Topic::new(b"topic");
+        let ext = Extensions {
+            hops: 2,
+            ack: true,
+            ..Default::default()
+        };
+        let msg = Message::Broadcast(topic, ext.clone(), Bytes::from_static(b"content"));
+        assert_eq!(Version::V1_1.encodable(msg.clone()), Some(msg));
+        assert_eq!(
+            Version::V1_0.encodable(Message::Broadcast(
+                topic,
+                ext,
+                Bytes::from_static(b"content")
+            )),
+            Some(Message::Broadcast(
+                topic,
+                Extensions::default(),
+                Bytes::from_static(b"content")
+            ))
+        );
+        let ack = Message::Ack(topic, MessageId::new(&topic, b"content"));
+        assert_eq!(Version::V1_0.encodable(ack), None);
+        let subscribe = Message::Subscribe(topic);
+        assert_eq!(Version::V1_0.encodable(subscribe.clone()), Some(subscribe));
+    }
+
+    #[test]
+    fn test_floodsub_rpc() {
+        let topic = Topic::new(b"topic");
+        let keypair = Keypair::generate_ed25519();
+        let relayed = Extensions {
+            floodsub: Some(FloodsubId::new(PeerId::random())),
+            ..Default::default()
+        };
+        let msgs = [
+            Message::Subscribe(topic),
+            Message::Unsubscribe(topic),
+            Message::Broadcast(topic, relayed, Bytes::from_static(b"content")),
+        ];
+        for msg in msgs {
+            for keypair in [None, Some(&keypair)] {
+                let rpc = msg.to_rpc(keypair).unwrap();
+                match &Message::from_rpc(&rpc, 1024).unwrap()[..] {
+                    [Frame::Message(msg2)] => assert_eq!(msg2, &msg),
+                    frames => panic!("unexpected frames {:?}", frames),
+                }
+            }
+        }
+        // Published by the signing peer, with a new sequence number each
+        // time.
+        let msg = Message::Broadcast(topic, Extensions::default(), Bytes::from_static(b"content"));
+        let published = || {
+            let rpc = msg.to_rpc(Some(&keypair)).unwrap();
+            match &Message::from_rpc(&rpc, 1024).unwrap()[..] {
+                [Frame::Message(Message::Broadcast(_, ext, _))] => ext.floodsub.clone().unwrap(),
+                frames => panic!("unexpected frames {:?}", frames),
+            }
+        };
+        let (first, second) = (published(), published());
+        assert_eq!(first.from, keypair.public().to_peer_id());
+        assert_eq!(second.from, first.from);
+        assert_ne!(second.seqno, first.seqno);
+        let ack = Message::Ack(topic, MessageId::new(&topic, b"content"));
+        assert!(Message::from_rpc(&ack.to_rpc(None).unwrap(), 1024)
+            .unwrap()
+            .is_empty());
+
+        // A publish with two topics, as sent by other implementations.
+        let mut publish = Vec::new();
+        write_proto_bytes(&mut publish, 2, b"content");
+        write_proto_bytes(&mut publish, 4, b"a");
+        write_proto_bytes(&mut publish, 4, b"b");
+        let mut rpc = Vec::new();
+        write_proto_bytes(&mut rpc, 2, &publish);
+        let frames = Message::from_rpc(&rpc, 4).unwrap();
+        assert!(matches!(
+            &frames[..],
+            [Frame::TooLarge(a), Frame::TooLarge(b)] if **a == *b"a" && **b == *b"b"
+        ));
+    }
+
+    #[test]
+    fn test_too_large() {
+        let topic = Topic::new(b"topic");
+        let small = Message::Broadcast(topic, Extensions::default(), Bytes::from(vec![0; 8]));
+        let large = Message::Broadcast(topic, Extensions::default(), Bytes::from(vec![0; 16]));
+        let huge = Message::Broadcast(topic, Extensions::default(), vec![0; 8192].into());
+        let mut socket = futures::io::Cursor::new(Vec::new());
+        futures::executor::block_on(async {
+            for msg in [&large, &huge, &small] {
+                msg.write(&mut socket, &DefaultCodec).await.unwrap();
+            }
+            socket.set_position(0);
+            for _ in 0..2 {
+                match Message::read(&mut socket, 8, &DefaultCodec).await.unwrap() {
+                    Frame::TooLarge(topic2) => assert_eq!(topic2, topic),
+                    frame => panic!("unexpected frame {:?}", frame),
+                }
+            }
+            match Message::read(&mut socket, 8, &DefaultCodec).await.unwrap() {
+                Frame::Message(msg) => assert_eq!(msg, small),
+                frame => panic!("unexpected frame {:?}", frame),
+            }
+        });
     }
 
     #[test]
@@ -190,4 +3070,76 @@ mod tests {
         let out_of_range = [0b0000_0100];
         Message::from_bytes(&out_of_range).unwrap();
     }
+
+    #[test]
+    fn test_eviction_policy() {
+        let (a, b) = (Some(PeerId::random()), Some(PeerId::random()));
+        let messages = [(a, 3), (b, 5), (b, 1), (a, 5)];
+        assert_eq!(EvictionPolicy::DropOldest.select(&messages), Some(0));
+        assert_eq!(EvictionPolicy::DropLargest.select(&messages), Some(1));
+        assert_eq!(EvictionPolicy::PerPeerFair.select(&messages), Some(0));
+        assert_eq!(EvictionPolicy::PerPeerFair.select(&messages[1..]), Some(0));
+        assert_eq!(EvictionPolicy::DropLargest.select(&[]), None);
+    }
+
+    #[test]
+    fn test_message_codec() {
+        /// Subscriptions as `+topic` and `-topic`.
+        #[derive(Debug)]
+        struct TextCodec;
+
+        impl MessageCodec for TextCodec {
+            fn encode(&self, msg: &Message) -> Vec<u8> {
+                let (sign, topic) = match msg {
+                    Message::Subscribe(topic) => (b'+', topic),
+                    Message::Unsubscribe(topic) => (b'-', topic),
+                    _ => unimplemented!(),
+                };
+                [&[sign], &topic[..]].concat()
+            }
+
+            fn decode(&self, frame: &[u8]) -> Result<Message> {
+                match frame.split_first() {
+                    Some((b'+', topic)) => Ok(Message::Subscribe(Topic::new(topic))),
+                    Some((b'-', topic)) => Ok(Message::Unsubscribe(Topic::new(topic))),
+                    _ => Err(ErrorKind::InvalidData.into()),
+                }
+            }
+        }
+
+        let topic = Topic::new(b"topic");
+        let mut socket = futures::io::Cursor::new(Vec::new());
+        futures::executor::block_on(async {
+            let msg = Message::Unsubscribe(topic);
+            msg.write(&mut socket, &TextCodec).await.unwrap();
+            assert_eq!(&socket.get_ref()[1..], b"-topic");
+            socket.set_position(0);
+            match Message::read(&mut socket, 8, &TextCodec).await.unwrap() {
+                Frame::Message(msg2) => assert_eq!(msg2, msg),
+                frame => panic!("unexpected frame {:?}", frame),
+            }
+        });
+    }
+
+    #[test]
+    fn test_protocol_name() {
+        let versions = [Version::V1_1, Version::V1_0, Version::Floodsub];
+        let protocol = BroadcastProtocol::new(&versions, &[], None);
+        assert_eq!(
+            protocol.protocol_info(),
+            vec![
+                Bytes::from_static(b"/ax/broadcast/1.1.0"),
+                Bytes::from_static(b"/ax/broadcast/1.0.0"),
+                Bytes::from_static(b"/floodsub/1.0.0"),
+            ]
+        );
+
+        let name = Bytes::from_static(b"/myapp/broadcast/1.0.0");
+        let protocol = BroadcastProtocol::new(&versions, &[], Some(&name));
+        assert_eq!(
+            protocol.protocol_info(),
+            vec![name.clone(), Bytes::from_static(b"/floodsub/1.0.0")]
+        );
+        assert_eq!(protocol.negotiated(&name), (Version::V1_1, None));
+    }
 }
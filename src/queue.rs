@@ -0,0 +1,143 @@
+use crate::delivery::BroadcastId;
+use crate::handler::payload_len;
+use crate::protocol::Message;
+use std::collections::VecDeque;
+
+/// Priority of a broadcast in the send queues of the handlers, see
+/// `Broadcast::broadcast_with_priority`.
+///
+/// Control messages like subscriptions and acks are always sent with
+/// `Priority::High`.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Priority {
+    High,
+    Normal,
+    Low,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Self::Normal
+    }
+}
+
+impl Priority {
+    fn lane(self) -> usize {
+        self as usize
+    }
+}
+
+/// Batching that adapts to the depth of a peer's send queue, see
+/// `BroadcastConfig::adaptive_batching`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AdaptiveBatching {
+    /// Queued messages from which on the queue counts as congested.
+    pub threshold: usize,
+    /// Maximum number of messages in a batch while congested.
+    pub max_batch_len: usize,
+    /// Whether to compress all payloads while congested, regardless of
+    /// `BroadcastConfig::compression_threshold`.
+    pub compress: bool,
+}
+
+impl Default for AdaptiveBatching {
+    fn default() -> Self {
+        Self {
+            threshold: 16,
+            max_batch_len: 256,
+            compress: false,
+        }
+    }
+}
+
+/// A queued message with the id it is tracked with.
+pub(crate) type Queued = (Message, Option<BroadcastId>, Priority);
+
+/// Send queue with a lane per priority. Lower priority lanes are only
+/// served once the higher ones are empty, each lane is in order.
+#[derive(Debug, Default)]
+pub(crate) struct SendQueue {
+    lanes: [VecDeque<Queued>; 3],
+    /// Payload bytes of the queued messages.
+    bytes: usize,
+}
+
+impl SendQueue {
+    pub fn push_back(&mut self, queued: Queued) {
+        self.bytes += payload_len(&queued.0);
+        self.lanes[queued.2.lane()].push_back(queued);
+    }
+
+    /// Puts a message back in front of its lane.
+    pub fn push_front(&mut self, queued: Queued) {
+        self.bytes += payload_len(&queued.0);
+        self.lanes[queued.2.lane()].push_front(queued);
+    }
+
+    pub fn pop_front(&mut self) -> Option<Queued> {
+        let queued = self.lanes.iter_mut().find_map(VecDeque::pop_front)?;
+        self.bytes -= payload_len(&queued.0);
+        Some(queued)
+    }
+
+    pub fn front(&self) -> Option<&Queued> {
+        self.lanes.iter().find_map(VecDeque::front)
+    }
+
+    pub fn len(&self) -> usize {
+        self.lanes.iter().map(VecDeque::len).sum()
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lanes.iter().all(VecDeque::is_empty)
+    }
+
+    /// Removes all messages in the order they would have been sent.
+    pub fn drain(&mut self) -> Vec<Queued> {
+        self.bytes = 0;
+        self.lanes
+            .iter_mut()
+            .flat_map(|lane| lane.drain(..))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Topic;
+    use bytes::Bytes;
+
+    #[test]
+    fn test_send_queue() {
+        let msg = |name: &[u8]| Message::Subscribe(Topic::new(name));
+        let mut queue = SendQueue::default();
+        queue.push_back((msg(b"low"), None, Priority::Low));
+        queue.push_back((msg(b"normal"), None, Priority::Normal));
+        queue.push_back((msg(b"high"), None, Priority::High));
+        queue.push_front((msg(b"first"), None, Priority::Normal));
+        assert_eq!(queue.len(), 4);
+        assert_eq!(queue.front().unwrap().0, msg(b"high"));
+        assert_eq!(queue.pop_front().unwrap().0, msg(b"high"));
+        let order: Vec<_> = queue.drain().into_iter().map(|(msg, _, _)| msg).collect();
+        assert_eq!(order, vec![msg(b"first"), msg(b"normal"), msg(b"low")]);
+        assert!(queue.is_empty());
+
+        let broadcast = Message::Broadcast(
+            Topic::new(b"topic"),
+            Default::default(),
+            Bytes::from_static(b"msg"),
+        );
+        queue.push_back((broadcast.clone(), None, Priority::Normal));
+        queue.push_front((broadcast, None, Priority::Low));
+        assert_eq!(queue.bytes(), 6);
+        queue.pop_front();
+        assert_eq!(queue.bytes(), 3);
+        queue.drain();
+        assert_eq!(queue.bytes(), 0);
+    }
+}
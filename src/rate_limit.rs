@@ -0,0 +1,304 @@
+use crate::delivery::BroadcastId;
+use crate::protocol::{Message, Topic};
+use crate::queue::Priority;
+use fnv::{FnvHashMap, FnvHashSet};
+use futures::FutureExt;
+use futures_timer::Delay;
+use instant::Instant;
+use libp2p::PeerId;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// Maximum sustained send rate. Bursts of up to one second worth of messages
+/// are sent without delay.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RateLimit {
+    pub messages_per_sec: u32,
+    pub bytes_per_sec: u64,
+}
+
+impl RateLimit {
+    pub fn new(messages_per_sec: u32, bytes_per_sec: u64) -> Self {
+        Self {
+            messages_per_sec,
+            bytes_per_sec,
+        }
+    }
+}
+
+pub(crate) struct TokenBucket {
+    limit: RateLimit,
+    messages: f64,
+    bytes: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(limit: RateLimit, now: Instant) -> Self {
+        Self {
+            limit,
+            messages: limit.messages_per_sec as f64,
+            bytes: limit.bytes_per_sec as f64,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        let messages = self.limit.messages_per_sec as f64;
+        let bytes = self.limit.bytes_per_sec as f64;
+        self.messages = (self.messages + elapsed * messages).min(messages);
+        self.bytes = (self.bytes + elapsed * bytes).min(bytes);
+        self.updated = now;
+    }
+
+    /// Time until a message of `len` bytes may be sent.
+    pub fn wait(&mut self, now: Instant, len: usize) -> Duration {
+        self.refill(now);
+        let messages = self.limit.messages_per_sec.max(1) as f64;
+        let bytes = self.limit.bytes_per_sec.max(1) as f64;
+        // Messages larger than the burst size are sent once the bucket is full.
+        let needed = (len as f64).min(bytes);
+        let secs = f64::max(
+            (1.0 - self.messages) / messages,
+            (needed - self.bytes) / bytes,
+        );
+        Duration::from_secs_f64(secs.max(0.0))
+    }
+
+    pub fn take(&mut self, len: usize) {
+        self.messages -= 1.0;
+        self.bytes -= len as f64;
+    }
+}
+
+/// Result of passing a message through the `RateLimiter`.
+#[allow(clippy::large_enum_variant)]
+pub(crate) enum Admission {
+    /// The message can be sent right away.
+    Send(Arc<Message>),
+    /// The message was queued until the rate limit allows sending it.
+    Delayed(Topic),
+    /// The message was dropped because too many are queued for the peer.
+    Dropped(Topic),
+}
+
+/// A delayed message with the id it is tracked with and its priority.
+pub(crate) type Delayed = (PeerId, Arc<Message>, Option<BroadcastId>, Priority);
+
+/// Delays broadcasts exceeding the per peer or per topic rate limit.
+#[derive(Default)]
+pub(crate) struct RateLimiter {
+    peer_limit: Option<RateLimit>,
+    topic_limit: Option<RateLimit>,
+    max_delayed: usize,
+    peers: FnvHashMap<PeerId, TokenBucket>,
+    topics: FnvHashMap<Topic, TokenBucket>,
+    delayed: VecDeque<Delayed>,
+    /// Number of delayed messages per peer.
+    queued: FnvHashMap<PeerId, usize>,
+    timer: Option<Delay>,
+}
+
+impl RateLimiter {
+    pub fn new(
+        peer_limit: Option<RateLimit>,
+        topic_limit: Option<RateLimit>,
+        max_delayed: usize,
+    ) -> Self {
+        Self {
+            peer_limit,
+            topic_limit,
+            max_delayed,
+            ..Default::default()
+        }
+    }
+
+    fn cost(msg: &Message) -> Option<(Topic, usize)> {
+        match msg {
+            Message::Broadcast(topic, _, payload) => Some((*topic, payload.len())),
+            _ => None,
+        }
+    }
+
+    fn wait(&mut self, now: Instant, peer: &PeerId, topic: &Topic, len: usize) -> Duration {
+        let mut wait = Duration::ZERO;
+        if let Some(limit) = self.peer_limit {
+            let bucket = self
+                .peers
+                .entry(*peer)
+                .or_insert_with(|| TokenBucket::new(limit, now));
+            wait = wait.max(bucket.wait(now, len));
+        }
+        if let Some(limit) = self.topic_limit {
+            let bucket = self
+                .topics
+                .entry(*topic)
+                .or_insert_with(|| TokenBucket::new(limit, now));
+            wait = wait.max(bucket.wait(now, len));
+        }
+        wait
+    }
+
+    fn take(&mut self, peer: &PeerId, topic: &Topic, len: usize) {
+        if let Some(bucket) = self.peers.get_mut(peer) {
+            bucket.take(len);
+        }
+        if let Some(bucket) = self.topics.get_mut(topic) {
+            bucket.take(len);
+        }
+    }
+
+    /// Admits a message to `peer`. Messages other than broadcasts are not
+    /// rate limited.
+    pub fn send(
+        &mut self,
+        peer: PeerId,
+        msg: Arc<Message>,
+        id: Option<BroadcastId>,
+        priority: Priority,
+    ) -> Admission {
+        let (topic, len) = match Self::cost(&msg) {
+            Some(cost) => cost,
+            None => return Admission::Send(msg),
+        };
+        let queued = self.queued.get(&peer).copied().unwrap_or_default();
+        if queued == 0 && self.wait(Instant::now(), &peer, &topic, len) == Duration::ZERO {
+            self.take(&peer, &topic, len);
+            return Admission::Send(msg);
+        }
+        if queued >= self.max_delayed {
+            return Admission::Dropped(topic);
+        }
+        self.queued.insert(peer, queued + 1);
+        self.delayed.push_back((peer, msg, id, priority));
+        Admission::Delayed(topic)
+    }
+
+    /// Returns the next delayed message that may be sent now.
+    pub fn poll_ready(&mut self, cx: &mut Context) -> Poll<Delayed> {
+        let now = Instant::now();
+        // Peers whose oldest delayed message has to wait, keeping the
+        // messages to each peer in order.
+        let mut blocked = FnvHashSet::default();
+        let mut next = None;
+        for i in 0..self.delayed.len() {
+            let (peer, msg, _, _) = &self.delayed[i];
+            if blocked.contains(peer) {
+                continue;
+            }
+            let peer = *peer;
+            let (topic, len) = Self::cost(msg).unwrap();
+            let wait = self.wait(now, &peer, &topic, len);
+            if wait == Duration::ZERO {
+                self.take(&peer, &topic, len);
+                let delayed = self.delayed.remove(i).unwrap();
+                if let Some(queued) = self.queued.get_mut(&peer) {
+                    *queued -= 1;
+                    if *queued == 0 {
+                        self.queued.remove(&peer);
+                    }
+                }
+                return Poll::Ready(delayed);
+            }
+            blocked.insert(peer);
+            next = Some(next.map_or(wait, |next: Duration| next.min(wait)));
+        }
+        if let Some(wait) = next {
+            let timer = self.timer.get_or_insert_with(|| Delay::new(wait));
+            timer.reset(wait);
+            if timer.poll_unpin(cx).is_ready() {
+                cx.waker().wake_by_ref();
+            }
+        }
+        Poll::Pending
+    }
+
+    /// Number of messages delayed for the peer.
+    pub fn delayed(&self, peer: &PeerId) -> usize {
+        self.queued.get(peer).copied().unwrap_or_default()
+    }
+
+    /// Releases all delayed messages regardless of the limits.
+    pub fn drain(&mut self) -> Vec<Delayed> {
+        self.queued.clear();
+        self.delayed.drain(..).collect()
+    }
+
+    /// Forgets the state of a disconnected peer, dropping its delayed
+    /// messages.
+    pub fn remove_peer(&mut self, peer: &PeerId) {
+        self.peers.remove(peer);
+        if self.queued.remove(peer).is_some() {
+            self.delayed.retain(|(p, _, _, _)| p != peer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Extensions;
+    use bytes::Bytes;
+
+    #[test]
+    fn test_token_bucket() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(RateLimit::new(2, 100), now);
+        assert_eq!(bucket.wait(now, 50), Duration::ZERO);
+        bucket.take(50);
+        assert_eq!(bucket.wait(now, 50), Duration::ZERO);
+        bucket.take(50);
+        assert_eq!(bucket.wait(now, 50), Duration::from_millis(500));
+        let later = now + Duration::from_millis(500);
+        assert_eq!(bucket.wait(later, 50), Duration::ZERO);
+        // Oversized messages wait for a full bucket.
+        assert_eq!(bucket.wait(later, 1000), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let topic = Topic::new(b"topic");
+        let peer = PeerId::random();
+        let msg = Arc::new(Message::Broadcast(
+            topic,
+            Extensions::default(),
+            Bytes::from_static(b"msg"),
+        ));
+        let mut limiter = RateLimiter::new(Some(RateLimit::new(1, 1024)), None, 1);
+        assert!(matches!(
+            limiter.send(peer, msg.clone(), None, Priority::Normal),
+            Admission::Send(_)
+        ));
+        assert!(matches!(
+            limiter.send(
+                peer,
+                Arc::new(Message::Subscribe(topic)),
+                None,
+                Priority::Normal
+            ),
+            Admission::Send(_)
+        ));
+        assert!(matches!(
+            limiter.send(peer, msg.clone(), None, Priority::Normal),
+            Admission::Delayed(_)
+        ));
+        assert!(matches!(
+            limiter.send(peer, msg.clone(), None, Priority::Normal),
+            Admission::Dropped(_)
+        ));
+        // Other peers are not affected.
+        assert!(matches!(
+            limiter.send(PeerId::random(), msg.clone(), None, Priority::Normal),
+            Admission::Send(_)
+        ));
+
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        assert!(limiter.poll_ready(&mut cx).is_pending());
+        limiter.remove_peer(&peer);
+        assert!(limiter.delayed.is_empty());
+    }
+}
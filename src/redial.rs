@@ -0,0 +1,110 @@
+use crate::protocol::Topic;
+use fnv::{FnvHashMap, FnvHashSet};
+use instant::Instant;
+use libp2p::{Multiaddr, PeerId};
+use std::time::Duration;
+
+/// Number of disconnected subscribers remembered.
+const MAX_FORMER_SUBSCRIBERS: usize = 1024;
+/// Minimum time between two dials of the same peer.
+const REDIAL_BACKOFF: Duration = Duration::from_secs(30);
+
+struct FormerSubscriber {
+    topics: FnvHashSet<Topic>,
+    addresses: Vec<Multiaddr>,
+    disconnected: Instant,
+    dialed: Option<Instant>,
+}
+
+/// Disconnected peers with the topics they were subscribed to, redialed by
+/// `BroadcastConfig::dial_on_broadcast`.
+#[derive(Default)]
+pub(crate) struct FormerSubscribers {
+    peers: FnvHashMap<PeerId, FormerSubscriber>,
+}
+
+impl FormerSubscribers {
+    /// Remembers a peer that had dialable addresses, forgetting the one
+    /// disconnected the longest when full.
+    pub fn disconnected(
+        &mut self,
+        peer: PeerId,
+        topics: FnvHashSet<Topic>,
+        addresses: Vec<Multiaddr>,
+    ) {
+        if topics.is_empty() || addresses.is_empty() {
+            return;
+        }
+        if self.peers.len() >= MAX_FORMER_SUBSCRIBERS && !self.peers.contains_key(&peer) {
+            let oldest = self
+                .peers
+                .iter()
+                .min_by_key(|(_, former)| former.disconnected)
+                .map(|(peer, _)| *peer);
+            if let Some(oldest) = oldest {
+                self.peers.remove(&oldest);
+            }
+        }
+        self.peers.insert(
+            peer,
+            FormerSubscriber {
+                topics,
+                addresses,
+                disconnected: Instant::now(),
+                dialed: None,
+            },
+        );
+    }
+
+    /// Forgets a peer that reconnected or can't be dialed.
+    pub fn remove(&mut self, peer: &PeerId) {
+        self.peers.remove(peer);
+    }
+
+    /// Up to `amount` peers formerly subscribed to `topic` that weren't
+    /// dialed recently, the most recently disconnected first.
+    pub fn redial(&mut self, topic: &Topic, amount: usize) -> Vec<(PeerId, Vec<Multiaddr>)> {
+        let now = Instant::now();
+        let mut candidates: Vec<_> = self
+            .peers
+            .iter_mut()
+            .filter(|(_, former)| former.topics.contains(topic))
+            .filter(|(_, former)| {
+                !matches!(former.dialed, Some(dialed) if now.duration_since(dialed) < REDIAL_BACKOFF)
+            })
+            .collect();
+        candidates.sort_by_key(|(_, former)| std::cmp::Reverse(former.disconnected));
+        candidates
+            .into_iter()
+            .take(amount)
+            .map(|(peer, former)| {
+                former.dialed = Some(now);
+                (*peer, former.addresses.clone())
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_former_subscribers() {
+        let (topic, other) = (Topic::new(b"topic"), Topic::new(b"other"));
+        let address: Multiaddr = "/memory/1".parse().unwrap();
+        let (a, b, c) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let mut former = FormerSubscribers::default();
+        former.disconnected(a, std::iter::once(topic).collect(), vec![address.clone()]);
+        former.disconnected(b, std::iter::once(other).collect(), vec![address.clone()]);
+        // Peers without addresses can't be dialed.
+        former.disconnected(c, std::iter::once(topic).collect(), vec![]);
+
+        assert_eq!(former.redial(&topic, 2), vec![(a, vec![address.clone()])]);
+        // Not dialed again right away.
+        assert!(former.redial(&topic, 2).is_empty());
+
+        former.remove(&b);
+        assert!(former.redial(&other, 2).is_empty());
+    }
+}
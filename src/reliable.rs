@@ -0,0 +1,174 @@
+use crate::protocol::{Message, MessageId, Topic};
+use fnv::{FnvHashMap, FnvHashSet};
+use futures::FutureExt;
+use futures_timer::Delay;
+use instant::Instant;
+use libp2p::PeerId;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// A broadcast sent with `Broadcast::broadcast_reliable`.
+struct Reliable {
+    topic: Topic,
+    msg: Message,
+    quorum: usize,
+    acked: FnvHashSet<PeerId>,
+    deadline: Instant,
+    retransmit: Instant,
+}
+
+/// Work of a reliable broadcast that became due.
+#[allow(clippy::large_enum_variant)]
+pub(crate) enum Due {
+    /// Resend the message to the subscribers of the topic that didn't
+    /// acknowledge it yet.
+    Retransmit(Topic, Message, FnvHashSet<PeerId>),
+    /// The deadline passed with that many acks, short of the quorum.
+    Expired(MessageId, usize),
+}
+
+/// Broadcasts retransmitted to the subscribers that didn't acknowledge them
+/// until a quorum did or their deadline passed.
+#[derive(Default)]
+pub(crate) struct ReliableBroadcasts {
+    interval: Duration,
+    pending: FnvHashMap<MessageId, Reliable>,
+    timer: Option<Delay>,
+}
+
+impl ReliableBroadcasts {
+    /// Retransmits unacknowledged messages every `interval`.
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            ..Default::default()
+        }
+    }
+
+    pub fn insert(
+        &mut self,
+        id: MessageId,
+        topic: Topic,
+        msg: Message,
+        quorum: usize,
+        deadline: Duration,
+    ) {
+        let now = Instant::now();
+        let reliable = Reliable {
+            topic,
+            msg,
+            quorum,
+            acked: Default::default(),
+            deadline: now + deadline,
+            retransmit: now + self.interval,
+        };
+        self.pending.insert(id, reliable);
+        self.timer = None;
+    }
+
+    pub fn contains(&self, id: &MessageId) -> bool {
+        self.pending.contains_key(id)
+    }
+
+    /// Records the ack of a peer, returning the number of acks once the
+    /// quorum is met.
+    pub fn ack(&mut self, peer: PeerId, id: MessageId) -> Option<usize> {
+        let reliable = self.pending.get_mut(&id)?;
+        reliable.acked.insert(peer);
+        if reliable.acked.len() < reliable.quorum {
+            return None;
+        }
+        self.pending
+            .remove(&id)
+            .map(|reliable| reliable.acked.len())
+    }
+
+    /// The messages on `topic` the peer didn't acknowledge, sent to peers
+    /// subscribing while they are pending.
+    pub fn unacked(&self, peer: &PeerId, topic: &Topic) -> Vec<Message> {
+        self.pending
+            .values()
+            .filter(|reliable| reliable.topic == *topic && !reliable.acked.contains(peer))
+            .map(|reliable| reliable.msg.clone())
+            .collect()
+    }
+
+    pub fn poll_due(&mut self, cx: &mut Context) -> Poll<Vec<Due>> {
+        let now = Instant::now();
+        let mut due = Vec::new();
+        let mut next = None;
+        let interval = self.interval;
+        self.pending.retain(|id, reliable| {
+            if reliable.deadline <= now {
+                due.push(Due::Expired(*id, reliable.acked.len()));
+                return false;
+            }
+            if reliable.retransmit <= now {
+                reliable.retransmit = now + interval;
+                due.push(Due::Retransmit(
+                    reliable.topic,
+                    reliable.msg.clone(),
+                    reliable.acked.clone(),
+                ));
+            }
+            let wake = reliable.deadline.min(reliable.retransmit);
+            next = Some(next.map_or(wake, |next: Instant| next.min(wake)));
+            true
+        });
+        match next {
+            Some(next) => {
+                let timer = self.timer.get_or_insert_with(|| Delay::new(next - now));
+                timer.reset(next - now);
+                if timer.poll_unpin(cx).is_ready() {
+                    cx.waker().wake_by_ref();
+                }
+            }
+            None => self.timer = None,
+        }
+        if due.is_empty() {
+            Poll::Pending
+        } else {
+            Poll::Ready(due)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Extensions;
+    use bytes::Bytes;
+
+    #[test]
+    fn test_reliable_broadcasts() {
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let topic = Topic::new(b"topic");
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let msg = Message::Broadcast(topic, Extensions::default(), Bytes::from_static(b"msg"));
+        let id = MessageId::new(&topic, b"msg");
+        let mut reliable = ReliableBroadcasts::new(Duration::ZERO);
+        reliable.insert(id, topic, msg.clone(), 2, Duration::from_secs(10));
+
+        assert_eq!(reliable.ack(a, id), None);
+        assert_eq!(reliable.unacked(&a, &topic), vec![]);
+        assert_eq!(reliable.unacked(&b, &topic), vec![msg]);
+        match reliable.poll_due(&mut cx) {
+            Poll::Ready(due) => match due.as_slice() {
+                [Due::Retransmit(_, _, acked)] => assert!(acked.contains(&a)),
+                _ => panic!("expected a retransmission"),
+            },
+            Poll::Pending => panic!("expected a retransmission"),
+        }
+        assert_eq!(reliable.ack(b, id), Some(2));
+        assert!(!reliable.contains(&id));
+
+        reliable.insert(id, topic, Message::Unsubscribe(topic), 2, Duration::ZERO);
+        reliable.ack(a, id);
+        match reliable.poll_due(&mut cx) {
+            Poll::Ready(due) => assert!(matches!(due.as_slice(), [Due::Expired(_, 1)])),
+            Poll::Pending => panic!("expected the deadline to pass"),
+        }
+        assert!(reliable.poll_due(&mut cx).is_pending());
+    }
+}
@@ -0,0 +1,189 @@
+use crate::protocol::Topic;
+use crate::BroadcastEvent;
+use fnv::FnvHashMap;
+use futures::FutureExt;
+use futures_timer::Delay;
+use instant::Instant;
+use libp2p::PeerId;
+use std::collections::BTreeMap;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+struct Origin {
+    /// Sequence number of the next message to deliver.
+    next: u64,
+    /// Held back messages with the time they arrived.
+    pending: BTreeMap<u64, (Instant, BroadcastEvent)>,
+}
+
+impl Origin {
+    /// Delivers the messages that are in order, skipping the missing ones
+    /// once the window is full or they waited for `timeout`.
+    fn release(
+        &mut self,
+        source: PeerId,
+        topic: Topic,
+        max_messages: usize,
+        timeout: Duration,
+    ) -> Vec<BroadcastEvent> {
+        let mut events = Vec::new();
+        loop {
+            while let Some((_, event)) = self.pending.remove(&self.next) {
+                events.push(event);
+                self.next += 1;
+            }
+            let first = match self.pending.keys().next() {
+                Some(first) => *first,
+                None => break,
+            };
+            let expired = self
+                .pending
+                .values()
+                .any(|(arrived, _)| arrived.elapsed() >= timeout);
+            if self.pending.len() <= max_messages && !expired {
+                break;
+            }
+            events.push(BroadcastEvent::GapDetected(source, topic, self.next..first));
+            self.next = first;
+        }
+        events
+    }
+
+    fn deadline(&self, timeout: Duration) -> Option<Instant> {
+        self.pending
+            .values()
+            .map(|(arrived, _)| *arrived + timeout)
+            .min()
+    }
+}
+
+/// Received broadcasts held back until the ones published before them by
+/// the same origin on the topic arrived, see
+/// `BroadcastConfig::reorder_window`.
+pub(crate) struct ReorderBuffer {
+    max_messages: usize,
+    timeout: Duration,
+    origins: FnvHashMap<(PeerId, Topic), Origin>,
+    timer: Option<Delay>,
+}
+
+impl ReorderBuffer {
+    pub fn new(max_messages: usize, timeout: Duration) -> Self {
+        Self {
+            max_messages,
+            timeout,
+            origins: Default::default(),
+            timer: None,
+        }
+    }
+
+    /// Adds the received event of a message, returning the events that can
+    /// be delivered now. Messages arriving after their gap was skipped are
+    /// delivered right away.
+    pub fn insert(
+        &mut self,
+        source: PeerId,
+        topic: Topic,
+        seqno: u64,
+        event: BroadcastEvent,
+    ) -> Vec<BroadcastEvent> {
+        let origin = self
+            .origins
+            .entry((source, topic))
+            .or_insert_with(|| Origin {
+                next: seqno,
+                pending: BTreeMap::new(),
+            });
+        if seqno < origin.next {
+            return vec![event];
+        }
+        origin.pending.insert(seqno, (Instant::now(), event));
+        origin.release(source, topic, self.max_messages, self.timeout)
+    }
+
+    /// Returns the events of messages that waited too long for the ones
+    /// before them.
+    pub fn poll_expired(&mut self, cx: &mut Context) -> Poll<Vec<BroadcastEvent>> {
+        let mut events = Vec::new();
+        let mut deadline: Option<Instant> = None;
+        for ((source, topic), origin) in &mut self.origins {
+            events.extend(origin.release(*source, *topic, self.max_messages, self.timeout));
+            if let Some(next) = origin.deadline(self.timeout) {
+                deadline = Some(deadline.map_or(next, |deadline| deadline.min(next)));
+            }
+        }
+        if let Some(deadline) = deadline {
+            let delay = deadline.saturating_duration_since(Instant::now());
+            let timer = self.timer.get_or_insert_with(|| Delay::new(delay));
+            timer.reset(delay);
+            if timer.poll_unpin(cx).is_ready() {
+                cx.waker().wake_by_ref();
+            }
+        }
+        if events.is_empty() {
+            Poll::Pending
+        } else {
+            Poll::Ready(events)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::MessageId;
+    use bytes::Bytes;
+
+    fn received(source: PeerId, topic: Topic, seqno: u8) -> BroadcastEvent {
+        let msg = Bytes::from(vec![seqno]);
+        BroadcastEvent::Received(source, topic, MessageId::new(&topic, &msg), msg)
+    }
+
+    #[test]
+    fn test_reorder_buffer() {
+        let topic = Topic::new(b"topic");
+        let source = PeerId::random();
+        let mut buffer = ReorderBuffer::new(2, Duration::from_secs(60));
+        let mut insert =
+            |seqno| buffer.insert(source, topic, seqno as u64, received(source, topic, seqno));
+
+        assert_eq!(insert(0), vec![received(source, topic, 0)]);
+        assert!(insert(2).is_empty());
+        assert_eq!(
+            insert(1),
+            vec![received(source, topic, 1), received(source, topic, 2)]
+        );
+
+        // The gap is skipped when the window overflows.
+        assert!(insert(5).is_empty());
+        assert!(insert(6).is_empty());
+        assert_eq!(
+            insert(7),
+            vec![
+                BroadcastEvent::GapDetected(source, topic, 3..5),
+                received(source, topic, 5),
+                received(source, topic, 6),
+                received(source, topic, 7),
+            ]
+        );
+        // Late messages aren't held back.
+        assert_eq!(insert(4), vec![received(source, topic, 4)]);
+
+        // Or once a message waited for the timeout.
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut buffer = ReorderBuffer::new(2, Duration::from_secs(60));
+        for seqno in [0, 2] {
+            buffer.insert(source, topic, seqno as u64, received(source, topic, seqno));
+        }
+        assert!(buffer.poll_expired(&mut cx).is_pending());
+        buffer.timeout = Duration::ZERO;
+        assert_eq!(
+            buffer.poll_expired(&mut cx),
+            Poll::Ready(vec![
+                BroadcastEvent::GapDetected(source, topic, 1..2),
+                received(source, topic, 2),
+            ])
+        );
+    }
+}
@@ -0,0 +1,92 @@
+use crate::protocol::{unix_millis, Extensions, MessageId};
+use fnv::FnvHashMap;
+use libp2p::PeerId;
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+/// Signed messages accepted within `BroadcastConfig::replay_window`, by
+/// publisher and timestamp.
+#[derive(Default)]
+pub(crate) struct ReplayWindow {
+    window: Option<u64>,
+    seen: FnvHashMap<PeerId, BTreeSet<(u64, MessageId)>>,
+}
+
+impl ReplayWindow {
+    pub fn new(window: Option<Duration>) -> Self {
+        Self {
+            window: window.map(|window| window.as_millis() as u64),
+            seen: Default::default(),
+        }
+    }
+
+    /// Records a message published by `origin`, returning `false` if it is
+    /// a replay. Unsigned messages are always accepted.
+    pub fn accept(&mut self, origin: PeerId, ext: &Extensions, id: MessageId) -> bool {
+        self.accept_at(origin, ext, id, unix_millis())
+    }
+
+    fn accept_at(&mut self, origin: PeerId, ext: &Extensions, id: MessageId, now: u64) -> bool {
+        let window = match self.window {
+            Some(window) if ext.signature.is_some() => window,
+            _ => return true,
+        };
+        let timestamp = match ext.timestamp {
+            Some(timestamp) => timestamp,
+            None => return false,
+        };
+        if timestamp.saturating_add(window) < now || timestamp > now.saturating_add(window) {
+            return false;
+        }
+        let seen = self.seen.entry(origin).or_default();
+        // Anything older is rejected by its timestamp alone.
+        while let Some(&first) = seen.iter().next() {
+            if first.0.saturating_add(window) >= now {
+                break;
+            }
+            seen.remove(&first);
+        }
+        seen.insert((timestamp, id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{Signature, Topic};
+    use libp2p::identity::Keypair;
+
+    #[test]
+    fn test_replay_window() {
+        let topic = Topic::new(b"topic");
+        let keypair = Keypair::generate_ed25519();
+        let origin = keypair.public().to_peer_id();
+        let stamped = |timestamp| Extensions {
+            signature: Some(Signature::sign_stamped(&keypair, &topic, timestamp, b"msg").unwrap()),
+            timestamp: Some(timestamp),
+            ..Default::default()
+        };
+        let id = MessageId::new(&topic, b"msg");
+        let mut replay = ReplayWindow::new(Some(Duration::from_millis(100)));
+
+        assert!(replay.accept_at(origin, &stamped(1000), id, 1000));
+        assert!(!replay.accept_at(origin, &stamped(1000), id, 1050));
+        // The same payload published again.
+        assert!(replay.accept_at(origin, &stamped(1010), id, 1050));
+        assert!(!replay.accept_at(origin, &stamped(1000), id, 1101));
+        assert!(!replay.accept_at(origin, &stamped(1300), id, 1101));
+        assert!(!replay.accept_at(
+            origin,
+            &Extensions {
+                timestamp: None,
+                ..stamped(1000)
+            },
+            id,
+            1000
+        ));
+        assert!(replay.accept_at(origin, &Extensions::default(), id, 1000));
+
+        let mut disabled = ReplayWindow::new(None);
+        assert!(disabled.accept_at(origin, &stamped(0), id, 1000));
+    }
+}
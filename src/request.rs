@@ -0,0 +1,120 @@
+use crate::protocol::Topic;
+use bytes::Bytes;
+use fnv::FnvHashMap;
+use futures::channel::oneshot;
+use futures::FutureExt;
+use futures_timer::Delay;
+use libp2p::PeerId;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// Replies collected for a request.
+pub(crate) type Replies = Vec<(PeerId, Bytes)>;
+
+struct Request {
+    replies: Replies,
+    tx: oneshot::Sender<Replies>,
+    timer: Delay,
+}
+
+/// Requests sent with `Broadcast::request`, by reply topic.
+#[derive(Default)]
+pub(crate) struct Requests {
+    pending: FnvHashMap<Topic, Request>,
+}
+
+impl Requests {
+    /// Id of a reply topic not used by any other request.
+    pub fn reply_id(&self) -> u64 {
+        loop {
+            let id = rand::random();
+            if !self.pending.contains_key(&ReplyTo(id).topic()) {
+                return id;
+            }
+        }
+    }
+
+    /// Collects the replies on `topic` for `timeout`.
+    pub fn insert(&mut self, topic: Topic, timeout: Duration) -> oneshot::Receiver<Replies> {
+        let (tx, rx) = oneshot::channel();
+        let request = Request {
+            replies: Vec::new(),
+            tx,
+            timer: Delay::new(timeout),
+        };
+        self.pending.insert(topic, request);
+        rx
+    }
+
+    pub fn remove(&mut self, topic: &Topic) {
+        self.pending.remove(topic);
+    }
+
+    pub fn contains(&self, topic: &Topic) -> bool {
+        self.pending.contains_key(topic)
+    }
+
+    pub fn reply(&mut self, topic: &Topic, peer: PeerId, msg: Bytes) {
+        if let Some(request) = self.pending.get_mut(topic) {
+            request.replies.push((peer, msg));
+        }
+    }
+
+    /// Completes a request whose timeout elapsed, returning its reply topic.
+    pub fn poll_expired(&mut self, cx: &mut Context) -> Poll<Topic> {
+        let expired = self.pending.iter_mut().find_map(|(topic, request)| {
+            if request.timer.poll_unpin(cx).is_ready() {
+                Some(*topic)
+            } else {
+                None
+            }
+        });
+        if let Some(topic) = expired {
+            let request = self.pending.remove(&topic).unwrap();
+            request.tx.send(request.replies).ok();
+            return Poll::Ready(topic);
+        }
+        Poll::Pending
+    }
+}
+
+/// Where the replies to a `BroadcastEvent::Request` go, see
+/// `Broadcast::reply`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct ReplyTo(pub(crate) u64);
+
+impl ReplyTo {
+    /// The topic the requester collects the replies on.
+    pub fn topic(&self) -> Topic {
+        Topic::new(format!("/reply/{:016x}", self.0).as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::future::poll_fn;
+
+    #[test]
+    fn test_requests() {
+        let peer = PeerId::random();
+        let mut requests = Requests::default();
+        let topic = ReplyTo(requests.reply_id()).topic();
+        assert_eq!(
+            ReplyTo(0xff).topic(),
+            Topic::new(b"/reply/00000000000000ff")
+        );
+        let rx = requests.insert(topic, Duration::ZERO);
+        requests.reply(&topic, peer, Bytes::from_static(b"pong"));
+        assert!(requests.contains(&topic));
+        assert!(!requests.contains(&Topic::new(b"other")));
+
+        assert_eq!(block_on(poll_fn(|cx| requests.poll_expired(cx))), topic);
+        assert_eq!(
+            block_on(rx).unwrap(),
+            vec![(peer, Bytes::from_static(b"pong"))]
+        );
+        assert!(!requests.contains(&topic));
+    }
+}
@@ -0,0 +1,156 @@
+use crate::rate_limit::{RateLimit, TokenBucket};
+use fnv::FnvHashMap;
+use instant::Instant;
+use libp2p::PeerId;
+use std::time::Duration;
+
+/// Penalties and thresholds of the peer scoring.
+///
+/// Scores start at zero, decrease with every penalty and recover towards zero
+/// over time.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerScoreParams {
+    /// Penalty for malformed frames and rejected messages.
+    pub invalid_message_penalty: f64,
+    /// Penalty for broadcasts on topics we are not subscribed to, under
+    /// `UnsolicitedPolicy::Report`.
+    pub unsubscribed_topic_penalty: f64,
+    /// Penalty for each message exceeding `inbound_rate_limit`.
+    pub rate_limit_penalty: f64,
+    pub inbound_rate_limit: Option<RateLimit>,
+    /// Fraction of the score remaining after one second.
+    pub decay: f64,
+    /// Messages from peers scoring below this are ignored.
+    pub graylist_threshold: f64,
+    /// Connections to peers scoring below this are closed.
+    pub disconnect_threshold: f64,
+}
+
+impl Default for PeerScoreParams {
+    fn default() -> Self {
+        Self {
+            invalid_message_penalty: -10.0,
+            unsubscribed_topic_penalty: -1.0,
+            rate_limit_penalty: -1.0,
+            inbound_rate_limit: None,
+            decay: 0.99,
+            graylist_threshold: -50.0,
+            disconnect_threshold: -100.0,
+        }
+    }
+}
+
+struct Score {
+    value: f64,
+    updated: Instant,
+}
+
+impl Score {
+    fn value(&self, decay: f64, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.value * decay.powf(elapsed)
+    }
+}
+
+/// Scores of peers that misbehaved.
+///
+/// Scores outlive connections, so a peer can't reset its score by
+/// reconnecting.
+#[derive(Default)]
+pub(crate) struct PeerScores {
+    params: Option<PeerScoreParams>,
+    scores: FnvHashMap<PeerId, Score>,
+    inbound: FnvHashMap<PeerId, TokenBucket>,
+}
+
+impl PeerScores {
+    pub fn new(params: Option<PeerScoreParams>) -> Self {
+        Self {
+            params,
+            ..Default::default()
+        }
+    }
+
+    pub fn params(&self) -> Option<&PeerScoreParams> {
+        self.params.as_ref()
+    }
+
+    pub fn score(&self, peer: &PeerId) -> Option<f64> {
+        let params = self.params.as_ref()?;
+        let score = match self.scores.get(peer) {
+            Some(score) => score.value(params.decay, Instant::now()),
+            None => 0.0,
+        };
+        Some(score)
+    }
+
+    /// Adds `penalty` to the peer's score, returning the new score.
+    pub fn penalize(&mut self, peer: &PeerId, penalty: f64) -> f64 {
+        let decay = match &self.params {
+            Some(params) => params.decay,
+            None => return 0.0,
+        };
+        let now = Instant::now();
+        let score = self.scores.entry(*peer).or_insert(Score {
+            value: 0.0,
+            updated: now,
+        });
+        score.value = score.value(decay, now) + penalty;
+        score.updated = now;
+        score.value
+    }
+
+    pub fn is_graylisted(&self, peer: &PeerId) -> bool {
+        match (&self.params, self.score(peer)) {
+            (Some(params), Some(score)) => score < params.graylist_threshold,
+            _ => false,
+        }
+    }
+
+    /// Records a received message, returning `false` if it exceeds the
+    /// inbound rate limit.
+    pub fn inbound(&mut self, peer: &PeerId, len: usize) -> bool {
+        let limit = match self
+            .params
+            .as_ref()
+            .and_then(|params| params.inbound_rate_limit)
+        {
+            Some(limit) => limit,
+            None => return true,
+        };
+        let now = Instant::now();
+        let bucket = self
+            .inbound
+            .entry(*peer)
+            .or_insert_with(|| TokenBucket::new(limit, now));
+        let allowed = bucket.wait(now, len) == Duration::ZERO;
+        if allowed {
+            bucket.take(len);
+        }
+        allowed
+    }
+
+    /// Forgets a disconnected peer unless it still has a negative score.
+    pub fn remove_peer(&mut self, peer: &PeerId) {
+        self.inbound.remove(peer);
+        if self.score(peer).unwrap_or_default() > -1.0 {
+            self.scores.remove(peer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_score_decay() {
+        let now = Instant::now();
+        let score = Score {
+            value: -100.0,
+            updated: now,
+        };
+        assert_eq!(score.value(0.5, now), -100.0);
+        assert_eq!(score.value(0.5, now + Duration::from_secs(2)), -25.0);
+    }
+}
@@ -0,0 +1,180 @@
+use crate::protocol::Topic;
+use crate::reorder::ReorderBuffer;
+use crate::BroadcastEvent;
+use fnv::{FnvHashMap, FnvHashSet};
+use libp2p::PeerId;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// Stamped messages held back until the ones stamped before them arrived.
+const ORDER_WINDOW: usize = 256;
+/// Time a stamped message waits for the ones before it.
+const ORDER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Sequencers of the topics with total order, see
+/// `BroadcastConfig::total_order`.
+pub(crate) struct Sequencers {
+    topics: FnvHashSet<Topic>,
+    local: Option<PeerId>,
+    elected: FnvHashMap<Topic, PeerId>,
+    /// Next position stamped on the topics we're or were the sequencer of.
+    /// Kept across terms, receivers would take restarted positions for late
+    /// messages.
+    next: FnvHashMap<Topic, u64>,
+    buffer: ReorderBuffer,
+}
+
+impl Default for Sequencers {
+    fn default() -> Self {
+        Self::new(Default::default())
+    }
+}
+
+impl Sequencers {
+    pub fn new(topics: FnvHashSet<Topic>) -> Self {
+        Self {
+            topics,
+            local: None,
+            elected: Default::default(),
+            next: Default::default(),
+            buffer: ReorderBuffer::new(ORDER_WINDOW, ORDER_TIMEOUT),
+        }
+    }
+
+    pub fn topics(&self) -> impl Iterator<Item = &Topic> + '_ {
+        self.topics.iter()
+    }
+
+    pub fn is_ordered(&self, topic: &Topic) -> bool {
+        self.topics.contains(topic)
+    }
+
+    /// Makes the lowest of `candidates` the sequencer of `topic`, returning
+    /// the new sequencer if it changed.
+    pub fn elect(
+        &mut self,
+        local: PeerId,
+        topic: Topic,
+        candidates: impl Iterator<Item = PeerId>,
+    ) -> Option<Option<PeerId>> {
+        self.local = Some(local);
+        let lowest = candidates.min();
+        let previous = match lowest {
+            Some(lowest) => self.elected.insert(topic, lowest),
+            None => self.elected.remove(&topic),
+        };
+        if previous == lowest {
+            return None;
+        }
+        Some(lowest)
+    }
+
+    /// Whether we stamp the messages on `topic`.
+    pub fn is_local(&self, topic: &Topic) -> bool {
+        self.local.is_some() && self.elected.get(topic) == self.local.as_ref()
+    }
+
+    /// The peer stamping the messages on `topic`, unless it's us.
+    pub fn remote(&self, topic: &Topic) -> Option<PeerId> {
+        self.elected
+            .get(topic)
+            .filter(|sequencer| Some(**sequencer) != self.local)
+            .copied()
+    }
+
+    /// Position of the next message on a topic we're the sequencer of.
+    pub fn stamp(&mut self, topic: &Topic) -> u64 {
+        let next = self.next.entry(*topic).or_default();
+        *next += 1;
+        *next - 1
+    }
+
+    /// Adds the received event of a message stamped by `sequencer`,
+    /// returning the events that can be delivered now.
+    pub fn insert(
+        &mut self,
+        sequencer: PeerId,
+        topic: Topic,
+        order: u64,
+        event: BroadcastEvent,
+    ) -> Vec<BroadcastEvent> {
+        self.buffer.insert(sequencer, topic, order, event)
+    }
+
+    pub fn poll_expired(&mut self, cx: &mut Context) -> Poll<Vec<BroadcastEvent>> {
+        self.buffer.poll_expired(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::MessageId;
+    use bytes::Bytes;
+
+    fn received(source: PeerId, topic: Topic, order: u64) -> BroadcastEvent {
+        let msg = Bytes::from(vec![order as u8]);
+        BroadcastEvent::Received(source, topic, MessageId::new(&topic, &msg), msg)
+    }
+
+    #[test]
+    fn test_election() {
+        let topic = Topic::new(b"topic");
+        let mut peers = [PeerId::random(), PeerId::random(), PeerId::random()];
+        peers.sort();
+        let (low, mid, local) = (peers[0], peers[1], peers[2]);
+        let mut sequencers = Sequencers::new(std::iter::once(topic).collect());
+
+        let elected = sequencers.elect(local, topic, std::iter::once(local));
+        assert_eq!(elected, Some(Some(local)));
+        assert!(sequencers.is_local(&topic));
+        assert_eq!(sequencers.stamp(&topic), 0);
+        assert_eq!(sequencers.stamp(&topic), 1);
+        assert_eq!(sequencers.elect(local, topic, std::iter::once(local)), None);
+
+        let candidates = [mid, local, low];
+        let elected = sequencers.elect(local, topic, candidates.iter().copied());
+        assert_eq!(elected, Some(Some(low)));
+        assert!(!sequencers.is_local(&topic));
+        assert_eq!(sequencers.remote(&topic), Some(low));
+
+        // Numbering continues when elected again.
+        sequencers.elect(local, topic, std::iter::once(local));
+        assert_eq!(sequencers.stamp(&topic), 2);
+        assert_eq!(sequencers.stamp(&topic), 3);
+
+        assert_eq!(
+            sequencers.elect(local, topic, std::iter::empty()),
+            Some(None)
+        );
+        assert_eq!(sequencers.remote(&topic), None);
+    }
+
+    #[test]
+    fn test_order_across_terms() {
+        let topic = Topic::new(b"topic");
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let mut sequencer = Sequencers::new(std::iter::once(topic).collect());
+        let mut receiver = Sequencers::new(std::iter::once(topic).collect());
+        let mut deliver = |order| receiver.insert(a, topic, order, received(a, topic, order));
+
+        // The sequencer changes from a to b and back to a.
+        sequencer.elect(a, topic, std::iter::once(a));
+        assert_eq!(
+            deliver(sequencer.stamp(&topic)),
+            vec![received(a, topic, 0)]
+        );
+        sequencer.elect(a, topic, std::iter::once(b));
+        sequencer.elect(a, topic, std::iter::once(a));
+
+        // Messages of the second term are delivered in order rather than as
+        // late ones.
+        let first = sequencer.stamp(&topic);
+        let second = sequencer.stamp(&topic);
+        assert!(deliver(second).is_empty());
+        assert_eq!(
+            deliver(first),
+            vec![received(a, topic, first), received(a, topic, second)]
+        );
+    }
+}
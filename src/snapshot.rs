@@ -0,0 +1,58 @@
+use crate::protocol::{Topic, Version};
+use libp2p::PeerId;
+
+/// State of a `Broadcast` behaviour returned by `Broadcast::snapshot`, for
+/// debugging. Serializable with the `serde` feature.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct BroadcastState {
+    pub subscriptions: Vec<Topic>,
+    pub prefix_subscriptions: Vec<Topic>,
+    pub peers: Vec<PeerState>,
+    pub topics: Vec<TopicState>,
+    /// Peers that disconnected recently with the number of broadcasts
+    /// buffered for them.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_buffered"))]
+    pub offline: Vec<(PeerId, usize)>,
+    /// Payload bytes of the broadcasts waiting to be passed to a handler.
+    pub queued_bytes: usize,
+    /// Payload bytes buffered for offline peers.
+    pub buffered_bytes: usize,
+}
+
+/// State of a connected peer.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct PeerState {
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_peer"))]
+    pub peer: PeerId,
+    pub topics: Vec<Topic>,
+    pub prefixes: Vec<Topic>,
+    pub version: Option<Version>,
+    pub connections: usize,
+    /// Messages waiting to be passed to the peer's handlers.
+    pub queued: usize,
+    /// Messages delayed by the rate limits.
+    pub delayed: usize,
+}
+
+/// Subscribers of a topic.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct TopicState {
+    pub topic: Topic,
+    pub peers: usize,
+}
+
+#[cfg(feature = "serde")]
+fn serialize_peer<S: serde::Serializer>(peer: &PeerId, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&peer.to_base58())
+}
+
+#[cfg(feature = "serde")]
+fn serialize_buffered<S: serde::Serializer>(
+    offline: &[(PeerId, usize)],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(offline.iter().map(|(peer, len)| (peer.to_base58(), len)))
+}
@@ -0,0 +1,97 @@
+use crate::protocol::{RelayStamp, Topic, STAMP_LEN};
+use hmac::{Hmac, Mac};
+use libp2p::PeerId;
+use rand::RngCore;
+use sha2::Sha256;
+use std::fmt;
+
+/// Symmetric key shared by the relays allowed to forward the messages of a
+/// topic, stamping them with HMAC-SHA256 tags.
+#[derive(Clone)]
+pub struct RelayKey([u8; 32]);
+
+impl RelayKey {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self(key)
+    }
+
+    pub fn generate() -> Self {
+        let mut key = [0; 32];
+        rand::thread_rng().fill_bytes(&mut key);
+        Self(key)
+    }
+
+    /// Stamps `msg` as sent by `relay`.
+    pub(crate) fn stamp(&self, topic: &Topic, relay: PeerId, msg: &[u8]) -> RelayStamp {
+        let tag = self.mac(topic, &relay, msg).finalize().into_bytes();
+        let mut stamp = RelayStamp {
+            relay,
+            tag: [0; STAMP_LEN],
+        };
+        stamp.tag.copy_from_slice(&tag[..STAMP_LEN]);
+        stamp
+    }
+
+    /// Whether every stamp is valid and the last one is from `sender`.
+    pub(crate) fn verify(
+        &self,
+        topic: &Topic,
+        sender: &PeerId,
+        stamps: &[RelayStamp],
+        msg: &[u8],
+    ) -> bool {
+        match stamps.last() {
+            Some(last) if last.relay == *sender => stamps.iter().all(|stamp| {
+                self.mac(topic, &stamp.relay, msg)
+                    .verify_truncated_left(&stamp.tag)
+                    .is_ok()
+            }),
+            _ => false,
+        }
+    }
+
+    fn mac(&self, topic: &Topic, relay: &PeerId, msg: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.0).expect("any key length is valid");
+        let relay = relay.to_bytes();
+        for part in [&topic[..], &relay, msg] {
+            mac.update(&(part.len() as u64).to_be_bytes());
+            mac.update(part);
+        }
+        mac
+    }
+}
+
+impl From<[u8; 32]> for RelayKey {
+    fn from(key: [u8; 32]) -> Self {
+        Self(key)
+    }
+}
+
+impl fmt::Debug for RelayKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RelayKey(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relay_key() {
+        let topic = Topic::new(b"topic");
+        let key = RelayKey::generate();
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let stamps = [key.stamp(&topic, a, b"msg"), key.stamp(&topic, b, b"msg")];
+        assert!(key.verify(&topic, &b, &stamps, b"msg"));
+        assert!(!key.verify(&topic, &a, &stamps, b"msg"));
+        assert!(!key.verify(&topic, &b, &stamps, b"other"));
+        assert!(!key.verify(&topic, &b, &[], b"msg"));
+        assert!(!RelayKey::generate().verify(&topic, &b, &stamps, b"msg"));
+        let forged = RelayStamp {
+            relay: b,
+            ..stamps[0]
+        };
+        assert!(!key.verify(&topic, &b, &[forged], b"msg"));
+    }
+}
@@ -0,0 +1,18 @@
+use crate::protocol::Topic;
+use bytes::Bytes;
+
+/// Provides the current state of a topic whose broadcasts update a shared
+/// state, sent to peers subscribing with `Broadcast::subscribe_with_snapshot`.
+pub trait SnapshotProvider: Send {
+    /// The state of `topic`, `None` if there is none to send.
+    fn snapshot(&mut self, topic: &Topic) -> Option<Bytes>;
+}
+
+impl<F> SnapshotProvider for F
+where
+    F: FnMut(&Topic) -> Option<Bytes> + Send,
+{
+    fn snapshot(&mut self, topic: &Topic) -> Option<Bytes> {
+        self(topic)
+    }
+}
@@ -0,0 +1,162 @@
+use fnv::FnvHashMap;
+use instant::Instant;
+use libp2p::PeerId;
+
+/// Counters of the broadcasts exchanged with a connected peer, see
+/// `Broadcast::peer_stats`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct PeerStats {
+    /// Broadcasts handed to the peer's connection handlers.
+    pub messages_sent: u64,
+    /// Payload bytes of the sent broadcasts.
+    pub bytes_sent: u64,
+    pub messages_received: u64,
+    /// Payload bytes of the received broadcasts.
+    pub bytes_received: u64,
+    /// Broadcasts to the peer that were dropped or couldn't be written.
+    pub send_failures: u64,
+    /// Time any frame was last received from the peer.
+    pub last_seen: Option<Instant>,
+}
+
+/// Counters of the broadcasts exchanged with all peers since the behaviour
+/// was created, see `Broadcast::stats`. Serializable with the `serde`
+/// feature.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct BroadcastStats {
+    pub messages_sent: u64,
+    pub bytes_sent: u64,
+    pub messages_received: u64,
+    pub bytes_received: u64,
+    pub send_failures: u64,
+    /// Frames written with more than one message.
+    pub batches_sent: u64,
+    /// Messages written as part of a batch.
+    pub batched_messages: u64,
+    /// Batches taken from a congested send queue, see
+    /// `BroadcastConfig::adaptive_batching`.
+    pub coalesced_batches: u64,
+    /// Connected peers.
+    pub peers: usize,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Stats {
+    peers: FnvHashMap<PeerId, PeerStats>,
+    total: BroadcastStats,
+}
+
+impl Stats {
+    pub fn peer(&self, peer: &PeerId) -> Option<PeerStats> {
+        self.peers.get(peer).copied()
+    }
+
+    pub fn total(&self) -> BroadcastStats {
+        BroadcastStats {
+            peers: self.peers.len(),
+            ..self.total
+        }
+    }
+
+    pub fn connected(&mut self, peer: PeerId) {
+        self.peers.entry(peer).or_default();
+    }
+
+    pub fn disconnected(&mut self, peer: &PeerId) {
+        self.peers.remove(peer);
+    }
+
+    /// A frame was received from the peer, `len` is the payload size of
+    /// broadcasts.
+    pub fn received(&mut self, peer: &PeerId, len: Option<usize>) {
+        if let Some(len) = len {
+            self.total.messages_received += 1;
+            self.total.bytes_received += len as u64;
+        }
+        if let Some(stats) = self.peers.get_mut(peer) {
+            stats.last_seen = Some(Instant::now());
+            if let Some(len) = len {
+                stats.messages_received += 1;
+                stats.bytes_received += len as u64;
+            }
+        }
+    }
+
+    pub fn sent(&mut self, peer: &PeerId, len: usize) {
+        if let Some(stats) = self.peers.get_mut(peer) {
+            stats.messages_sent += 1;
+            stats.bytes_sent += len as u64;
+        }
+        self.total.messages_sent += 1;
+        self.total.bytes_sent += len as u64;
+    }
+
+    pub fn batch_sent(&mut self, len: usize, coalesced: bool) {
+        self.total.batches_sent += 1;
+        self.total.batched_messages += len as u64;
+        if coalesced {
+            self.total.coalesced_batches += 1;
+        }
+    }
+
+    pub fn failed(&mut self, peer: &PeerId) {
+        if let Some(stats) = self.peers.get_mut(peer) {
+            stats.send_failures += 1;
+        }
+        self.total.send_failures += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let mut stats = Stats::default();
+        stats.connected(a);
+        stats.received(&a, None);
+        stats.received(&a, Some(3));
+        stats.sent(&a, 2);
+        stats.failed(&a);
+        // Unknown peers only count towards the totals.
+        stats.sent(&b, 5);
+        stats.received(&b, Some(7));
+        stats.batch_sent(3, false);
+        stats.batch_sent(5, true);
+
+        let peer = stats.peer(&a).unwrap();
+        assert!(peer.last_seen.is_some());
+        assert_eq!(
+            peer,
+            PeerStats {
+                messages_sent: 1,
+                bytes_sent: 2,
+                messages_received: 1,
+                bytes_received: 3,
+                send_failures: 1,
+                last_seen: peer.last_seen,
+            }
+        );
+        assert_eq!(
+            stats.total(),
+            BroadcastStats {
+                messages_sent: 2,
+                bytes_sent: 7,
+                messages_received: 2,
+                bytes_received: 10,
+                send_failures: 1,
+                batches_sent: 2,
+                batched_messages: 8,
+                coalesced_batches: 1,
+                peers: 1,
+            }
+        );
+
+        stats.disconnected(&a);
+        assert_eq!(stats.peer(&a), None);
+        assert_eq!(stats.total().messages_sent, 2);
+    }
+}
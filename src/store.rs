@@ -0,0 +1,146 @@
+use crate::protocol::Topic;
+use libp2p::PeerId;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
+
+/// Subscriptions persisted across restarts.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct StoredState {
+    pub subscriptions: Vec<Topic>,
+    pub prefix_subscriptions: Vec<Topic>,
+    /// Topics and prefixes peers were last known to be subscribed to.
+    pub peers: Vec<(PeerId, Vec<Topic>, Vec<Topic>)>,
+}
+
+/// Storage of the subscription state of a `Broadcast` behaviour.
+pub trait SubscriptionStore: Send {
+    /// Loads the stored state, an empty state if nothing was stored yet.
+    fn load(&mut self) -> Result<StoredState>;
+    fn save(&mut self, state: &StoredState) -> Result<()>;
+}
+
+/// Stores the subscription state in a text file.
+///
+/// The file is replaced atomically on every save.
+#[derive(Clone, Debug)]
+pub struct FileStore {
+    path: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>> {
+    let invalid = || Error::new(ErrorKind::InvalidData, "invalid hex");
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            let byte = hex.get(i..i + 2).ok_or_else(invalid)?;
+            u8::from_str_radix(byte, 16).map_err(|_| invalid())
+        })
+        .collect()
+}
+
+fn parse_topic(hex: &str) -> Result<Topic> {
+    let bytes = from_hex(hex)?;
+    Topic::try_new(&bytes).ok_or_else(|| Error::new(ErrorKind::InvalidData, "topic too long"))
+}
+
+/// Empty lists are written as `-` to tell them apart from a list holding
+/// the empty topic.
+fn parse_topics(list: &str) -> Result<Vec<Topic>> {
+    if list == "-" {
+        return Ok(Vec::new());
+    }
+    list.split(',').map(parse_topic).collect()
+}
+
+fn format_topics(topics: &[Topic]) -> String {
+    if topics.is_empty() {
+        return "-".to_string();
+    }
+    topics
+        .iter()
+        .map(|topic| to_hex(topic))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+impl SubscriptionStore for FileStore {
+    fn load(&mut self) -> Result<StoredState> {
+        let text = match fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Default::default()),
+            Err(err) => return Err(err),
+        };
+        let mut state = StoredState::default();
+        for line in text.lines() {
+            let fields: Vec<&str> = line.split(' ').collect();
+            match fields.as_slice() {
+                ["sub", topic] => state.subscriptions.push(parse_topic(topic)?),
+                ["prefix", prefix] => state.prefix_subscriptions.push(parse_topic(prefix)?),
+                ["peer", peer, topics, prefixes] => {
+                    let peer = PeerId::from_bytes(&from_hex(peer)?)
+                        .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+                    state
+                        .peers
+                        .push((peer, parse_topics(topics)?, parse_topics(prefixes)?));
+                }
+                _ => return Err(Error::new(ErrorKind::InvalidData, "invalid line")),
+            }
+        }
+        Ok(state)
+    }
+
+    fn save(&mut self, state: &StoredState) -> Result<()> {
+        let mut text = String::new();
+        for topic in &state.subscriptions {
+            text.push_str(&format!("sub {}\n", to_hex(topic)));
+        }
+        for prefix in &state.prefix_subscriptions {
+            text.push_str(&format!("prefix {}\n", to_hex(prefix)));
+        }
+        for (peer, topics, prefixes) in &state.peers {
+            text.push_str(&format!(
+                "peer {} {} {}\n",
+                to_hex(&peer.to_bytes()),
+                format_topics(topics),
+                format_topics(prefixes),
+            ));
+        }
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, text)?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_store() {
+        let path = std::env::temp_dir().join(format!("broadcast-{}.subs", PeerId::random()));
+        let mut store = FileStore::new(&path);
+        assert_eq!(store.load().unwrap(), StoredState::default());
+        let state = StoredState {
+            subscriptions: vec![Topic::new(b"a"), Topic::new(b"")],
+            prefix_subscriptions: vec![Topic::new(b"chat/")],
+            peers: vec![
+                (PeerId::random(), vec![Topic::new(b"a")], vec![]),
+                (PeerId::random(), vec![], vec![Topic::new(b"")]),
+            ],
+        };
+        store.save(&state).unwrap();
+        assert_eq!(store.load().unwrap(), state);
+        fs::remove_file(path).unwrap();
+    }
+}
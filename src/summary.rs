@@ -0,0 +1,95 @@
+use crate::protocol::Topic;
+use fnv::FnvHasher;
+use std::hash::Hasher;
+
+/// Bits per topic, giving about 1% false positives.
+const BITS_PER_TOPIC: usize = 10;
+const HASHES: u8 = 7;
+pub(crate) const MAX_HASHES: u8 = 16;
+
+/// Bloom filter of the topics a peer is subscribed to, exchanged instead of
+/// the full subscriptions with `BroadcastConfig::lazy_subscriptions`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TopicSummary {
+    hashes: u8,
+    bits: Vec<u8>,
+}
+
+impl TopicSummary {
+    pub fn new<'a>(topics: impl ExactSizeIterator<Item = &'a Topic>) -> Self {
+        let len = (topics.len() * BITS_PER_TOPIC).max(64);
+        let mut summary = Self {
+            hashes: HASHES,
+            bits: vec![0; (len - 1) / 8 + 1],
+        };
+        for topic in topics {
+            summary.insert(topic);
+        }
+        summary
+    }
+
+    pub(crate) fn from_parts(hashes: u8, bits: Vec<u8>) -> Option<Self> {
+        if hashes == 0 || hashes > MAX_HASHES || bits.is_empty() {
+            return None;
+        }
+        Some(Self { hashes, bits })
+    }
+
+    pub(crate) fn hashes(&self) -> u8 {
+        self.hashes
+    }
+
+    pub(crate) fn bits(&self) -> &[u8] {
+        &self.bits
+    }
+
+    pub fn insert(&mut self, topic: &Topic) {
+        for bit in self.positions(topic) {
+            self.bits[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    /// Whether the topic may be contained, false positives are possible.
+    pub fn contains(&self, topic: &Topic) -> bool {
+        self.positions(topic)
+            .all(|bit| self.bits[bit / 8] & 1 << (bit % 8) != 0)
+    }
+
+    fn positions(&self, topic: &Topic) -> impl Iterator<Item = usize> {
+        // Double hashing, see Kirsch and Mitzenmacher.
+        let hash = |seed: u8| {
+            let mut hasher = FnvHasher::default();
+            hasher.write_u8(seed);
+            hasher.write(topic);
+            hasher.finish()
+        };
+        let (a, b) = (hash(0), hash(1));
+        let len = self.bits.len() as u64 * 8;
+        (0..self.hashes as u64).map(move |i| (a.wrapping_add(i.wrapping_mul(b)) % len) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_topic_summary() {
+        let topics: Vec<_> = (0..1000)
+            .map(|i| Topic::new(format!("topic/{}", i).as_bytes()))
+            .collect();
+        let summary = TopicSummary::new(topics.iter());
+        assert!(topics.iter().all(|topic| summary.contains(topic)));
+        let false_positives = (0..1000)
+            .map(|i| Topic::new(format!("other/{}", i).as_bytes()))
+            .filter(|topic| summary.contains(topic))
+            .count();
+        assert!(false_positives < 50);
+
+        let mut empty = TopicSummary::new([].iter());
+        assert!(!empty.contains(&topics[0]));
+        empty.insert(&topics[0]);
+        assert!(empty.contains(&topics[0]));
+        assert!(TopicSummary::from_parts(0, vec![0]).is_none());
+    }
+}
@@ -0,0 +1,72 @@
+use crate::protocol::{Message, Topic};
+use fnv::FnvHashSet;
+use futures::FutureExt;
+use futures_timer::Delay;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// Periodic exchange of the full subscription state, see
+/// `BroadcastConfig::topic_sync`.
+#[derive(Default)]
+pub(crate) struct TopicSync {
+    interval: Duration,
+    timer: Option<Delay>,
+}
+
+impl TopicSync {
+    pub fn new(interval: Option<Duration>) -> Self {
+        match interval {
+            Some(interval) => Self {
+                interval,
+                timer: Some(Delay::new(interval)),
+            },
+            None => Self::default(),
+        }
+    }
+
+    /// Ready when the subscriptions are due to be sent.
+    pub fn poll_tick(&mut self, cx: &mut Context) -> Poll<()> {
+        if let Some(timer) = &mut self.timer {
+            if timer.poll_unpin(cx).is_ready() {
+                timer.reset(self.interval);
+                return Poll::Ready(());
+            }
+        }
+        Poll::Pending
+    }
+}
+
+/// The subscription changes turning the `known` subscriptions of a peer
+/// into the `synced` ones, ordered by topic.
+pub(crate) fn diff(known: &FnvHashSet<Topic>, synced: &[Topic]) -> Vec<Message> {
+    let synced: FnvHashSet<_> = synced.iter().copied().collect();
+    let mut added: Vec<_> = synced.difference(known).copied().collect();
+    let mut removed: Vec<_> = known.difference(&synced).copied().collect();
+    added.sort();
+    removed.sort();
+    added
+        .into_iter()
+        .map(Message::Subscribe)
+        .chain(removed.into_iter().map(Message::Unsubscribe))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        let (a, b, c) = (Topic::new(b"a"), Topic::new(b"b"), Topic::new(b"c"));
+        let known = [a, b].iter().copied().collect();
+        assert_eq!(
+            diff(&known, &[b, c]),
+            vec![Message::Subscribe(c), Message::Unsubscribe(a)]
+        );
+        assert!(diff(&known, &[a, b]).is_empty());
+        assert_eq!(
+            diff(&FnvHashSet::default(), &[a]),
+            vec![Message::Subscribe(a)]
+        );
+    }
+}
@@ -0,0 +1,384 @@
+//! An in-memory network of `Broadcast` behaviours with simulated latency
+//! and loss, for testing code built on broadcasts without real connections.
+//!
+//! Peer ids, delivery order, latencies and losses are derived from the seed
+//! of the network, and the simulated time only advances when the network
+//! is stepped. The scheduling is not fully deterministic though: the timers
+//! of the behaviours run on real time and don't see the simulated clock.
+//! Features driven by them can't be simulated and behave differently from
+//! run to run, among them:
+//!
+//! - ack timeouts of `Broadcast::broadcast_with_ack` and the retransmits and
+//!   deadlines of `Broadcast::broadcast_reliable`
+//! - `BroadcastConfig::subscription_lease` and
+//!   `BroadcastConfig::topic_heartbeat`
+//! - the timeout of `BroadcastConfig::reorder_window`
+//! - rate limits, message ttls, `BroadcastConfig::topic_gc` and request
+//!   timeouts
+//!
+//! Leave these disabled or give them timeouts far beyond the simulated
+//! latencies to keep tests reproducible.
+use crate::{Broadcast, BroadcastConfig, BroadcastEvent, HandlerEvent, Message};
+use fnv::{FnvHashMap, FnvHashSet};
+use libp2p::core::Multiaddr;
+use libp2p::swarm::{AddressRecord, NetworkBehaviour, NetworkBehaviourAction, PollParameters};
+use libp2p::PeerId;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{BTreeMap, VecDeque};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+struct Params(PeerId);
+
+impl PollParameters for Params {
+    type SupportedProtocolsIter = std::iter::Empty<Vec<u8>>;
+    type ListenedAddressesIter = std::iter::Empty<Multiaddr>;
+    type ExternalAddressesIter = std::iter::Empty<AddressRecord>;
+
+    fn supported_protocols(&self) -> Self::SupportedProtocolsIter {
+        std::iter::empty()
+    }
+
+    fn listened_addresses(&self) -> Self::ListenedAddressesIter {
+        std::iter::empty()
+    }
+
+    fn external_addresses(&self) -> Self::ExternalAddressesIter {
+        std::iter::empty()
+    }
+
+    fn local_peer_id(&self) -> &PeerId {
+        &self.0
+    }
+}
+
+struct Node {
+    params: Params,
+    behaviour: Broadcast,
+    peers: FnvHashSet<PeerId>,
+    events: VecDeque<BroadcastEvent>,
+}
+
+/// A frame on its way from one node to another.
+struct Frame {
+    from: PeerId,
+    to: PeerId,
+    msg: Message,
+}
+
+/// Nodes connected by simulated links, see the module documentation.
+pub struct TestNetwork {
+    rng: StdRng,
+    latency: Duration,
+    jitter: Duration,
+    drop_rate: f64,
+    nodes: Vec<Node>,
+    index: FnvHashMap<PeerId, usize>,
+    /// Frames by delivery time and sequence number.
+    in_flight: BTreeMap<(Duration, u64), Frame>,
+    /// Delivery time of the last frame sent on each link, links are ordered.
+    last_delivery: FnvHashMap<(PeerId, PeerId), Duration>,
+    seq: u64,
+    now: Duration,
+    dropped: u64,
+}
+
+impl TestNetwork {
+    /// Creates an empty network without latency or loss.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+            latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            drop_rate: 0.0,
+            nodes: Vec::new(),
+            index: Default::default(),
+            in_flight: Default::default(),
+            last_delivery: Default::default(),
+            seq: 0,
+            now: Duration::ZERO,
+            dropped: 0,
+        }
+    }
+
+    /// Time it takes a frame to reach its destination.
+    pub fn latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Random delay of up to `jitter` added to the latency of each frame.
+    pub fn jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Fraction of broadcasts lost in transit. Subscriptions are never
+    /// lost, like on a real connection.
+    ///
+    /// # Panics
+    ///
+    /// If `rate` is not between `0.0` and `1.0`.
+    pub fn drop_rate(mut self, rate: f64) -> Self {
+        assert!((0.0..=1.0).contains(&rate), "invalid drop rate");
+        self.drop_rate = rate;
+        self
+    }
+
+    /// Adds a behaviour with `config`, returning its peer id. Nodes with
+    /// a `BroadcastConfig::keypair` get its peer id, like a swarm built
+    /// from it.
+    pub fn add_node(&mut self, config: BroadcastConfig) -> PeerId {
+        let mut bytes = vec![0, 32];
+        bytes.extend_from_slice(&self.rng.gen::<[u8; 32]>());
+        let peer_id = match &config.keypair {
+            Some(keypair) => keypair.public().to_peer_id(),
+            None => PeerId::from_bytes(&bytes).unwrap(),
+        };
+        self.index.insert(peer_id, self.nodes.len());
+        self.nodes.push(Node {
+            params: Params(peer_id),
+            behaviour: Broadcast::new(config),
+            peers: Default::default(),
+            events: Default::default(),
+        });
+        peer_id
+    }
+
+    /// The peer ids of all nodes, in the order they were added.
+    pub fn peers(&self) -> impl Iterator<Item = &PeerId> + '_ {
+        self.nodes.iter().map(|node| &node.params.0)
+    }
+
+    /// The behaviour of a node.
+    ///
+    /// # Panics
+    ///
+    /// If `peer` isn't a node of the network.
+    pub fn node(&mut self, peer: &PeerId) -> &mut Broadcast {
+        let i = self.node_index(peer);
+        &mut self.nodes[i].behaviour
+    }
+
+    /// Connects two nodes. Does nothing if they are already connected.
+    pub fn connect(&mut self, a: &PeerId, b: &PeerId) {
+        let (i, j) = (self.node_index(a), self.node_index(b));
+        if !self.nodes[i].peers.insert(*b) {
+            return;
+        }
+        self.nodes[j].peers.insert(*a);
+        self.nodes[i].behaviour.inject_connected(b);
+        self.nodes[j].behaviour.inject_connected(a);
+    }
+
+    /// Disconnects two nodes, dropping the frames in flight between them.
+    pub fn disconnect(&mut self, a: &PeerId, b: &PeerId) {
+        let (i, j) = (self.node_index(a), self.node_index(b));
+        if !self.nodes[i].peers.remove(b) {
+            return;
+        }
+        self.nodes[j].peers.remove(a);
+        self.nodes[i].behaviour.inject_disconnected(b);
+        self.nodes[j].behaviour.inject_disconnected(a);
+        self.in_flight.retain(|_, frame| {
+            !(frame.from == *a && frame.to == *b || frame.from == *b && frame.to == *a)
+        });
+    }
+
+    /// Drops the frames in flight from `from` to `to`, as if they were
+    /// lost. Returns the number of frames dropped.
+    pub fn drop_in_flight(&mut self, from: &PeerId, to: &PeerId) -> usize {
+        let len = self.in_flight.len();
+        self.in_flight
+            .retain(|_, frame| !(frame.from == *from && frame.to == *to));
+        len - self.in_flight.len()
+    }
+
+    pub fn is_connected(&self, a: &PeerId, b: &PeerId) -> bool {
+        let i = self.node_index(a);
+        self.nodes[i].peers.contains(b)
+    }
+
+    /// The next event emitted by the behaviour of a node.
+    pub fn next_event(&mut self, peer: &PeerId) -> Option<BroadcastEvent> {
+        let i = self.node_index(peer);
+        self.nodes[i].events.pop_front()
+    }
+
+    /// All events emitted by the behaviour of a node so far.
+    pub fn events(&mut self, peer: &PeerId) -> Vec<BroadcastEvent> {
+        let i = self.node_index(peer);
+        self.nodes[i].events.drain(..).collect()
+    }
+
+    /// Time elapsed in the network.
+    pub fn now(&self) -> Duration {
+        self.now
+    }
+
+    /// Frames sent but not delivered yet.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Broadcasts lost in transit so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Polls all nodes, then delivers the frames due next. Returns `false`
+    /// if there were no frames in flight.
+    pub fn step(&mut self) -> bool {
+        self.poll();
+        let at = match self.in_flight.keys().next() {
+            Some((at, _)) => *at,
+            None => return false,
+        };
+        self.deliver(at);
+        self.poll();
+        true
+    }
+
+    /// Steps until no frames are in flight.
+    pub fn run(&mut self) {
+        while self.step() {}
+    }
+
+    /// Delivers all frames due within `duration`, advancing the simulated
+    /// time. The timers of the behaviours don't advance with it.
+    pub fn advance(&mut self, duration: Duration) {
+        let until = self.now + duration;
+        loop {
+            self.poll();
+            match self.in_flight.keys().next() {
+                Some((at, _)) if *at <= until => self.deliver(*at),
+                _ => break,
+            }
+        }
+        self.now = until;
+    }
+
+    fn node_index(&self, peer: &PeerId) -> usize {
+        *self.index.get(peer).expect("unknown peer")
+    }
+
+    /// Delivers the frames due at `at`.
+    fn deliver(&mut self, at: Duration) {
+        self.now = at;
+        while let Some(&key) = self.in_flight.keys().next() {
+            if key.0 > at {
+                break;
+            }
+            let frame = self.in_flight.remove(&key).unwrap();
+            let node = &mut self.nodes[self.index[&frame.to]];
+            node.behaviour
+                .inject_handler_event(frame.from, HandlerEvent::Rx(frame.msg));
+        }
+    }
+
+    /// Polls the nodes in the order they were added until none of them has
+    /// anything left to do.
+    fn poll(&mut self) {
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut busy = true;
+        while busy {
+            busy = false;
+            for i in 0..self.nodes.len() {
+                loop {
+                    let node = &mut self.nodes[i];
+                    let action = match node.behaviour.poll(&mut cx, &mut node.params) {
+                        Poll::Ready(action) => action,
+                        Poll::Pending => break,
+                    };
+                    busy = true;
+                    let from = node.params.0;
+                    match action {
+                        NetworkBehaviourAction::GenerateEvent(event) => {
+                            node.events.push_back(event)
+                        }
+                        NetworkBehaviourAction::NotifyHandler { peer_id, event, .. } => {
+                            if let Some(msg) = event.message() {
+                                self.send(from, peer_id, msg.clone())
+                            }
+                        }
+                        NetworkBehaviourAction::CloseConnection { peer_id, .. } => {
+                            self.disconnect(&from, &peer_id)
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    fn send(&mut self, from: PeerId, to: PeerId, msg: Message) {
+        if !self.nodes[self.index[&from]].peers.contains(&to) {
+            return;
+        }
+        if matches!(msg, Message::Broadcast(..)) && self.rng.gen_bool(self.drop_rate) {
+            self.dropped += 1;
+            return;
+        }
+        let jitter = self.rng.gen_range(Duration::ZERO..=self.jitter);
+        let last = self.last_delivery.entry((from, to)).or_default();
+        let at = (self.now + self.latency + jitter).max(*last);
+        *last = at;
+        self.seq += 1;
+        self.in_flight
+            .insert((at, self.seq), Frame { from, to, msg });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Topic;
+    use bytes::Bytes;
+
+    #[test]
+    fn test_network() {
+        let topic = Topic::new(b"topic");
+        let mut net = TestNetwork::new(0).latency(Duration::from_millis(10));
+        let a = net.add_node(BroadcastConfig::default());
+        let b = net.add_node(BroadcastConfig::default());
+        net.connect(&a, &b);
+        net.node(&b).subscribe(topic).unwrap().detach();
+        net.run();
+        assert_eq!(net.now(), Duration::from_millis(10));
+        net.events(&a);
+
+        net.node(&a)
+            .broadcast(&topic, Bytes::from_static(b"msg"))
+            .unwrap();
+        net.advance(Duration::from_millis(5));
+        assert_eq!(net.in_flight(), 1);
+        net.advance(Duration::from_millis(5));
+        assert_eq!(net.in_flight(), 0);
+        assert!(net.events(&b).iter().any(
+            |event| matches!(event, BroadcastEvent::Received(peer, _, _, msg) if *peer == a && msg == "msg")
+        ));
+
+        // The same seed gives the same peer ids and losses.
+        let lossy = || {
+            let mut net = TestNetwork::new(7).drop_rate(0.5);
+            let a = net.add_node(BroadcastConfig::default());
+            let b = net.add_node(BroadcastConfig::default());
+            net.connect(&a, &b);
+            net.node(&b).subscribe(topic).unwrap().detach();
+            net.run();
+            for i in 0..20u8 {
+                net.node(&a).broadcast(&topic, vec![i]).unwrap();
+            }
+            net.run();
+            (a, net.dropped())
+        };
+        let (peer, dropped) = lossy();
+        assert!(dropped > 0 && dropped < 20);
+        assert_eq!(lossy(), (peer, dropped));
+
+        net.disconnect(&a, &b);
+        assert!(!net.is_connected(&b, &a));
+    }
+}
@@ -0,0 +1,35 @@
+//! Instrumentation with `tracing`, compiled out without the `tracing`
+//! feature.
+//!
+//! Events are emitted below the `libp2p_broadcast` target so their noise
+//! can be tuned per path with a filter like
+//! `libp2p_broadcast::subscription=debug,libp2p_broadcast::queue=trace`:
+//!
+//! - `libp2p_broadcast::subscription`: local and remote (un)subscriptions.
+//! - `libp2p_broadcast::broadcast`: published and received broadcasts.
+//! - `libp2p_broadcast::handler`: events of the connection handlers.
+//! - `libp2p_broadcast::queue`: queueing and dropping of messages.
+//!
+//! Changes of state are logged at `debug`, per message events at `trace`.
+
+/// Emits an event at `$level` below `libp2p_broadcast::$target`.
+macro_rules! event {
+    ($level:ident, $target:literal, $($args:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::$level!(target: concat!("libp2p_broadcast::", $target), $($args)*);
+    };
+}
+
+/// Enters a span at `$level` below `libp2p_broadcast::$target` until the
+/// end of the enclosing block.
+macro_rules! span {
+    ($level:ident, $target:literal, $($args:tt)*) => {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::span!(
+            target: concat!("libp2p_broadcast::", $target),
+            tracing::Level::$level,
+            $($args)*
+        )
+        .entered();
+    };
+}
@@ -0,0 +1,376 @@
+use crate::{
+    Broadcast, BroadcastConfig, BroadcastError, BroadcastEvent, BroadcastHandler, HandlerEvent,
+    MessageId, Topic,
+};
+use bytes::Bytes;
+use libp2p::core::connection::ConnectionId;
+use libp2p::core::ConnectedPoint;
+use libp2p::swarm::{DialError, NetworkBehaviour, NetworkBehaviourAction, PollParameters};
+use libp2p::{Multiaddr, PeerId};
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::task::{Context, Poll};
+
+/// Error encoding or decoding a typed payload.
+#[derive(Debug)]
+pub struct CodecError(Box<dyn std::error::Error + Send + Sync>);
+
+impl CodecError {
+    pub fn new(error: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Self {
+        Self(error.into())
+    }
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+/// Error publishing a typed message.
+#[derive(Debug)]
+pub enum PublishError {
+    Codec(CodecError),
+    Broadcast(BroadcastError),
+}
+
+impl fmt::Display for PublishError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Codec(err) => err.fmt(f),
+            Self::Broadcast(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for PublishError {}
+
+impl From<CodecError> for PublishError {
+    fn from(err: CodecError) -> Self {
+        Self::Codec(err)
+    }
+}
+
+impl From<BroadcastError> for PublishError {
+    fn from(err: BroadcastError) -> Self {
+        Self::Broadcast(err)
+    }
+}
+
+/// Converts values of type `T` to and from broadcast payloads.
+pub trait PayloadCodec<T> {
+    fn encode(&self, value: &T) -> Result<Vec<u8>, CodecError>;
+    fn decode(&self, bytes: &[u8]) -> Result<T, CodecError>;
+}
+
+/// JSON payloads using `serde_json`.
+#[cfg(feature = "json")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl<T: serde::Serialize + serde::de::DeserializeOwned> PayloadCodec<T> for Json {
+    fn encode(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        serde_json::to_vec(value).map_err(CodecError::new)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        serde_json::from_slice(bytes).map_err(CodecError::new)
+    }
+}
+
+/// Bincode payloads.
+#[cfg(feature = "bincode")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl<T: serde::Serialize + serde::de::DeserializeOwned> PayloadCodec<T> for Bincode {
+    fn encode(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        bincode::serialize(value).map_err(CodecError::new)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        bincode::deserialize(bytes).map_err(CodecError::new)
+    }
+}
+
+/// CBOR payloads using `serde_cbor`.
+#[cfg(feature = "cbor")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl<T: serde::Serialize + serde::de::DeserializeOwned> PayloadCodec<T> for Cbor {
+    fn encode(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        serde_cbor::to_vec(value).map_err(CodecError::new)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        serde_cbor::from_slice(bytes).map_err(CodecError::new)
+    }
+}
+
+#[derive(Debug)]
+pub enum TypedEvent<T> {
+    /// A message was received and decoded.
+    Received(PeerId, Topic, MessageId, T),
+    /// A message was received but couldn't be decoded.
+    DecodeError(PeerId, Topic, CodecError),
+    /// Any other event of the underlying `Broadcast` behaviour.
+    Event(BroadcastEvent),
+}
+
+/// `Broadcast` behaviour encoding and decoding payloads of type `T` with a
+/// `PayloadCodec`.
+///
+/// Dereferences to the underlying `Broadcast` for managing subscriptions.
+pub struct TypedBroadcast<T, C> {
+    inner: Broadcast,
+    codec: C,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T, C: PayloadCodec<T>> TypedBroadcast<T, C> {
+    pub fn new(config: BroadcastConfig, codec: C) -> Self {
+        Self {
+            inner: Broadcast::new(config),
+            codec,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Encodes `value` and broadcasts it to all peers subscribed to `topic`.
+    pub fn publish(&mut self, topic: &Topic, value: &T) -> Result<(), PublishError> {
+        let payload: Bytes = self.codec.encode(value)?.into();
+        self.inner.broadcast(topic, payload)?;
+        Ok(())
+    }
+
+    fn decode(&self, event: BroadcastEvent) -> TypedEvent<T> {
+        match event {
+            BroadcastEvent::Received(peer, topic, id, payload) => match self.codec.decode(&payload)
+            {
+                Ok(value) => TypedEvent::Received(peer, topic, id, value),
+                Err(err) => TypedEvent::DecodeError(peer, topic, err),
+            },
+            event => TypedEvent::Event(event),
+        }
+    }
+}
+
+impl<T, C> Deref for TypedBroadcast<T, C> {
+    type Target = Broadcast;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<T, C> DerefMut for TypedBroadcast<T, C> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+impl<T, C> fmt::Debug for TypedBroadcast<T, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TypedBroadcast")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+impl<T, C> NetworkBehaviour for TypedBroadcast<T, C>
+where
+    T: Send + 'static,
+    C: PayloadCodec<T> + Send + 'static,
+{
+    type ConnectionHandler = BroadcastHandler;
+    type OutEvent = TypedEvent<T>;
+
+    fn new_handler(&mut self) -> Self::ConnectionHandler {
+        self.inner.new_handler()
+    }
+
+    fn addresses_of_peer(&mut self, peer: &PeerId) -> Vec<Multiaddr> {
+        self.inner.addresses_of_peer(peer)
+    }
+
+    fn inject_connection_established(
+        &mut self,
+        peer: &PeerId,
+        connection_id: &ConnectionId,
+        endpoint: &ConnectedPoint,
+        failed_addresses: Option<&Vec<Multiaddr>>,
+        other_established: usize,
+    ) {
+        self.inner.inject_connection_established(
+            peer,
+            connection_id,
+            endpoint,
+            failed_addresses,
+            other_established,
+        )
+    }
+
+    fn inject_connection_closed(
+        &mut self,
+        peer: &PeerId,
+        connection_id: &ConnectionId,
+        endpoint: &ConnectedPoint,
+        handler: BroadcastHandler,
+        remaining_established: usize,
+    ) {
+        self.inner.inject_connection_closed(
+            peer,
+            connection_id,
+            endpoint,
+            handler,
+            remaining_established,
+        )
+    }
+
+    fn inject_address_change(
+        &mut self,
+        peer: &PeerId,
+        connection_id: &ConnectionId,
+        old: &ConnectedPoint,
+        new: &ConnectedPoint,
+    ) {
+        self.inner
+            .inject_address_change(peer, connection_id, old, new)
+    }
+
+    fn inject_dial_failure(
+        &mut self,
+        peer: Option<PeerId>,
+        handler: BroadcastHandler,
+        error: &DialError,
+    ) {
+        self.inner.inject_dial_failure(peer, handler, error)
+    }
+
+    fn inject_listen_failure(
+        &mut self,
+        local_addr: &Multiaddr,
+        send_back_addr: &Multiaddr,
+        handler: BroadcastHandler,
+    ) {
+        self.inner
+            .inject_listen_failure(local_addr, send_back_addr, handler)
+    }
+
+    fn inject_event(&mut self, peer: PeerId, connection: ConnectionId, event: HandlerEvent) {
+        self.inner.inject_event(peer, connection, event)
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context,
+        params: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<TypedEvent<T>, BroadcastHandler>> {
+        match self.inner.poll(cx, params) {
+            Poll::Ready(action) => Poll::Ready(action.map_out(|event| self.decode(event))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{Extensions, Message};
+    use crate::tests::DummyPollParameters;
+
+    struct Utf8;
+
+    impl PayloadCodec<String> for Utf8 {
+        fn encode(&self, value: &String) -> Result<Vec<u8>, CodecError> {
+            Ok(value.as_bytes().to_vec())
+        }
+
+        fn decode(&self, bytes: &[u8]) -> Result<String, CodecError> {
+            String::from_utf8(bytes.to_vec()).map_err(CodecError::new)
+        }
+    }
+
+    #[test]
+    fn test_typed_broadcast() {
+        let topic = Topic::new(b"topic");
+        let peer = PeerId::random();
+        let mut behaviour = TypedBroadcast::new(BroadcastConfig::default(), Utf8);
+        behaviour.subscribe(topic).unwrap().detach();
+        for payload in [&b"hello"[..], &[0xff]] {
+            let msg = Message::Broadcast(topic, Extensions::default(), payload.into());
+            behaviour.inject_event(peer, ConnectionId::new(0), HandlerEvent::Rx(msg));
+        }
+
+        let mut params = DummyPollParameters(PeerId::random());
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        for _ in 0..2 {
+            assert!(matches!(
+                behaviour.poll(&mut cx, &mut params),
+                Poll::Ready(NetworkBehaviourAction::GenerateEvent(TypedEvent::Event(
+                    BroadcastEvent::LocalSubscribed(_) | BroadcastEvent::NotifiedPeers(..)
+                )))
+            ));
+        }
+        match behaviour.poll(&mut cx, &mut params) {
+            Poll::Ready(NetworkBehaviourAction::GenerateEvent(TypedEvent::Received(
+                source,
+                topic2,
+                _,
+                value,
+            ))) => {
+                assert_eq!(source, peer);
+                assert_eq!(topic2, topic);
+                assert_eq!(value, "hello");
+            }
+            _ => panic!("expected received event"),
+        }
+        assert!(matches!(
+            behaviour.poll(&mut cx, &mut params),
+            Poll::Ready(NetworkBehaviourAction::GenerateEvent(
+                TypedEvent::DecodeError(..)
+            ))
+        ));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_json() {
+        let value = vec![1u32, 2, 3];
+        let bytes = Json.encode(&value).unwrap();
+        assert_eq!(
+            PayloadCodec::<Vec<u32>>::decode(&Json, &bytes).unwrap(),
+            value
+        );
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn test_bincode() {
+        let value = vec![1u32, 2, 3];
+        let bytes = Bincode.encode(&value).unwrap();
+        assert_eq!(
+            PayloadCodec::<Vec<u32>>::decode(&Bincode, &bytes).unwrap(),
+            value
+        );
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn test_cbor() {
+        let value = vec![1u32, 2, 3];
+        let bytes = Cbor.encode(&value).unwrap();
+        assert_eq!(
+            PayloadCodec::<Vec<u32>>::decode(&Cbor, &bytes).unwrap(),
+            value
+        );
+    }
+}
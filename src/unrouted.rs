@@ -0,0 +1,79 @@
+use crate::protocol::{Message, Topic};
+use fnv::FnvHashMap;
+use instant::Instant;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Broadcasts published to topics without subscribers, held for the first
+/// subscriber with `NoPeersPolicy::Buffer`.
+#[derive(Default)]
+pub(crate) struct Unrouted {
+    max: usize,
+    ttl: Duration,
+    topics: FnvHashMap<Topic, VecDeque<(Instant, Message)>>,
+}
+
+impl Unrouted {
+    pub fn new(max: usize, ttl: Duration) -> Self {
+        Self {
+            max,
+            ttl,
+            ..Default::default()
+        }
+    }
+
+    /// Buffers a broadcast, dropping the expired ones of the topic and the
+    /// oldest one if `max` are buffered already.
+    pub fn push(&mut self, topic: Topic, msg: Message) {
+        if self.max == 0 {
+            return;
+        }
+        let ttl = self.ttl;
+        let messages = self.topics.entry(topic).or_default();
+        messages.retain(|(buffered, _)| buffered.elapsed() < ttl);
+        if messages.len() >= self.max {
+            messages.pop_front();
+        }
+        messages.push_back((Instant::now(), msg));
+    }
+
+    /// Removes the broadcasts of the topics matching `filter` that didn't
+    /// expire yet.
+    pub fn take(&mut self, filter: impl Fn(&Topic) -> bool) -> Vec<Message> {
+        let topics: Vec<_> = self.topics.keys().filter(|t| filter(t)).copied().collect();
+        let mut messages = Vec::new();
+        for topic in topics {
+            for (buffered, msg) in self.topics.remove(&topic).unwrap_or_default() {
+                if buffered.elapsed() < self.ttl {
+                    messages.push(msg);
+                }
+            }
+        }
+        messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Extensions;
+    use bytes::Bytes;
+
+    #[test]
+    fn test_unrouted() {
+        let (topic, other) = (Topic::new(b"topic"), Topic::new(b"other"));
+        let msg = |i: u8| Message::Broadcast(topic, Extensions::default(), Bytes::from(vec![i]));
+        let mut unrouted = Unrouted::new(2, Duration::from_secs(60));
+        for i in 0..3 {
+            unrouted.push(topic, msg(i));
+        }
+        unrouted.push(other, msg(3));
+        assert_eq!(unrouted.take(|t| *t == topic), vec![msg(1), msg(2)]);
+        assert!(unrouted.take(|t| *t == topic).is_empty());
+        assert_eq!(unrouted.take(|_| true), vec![msg(3)]);
+
+        let mut unrouted = Unrouted::new(2, Duration::ZERO);
+        unrouted.push(topic, msg(0));
+        assert!(unrouted.take(|_| true).is_empty());
+    }
+}
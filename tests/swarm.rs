@@ -0,0 +1,361 @@
+//! End-to-end tests of `Broadcast` in real swarms connected over the memory
+//! transport with noise and yamux.
+use bytes::Bytes;
+use futures::executor::block_on;
+use futures::future::poll_fn;
+use futures::{FutureExt, StreamExt};
+use futures_timer::Delay;
+use libp2p::core::muxing::StreamMuxerBox;
+use libp2p::core::transport::{Boxed, MemoryTransport};
+use libp2p::core::upgrade;
+use libp2p::floodsub::{self, Floodsub, FloodsubEvent};
+use libp2p::identity::Keypair;
+use libp2p::noise::{self, NoiseConfig, X25519Spec};
+use libp2p::swarm::{NetworkBehaviour, Swarm, SwarmEvent};
+use libp2p::yamux::YamuxConfig;
+use libp2p::{Multiaddr, PeerId, Transport};
+use libp2p_broadcast::{
+    Broadcast, BroadcastConfig, BroadcastError, BroadcastEvent, MessageId, Topic,
+};
+use std::task::Poll;
+use std::time::Duration;
+
+/// How long a test waits for an event before failing.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a test waits for unexpected events.
+const QUIET_PERIOD: Duration = Duration::from_millis(500);
+
+fn transport(keypair: &Keypair) -> Boxed<(PeerId, StreamMuxerBox)> {
+    let noise_keys = noise::Keypair::<X25519Spec>::new()
+        .into_authentic(keypair)
+        .unwrap();
+    MemoryTransport
+        .upgrade(upgrade::Version::V1)
+        .authenticate(NoiseConfig::xx(noise_keys).into_authenticated())
+        .multiplex(YamuxConfig::default())
+        .boxed()
+}
+
+fn swarm() -> Swarm<Broadcast> {
+    swarm_with_config(BroadcastConfig::default())
+}
+
+fn swarm_with_config(config: BroadcastConfig) -> Swarm<Broadcast> {
+    let keypair = Keypair::generate_ed25519();
+    let peer_id = keypair.public().to_peer_id();
+    Swarm::new(transport(&keypair), Broadcast::new(config), peer_id)
+}
+
+fn floodsub_swarm() -> Swarm<Floodsub> {
+    let keypair = Keypair::generate_ed25519();
+    let peer_id = keypair.public().to_peer_id();
+    Swarm::new(transport(&keypair), Floodsub::new(peer_id), peer_id)
+}
+
+/// Listens on a random memory address, returning it.
+fn listen<B: NetworkBehaviour>(swarm: &mut Swarm<B>) -> Multiaddr {
+    swarm.listen_on("/memory/0".parse().unwrap()).unwrap();
+    block_on(async {
+        loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = swarm.select_next_some().await {
+                return address;
+            }
+        }
+    })
+}
+
+/// The swarm events the tests look at.
+#[derive(Debug)]
+enum Event {
+    Behaviour(BroadcastEvent),
+    ConnectionEstablished,
+    ConnectionClosed,
+    Other,
+}
+
+/// Polls all swarms until `f` returns true for an event of the swarm with
+/// the given index.
+fn run_until(swarms: &mut [&mut Swarm<Broadcast>], mut f: impl FnMut(usize, Event) -> bool) {
+    let mut timeout = Delay::new(TIMEOUT);
+    block_on(poll_fn(|cx| {
+        if timeout.poll_unpin(cx).is_ready() {
+            panic!("timed out");
+        }
+        for (i, swarm) in swarms.iter_mut().enumerate() {
+            while let Poll::Ready(Some(event)) = swarm.poll_next_unpin(cx) {
+                let event = match event {
+                    SwarmEvent::Behaviour(event) => Event::Behaviour(event),
+                    SwarmEvent::ConnectionEstablished { .. } => Event::ConnectionEstablished,
+                    SwarmEvent::ConnectionClosed { .. } => Event::ConnectionClosed,
+                    _ => Event::Other,
+                };
+                if f(i, event) {
+                    return Poll::Ready(());
+                }
+            }
+        }
+        Poll::Pending
+    }))
+}
+
+/// Polls all swarms for `duration`, passing their events to `f`.
+fn run_for(
+    swarms: &mut [&mut Swarm<Broadcast>],
+    duration: Duration,
+    mut f: impl FnMut(usize, Event),
+) {
+    let mut quiet = Delay::new(duration);
+    block_on(poll_fn(|cx| {
+        for (i, swarm) in swarms.iter_mut().enumerate() {
+            while let Poll::Ready(Some(event)) = swarm.poll_next_unpin(cx) {
+                let event = match event {
+                    SwarmEvent::Behaviour(event) => Event::Behaviour(event),
+                    SwarmEvent::ConnectionEstablished { .. } => Event::ConnectionEstablished,
+                    SwarmEvent::ConnectionClosed { .. } => Event::ConnectionClosed,
+                    _ => Event::Other,
+                };
+                f(i, event);
+            }
+        }
+        quiet.poll_unpin(cx)
+    }))
+}
+
+/// Polls all swarms until swarm `i` emits `expected`.
+fn expect(swarms: &mut [&mut Swarm<Broadcast>], i: usize, expected: BroadcastEvent) {
+    run_until(
+        swarms,
+        |j, event| matches!(event, Event::Behaviour(event) if i == j && event == expected),
+    );
+}
+
+/// Connects `b` to `a`, waiting until both see the connection.
+fn connect(a: &mut Swarm<Broadcast>, b: &mut Swarm<Broadcast>, addr: &Multiaddr) {
+    b.dial(addr.clone()).unwrap();
+    let mut established = [false; 2];
+    run_until(&mut [a, b], |i, event| {
+        if let Event::ConnectionEstablished = event {
+            established[i] = true;
+        }
+        established == [true, true]
+    });
+}
+
+#[test]
+fn subscribe_broadcast_unsubscribe() {
+    let topic = Topic::new(b"topic");
+    let msg: Bytes = Bytes::from_static(b"msg");
+    let (mut a, mut b) = (swarm(), swarm());
+    let (a_id, b_id) = (*a.local_peer_id(), *b.local_peer_id());
+    let addr = listen(&mut a);
+    connect(&mut a, &mut b, &addr);
+
+    a.behaviour_mut().subscribe(topic).unwrap().detach();
+    expect(
+        &mut [&mut a, &mut b],
+        1,
+        BroadcastEvent::Subscribed(a_id, topic),
+    );
+    b.behaviour_mut().subscribe(topic).unwrap().detach();
+    expect(
+        &mut [&mut a, &mut b],
+        0,
+        BroadcastEvent::Subscribed(b_id, topic),
+    );
+
+    a.behaviour_mut().broadcast(&topic, msg.clone()).unwrap();
+    expect(
+        &mut [&mut a, &mut b],
+        1,
+        BroadcastEvent::Received(a_id, topic, MessageId::new(&topic, &msg), msg.clone()),
+    );
+
+    b.behaviour_mut().unsubscribe(&topic).unwrap();
+    expect(
+        &mut [&mut a, &mut b],
+        0,
+        BroadcastEvent::Unsubscribed(b_id, topic),
+    );
+    assert_eq!(
+        a.behaviour_mut().broadcast(&topic, msg),
+        Err(BroadcastError::NoPeers)
+    );
+}
+
+#[test]
+fn multiple_connections() {
+    let topic = Topic::new(b"topic");
+    let (mut a, mut b) = (swarm(), swarm());
+    let (a_id, b_id) = (*a.local_peer_id(), *b.local_peer_id());
+    let addr = listen(&mut a);
+    b.behaviour_mut().subscribe(topic).unwrap().detach();
+    connect(&mut a, &mut b, &addr);
+    connect(&mut a, &mut b, &addr);
+    expect(
+        &mut [&mut a, &mut b],
+        0,
+        BroadcastEvent::Subscribed(b_id, topic),
+    );
+
+    // Each message is received once although both connections are open.
+    let msgs: Vec<Bytes> = (0..3u8).map(|i| Bytes::from(vec![i])).collect();
+    for msg in &msgs {
+        a.behaviour_mut().broadcast(&topic, msg.clone()).unwrap();
+    }
+    let mut received = Vec::new();
+    run_until(&mut [&mut a, &mut b], |_, event| {
+        if let Event::Behaviour(BroadcastEvent::Received(peer, _, _, msg)) = event {
+            assert_eq!(peer, a_id);
+            received.push(msg);
+        }
+        received.len() == msgs.len()
+    });
+    // Duplicates would arrive shortly after.
+    run_for(&mut [&mut a, &mut b], QUIET_PERIOD, |_, event| {
+        if let Event::Behaviour(BroadcastEvent::Received(_, _, _, msg)) = event {
+            received.push(msg);
+        }
+    });
+    received.sort();
+    assert_eq!(received, msgs);
+}
+
+#[test]
+fn reconnection() {
+    let topic = Topic::new(b"topic");
+    let msg: Bytes = Bytes::from_static(b"msg");
+    let (mut a, mut b) = (swarm(), swarm());
+    let (a_id, b_id) = (*a.local_peer_id(), *b.local_peer_id());
+    let addr = listen(&mut a);
+    b.behaviour_mut().subscribe(topic).unwrap().detach();
+    connect(&mut a, &mut b, &addr);
+    expect(
+        &mut [&mut a, &mut b],
+        0,
+        BroadcastEvent::Subscribed(b_id, topic),
+    );
+
+    b.disconnect_peer_id(a_id).unwrap();
+    run_until(&mut [&mut a, &mut b], |i, event| {
+        i == 0 && matches!(event, Event::ConnectionClosed)
+    });
+    assert_eq!(
+        a.behaviour_mut().broadcast(&topic, msg.clone()),
+        Err(BroadcastError::NoPeers)
+    );
+
+    // The subscription is announced again on the new connection.
+    connect(&mut a, &mut b, &addr);
+    expect(
+        &mut [&mut a, &mut b],
+        0,
+        BroadcastEvent::Subscribed(b_id, topic),
+    );
+    a.behaviour_mut().broadcast(&topic, msg.clone()).unwrap();
+    expect(
+        &mut [&mut a, &mut b],
+        1,
+        BroadcastEvent::Received(a_id, topic, MessageId::new(&topic, &msg), msg),
+    );
+}
+
+/// Polls a broadcast swarm and floodsub swarms until `f` returns true for
+/// an event of the broadcast swarm, or of the floodsub swarm with the given
+/// index.
+fn run_with_floodsub(
+    a: &mut Swarm<Broadcast>,
+    floodsub: &mut [&mut Swarm<Floodsub>],
+    mut f: impl FnMut(Result<BroadcastEvent, (usize, FloodsubEvent)>) -> bool,
+) {
+    let mut timeout = Delay::new(TIMEOUT);
+    block_on(poll_fn(|cx| {
+        if timeout.poll_unpin(cx).is_ready() {
+            panic!("timed out");
+        }
+        while let Poll::Ready(Some(event)) = a.poll_next_unpin(cx) {
+            if let SwarmEvent::Behaviour(event) = event {
+                if f(Ok(event)) {
+                    return Poll::Ready(());
+                }
+            }
+        }
+        for (i, swarm) in floodsub.iter_mut().enumerate() {
+            while let Poll::Ready(Some(event)) = swarm.poll_next_unpin(cx) {
+                if let SwarmEvent::Behaviour(event) = event {
+                    if f(Err((i, event))) {
+                        return Poll::Ready(());
+                    }
+                }
+            }
+        }
+        Poll::Pending
+    }))
+}
+
+#[test]
+fn floodsub_interop() {
+    let topic = Topic::new(b"topic");
+    let floodsub_topic = floodsub::Topic::new("topic");
+    let msg: Bytes = Bytes::from_static(b"msg");
+    let mut a = swarm_with_config(BroadcastConfig::default().floodsub_compat());
+    let (mut f1, mut f2) = (floodsub_swarm(), floodsub_swarm());
+    let (a_id, f1_id, f2_id) = (*a.local_peer_id(), *f1.local_peer_id(), *f2.local_peer_id());
+
+    // f2 only reaches `a` through f1.
+    let addr = listen(&mut f1);
+    f1.behaviour_mut().add_node_to_partial_view(a_id);
+    f1.behaviour_mut().add_node_to_partial_view(f2_id);
+    f2.behaviour_mut().add_node_to_partial_view(f1_id);
+    f1.behaviour_mut().subscribe(floodsub_topic.clone());
+    f2.behaviour_mut().subscribe(floodsub_topic.clone());
+    a.behaviour_mut().subscribe(topic).unwrap().detach();
+    a.dial(addr.clone()).unwrap();
+    f2.dial(addr).unwrap();
+    let mut subscribed = [false; 3];
+    run_with_floodsub(&mut a, &mut [&mut f1, &mut f2], |event| {
+        match event {
+            Ok(BroadcastEvent::Subscribed(peer, _)) if peer == f1_id => subscribed[0] = true,
+            Err((0, FloodsubEvent::Subscribed { peer_id, .. })) if peer_id == a_id => {
+                subscribed[1] = true
+            }
+            Err((1, FloodsubEvent::Subscribed { peer_id, .. })) if peer_id == f1_id => {
+                subscribed[2] = true
+            }
+            _ => {}
+        }
+        subscribed == [true; 3]
+    });
+
+    // The same payload published by two peers is two messages, each
+    // received from its publisher.
+    f1.behaviour_mut()
+        .publish(floodsub_topic.clone(), msg.to_vec());
+    f2.behaviour_mut()
+        .publish(floodsub_topic.clone(), msg.to_vec());
+    let mut sources = Vec::new();
+    run_with_floodsub(&mut a, &mut [&mut f1, &mut f2], |event| {
+        if let Ok(BroadcastEvent::Received(source, received_topic, _, received)) = event {
+            assert_eq!((received_topic, &received), (topic, &msg));
+            sources.push(source);
+        }
+        sources.len() == 2
+    });
+    sources.sort();
+    let mut publishers = vec![f1_id, f2_id];
+    publishers.sort();
+    assert_eq!(sources, publishers);
+
+    // Unsigned broadcasts carry their publisher and a sequence number, which
+    // tells repeated payloads apart.
+    a.behaviour_mut().broadcast(&topic, msg.clone()).unwrap();
+    a.behaviour_mut().broadcast(&topic, msg.clone()).unwrap();
+    let mut received = 0;
+    run_with_floodsub(&mut a, &mut [&mut f1, &mut f2], |event| {
+        if let Err((0, FloodsubEvent::Message(message))) = event {
+            assert_eq!((message.source, message.data), (a_id, msg.to_vec()));
+            received += 1;
+        }
+        received == 2
+    });
+}